@@ -1,5 +1,8 @@
+use std::path::PathBuf;
+
 use clap::Parser;
-use dw_client::{error::ClientError, LogHandler};
+use dw_client::LogHandler;
+use metrics_types::format_env_name;
 
 #[derive(Parser)]
 struct AgentArgs {
@@ -15,41 +18,31 @@ struct AgentArgs {
     #[clap(short = 'd', long = "database")]
     env_name: String,
 
-    /// -- might be deleted, split database by date
-    #[clap(long = "split")]
+    /// deprecated, no effect: tables are split by date on the server, see dw_server `--split`
+    #[clap(long = "split", hide = true)]
     split: bool,
 
     /// use local ip
     #[clap(long = "local")]
     local: bool,
-}
-
-fn format_env_name(input: String) -> Result<String, ClientError> {
-    let mut input = input;
-    if !input.is_ascii() {
-        return Err(ClientError::CustomError("env_name contain non ascii character".into()));
-    }
-    input = input
-        .chars()
-        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
-        .collect();
 
-    if input.len() > 52 {
-        input = String::from(&input[0..25]) + "__" + &input[input.len() - 25..];
-    }
-
-    Ok(input)
+    /// last config got from the proxy's /api/agent-config, used while the proxy is unavailable
+    #[clap(long = "config_cache", default_value = "./agent_config.json")]
+    config_cache: PathBuf,
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     let args = AgentArgs::parse();
+    if args.split {
+        println!("--split is deprecated and ignored, partitioning is configured on dw_server_consumer");
+    }
 
     let server_address = args.server_address;
     let log_file = args.log_file;
-    let env_name = format_env_name(args.env_name)?;
+    let env_name = format_env_name(&args.env_name)?;
 
-    let log_handler = LogHandler::new(server_address, args.local, log_file, env_name).await?;
+    let log_handler = LogHandler::new(server_address, args.local, log_file, env_name, args.config_cache).await?;
     log_handler.start().await?;
 
     #[allow(unreachable_code)]
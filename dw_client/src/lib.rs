@@ -1,8 +1,8 @@
 #![feature(never_type)]
-#![feature(stmt_expr_attributes)]
 
 mod client_status;
 pub mod error;
 pub mod log_handler;
+mod remote_config;
 
 pub use log_handler::LogHandler;
@@ -1,9 +1,10 @@
 use crate::client_status::ClientStatusInfo;
 use crate::error::ClientError;
+use crate::remote_config::RemoteConfig;
 use concurrent_queue::ConcurrentQueue;
 use hyper::{Body, Client, Method, Request};
 use lazy_static::lazy_static;
-use std::{str::FromStr, sync::Arc, time::Duration};
+use std::{path::PathBuf, str::FromStr, sync::Arc, time::Duration};
 use tokio::{
     fs::File,
     io::{AsyncBufReadExt, BufReader},
@@ -22,6 +23,19 @@ pub struct LogHandler {
     log_path: String,
     _env_name: String,
     meta: MetaInfos,
+    remote_config: RemoteConfig,
+}
+
+/// Wait until `queue` holds a batch of 10, or `flush_interval` passed.
+async fn wait_batch(queue: &ConcurrentQueue<String>, flush_interval: Duration) {
+    let deadline = tokio::time::Instant::now() + flush_interval;
+    while queue.len() < 10 {
+        let left = deadline.saturating_duration_since(tokio::time::Instant::now());
+        if left.is_zero() {
+            break;
+        }
+        tokio::time::sleep(left.min(Duration::from_millis(100))).await;
+    }
 }
 
 impl LogHandler {
@@ -30,11 +44,14 @@ impl LogHandler {
         self_address_use_local: bool,
         log_path: String,
         env_name: String,
+        config_cache: PathBuf,
     ) -> Result<Self, ClientError> {
+        let meta = MetaInfos::new(server_ip_port, self_address_use_local, env_name.clone()).await?;
         Ok(Self {
             log_path,
-            _env_name: env_name.clone(),
-            meta: MetaInfos::new(server_ip_port, self_address_use_local, env_name).await?,
+            _env_name: env_name,
+            remote_config: RemoteConfig::new(&meta, config_cache),
+            meta,
         })
     }
 
@@ -59,6 +76,9 @@ impl LogHandler {
             Err(e) = self.dump_client_status(client_status.clone()) => {
                 Err(e)
             },
+            Err(e) = self.remote_config.sync() => {
+                Err(e)
+            },
         }
     }
 
@@ -92,7 +112,7 @@ impl LogHandler {
                     }
                     Err(e) => {
                         // error while monitor file, should be bug or file io error?
-                        println!("ERROR: loop_monitor_file {}", e.to_string());
+                        println!("ERROR: loop_monitor_file {}", e);
                         tokio::time::sleep(Duration::from_secs(1)).await;
                     }
                 },
@@ -134,8 +154,6 @@ impl LogHandler {
                         let file_end_pos = buf_reader.seek(SeekFrom::End(0)).await?;
                         client_status.lock().await.update_file_info_end(file_end_pos);
                         // println!("file_end_pos:{}", file_end_pos);
-                        #[allow(unused_assignments)]
-                        file_might_stop_count = 0;
                         match file_end_pos < new_pos {
                             true => {
                                 // re-begin read file from begining.
@@ -162,12 +180,8 @@ impl LogHandler {
         client_status: Arc<Mutex<ClientStatusInfo>>,
     ) -> Result<!, ClientError> {
         loop {
-            let mut cnt = 0;
-            while cnt < 10 && metrics_log_queue.len() < 10 {
-                cnt += 1;
-                tokio::time::sleep(Duration::from_millis(100)).await;
-            }
-            // 1s timeout or len > 10
+            // flush interval timeout or len > 10
+            wait_batch(&metrics_log_queue, self.remote_config.config().flush_interval()).await;
             match metrics_log_queue.pop() {
                 Ok(log) => {
                     // println!("Got : {}", log);
@@ -210,12 +224,8 @@ impl LogHandler {
         client_status: Arc<Mutex<ClientStatusInfo>>,
     ) -> Result<!, ClientError> {
         loop {
-            let mut cnt = 0;
-            while cnt < 10 && metrics_send_queue.len() < 10 {
-                cnt += 1;
-                tokio::time::sleep(Duration::from_millis(100)).await;
-            }
-            // 1s timeout or len > 10
+            // flush interval timeout or len > 10
+            wait_batch(&metrics_send_queue, self.remote_config.config().flush_interval()).await;
 
             match metrics_send_queue.pop() {
                 Ok(send_data) => {
@@ -260,6 +270,10 @@ impl LogHandler {
             .method(Method::POST)
             .uri(self.meta.alarm_api())
             .header("content-type", "application/json")
+            // lets the proxy list this agent at /api/agents.
+            .header("x-dw-agent-version", env!("CARGO_PKG_VERSION"))
+            .header("x-dw-env", &self.meta.env_name)
+            .header("x-dw-node", self.meta.node_ip_port.to_string())
             .body(Body::from(data))?;
         match Client::new().request(req).await {
             Ok(resp) => {
@@ -268,7 +282,7 @@ impl LogHandler {
             }
             Err(e) => {
                 client_status.lock().await.net_queue_count(false);
-                println!("send alarm err: {}", e.to_string());
+                println!("send alarm err: {}", e);
             }
         }
         Ok(())
@@ -292,8 +306,14 @@ impl LogHandler {
         if let Some((fulllog, r#type)) = match_result {
             let type_str = r#type.as_str();
             let fulllog_str = fulllog.as_str();
-            if let Ok(json_value) = json::parse(fulllog_str) {
+            if let Ok(mut json_value) = json::parse(fulllog_str) {
                 if let Ok(alarm_type) = MetricsAlarmType::from_str(type_str) {
+                    // drop and relabel rules of the remote config.
+                    if let (Some(category), Some(tag)) = (json_value["category"].as_str(), json_value["tag"].as_str()) {
+                        let (category, tag) = self.remote_config.config().apply(alarm_type, category, tag)?;
+                        json_value["category"] = category.into();
+                        json_value["tag"] = tag.into();
+                    }
                     match alarm_type {
                         MetricsAlarmType::Counter => {
                             let wrapper_unit = CounterUnit::handle_log(json_value, &self.meta)?;
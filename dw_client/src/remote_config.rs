@@ -0,0 +1,163 @@
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use hyper::{header, Body, Client, Method, Request, StatusCode};
+use metrics_types::agent_config::{AgentConfig, VersionedAgentConfig};
+use metrics_types::MetaInfos;
+
+use crate::error::ClientError;
+
+/// how long the proxy may hold a request for a change.
+const LONG_POLL_WAIT: Duration = Duration::from_secs(30);
+/// on top of the long-poll wait, before a request is given up.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+/// before fetching again after a failure.
+const RETRY_INTERVAL: Duration = Duration::from_secs(30);
+
+/// #### RemoteConfig
+///
+/// The `AgentConfig` of this agent's env, long-polled from the proxy and cached in `cache_path`, used as is
+/// while the proxy can't be reached.
+pub(crate) struct RemoteConfig {
+    api: String,
+    env: String,
+    cache_path: PathBuf,
+    /// `None` until one is fetched or read from the cache.
+    version: RwLock<Option<String>>,
+    config: RwLock<Arc<AgentConfig>>,
+}
+
+impl RemoteConfig {
+    /// Start from the cached config of `meta`'s env, if any.
+    pub fn new(meta: &MetaInfos, cache_path: PathBuf) -> Self {
+        let remote = RemoteConfig {
+            api: meta.agent_config_api(),
+            env: meta.env_name.clone(),
+            cache_path,
+            version: RwLock::new(None),
+            config: RwLock::new(Arc::new(AgentConfig::default())),
+        };
+        match std::fs::read(&remote.cache_path) {
+            Ok(cached) => match serde_json::from_slice::<VersionedAgentConfig>(&cached) {
+                Ok(cached) if cached.env == remote.env => {
+                    println!("agent config version {} from cache", cached.version);
+                    remote.apply(cached);
+                }
+                Ok(_) => println!("agent config cache of another env, ignored"),
+                Err(e) => println!("agent config cache error {}, ignored", e),
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => println!("agent config cache error {}, ignored", e),
+        }
+        remote
+    }
+
+    /// The config in use now.
+    pub fn config(&self) -> Arc<AgentConfig> {
+        self.config.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    fn version(&self) -> Option<String> {
+        self.version.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    fn apply(&self, versioned: VersionedAgentConfig) {
+        *self.config.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(versioned.config);
+        *self.version.write().unwrap_or_else(|e| e.into_inner()) = Some(versioned.version);
+    }
+
+    /// Fetch the config once, `wait` for a change of the current version.
+    async fn fetch(&self, wait: Duration) -> Result<(), ClientError> {
+        let mut req = Request::builder()
+            .method(Method::GET)
+            .uri(format!("{}&wait_secs={}", self.api, wait.as_secs()));
+        if let Some(version) = self.version() {
+            req = req.header(header::IF_NONE_MATCH, format!("\"{}\"", version));
+        }
+        let resp = Client::new().request(req.body(Body::empty())?).await?;
+        match resp.status() {
+            StatusCode::NOT_MODIFIED => Ok(()),
+            StatusCode::OK => {
+                let body = hyper::body::to_bytes(resp.into_body()).await?;
+                let versioned = serde_json::from_slice::<VersionedAgentConfig>(&body)
+                    .map_err(|e| ClientError::HttpError(format!("agent config {}", e)))?;
+                println!("agent config version {} applied", versioned.version);
+                // the config applies even if the cache can't be written.
+                if let Err(e) = self.write_cache(&body).await {
+                    println!("agent config cache write error {}", e);
+                }
+                self.apply(versioned);
+                Ok(())
+            }
+            status => Err(ClientError::HttpError(format!("agent config status {}", status))),
+        }
+    }
+
+    async fn write_cache(&self, body: &[u8]) -> Result<(), ClientError> {
+        let tmp = self.cache_path.with_extension("tmp");
+        tokio::fs::write(&tmp, body).await?;
+        tokio::fs::rename(&tmp, &self.cache_path).await?;
+        Ok(())
+    }
+
+    /// Fetch the config at once, then long-poll the proxy for changes, forever.
+    pub async fn sync(&self) -> Result<!, ClientError> {
+        let mut wait = Duration::ZERO;
+        loop {
+            let error = match tokio::time::timeout(wait + FETCH_TIMEOUT, self.fetch(wait)).await {
+                Ok(Ok(())) => {
+                    wait = LONG_POLL_WAIT;
+                    continue;
+                }
+                Ok(Err(e)) => e.to_string(),
+                Err(_) => String::from("timeout"),
+            };
+            println!("agent config fetch error {}, keep version {:?}", error, self.version());
+            tokio::time::sleep(RETRY_INTERVAL).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_remote_config_cache() {
+        let meta = tokio_test::block_on(MetaInfos::new("127.0.0.1:3000".into(), true, "prod".into())).unwrap();
+        let dir = std::env::temp_dir().join(format!("dw_agent_config_test_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let cache_path = dir.join("agent_config.json");
+
+        let remote = RemoteConfig::new(&meta, cache_path.clone());
+        assert_eq!(remote.api, "http://127.0.0.1:3000/api/agent-config?env=prod");
+        assert_eq!((remote.version(), remote.config().flush_interval_ms), (None, None));
+
+        let cached = VersionedAgentConfig {
+            env: "prod".into(),
+            version: "v1".into(),
+            config: AgentConfig {
+                flush_interval_ms: Some(5000),
+                ..Default::default()
+            },
+        };
+        std::fs::write(&cache_path, serde_json::to_vec(&cached).unwrap()).unwrap();
+        let remote = RemoteConfig::new(&meta, cache_path.clone());
+        assert_eq!(remote.version().as_deref(), Some("v1"));
+        assert_eq!(remote.config().flush_interval(), Duration::from_secs(5));
+
+        // a cache of another env is not used.
+        std::fs::write(
+            &cache_path,
+            serde_json::to_vec(&VersionedAgentConfig {
+                env: "dev".into(),
+                ..cached
+            })
+            .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(RemoteConfig::new(&meta, cache_path).version(), None);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
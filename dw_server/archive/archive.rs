@@ -0,0 +1,37 @@
+use dw_server::archive::{self, Manifest};
+use dw_server::config::{ArchiveCommand, ArchiveToolConfig, MysqlPoolConfig, ServerConfig};
+use dw_server::store::open_store;
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let config = ArchiveToolConfig::load()?;
+    let store = open_store(&config.store, &config.mysql_url(), &MysqlPoolConfig::default()).await?;
+
+    match config.command.clone().unwrap() {
+        ArchiveCommand::Export {
+            env,
+            since,
+            until,
+            format,
+            out,
+        } => {
+            let db_name = config.database.database_name(&env)?;
+            let manifest = archive::export(store.as_ref(), &db_name, &env, &out, format, since, until).await?;
+            println!(
+                "exported {} rows of {} in {} files to {}",
+                manifest.rows(),
+                db_name,
+                manifest.files.len(),
+                out.display()
+            );
+        }
+        ArchiveCommand::Import { manifest, env } => {
+            let dir = manifest.parent().map(|p| p.to_path_buf()).unwrap_or_default();
+            let manifest = Manifest::read(&manifest)?;
+            let db_name = config.database.database_name(env.as_deref().unwrap_or(&manifest.env))?;
+            let rows = archive::import(store.as_ref(), &db_name, &dir, &manifest).await?;
+            println!("imported {} rows into {}", rows, db_name);
+        }
+    }
+    Ok(())
+}
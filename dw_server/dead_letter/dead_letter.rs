@@ -0,0 +1,105 @@
+use std::collections::HashMap;
+use std::io::Read;
+use std::sync::Arc;
+
+use dw_server::config::{DeadLetterCommand, DeadLetterToolConfig, ServerConfig, StreamConfig};
+use dw_server::dead_letter::{open_dead_letter, DeadLetterStore};
+use dw_server::error::ServerError;
+use dw_server::queue::{open_queue, MetricsQueue};
+use metrics_types::alarm_wrapper::AlarmWrapper;
+use metrics_types::{CounterUnit, FlowUnit, MetricsAlarmType, TimerUnit};
+
+/// dead letters handled at a time by `replay --all`.
+const REPLAY_BATCH_SIZE: usize = 100;
+
+/// Check `data` is a metrics json of `source` type, the consumer would dead-letter it again otherwise.
+fn check_data(source: MetricsAlarmType, data: &str) -> Result<(), ServerError> {
+    let alarm_type = match source {
+        MetricsAlarmType::Counter => serde_json::from_str::<AlarmWrapper<CounterUnit>>(data).map(|w| w.alarm_type),
+        MetricsAlarmType::Timer => serde_json::from_str::<AlarmWrapper<TimerUnit>>(data).map(|w| w.alarm_type),
+        MetricsAlarmType::Flow => serde_json::from_str::<AlarmWrapper<FlowUnit>>(data).map(|w| w.alarm_type),
+        MetricsAlarmType::Invalid => return Err(ServerError::QueueError("invalid source".into())),
+    }
+    .map_err(|e| ServerError::QueueError(format!("bad metrics json: {}", e)))?;
+    match alarm_type == source {
+        true => Ok(()),
+        false => Err(ServerError::QueueError(format!(
+            "alarm_type {} is not {}",
+            alarm_type, source
+        ))),
+    }
+}
+
+/// Push `data` of dead letters `ids` to the metrics queue, then remove them.
+async fn requeue(
+    store: &Arc<dyn DeadLetterStore>,
+    queue: &Arc<dyn MetricsQueue>,
+    source: MetricsAlarmType,
+    ids: Vec<String>,
+    data: Vec<String>,
+) -> Result<(), ServerError> {
+    queue.push(&HashMap::from([(source, data)])).await?;
+    store.remove(&source, &ids).await?;
+    println!("requeued {} dead letters", ids.len());
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let config = DeadLetterToolConfig::load()?;
+    let store = open_dead_letter(&config.dead_letter, &config.redis).await?;
+
+    match config.command.clone().unwrap() {
+        DeadLetterCommand::List { source, limit } => {
+            for (id, letter) in store.list(&source, limit).await? {
+                println!(
+                    "{} time:{} env:{} error:{}\n    {}",
+                    id, letter.time, letter.env, letter.error, letter.data
+                );
+            }
+        }
+        DeadLetterCommand::Replay { source, all, ids } => {
+            let queue = open_queue(&config.queue, &config.redis, &StreamConfig::default()).await?;
+            if all {
+                loop {
+                    let letters = store.list(&source, REPLAY_BATCH_SIZE).await?;
+                    if letters.is_empty() {
+                        break;
+                    }
+                    let (ids, data) = letters.into_iter().map(|(id, l)| (id, l.data)).unzip();
+                    requeue(&store, &queue, source, ids, data).await?;
+                }
+            } else {
+                let (ids, data) = store
+                    .list(&source, usize::MAX)
+                    .await?
+                    .into_iter()
+                    .filter(|(id, _)| ids.contains(id))
+                    .map(|(id, l)| (id, l.data))
+                    .unzip();
+                requeue(&store, &queue, source, ids, data).await?;
+            }
+        }
+        DeadLetterCommand::Fix { source, id, data } => {
+            let data = match data.as_str() {
+                "-" => {
+                    let mut buf = String::new();
+                    std::io::stdin().read_to_string(&mut buf)?;
+                    buf.trim().to_string()
+                }
+                _ => data,
+            };
+            check_data(source, &data)?;
+            if !store.list(&source, usize::MAX).await?.iter().any(|(i, _)| *i == id) {
+                return Err(ServerError::QueueError(format!("dead letter {} not found", id)).into());
+            }
+            let queue = open_queue(&config.queue, &config.redis, &StreamConfig::default()).await?;
+            requeue(&store, &queue, source, vec![id], vec![data]).await?;
+        }
+        DeadLetterCommand::Remove { source, ids } => {
+            store.remove(&source, &ids).await?;
+            println!("removed {} dead letters", ids.len());
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,45 @@
+use dw_server::config::{MigrateToolConfig, MysqlPoolConfig, ServerConfig, SplitMode};
+use dw_server::mysql_conn::MysqlPool;
+use dw_server::{migration, partition};
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let config = MigrateToolConfig::load()?;
+    let pool = MysqlPool::new(&config.mysql_url(), &MysqlPoolConfig::default())?;
+
+    let db_names = match config.all {
+        true => migration::metrics_databases(&pool)
+            .await?
+            .into_iter()
+            .filter(|db| db.starts_with(config.database.prefix()) && db.ends_with(config.database.suffix()))
+            .collect(),
+        false => config
+            .envs
+            .iter()
+            .map(|env| config.database.database_name(env))
+            .collect::<Result<Vec<_>, _>>()?,
+    };
+
+    for db_name in db_names {
+        let pending = migration::migrate_database(&pool, &db_name, config.dry_run).await?;
+        match (pending.is_empty(), config.dry_run) {
+            (true, _) => println!("{}: up to date", db_name),
+            (false, true) => println!("{}: {} pending migrations", db_name, pending.len()),
+            (false, false) => println!("{}: applied {} migrations", db_name, pending.len()),
+        }
+        pending.iter().for_each(|p| println!("    {}", p));
+
+        let split = config.database.split();
+        if config.dry_run || split == SplitMode::None {
+            continue;
+        }
+        let partitioned = partition::partition_database(&pool, &db_name, split, config.database.split_ahead()).await?;
+        for (table, created) in partitioned {
+            if !created.is_empty() {
+                println!("{}: {} created {} partitions", db_name, table, created.len());
+            }
+        }
+    }
+    pool.disconnect().await?;
+    Ok(())
+}
@@ -0,0 +1,33 @@
+use dw_server::config::{MysqlPoolConfig, RollupToolConfig, ServerConfig};
+use dw_server::mysql_conn::MysqlPool;
+use dw_server::{migration, rollup};
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let config = RollupToolConfig::load()?;
+    let pool = MysqlPool::new(&config.mysql_url(), &MysqlPoolConfig::default())?;
+    let until = config.until.unwrap_or_else(|| chrono::Utc::now().timestamp());
+
+    let db_names = match config.all {
+        true => migration::metrics_databases(&pool)
+            .await?
+            .into_iter()
+            .filter(|db| config.database.env_of(db).is_some())
+            .collect(),
+        false => config
+            .envs
+            .iter()
+            .map(|env| config.database.database_name(env))
+            .collect::<Result<Vec<_>, _>>()?,
+    };
+
+    for db_name in db_names {
+        // rollup tables come with the migrations.
+        migration::migrate_database(&pool, &db_name, false).await?;
+        for (table, days) in rollup::backfill_database(&pool, &db_name, config.since, until).await? {
+            println!("{}: {} recomputed rollups of {} days", db_name, table, days);
+        }
+    }
+    pool.disconnect().await?;
+    Ok(())
+}
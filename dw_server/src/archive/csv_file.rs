@@ -0,0 +1,59 @@
+use std::path::Path;
+
+use metrics_types::sql::{SqlType, SqlValue, TableSchema};
+
+use super::archive_error;
+use crate::error::ServerError;
+
+/// Write `rows` of `table` to csv file `path`, with a header of the column names.
+pub(super) fn write(path: &Path, table: &TableSchema, rows: &[Vec<SqlValue>]) -> Result<(), ServerError> {
+    let mut writer = csv::Writer::from_path(path).map_err(archive_error)?;
+    writer.write_record(table.columns).map_err(archive_error)?;
+    for row in rows {
+        writer
+            .write_record(row.iter().map(|value| match value {
+                SqlValue::Int(v) => v.to_string(),
+                SqlValue::UInt(v) => v.to_string(),
+                SqlValue::Double(v) => v.to_string(),
+                SqlValue::Text(v) => v.clone(),
+            }))
+            .map_err(archive_error)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Rows of `table` in csv file `path`, values in `table.columns` order.
+pub(super) fn read(path: &Path, table: &TableSchema) -> Result<Vec<Vec<SqlValue>>, ServerError> {
+    let mut reader = csv::Reader::from_path(path).map_err(archive_error)?;
+    if reader
+        .headers()
+        .map_err(archive_error)?
+        .iter()
+        .ne(table.columns.iter().copied())
+    {
+        return Err(archive_error(format!(
+            "{} is not an archive of {}",
+            path.display(),
+            table.name
+        )));
+    }
+    reader
+        .records()
+        .map(|record| {
+            let record = record.map_err(archive_error)?;
+            record
+                .iter()
+                .zip(table.types)
+                .map(|(field, sql_type)| {
+                    match sql_type {
+                        SqlType::Int | SqlType::BigInt => field.parse().ok().map(SqlValue::Int),
+                        SqlType::Double => field.parse().ok().map(SqlValue::Double),
+                        SqlType::Varchar(_) => Some(SqlValue::Text(field.to_string())),
+                    }
+                    .ok_or_else(|| archive_error(format!("{}: {} is not {:?}", path.display(), field, sql_type)))
+                })
+                .collect()
+        })
+        .collect()
+}
@@ -0,0 +1,302 @@
+mod csv_file;
+mod parquet_file;
+
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+
+use chrono::{TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+
+use metrics_types::sql::{SqlValue, TableSchema};
+
+use crate::config::ArchiveFormat;
+use crate::error::ServerError;
+use crate::store::{metrics_tables, MetricsStore};
+
+pub const MANIFEST_FILE: &str = "manifest.json";
+const DAY_SECS: i64 = 86400;
+/// rows inserted at a time by `import`.
+const IMPORT_BATCH_ROWS: usize = 1000;
+
+fn archive_error(e: impl Display) -> ServerError {
+    ServerError::ArchiveError(e.to_string())
+}
+
+fn integer(value: Option<&SqlValue>) -> Option<i64> {
+    match value {
+        Some(SqlValue::Int(v)) => Some(*v),
+        Some(SqlValue::UInt(v)) => i64::try_from(*v).ok(),
+        _ => None,
+    }
+}
+
+/// One exported file, rows of `table` sent on UTC `day`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ArchiveFile {
+    pub table: String,
+    /// `%Y-%m-%d`
+    pub day: String,
+    /// relative to the manifest.
+    pub path: PathBuf,
+    pub rows: u64,
+}
+
+/// `manifest.json` of an archive directory, written once all its files are written and verified.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub env: String,
+    pub format: ArchiveFormat,
+    /// rows sent in `[since, until)`.
+    pub since: i64,
+    pub until: i64,
+    pub exported_at: i64,
+    pub files: Vec<ArchiveFile>,
+}
+
+impl Manifest {
+    pub fn rows(&self) -> u64 {
+        self.files.iter().map(|f| f.rows).sum()
+    }
+
+    pub fn read(path: &Path) -> Result<Self, ServerError> {
+        serde_json::from_slice(&std::fs::read(path)?).map_err(archive_error)
+    }
+}
+
+/// UTC days overlapping `[since, until)`: name, and the part of the range in it.
+fn days(since: i64, until: i64) -> Vec<(String, i64, i64)> {
+    let mut days = Vec::new();
+    let mut start = since.div_euclid(DAY_SECS) * DAY_SECS;
+    while start < until && since < until {
+        let end = start + DAY_SECS;
+        let day = Utc.timestamp_opt(start, 0).unwrap().format("%Y-%m-%d").to_string();
+        days.push((day, start.max(since), end.min(until)));
+        start = end;
+    }
+    days
+}
+
+fn write_file(
+    path: &Path,
+    format: ArchiveFormat,
+    table: &TableSchema,
+    rows: &[Vec<SqlValue>],
+) -> Result<(), ServerError> {
+    match format {
+        ArchiveFormat::Parquet => parquet_file::write(path, table, rows),
+        ArchiveFormat::Csv => csv_file::write(path, table, rows),
+    }
+}
+
+fn read_file(path: &Path, format: ArchiveFormat, table: &TableSchema) -> Result<Vec<Vec<SqlValue>>, ServerError> {
+    match format {
+        ArchiveFormat::Parquet => parquet_file::read(path, table),
+        ArchiveFormat::Csv => csv_file::read(path, table),
+    }
+}
+
+/// Export rows of env database `db_name` sent in `[since, until)` to directory `out`,
+/// in `<table>/<day>.<format>` files. Days without rows get no file.
+///
+/// Each file is read back and its row count checked against the store before the manifest is written,
+/// so an archive having a manifest is complete.
+pub async fn export(
+    store: &dyn MetricsStore,
+    db_name: &str,
+    env: &str,
+    out: &Path,
+    format: ArchiveFormat,
+    since: i64,
+    until: i64,
+) -> Result<Manifest, ServerError> {
+    if !store.ensure_schema(db_name, false, false).await? {
+        return Err(archive_error(format!("database {} not exist", db_name)));
+    }
+    let mut files = Vec::new();
+    for (_, table) in metrics_tables() {
+        tokio::fs::create_dir_all(out.join(table.name)).await?;
+        for (day, start, end) in days(since, until) {
+            let rows = store.query(db_name, &table, start, end).await?;
+            if rows.is_empty() {
+                continue;
+            }
+            let file = PathBuf::from(table.name).join(format!("{}.{}", day, format.extension()));
+            let path = out.join(&file);
+            let expected = rows.len();
+            let written = tokio::task::spawn_blocking(move || {
+                write_file(&path, format, &table, &rows)?;
+                read_file(&path, format, &table).map(|rows| rows.len())
+            })
+            .await
+            .map_err(archive_error)??;
+            if written != expected {
+                return Err(archive_error(format!(
+                    "{} has {} rows, {} in the store",
+                    file.display(),
+                    written,
+                    expected
+                )));
+            }
+            println!("{}: {} rows", file.display(), written);
+            files.push(ArchiveFile {
+                table: table.name.to_string(),
+                day,
+                path: file,
+                rows: written as u64,
+            });
+        }
+    }
+
+    let manifest = Manifest {
+        env: env.to_string(),
+        format,
+        since,
+        until,
+        exported_at: Utc::now().timestamp(),
+        files,
+    };
+    let tmp = out.join(format!("{}.tmp", MANIFEST_FILE));
+    tokio::fs::write(&tmp, serde_json::to_vec_pretty(&manifest).map_err(archive_error)?).await?;
+    tokio::fs::rename(&tmp, out.join(MANIFEST_FILE)).await?;
+    Ok(manifest)
+}
+
+/// Load the archive of `manifest` in directory `dir` into env database `db_name`, created if not exist.
+/// Return rows loaded.
+///
+/// Each file's row count is checked against the manifest before its rows are inserted.
+/// Rows already in the store are not skipped, importing an archive twice duplicates them.
+pub async fn import(
+    store: &dyn MetricsStore,
+    db_name: &str,
+    dir: &Path,
+    manifest: &Manifest,
+) -> Result<u64, ServerError> {
+    store.ensure_schema(db_name, true, true).await?;
+    let tables = metrics_tables();
+    let mut loaded = 0;
+    for file in &manifest.files {
+        let table = tables
+            .iter()
+            .map(|(_, table)| *table)
+            .find(|table| table.name == file.table)
+            .ok_or_else(|| archive_error(format!("unknown table {}", file.table)))?;
+        let path = dir.join(&file.path);
+        let format = manifest.format;
+        let rows = tokio::task::spawn_blocking(move || read_file(&path, format, &table))
+            .await
+            .map_err(archive_error)??;
+        if rows.len() as u64 != file.rows {
+            return Err(archive_error(format!(
+                "{} has {} rows, {} in the manifest",
+                file.path.display(),
+                rows.len(),
+                file.rows
+            )));
+        }
+        for chunk in rows.chunks(IMPORT_BATCH_ROWS) {
+            store.insert_batch(db_name, &table, chunk).await?;
+        }
+        println!("{}: {} rows", file.path.display(), rows.len());
+        loaded += file.rows;
+    }
+    Ok(loaded)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::store::SqliteStore;
+    use metrics_types::{CounterUnit, FlowUnit};
+
+    fn flow_row(send_timestamp: u64, tag: &str) -> Vec<SqlValue> {
+        let mut row = vec![
+            SqlValue::UInt(send_timestamp),
+            SqlValue::Text("127.0.0.1".into()),
+            SqlValue::Text("category".into()),
+            SqlValue::Text(tag.into()),
+        ];
+        row.extend((0..6).map(SqlValue::Int));
+        row.push(SqlValue::Double(0.25));
+        row
+    }
+
+    #[test]
+    fn test_days() {
+        assert_eq!(
+            days(1700000000, 1700100000),
+            vec![
+                ("2023-11-14".to_string(), 1700000000, 1700006400),
+                ("2023-11-15".to_string(), 1700006400, 1700092800),
+                ("2023-11-16".to_string(), 1700092800, 1700100000),
+            ]
+        );
+        assert!(days(1700000000, 1700000000).is_empty());
+    }
+
+    #[test]
+    fn test_export_import() {
+        tokio_test::block_on(async {
+            let dir = tempfile::tempdir().unwrap();
+            let store = SqliteStore::new(&dir.path().join("store")).await.unwrap();
+            let flow = TableSchema::of::<FlowUnit>();
+            assert!(store.ensure_schema("dw_src", true, true).await.unwrap());
+            store
+                .insert_batch(
+                    "dw_src",
+                    &flow,
+                    &[
+                        flow_row(1700000000, "a"),
+                        flow_row(1700000001, "b,\"c\"\nd"),
+                        flow_row(1700090000, "e"),
+                        flow_row(1700200000, "out of range"),
+                    ],
+                )
+                .await
+                .unwrap();
+
+            for format in [ArchiveFormat::Parquet, ArchiveFormat::Csv] {
+                let out = dir.path().join(format.extension());
+                let manifest = export(&store, "dw_src", "src", &out, format, 1699999999, 1700100000)
+                    .await
+                    .unwrap();
+                assert_eq!(manifest.rows(), 3);
+                assert_eq!(
+                    manifest.files.iter().map(|f| f.day.as_str()).collect::<Vec<_>>(),
+                    vec!["2023-11-14", "2023-11-15"]
+                );
+                assert_eq!(Manifest::read(&out.join(MANIFEST_FILE)).unwrap(), manifest);
+
+                let db_name = format!("dw_dst_{}", format.extension());
+                assert_eq!(import(&store, &db_name, &out, &manifest).await.unwrap(), 3);
+                assert_eq!(
+                    store.query(&db_name, &flow, 0, i64::MAX).await.unwrap(),
+                    store.query("dw_src", &flow, 0, 1700100000).await.unwrap()
+                );
+                assert!(store
+                    .query(&db_name, &TableSchema::of::<CounterUnit>(), 0, i64::MAX)
+                    .await
+                    .unwrap()
+                    .is_empty());
+
+                // an archive not matching its manifest loads nothing more.
+                let mut manifest = manifest;
+                manifest.files[0].rows += 1;
+                assert!(import(&store, "dw_dst_bad", &out, &manifest).await.is_err());
+                assert!(store.query("dw_dst_bad", &flow, 0, i64::MAX).await.unwrap().is_empty());
+            }
+
+            assert!(export(
+                &store,
+                "dw_none",
+                "none",
+                &dir.path().join("none"),
+                ArchiveFormat::Csv,
+                0,
+                1
+            )
+            .await
+            .is_err());
+        });
+    }
+}
@@ -0,0 +1,126 @@
+use std::fs::File;
+use std::path::Path;
+use std::sync::Arc;
+
+use arrow_array::{Array, ArrayRef, Float64Array, Int32Array, Int64Array, RecordBatch, StringArray};
+use arrow_schema::{DataType, Field, Schema};
+use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+use parquet::arrow::ArrowWriter;
+use parquet::basic::Compression;
+use parquet::file::properties::WriterProperties;
+
+use metrics_types::sql::{SqlType, SqlValue, TableSchema};
+
+use super::{archive_error, integer};
+use crate::error::ServerError;
+
+fn data_type(sql_type: SqlType) -> DataType {
+    match sql_type {
+        SqlType::Int => DataType::Int32,
+        SqlType::BigInt => DataType::Int64,
+        SqlType::Double => DataType::Float64,
+        SqlType::Varchar(_) => DataType::Utf8,
+    }
+}
+
+fn schema(table: &TableSchema) -> Arc<Schema> {
+    let fields = table
+        .columns
+        .iter()
+        .zip(table.types)
+        .map(|(name, sql_type)| Field::new(*name, data_type(*sql_type), false))
+        .collect::<Vec<_>>();
+    Arc::new(Schema::new(fields))
+}
+
+/// Column `i` of `rows` as an arrow array of `sql_type`.
+fn column(rows: &[Vec<SqlValue>], i: usize, sql_type: SqlType) -> Result<ArrayRef, ServerError> {
+    let bad_value = |row: &Vec<SqlValue>| archive_error(format!("{:?} is not a row of {:?}", row, sql_type));
+    Ok(match sql_type {
+        SqlType::Int => Arc::new(Int32Array::from(
+            rows.iter()
+                .map(|row| {
+                    integer(row.get(i))
+                        .and_then(|v| i32::try_from(v).ok())
+                        .ok_or_else(|| bad_value(row))
+                })
+                .collect::<Result<Vec<_>, _>>()?,
+        )),
+        SqlType::BigInt => Arc::new(Int64Array::from(
+            rows.iter()
+                .map(|row| integer(row.get(i)).ok_or_else(|| bad_value(row)))
+                .collect::<Result<Vec<_>, _>>()?,
+        )),
+        SqlType::Double => Arc::new(Float64Array::from(
+            rows.iter()
+                .map(|row| match row.get(i) {
+                    Some(SqlValue::Double(v)) => Ok(*v),
+                    _ => Err(bad_value(row)),
+                })
+                .collect::<Result<Vec<_>, _>>()?,
+        )),
+        SqlType::Varchar(_) => Arc::new(StringArray::from(
+            rows.iter()
+                .map(|row| match row.get(i) {
+                    Some(SqlValue::Text(v)) => Ok(v.as_str()),
+                    _ => Err(bad_value(row)),
+                })
+                .collect::<Result<Vec<_>, _>>()?,
+        )),
+    })
+}
+
+/// Write `rows` of `table` to parquet file `path`, as one snappy compressed row group.
+pub(super) fn write(path: &Path, table: &TableSchema, rows: &[Vec<SqlValue>]) -> Result<(), ServerError> {
+    let columns = table
+        .types
+        .iter()
+        .enumerate()
+        .map(|(i, sql_type)| column(rows, i, *sql_type))
+        .collect::<Result<Vec<_>, _>>()?;
+    let batch = RecordBatch::try_new(schema(table), columns).map_err(archive_error)?;
+    let props = WriterProperties::builder().set_compression(Compression::SNAPPY).build();
+    let mut writer = ArrowWriter::try_new(File::create(path)?, batch.schema(), Some(props)).map_err(archive_error)?;
+    writer.write(&batch).map_err(archive_error)?;
+    writer.close().map_err(archive_error)?;
+    Ok(())
+}
+
+fn downcast<'a, T: 'static>(array: &'a ArrayRef, path: &Path) -> Result<&'a T, ServerError> {
+    array
+        .as_any()
+        .downcast_ref::<T>()
+        .ok_or_else(|| archive_error(format!("{} has unexpected column types", path.display())))
+}
+
+/// Rows of `table` in parquet file `path`, values in `table.columns` order.
+pub(super) fn read(path: &Path, table: &TableSchema) -> Result<Vec<Vec<SqlValue>>, ServerError> {
+    let reader = ParquetRecordBatchReaderBuilder::try_new(File::open(path)?)
+        .and_then(|builder| builder.build())
+        .map_err(archive_error)?;
+    let expected = schema(table);
+    let mut rows = Vec::new();
+    for batch in reader {
+        let batch = batch.map_err(archive_error)?;
+        if batch.schema().fields() != expected.fields() {
+            return Err(archive_error(format!(
+                "{} is not an archive of {}",
+                path.display(),
+                table.name
+            )));
+        }
+        let start = rows.len();
+        rows.resize(start + batch.num_rows(), Vec::with_capacity(table.columns.len()));
+        for (array, sql_type) in batch.columns().iter().zip(table.types) {
+            for (r, row) in rows[start..].iter_mut().enumerate() {
+                row.push(match sql_type {
+                    SqlType::Int => SqlValue::Int(downcast::<Int32Array>(array, path)?.value(r) as i64),
+                    SqlType::BigInt => SqlValue::Int(downcast::<Int64Array>(array, path)?.value(r)),
+                    SqlType::Double => SqlValue::Double(downcast::<Float64Array>(array, path)?.value(r)),
+                    SqlType::Varchar(_) => SqlValue::Text(downcast::<StringArray>(array, path)?.value(r).to_string()),
+                });
+            }
+        }
+    }
+    Ok(rows)
+}
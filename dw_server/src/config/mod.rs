@@ -0,0 +1,2249 @@
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use redis::{ConnectionAddr, ConnectionInfo, IntoConnectionInfo, RedisResult};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use metrics_types::{format_env_name, MetricsAlarmType};
+
+use crate::error::ServerError;
+
+const DEFAULT_BIND_ADDRESS: IpAddr = IpAddr::V4(Ipv4Addr::UNSPECIFIED);
+const DEFAULT_PORT: u16 = 3000;
+const DEFAULT_MAX_BODY_BYTES: usize = 4 * 1024 * 1024;
+const DEFAULT_MAX_BATCH_ITEMS: usize = 1000;
+const DEFAULT_SPILL_MAX_BYTES: u64 = 1024 * 1024 * 1024;
+const DEFAULT_SPILL_REPLAY_INTERVAL_SECS: u64 = 5;
+const DEFAULT_AGENT_STALE_SECS: u64 = 600;
+const DEFAULT_REDIS_URL: &str = "redis://127.0.0.1";
+const DEFAULT_STREAM_GROUP: &str = "dw_consumer";
+const DEFAULT_CLAIM_IDLE_SECS: u64 = 60;
+const DEFAULT_QUEUE_PATH: &str = "./dw_queue";
+const DEFAULT_QUEUE_SEGMENT_BYTES: u64 = 64 * 1024 * 1024;
+const DEFAULT_QUEUE_MEMORY_CAPACITY: usize = 100_000;
+const DEFAULT_SPLIT_AHEAD: u32 = 3;
+const DEFAULT_COMMIT_ROWS: usize = 1000;
+const DEFAULT_MAX_COMMIT_ROWS: usize = 10000;
+const DEFAULT_MAX_CACHED_ROWS: usize = 10000;
+const DEFAULT_MAX_WAIT_MS: u64 = 3000;
+const DEFAULT_IDLE_EXPIRE_SECS: u64 = 120;
+const DEFAULT_FETCH_SIZE: usize = 100;
+const DEFAULT_POLL_INTERVAL_MS: u64 = 4000;
+const DEFAULT_MYSQL_POOL_MIN: usize = 1;
+const DEFAULT_MYSQL_POOL_MAX: usize = 16;
+const DEFAULT_RETENTION_INTERVAL_SECS: u64 = 3600;
+const DEFAULT_DEAD_LETTER_PATH: &str = "./dw_dead_letter";
+const DEFAULT_SQLITE_PATH: &str = "./dw_sqlite";
+const DEFAULT_CLICKHOUSE_URL: &str = "http://127.0.0.1:8123";
+const DEFAULT_STATUS_PORT: u16 = 3001;
+const DEFAULT_HEALTH_STALL_SECS: u64 = 300;
+
+/// #### ServerConfig
+///
+/// Server binaries' config, each option can be given by command line, env var (`DW_*`) or toml config file.
+///
+/// Command line and env var take precedence, the config file (`--config`) fills the rest.
+pub trait ServerConfig: Parser + DeserializeOwned {
+    fn config_file(&self) -> Option<&PathBuf>;
+
+    /// fill options not given by command line or env var with `file`'s.
+    fn merge(self, file: Self) -> Self;
+
+    fn validate(&self) -> Result<(), ServerError> {
+        Ok(())
+    }
+
+    fn load() -> Result<Self, ServerError> {
+        let args = Self::parse();
+        let config = match args.config_file() {
+            Some(path) => {
+                let file = toml::from_str::<Self>(&std::fs::read_to_string(path)?)?;
+                args.merge(file)
+            }
+            None => args,
+        };
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+#[derive(Debug, Clone, Default, Args, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ListenConfig {
+    /// proxy bind address, default 0.0.0.0
+    #[clap(long = "bind", env = "DW_PROXY_BIND")]
+    pub bind: Option<IpAddr>,
+
+    /// proxy listen port, default 3000
+    #[clap(short = 'p', long = "port", env = "DW_PROXY_PORT")]
+    pub port: Option<u16>,
+}
+
+impl ListenConfig {
+    fn merge(self, file: Self) -> Self {
+        ListenConfig {
+            bind: self.bind.or(file.bind),
+            port: self.port.or(file.port),
+        }
+    }
+
+    pub fn socket_addr(&self) -> SocketAddr {
+        SocketAddr::new(
+            self.bind.unwrap_or(DEFAULT_BIND_ADDRESS),
+            self.port.unwrap_or(DEFAULT_PORT),
+        )
+    }
+}
+
+/// Limits of `/api/alarm`, rates are off unless set.
+#[derive(Debug, Clone, Default, Args, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitConfig {
+    /// largest /api/alarm body in bytes, default 4194304
+    #[clap(long = "max_body_bytes", env = "DW_MAX_BODY_BYTES")]
+    pub max_body_bytes: Option<usize>,
+
+    /// most metrics items in one /api/alarm request, default 1000
+    #[clap(long = "max_batch_items", env = "DW_MAX_BATCH_ITEMS")]
+    pub max_batch_items: Option<usize>,
+
+    /// metrics items per second accepted from each env, default unlimited
+    #[clap(long = "env_rate_limit", env = "DW_ENV_RATE_LIMIT")]
+    pub env_rate: Option<f64>,
+
+    /// metrics items an idle env may send at once, default max_batch_items
+    #[clap(long = "env_burst", env = "DW_ENV_BURST")]
+    pub env_burst: Option<f64>,
+
+    /// /api/alarm requests per second accepted from each remote address, default unlimited
+    #[clap(long = "ip_rate_limit", env = "DW_IP_RATE_LIMIT")]
+    pub ip_rate: Option<f64>,
+
+    /// requests an idle remote address may send at once, default ip_rate_limit rounded up
+    #[clap(long = "ip_burst", env = "DW_IP_BURST")]
+    pub ip_burst: Option<f64>,
+}
+
+impl LimitConfig {
+    fn merge(self, file: Self) -> Self {
+        LimitConfig {
+            max_body_bytes: self.max_body_bytes.or(file.max_body_bytes),
+            max_batch_items: self.max_batch_items.or(file.max_batch_items),
+            env_rate: self.env_rate.or(file.env_rate),
+            env_burst: self.env_burst.or(file.env_burst),
+            ip_rate: self.ip_rate.or(file.ip_rate),
+            ip_burst: self.ip_burst.or(file.ip_burst),
+        }
+    }
+
+    fn validate(&self) -> Result<(), ServerError> {
+        if self.max_body_bytes() == 0 || self.max_batch_items() == 0 {
+            return Err(ServerError::ConfigError(
+                "max_body_bytes and max_batch_items must be > 0".into(),
+            ));
+        }
+        for (name, rate) in [("env", self.env_rate()), ("ip", self.ip_rate())] {
+            if let Some((rate, burst)) = rate {
+                if !(rate.is_finite() && burst.is_finite() && burst >= 1.0) {
+                    return Err(ServerError::ConfigError(format!(
+                        "invalid {} rate limit {} burst {}",
+                        name, rate, burst
+                    )));
+                }
+            }
+        }
+        match self.env_rate() {
+            // an env could never send a full batch otherwise.
+            Some((_, burst)) if burst < self.max_batch_items() as f64 => Err(ServerError::ConfigError(format!(
+                "env_burst {} below max_batch_items {}",
+                burst,
+                self.max_batch_items()
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn max_body_bytes(&self) -> usize {
+        self.max_body_bytes.unwrap_or(DEFAULT_MAX_BODY_BYTES)
+    }
+
+    pub fn max_batch_items(&self) -> usize {
+        self.max_batch_items.unwrap_or(DEFAULT_MAX_BATCH_ITEMS)
+    }
+
+    /// `(rate, burst)` of each env, `None` if unlimited.
+    pub fn env_rate(&self) -> Option<(f64, f64)> {
+        let rate = self.env_rate.filter(|r| *r > 0.0)?;
+        Some((rate, self.env_burst.unwrap_or(self.max_batch_items() as f64)))
+    }
+
+    /// `(rate, burst)` of each remote address, `None` if unlimited.
+    pub fn ip_rate(&self) -> Option<(f64, f64)> {
+        let rate = self.ip_rate.filter(|r| *r > 0.0)?;
+        Some((rate, self.ip_burst.unwrap_or(rate.ceil())))
+    }
+}
+
+/// Where the proxy keeps metrics it can't queue, off unless `path` is set.
+#[derive(Debug, Clone, Default, Args, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SpillConfig {
+    /// spill directory for metrics the queue can't take, replayed once it can, default none: answer 503
+    #[clap(id = "spill_path", long = "spill_path", env = "DW_SPILL_PATH")]
+    pub path: Option<PathBuf>,
+
+    /// spill size to answer 503 at, default 1073741824
+    #[clap(long = "spill_max_bytes", env = "DW_SPILL_MAX_BYTES")]
+    pub max_bytes: Option<u64>,
+
+    /// seconds between replays of the spill into the queue, default 5
+    #[clap(long = "spill_replay_interval_secs", env = "DW_SPILL_REPLAY_INTERVAL_SECS")]
+    pub replay_interval_secs: Option<u64>,
+}
+
+impl SpillConfig {
+    fn merge(self, file: Self) -> Self {
+        SpillConfig {
+            path: self.path.or(file.path),
+            max_bytes: self.max_bytes.or(file.max_bytes),
+            replay_interval_secs: self.replay_interval_secs.or(file.replay_interval_secs),
+        }
+    }
+
+    fn validate(&self, queue: &QueueConfig) -> Result<(), ServerError> {
+        match self.path() {
+            Some(path) if queue.backend() == QueueBackend::File && path == queue.path() => Err(
+                ServerError::ConfigError("spill_path must differ from the file queue path".into()),
+            ),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    pub fn max_bytes(&self) -> u64 {
+        self.max_bytes.unwrap_or(DEFAULT_SPILL_MAX_BYTES)
+    }
+
+    pub fn replay_interval(&self) -> Duration {
+        Duration::from_secs(
+            self.replay_interval_secs
+                .unwrap_or(DEFAULT_SPILL_REPLAY_INTERVAL_SECS)
+                .max(1),
+        )
+    }
+}
+
+/// How the proxy tracks the agents sending to it.
+#[derive(Debug, Clone, Default, Args, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AgentsConfig {
+    /// seconds without a batch before an agent is listed as stale, default 600
+    #[clap(long = "agent_stale_secs", env = "DW_AGENT_STALE_SECS")]
+    pub stale_secs: Option<u64>,
+
+    /// toml file of the config served to agents at /api/agent-config, reloaded on change, default none: empty config
+    #[clap(long = "agent_config", env = "DW_AGENT_CONFIG")]
+    pub config_path: Option<PathBuf>,
+}
+
+impl AgentsConfig {
+    fn merge(self, file: Self) -> Self {
+        AgentsConfig {
+            stale_secs: self.stale_secs.or(file.stale_secs),
+            config_path: self.config_path.or(file.config_path),
+        }
+    }
+
+    pub fn config_path(&self) -> Option<&Path> {
+        self.config_path.as_deref()
+    }
+
+    pub fn stale_after(&self) -> Duration {
+        Duration::from_secs(self.stale_secs.unwrap_or(DEFAULT_AGENT_STALE_SECS).max(1))
+    }
+}
+
+#[derive(Debug, Clone, Default, Args, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RedisConfig {
+    /// redis url, `redis://[<user>][:<password>@]<host>[:<port>][/<db>]` or `redis+unix:///<path>`, default redis://127.0.0.1
+    #[clap(long = "redis_url", env = "DW_REDIS_URL")]
+    pub url: Option<String>,
+
+    /// redis password, override the one in redis_url
+    #[clap(long = "redis_password", env = "DW_REDIS_PASSWORD")]
+    pub password: Option<String>,
+
+    /// redis database index, override the one in redis_url
+    #[clap(long = "redis_db", env = "DW_REDIS_DB")]
+    pub db: Option<i64>,
+
+    /// redis unix socket path, override the address in redis_url
+    #[clap(long = "redis_socket", env = "DW_REDIS_SOCKET")]
+    pub socket: Option<PathBuf>,
+
+    /// redis key prefix, so several deployments can share one redis
+    #[clap(long = "redis_key_prefix", env = "DW_REDIS_KEY_PREFIX")]
+    pub key_prefix: Option<String>,
+}
+
+impl RedisConfig {
+    fn merge(self, file: Self) -> Self {
+        RedisConfig {
+            url: self.url.or(file.url),
+            password: self.password.or(file.password),
+            db: self.db.or(file.db),
+            socket: self.socket.or(file.socket),
+            key_prefix: self.key_prefix.or(file.key_prefix),
+        }
+    }
+
+    pub fn connection_info(&self) -> RedisResult<ConnectionInfo> {
+        let mut info = self
+            .url
+            .as_deref()
+            .unwrap_or(DEFAULT_REDIS_URL)
+            .into_connection_info()?;
+        if let Some(socket) = &self.socket {
+            info.addr = ConnectionAddr::Unix(socket.clone());
+        }
+        if let Some(password) = &self.password {
+            info.redis.password = Some(password.clone());
+        }
+        if let Some(db) = self.db {
+            info.redis.db = db;
+        }
+        Ok(info)
+    }
+
+    pub fn key_prefix(&self) -> &str {
+        self.key_prefix.as_deref().unwrap_or("")
+    }
+}
+
+#[derive(Debug, Clone, Default, Args, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StreamConfig {
+    /// redis stream consumer group, shared by all consumers of one deployment, default dw_consumer
+    #[clap(long = "stream_group", env = "DW_STREAM_GROUP")]
+    pub group: Option<String>,
+
+    /// consumer name in the group, should be unique and stable across restarts, default <host name>[_<consumer_suffix>]
+    #[clap(long = "consumer_name", env = "DW_CONSUMER_NAME")]
+    pub consumer_name: Option<String>,
+
+    /// appended to the host name of the default consumer_name, to tell consumers of one host apart
+    #[clap(long = "consumer_suffix", env = "DW_CONSUMER_SUFFIX")]
+    pub consumer_suffix: Option<String>,
+
+    /// entries pending longer than this are claimed from dead consumers, default 60
+    #[clap(long = "claim_idle_secs", env = "DW_CLAIM_IDLE_SECS")]
+    pub claim_idle_secs: Option<u64>,
+
+    /// caps each stream to about this many newest entries, dropping unacked ones too, default unset (no cap)
+    #[clap(long = "stream_max_len", env = "DW_STREAM_MAX_LEN")]
+    pub max_len: Option<usize>,
+
+    /// XDEL entries once acked, only safe if no other group reads the streams, default false
+    #[clap(long = "stream_delete_acked", env = "DW_STREAM_DELETE_ACKED")]
+    pub delete_acked: Option<bool>,
+}
+
+impl StreamConfig {
+    fn merge(self, file: Self) -> Self {
+        StreamConfig {
+            group: self.group.or(file.group),
+            consumer_name: self.consumer_name.or(file.consumer_name),
+            consumer_suffix: self.consumer_suffix.or(file.consumer_suffix),
+            claim_idle_secs: self.claim_idle_secs.or(file.claim_idle_secs),
+            max_len: self.max_len.or(file.max_len),
+            delete_acked: self.delete_acked.or(file.delete_acked),
+        }
+    }
+
+    pub fn group(&self) -> &str {
+        self.group.as_deref().unwrap_or(DEFAULT_STREAM_GROUP)
+    }
+
+    /// Stable across restarts, so a restarted consumer re-reads its own pending entries.
+    pub fn consumer_name(&self) -> Result<String, ServerError> {
+        self.consumer_name_on(host_name())
+    }
+
+    fn consumer_name_on(&self, host: Option<String>) -> Result<String, ServerError> {
+        if let Some(name) = &self.consumer_name {
+            return Ok(name.clone());
+        }
+        let host = host.ok_or_else(|| {
+            ServerError::ConfigError("stream.consumer_name not set and the host name is unknown".into())
+        })?;
+        Ok(match &self.consumer_suffix {
+            Some(suffix) => format!("{}_{}", host, suffix),
+            None => host,
+        })
+    }
+
+    pub fn claim_idle(&self) -> Duration {
+        Duration::from_secs(self.claim_idle_secs.unwrap_or(DEFAULT_CLAIM_IDLE_SECS))
+    }
+
+    /// `None` if streams are not capped, only trimmed of entries every group acked.
+    pub fn max_len(&self) -> Option<usize> {
+        self.max_len.filter(|max_len| *max_len > 0)
+    }
+
+    pub fn delete_acked(&self) -> bool {
+        self.delete_acked.unwrap_or(false)
+    }
+}
+
+/// Name of this host, `None` if it can't be found.
+fn host_name() -> Option<String> {
+    ["/proc/sys/kernel/hostname", "/etc/hostname"]
+        .iter()
+        .find_map(|path| std::fs::read_to_string(path).ok())
+        .or_else(|| std::env::var("HOSTNAME").ok())
+        .or_else(|| std::env::var("COMPUTERNAME").ok())
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum QueueBackend {
+    #[default]
+    Redis,
+    /// in-process, only for proxy and consumer running in one process.
+    Memory,
+    File,
+}
+
+#[derive(Debug, Clone, Default, Args, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QueueConfig {
+    /// queue between proxy and consumer, default redis
+    #[clap(long = "queue", env = "DW_QUEUE", value_enum)]
+    pub backend: Option<QueueBackend>,
+
+    /// file queue directory, default ./dw_queue
+    #[clap(long = "queue_path", env = "DW_QUEUE_PATH")]
+    pub path: Option<PathBuf>,
+
+    /// file queue segment size in bytes, default 64MiB
+    #[clap(long = "queue_segment_bytes", env = "DW_QUEUE_SEGMENT_BYTES")]
+    pub segment_bytes: Option<u64>,
+
+    /// memory queue capacity of each alarm type, default 100000
+    #[clap(long = "queue_memory_capacity", env = "DW_QUEUE_MEMORY_CAPACITY")]
+    pub memory_capacity: Option<usize>,
+}
+
+impl QueueConfig {
+    fn merge(self, file: Self) -> Self {
+        QueueConfig {
+            backend: self.backend.or(file.backend),
+            path: self.path.or(file.path),
+            segment_bytes: self.segment_bytes.or(file.segment_bytes),
+            memory_capacity: self.memory_capacity.or(file.memory_capacity),
+        }
+    }
+
+    /// proxy and consumer in separate processes can't share a memory queue.
+    fn validate_multi_process(&self) -> Result<(), ServerError> {
+        match self.backend() {
+            QueueBackend::Memory => Err(ServerError::ConfigError(
+                "memory queue only works with proxy and consumer in one process".into(),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn backend(&self) -> QueueBackend {
+        self.backend.unwrap_or_default()
+    }
+
+    pub fn path(&self) -> &Path {
+        self.path.as_deref().unwrap_or(Path::new(DEFAULT_QUEUE_PATH))
+    }
+
+    pub fn segment_bytes(&self) -> u64 {
+        self.segment_bytes.unwrap_or(DEFAULT_QUEUE_SEGMENT_BYTES)
+    }
+
+    pub fn memory_capacity(&self) -> usize {
+        self.memory_capacity.unwrap_or(DEFAULT_QUEUE_MEMORY_CAPACITY)
+    }
+}
+
+/// mysql database name max length.
+const DATABASE_NAME_MAX_LEN: usize = 64;
+
+#[derive(Debug, Clone, Default, Args, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+    /// prefix of env database name, `[0-9a-zA-Z_]` only
+    #[clap(long = "db_prefix", env = "DW_DB_PREFIX")]
+    pub prefix: Option<String>,
+
+    /// suffix of env database name, `[0-9a-zA-Z_]` only
+    #[clap(long = "db_suffix", env = "DW_DB_SUFFIX")]
+    pub suffix: Option<String>,
+
+    /// envs allowed to create their database if not exist, comma separated, default all envs
+    #[clap(long = "auto_create_envs", env = "DW_AUTO_CREATE_ENVS", value_delimiter = ',')]
+    pub auto_create_envs: Option<Vec<String>>,
+
+    /// apply pending schema migrations when opening an existing database, default true
+    #[clap(long = "auto_migrate", env = "DW_AUTO_MIGRATE")]
+    pub auto_migrate: Option<bool>,
+
+    /// partition metrics tables by send_timestamp, default none
+    #[clap(long = "split", env = "DW_SPLIT", value_enum)]
+    pub split: Option<SplitMode>,
+
+    /// future partitions kept created ahead, default 3
+    #[clap(long = "split_ahead", env = "DW_SPLIT_AHEAD")]
+    pub split_ahead: Option<u32>,
+
+    /// keep 1m, 1h and 1d rollup tables updated as rows are committed, default true
+    #[clap(long = "rollup", env = "DW_ROLLUP")]
+    pub rollup: Option<bool>,
+}
+
+impl DatabaseConfig {
+    fn merge(self, file: Self) -> Self {
+        DatabaseConfig {
+            prefix: self.prefix.or(file.prefix),
+            suffix: self.suffix.or(file.suffix),
+            auto_create_envs: self.auto_create_envs.or(file.auto_create_envs),
+            auto_migrate: self.auto_migrate.or(file.auto_migrate),
+            split: self.split.or(file.split),
+            split_ahead: self.split_ahead.or(file.split_ahead),
+            rollup: self.rollup.or(file.rollup),
+        }
+    }
+
+    fn validate(&self) -> Result<(), ServerError> {
+        for part in [self.prefix(), self.suffix()] {
+            if !part.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+                return Err(ServerError::ConfigError(format!(
+                    "invalid database prefix/suffix {}",
+                    part
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn prefix(&self) -> &str {
+        self.prefix.as_deref().unwrap_or("")
+    }
+
+    pub fn suffix(&self) -> &str {
+        self.suffix.as_deref().unwrap_or("")
+    }
+
+    /// database name of client supplied `env`, normalized the same way as the agent does.
+    pub fn database_name(&self, env: &str) -> Result<String, ServerError> {
+        let name = format!(
+            "{}{}{}",
+            self.prefix(),
+            format_env_name(env).map_err(|e| ServerError::EnvNameError(e.to_string()))?,
+            self.suffix()
+        );
+        if name.len() > DATABASE_NAME_MAX_LEN {
+            return Err(ServerError::EnvNameError(format!("database name {} too long", name)));
+        }
+        Ok(name)
+    }
+
+    pub fn auto_migrate(&self) -> bool {
+        self.auto_migrate.unwrap_or(true)
+    }
+
+    pub fn split(&self) -> SplitMode {
+        self.split.unwrap_or_default()
+    }
+
+    pub fn split_ahead(&self) -> u32 {
+        self.split_ahead.unwrap_or(DEFAULT_SPLIT_AHEAD)
+    }
+
+    pub fn rollup(&self) -> bool {
+        self.rollup.unwrap_or(true)
+    }
+
+    /// normalized env of database `db_name`, `None` if it has not the configured prefix and suffix.
+    pub fn env_of<'a>(&self, db_name: &'a str) -> Option<&'a str> {
+        db_name
+            .strip_prefix(self.prefix())
+            .and_then(|name| name.strip_suffix(self.suffix()))
+            .filter(|env| !env.is_empty())
+    }
+
+    /// whether `env`'s database may be created when not exist.
+    pub fn may_auto_create(&self, env: &str) -> bool {
+        match &self.auto_create_envs {
+            Some(envs) => format_env_name(env).is_ok_and(|env| {
+                envs.iter()
+                    .any(|allowed| format_env_name(allowed).is_ok_and(|allowed| allowed == env))
+            }),
+            None => true,
+        }
+    }
+}
+
+/// Consumer batching of one alarm type, options not given fall back to `[batch.all]`.
+#[derive(Debug, Clone, Default, Args, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BatchSettings {
+    /// rows committed in one insert, the batch starts at this size, default 1000
+    #[clap(long = "batch_commit_rows", env = "DW_BATCH_COMMIT_ROWS")]
+    pub commit_rows: Option<usize>,
+
+    /// the batch doubles up to this size while rows keep piling up, default 10000
+    #[clap(long = "batch_max_commit_rows", env = "DW_BATCH_MAX_COMMIT_ROWS")]
+    pub max_commit_rows: Option<usize>,
+
+    /// stop fetching once this many rows wait for mysql, default 10000
+    #[clap(long = "batch_max_cached_rows", env = "DW_BATCH_MAX_CACHED_ROWS")]
+    pub max_cached_rows: Option<usize>,
+
+    /// latency target, a row waits at most this long before committed, default 3000
+    #[clap(long = "batch_max_wait_ms", env = "DW_BATCH_MAX_WAIT_MS")]
+    pub max_wait_ms: Option<u64>,
+
+    /// an env idle this long releases its cache, default 120
+    #[clap(long = "batch_idle_expire_secs", env = "DW_BATCH_IDLE_EXPIRE_SECS")]
+    pub idle_expire_secs: Option<u64>,
+
+    /// queue items fetched at once, default 100
+    #[clap(long = "batch_fetch_size", env = "DW_BATCH_FETCH_SIZE")]
+    pub fetch_size: Option<usize>,
+
+    /// wait between fetches when the queue is empty, default 4000
+    #[clap(long = "batch_poll_interval_ms", env = "DW_BATCH_POLL_INTERVAL_MS")]
+    pub poll_interval_ms: Option<u64>,
+}
+
+impl BatchSettings {
+    fn merge(self, file: Self) -> Self {
+        BatchSettings {
+            commit_rows: self.commit_rows.or(file.commit_rows),
+            max_commit_rows: self.max_commit_rows.or(file.max_commit_rows),
+            max_cached_rows: self.max_cached_rows.or(file.max_cached_rows),
+            max_wait_ms: self.max_wait_ms.or(file.max_wait_ms),
+            idle_expire_secs: self.idle_expire_secs.or(file.idle_expire_secs),
+            fetch_size: self.fetch_size.or(file.fetch_size),
+            poll_interval_ms: self.poll_interval_ms.or(file.poll_interval_ms),
+        }
+    }
+
+    fn validate(&self) -> Result<(), ServerError> {
+        if self.commit_rows() == 0 || self.fetch_size() == 0 {
+            return Err(ServerError::ConfigError(
+                "batch commit_rows and fetch_size must be positive".into(),
+            ));
+        }
+        if self.max_commit_rows() < self.commit_rows() {
+            return Err(ServerError::ConfigError(
+                "batch max_commit_rows less than commit_rows".into(),
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn commit_rows(&self) -> usize {
+        self.commit_rows.unwrap_or(DEFAULT_COMMIT_ROWS)
+    }
+
+    pub fn max_commit_rows(&self) -> usize {
+        self.max_commit_rows.unwrap_or(DEFAULT_MAX_COMMIT_ROWS)
+    }
+
+    pub fn max_cached_rows(&self) -> usize {
+        self.max_cached_rows.unwrap_or(DEFAULT_MAX_CACHED_ROWS)
+    }
+
+    pub fn max_wait(&self) -> Duration {
+        Duration::from_millis(self.max_wait_ms.unwrap_or(DEFAULT_MAX_WAIT_MS))
+    }
+
+    pub fn idle_expire(&self) -> Duration {
+        Duration::from_secs(self.idle_expire_secs.unwrap_or(DEFAULT_IDLE_EXPIRE_SECS))
+    }
+
+    pub fn fetch_size(&self) -> usize {
+        self.fetch_size.unwrap_or(DEFAULT_FETCH_SIZE)
+    }
+
+    pub fn poll_interval(&self) -> Duration {
+        Duration::from_millis(self.poll_interval_ms.unwrap_or(DEFAULT_POLL_INTERVAL_MS))
+    }
+}
+
+/// Consumer batching, `all` for every alarm type, given by command line or config file,
+/// then per alarm type overrides, config file only.
+#[derive(Debug, Clone, Default, Args, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BatchConfig {
+    #[clap(flatten)]
+    pub all: BatchSettings,
+
+    #[clap(skip)]
+    pub counter: BatchSettings,
+
+    #[clap(skip)]
+    pub timer: BatchSettings,
+
+    #[clap(skip)]
+    pub flow: BatchSettings,
+}
+
+impl BatchConfig {
+    fn merge(self, file: Self) -> Self {
+        BatchConfig {
+            all: self.all.merge(file.all),
+            counter: file.counter,
+            timer: file.timer,
+            flow: file.flow,
+        }
+    }
+
+    fn validate(&self) -> Result<(), ServerError> {
+        for alarm_type in [
+            MetricsAlarmType::Counter,
+            MetricsAlarmType::Timer,
+            MetricsAlarmType::Flow,
+        ] {
+            self.settings(alarm_type).validate()?;
+        }
+        Ok(())
+    }
+
+    /// batching of `alarm_type`, its own options first.
+    pub fn settings(&self, alarm_type: MetricsAlarmType) -> BatchSettings {
+        let own = match alarm_type {
+            MetricsAlarmType::Counter => &self.counter,
+            MetricsAlarmType::Timer => &self.timer,
+            MetricsAlarmType::Flow => &self.flow,
+            MetricsAlarmType::Invalid => return self.all.clone(),
+        };
+        own.clone().merge(self.all.clone())
+    }
+}
+
+#[derive(Debug, Clone, Default, Args, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MysqlPoolConfig {
+    /// idle mysql connections kept open, default 1
+    #[clap(id = "mysql_pool_min", long = "mysql_pool_min", env = "DW_MYSQL_POOL_MIN")]
+    pub min: Option<usize>,
+
+    /// mysql connections shared by all env databases, default 16
+    #[clap(id = "mysql_pool_max", long = "mysql_pool_max", env = "DW_MYSQL_POOL_MAX")]
+    pub max: Option<usize>,
+}
+
+impl MysqlPoolConfig {
+    fn merge(self, file: Self) -> Self {
+        MysqlPoolConfig {
+            min: self.min.or(file.min),
+            max: self.max.or(file.max),
+        }
+    }
+
+    fn validate(&self) -> Result<(), ServerError> {
+        match self.max() > 0 && self.min() <= self.max() {
+            true => Ok(()),
+            false => Err(ServerError::ConfigError(format!(
+                "invalid mysql pool min {} max {}",
+                self.min(),
+                self.max()
+            ))),
+        }
+    }
+
+    pub fn min(&self) -> usize {
+        self.min.unwrap_or(DEFAULT_MYSQL_POOL_MIN)
+    }
+
+    pub fn max(&self) -> usize {
+        self.max.unwrap_or(DEFAULT_MYSQL_POOL_MAX)
+    }
+}
+
+/// Keep `alarm_type` rows of `env` for `days`, an absent `env` or `alarm_type` matches all.
+///
+/// Command line form is `[env:]<alarm_type|*>=<days>`, e.g. `timer=14`, `prod:*=90`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RetentionRule {
+    #[serde(default)]
+    pub env: Option<String>,
+    #[serde(default)]
+    pub alarm_type: Option<MetricsAlarmType>,
+    pub days: u32,
+}
+
+impl FromStr for RetentionRule {
+    type Err = ServerError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || ServerError::ConfigError(format!("invalid retention rule {}", s));
+        let (target, days) = s.split_once('=').ok_or_else(invalid)?;
+        let (env, alarm_type) = match target.split_once(':') {
+            Some((env, alarm_type)) => (Some(env.trim().to_string()), alarm_type.trim()),
+            None => (None, target.trim()),
+        };
+        let alarm_type = match alarm_type {
+            "*" => None,
+            alarm_type => Some(alarm_type.parse().map_err(|_| invalid())?),
+        };
+        Ok(RetentionRule {
+            env,
+            alarm_type,
+            days: days.trim().parse().map_err(|_| invalid())?,
+        })
+    }
+}
+
+impl RetentionRule {
+    /// `None` if not matching, otherwise higher for a more specific rule.
+    fn specificity(&self, env: &str, alarm_type: MetricsAlarmType) -> Option<u8> {
+        let env_matched = match &self.env {
+            Some(rule_env) => format_env_name(rule_env)
+                .is_ok_and(|rule_env| rule_env == env)
+                .then_some(2),
+            None => Some(0),
+        }?;
+        let type_matched = match self.alarm_type {
+            Some(rule_type) => (rule_type == alarm_type).then_some(1),
+            None => Some(0),
+        }?;
+        Some(env_matched + type_matched)
+    }
+}
+
+#[derive(Debug, Clone, Default, Args, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetentionConfig {
+    /// retention rules `[env:]<alarm_type|*>=<days>`, comma separated, default keep forever
+    #[clap(long = "retention", env = "DW_RETENTION", value_delimiter = ',')]
+    pub rules: Option<Vec<RetentionRule>>,
+
+    /// seconds between retention runs, default 3600
+    #[clap(long = "retention_interval_secs", env = "DW_RETENTION_INTERVAL_SECS")]
+    pub interval_secs: Option<u64>,
+}
+
+impl RetentionConfig {
+    fn merge(self, file: Self) -> Self {
+        RetentionConfig {
+            rules: self.rules.or(file.rules),
+            interval_secs: self.interval_secs.or(file.interval_secs),
+        }
+    }
+
+    fn validate(&self) -> Result<(), ServerError> {
+        match self.rules().iter().any(|rule| rule.days == 0) {
+            true => Err(ServerError::ConfigError("retention days must be positive".into())),
+            false => Ok(()),
+        }
+    }
+
+    pub fn rules(&self) -> &[RetentionRule] {
+        self.rules.as_deref().unwrap_or_default()
+    }
+
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs(self.interval_secs.unwrap_or(DEFAULT_RETENTION_INTERVAL_SECS))
+    }
+
+    /// days to keep `alarm_type` rows of normalized `env`, from the most specific matching rule,
+    /// the later one of equally specific rules. `None` to keep forever.
+    pub fn days(&self, env: &str, alarm_type: MetricsAlarmType) -> Option<u32> {
+        self.rules()
+            .iter()
+            .filter_map(|rule| rule.specificity(env, alarm_type).map(|s| (s, rule.days)))
+            .max_by_key(|(s, _)| *s)
+            .map(|(_, days)| days)
+    }
+}
+
+#[derive(Debug, Clone, Default, Args, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StatusConfig {
+    /// consumer `/health` and `/metrics` bind address, default 0.0.0.0
+    #[clap(id = "status_bind", long = "status_bind", env = "DW_STATUS_BIND")]
+    pub bind: Option<IpAddr>,
+
+    /// consumer `/health` and `/metrics` port, default 3001, 0 to disable
+    #[clap(id = "status_port", long = "status_port", env = "DW_STATUS_PORT")]
+    pub port: Option<u16>,
+
+    /// `/health` fails once cached rows of an alarm type are not committed for this long, default 300
+    #[clap(long = "health_stall_secs", env = "DW_HEALTH_STALL_SECS")]
+    pub stall_secs: Option<u64>,
+}
+
+impl StatusConfig {
+    fn merge(self, file: Self) -> Self {
+        StatusConfig {
+            bind: self.bind.or(file.bind),
+            port: self.port.or(file.port),
+            stall_secs: self.stall_secs.or(file.stall_secs),
+        }
+    }
+
+    /// `None` if disabled.
+    pub fn socket_addr(&self) -> Option<SocketAddr> {
+        match self.port.unwrap_or(DEFAULT_STATUS_PORT) {
+            0 => None,
+            port => Some(SocketAddr::new(self.bind.unwrap_or(DEFAULT_BIND_ADDRESS), port)),
+        }
+    }
+
+    pub fn stall(&self) -> Duration {
+        Duration::from_secs(self.stall_secs.unwrap_or(DEFAULT_HEALTH_STALL_SECS))
+    }
+}
+
+/// Partition granularity of metrics tables, see `crate::partition`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SplitMode {
+    #[default]
+    None,
+    Day,
+    Month,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DeadLetterBackend {
+    #[default]
+    File,
+    Redis,
+}
+
+#[derive(Debug, Clone, Default, Args, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DeadLetterConfig {
+    /// where items the consumer failed to commit are kept, default file
+    #[clap(id = "dead_letter", long = "dead_letter", env = "DW_DEAD_LETTER", value_enum)]
+    pub backend: Option<DeadLetterBackend>,
+
+    /// file dead letter directory, default ./dw_dead_letter
+    #[clap(id = "dead_letter_path", long = "dead_letter_path", env = "DW_DEAD_LETTER_PATH")]
+    pub path: Option<PathBuf>,
+}
+
+impl DeadLetterConfig {
+    fn merge(self, file: Self) -> Self {
+        DeadLetterConfig {
+            backend: self.backend.or(file.backend),
+            path: self.path.or(file.path),
+        }
+    }
+
+    pub fn backend(&self) -> DeadLetterBackend {
+        self.backend.unwrap_or_default()
+    }
+
+    pub fn path(&self) -> &Path {
+        self.path.as_deref().unwrap_or(Path::new(DEFAULT_DEAD_LETTER_PATH))
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StoreBackend {
+    #[default]
+    Mysql,
+    Postgres,
+    /// one file per env database, for tests and small deployments.
+    Sqlite,
+    /// http interface, for envs with millions of rows per hour.
+    Clickhouse,
+}
+
+/// Where the consumer stores metrics, see `crate::store`.
+#[derive(Debug, Clone, Default, Args, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StoreConfig {
+    /// metrics store, default mysql
+    #[clap(id = "store", long = "store", env = "DW_STORE", value_enum)]
+    pub backend: Option<StoreBackend>,
+
+    /// postgres connection string, e.g. "host=localhost user=dw password=pswd dbname=dw"
+    #[clap(long = "postgres_url", env = "DW_POSTGRES_URL")]
+    pub postgres_url: Option<String>,
+
+    /// make postgres metrics tables timescaledb hypertables, default false
+    #[clap(long = "timescale", env = "DW_TIMESCALE")]
+    pub timescale: Option<bool>,
+
+    /// sqlite directory, one file per env database, default ./dw_sqlite
+    #[clap(long = "sqlite_path", env = "DW_SQLITE_PATH")]
+    pub sqlite_path: Option<PathBuf>,
+
+    /// clickhouse http interface, default http://127.0.0.1:8123
+    #[clap(long = "clickhouse_url", env = "DW_CLICKHOUSE_URL")]
+    pub clickhouse_url: Option<String>,
+
+    /// clickhouse user, default the server's default user
+    #[clap(long = "clickhouse_user", env = "DW_CLICKHOUSE_USER")]
+    pub clickhouse_user: Option<String>,
+
+    /// clickhouse password
+    #[clap(long = "clickhouse_password", env = "DW_CLICKHOUSE_PASSWORD")]
+    pub clickhouse_password: Option<String>,
+}
+
+impl StoreConfig {
+    fn merge(self, file: Self) -> Self {
+        StoreConfig {
+            backend: self.backend.or(file.backend),
+            postgres_url: self.postgres_url.or(file.postgres_url),
+            timescale: self.timescale.or(file.timescale),
+            sqlite_path: self.sqlite_path.or(file.sqlite_path),
+            clickhouse_url: self.clickhouse_url.or(file.clickhouse_url),
+            clickhouse_user: self.clickhouse_user.or(file.clickhouse_user),
+            clickhouse_password: self.clickhouse_password.or(file.clickhouse_password),
+        }
+    }
+
+    /// `mysql_url` is only required by the mysql store.
+    fn validate(&self, mysql_url: &Option<String>) -> Result<(), ServerError> {
+        match self.backend() {
+            StoreBackend::Mysql if mysql_url.is_none() => Err(ServerError::ConfigError("mysql_url is required".into())),
+            StoreBackend::Postgres if self.postgres_url.is_none() => Err(ServerError::ConfigError(
+                "postgres_url is required by postgres store".into(),
+            )),
+            StoreBackend::Clickhouse if !self.clickhouse_url().starts_with("http://") => {
+                Err(ServerError::ConfigError("clickhouse_url must be an http:// url".into()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub fn backend(&self) -> StoreBackend {
+        self.backend.unwrap_or_default()
+    }
+
+    pub fn postgres_url(&self) -> String {
+        self.postgres_url.clone().unwrap_or_default()
+    }
+
+    pub fn timescale(&self) -> bool {
+        self.timescale.unwrap_or(false)
+    }
+
+    pub fn sqlite_path(&self) -> &Path {
+        self.sqlite_path.as_deref().unwrap_or(Path::new(DEFAULT_SQLITE_PATH))
+    }
+
+    pub fn clickhouse_url(&self) -> &str {
+        self.clickhouse_url.as_deref().unwrap_or(DEFAULT_CLICKHOUSE_URL)
+    }
+}
+
+/// `dw_server_proxy` config.
+///
+/// ``` toml
+/// [listen]
+/// bind = "0.0.0.0"
+/// port = 3000
+///
+/// [redis]
+/// url = "redis://127.0.0.1"
+/// key_prefix = "some_deployment"
+///
+/// [queue]
+/// backend = "redis"
+///
+/// [limit]
+/// max_batch_items = 500
+/// env_rate = 200.0
+/// ip_rate = 10.0
+///
+/// [spill]
+/// path = "./dw_spill"
+///
+/// [agents]
+/// stale_secs = 600
+/// config_path = "./agent_config.toml"
+/// ```
+#[derive(Debug, Default, Parser, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProxyConfig {
+    /// toml config file
+    #[clap(short = 'c', long = "config", env = "DW_CONFIG")]
+    #[serde(skip)]
+    pub config: Option<PathBuf>,
+
+    #[clap(flatten)]
+    pub listen: ListenConfig,
+
+    #[clap(flatten)]
+    pub limit: LimitConfig,
+
+    #[clap(flatten)]
+    pub spill: SpillConfig,
+
+    #[clap(flatten)]
+    pub agents: AgentsConfig,
+
+    #[clap(flatten)]
+    pub redis: RedisConfig,
+
+    #[clap(flatten)]
+    pub queue: QueueConfig,
+}
+
+impl ServerConfig for ProxyConfig {
+    fn config_file(&self) -> Option<&PathBuf> {
+        self.config.as_ref()
+    }
+
+    fn merge(self, file: Self) -> Self {
+        ProxyConfig {
+            config: self.config,
+            listen: self.listen.merge(file.listen),
+            limit: self.limit.merge(file.limit),
+            spill: self.spill.merge(file.spill),
+            agents: self.agents.merge(file.agents),
+            redis: self.redis.merge(file.redis),
+            queue: self.queue.merge(file.queue),
+        }
+    }
+
+    fn validate(&self) -> Result<(), ServerError> {
+        self.limit.validate()?;
+        self.spill.validate(&self.queue)?;
+        self.queue.validate_multi_process()
+    }
+}
+
+/// `dw_server_consumer` config.
+///
+/// ``` toml
+/// mysql_url = "user:password@localhost:3306"
+///
+/// [store]
+/// backend = "mysql"
+///
+/// [mysql_pool]
+/// max = 16
+///
+/// [database]
+/// prefix = "dw_"
+/// auto_create_envs = ["test_env", "prod_env"]
+/// split = "day"
+///
+/// [batch.all]
+/// max_wait_ms = 2000
+///
+/// [batch.timer]
+/// commit_rows = 5000
+///
+/// [retention]
+/// rules = [
+///     { alarm_type = "timer", days = 14 },
+///     { env = "prod_env", days = 90 },
+/// ]
+///
+/// [status]
+/// port = 3001
+///
+/// [dead_letter]
+/// backend = "file"
+/// path = "/data/dw_dead_letter"
+///
+/// [redis]
+/// url = "redis://127.0.0.1"
+/// key_prefix = "some_deployment"
+///
+/// [stream]
+/// group = "dw_consumer"
+/// consumer_name = "consumer_node_1"
+/// claim_idle_secs = 60
+///
+/// [queue]
+/// backend = "redis"
+/// ```
+#[derive(Debug, Default, Parser, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConsumerConfig {
+    /// toml config file
+    #[clap(short = 'c', long = "config", env = "DW_CONFIG")]
+    #[serde(skip)]
+    pub config: Option<PathBuf>,
+
+    /// mysql_url
+    #[clap(short = 'm', long = "mysql_url", env = "DW_MYSQL_URL")]
+    pub mysql_url: Option<String>,
+
+    #[clap(flatten)]
+    pub store: StoreConfig,
+
+    #[clap(flatten)]
+    pub mysql_pool: MysqlPoolConfig,
+
+    #[clap(flatten)]
+    pub database: DatabaseConfig,
+
+    #[clap(flatten)]
+    pub batch: BatchConfig,
+
+    #[clap(flatten)]
+    pub retention: RetentionConfig,
+
+    #[clap(flatten)]
+    pub status: StatusConfig,
+
+    #[clap(flatten)]
+    pub dead_letter: DeadLetterConfig,
+
+    #[clap(flatten)]
+    pub redis: RedisConfig,
+
+    #[clap(flatten)]
+    pub stream: StreamConfig,
+
+    #[clap(flatten)]
+    pub queue: QueueConfig,
+}
+
+impl ServerConfig for ConsumerConfig {
+    fn config_file(&self) -> Option<&PathBuf> {
+        self.config.as_ref()
+    }
+
+    fn merge(self, file: Self) -> Self {
+        ConsumerConfig {
+            config: self.config,
+            mysql_url: self.mysql_url.or(file.mysql_url),
+            store: self.store.merge(file.store),
+            mysql_pool: self.mysql_pool.merge(file.mysql_pool),
+            database: self.database.merge(file.database),
+            batch: self.batch.merge(file.batch),
+            retention: self.retention.merge(file.retention),
+            status: self.status.merge(file.status),
+            dead_letter: self.dead_letter.merge(file.dead_letter),
+            redis: self.redis.merge(file.redis),
+            stream: self.stream.merge(file.stream),
+            queue: self.queue.merge(file.queue),
+        }
+    }
+
+    fn validate(&self) -> Result<(), ServerError> {
+        self.store.validate(&self.mysql_url)?;
+        self.mysql_pool.validate()?;
+        self.database.validate()?;
+        self.batch.validate()?;
+        self.retention.validate()?;
+        self.queue.validate_multi_process()
+    }
+}
+
+impl ConsumerConfig {
+    pub fn mysql_url(&self) -> String {
+        self.mysql_url.clone().unwrap_or_default()
+    }
+}
+
+/// `dw_server_standalone` config, proxy and consumer in one process.
+///
+/// Same options as proxy and consumer, queue backend defaults to memory.
+///
+/// ``` toml
+/// mysql_url = "user:password@localhost:3306"
+///
+/// [listen]
+/// bind = "0.0.0.0"
+/// port = 3000
+/// ```
+#[derive(Debug, Default, Parser, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StandaloneConfig {
+    /// toml config file
+    #[clap(short = 'c', long = "config", env = "DW_CONFIG")]
+    #[serde(skip)]
+    pub config: Option<PathBuf>,
+
+    /// mysql_url
+    #[clap(short = 'm', long = "mysql_url", env = "DW_MYSQL_URL")]
+    pub mysql_url: Option<String>,
+
+    #[clap(flatten)]
+    pub store: StoreConfig,
+
+    #[clap(flatten)]
+    pub mysql_pool: MysqlPoolConfig,
+
+    #[clap(flatten)]
+    pub database: DatabaseConfig,
+
+    #[clap(flatten)]
+    pub batch: BatchConfig,
+
+    #[clap(flatten)]
+    pub retention: RetentionConfig,
+
+    #[clap(flatten)]
+    pub status: StatusConfig,
+
+    #[clap(flatten)]
+    pub dead_letter: DeadLetterConfig,
+
+    #[clap(flatten)]
+    pub listen: ListenConfig,
+
+    #[clap(flatten)]
+    pub limit: LimitConfig,
+
+    #[clap(flatten)]
+    pub spill: SpillConfig,
+
+    #[clap(flatten)]
+    pub agents: AgentsConfig,
+
+    #[clap(flatten)]
+    pub redis: RedisConfig,
+
+    #[clap(flatten)]
+    pub stream: StreamConfig,
+
+    #[clap(flatten)]
+    pub queue: QueueConfig,
+}
+
+impl ServerConfig for StandaloneConfig {
+    fn config_file(&self) -> Option<&PathBuf> {
+        self.config.as_ref()
+    }
+
+    fn merge(self, file: Self) -> Self {
+        StandaloneConfig {
+            config: self.config,
+            mysql_url: self.mysql_url.or(file.mysql_url),
+            store: self.store.merge(file.store),
+            mysql_pool: self.mysql_pool.merge(file.mysql_pool),
+            database: self.database.merge(file.database),
+            batch: self.batch.merge(file.batch),
+            retention: self.retention.merge(file.retention),
+            status: self.status.merge(file.status),
+            dead_letter: self.dead_letter.merge(file.dead_letter),
+            listen: self.listen.merge(file.listen),
+            limit: self.limit.merge(file.limit),
+            spill: self.spill.merge(file.spill),
+            agents: self.agents.merge(file.agents),
+            redis: self.redis.merge(file.redis),
+            stream: self.stream.merge(file.stream),
+            queue: self.queue.merge(file.queue),
+        }
+    }
+
+    fn validate(&self) -> Result<(), ServerError> {
+        self.store.validate(&self.mysql_url)?;
+        self.mysql_pool.validate()?;
+        self.database.validate()?;
+        self.batch.validate()?;
+        self.retention.validate()?;
+        self.limit.validate()?;
+        self.spill.validate(&self.queue_config())
+    }
+}
+
+impl StandaloneConfig {
+    pub fn mysql_url(&self) -> String {
+        self.mysql_url.clone().unwrap_or_default()
+    }
+
+    pub fn queue_config(&self) -> QueueConfig {
+        QueueConfig {
+            backend: Some(self.queue.backend.unwrap_or(QueueBackend::Memory)),
+            ..self.queue.clone()
+        }
+    }
+}
+
+#[derive(Debug, Clone, Subcommand)]
+pub enum DeadLetterCommand {
+    /// print dead letters, oldest first
+    List {
+        #[clap(short = 's', long = "source")]
+        source: MetricsAlarmType,
+
+        #[clap(short = 'n', long = "limit", default_value_t = 100)]
+        limit: usize,
+    },
+
+    /// push dead letters back to the metrics queue as they are
+    Replay {
+        #[clap(short = 's', long = "source")]
+        source: MetricsAlarmType,
+
+        /// replay all dead letters of source
+        #[clap(long = "all")]
+        all: bool,
+
+        ids: Vec<String>,
+    },
+
+    /// push a fixed metrics json to the metrics queue in place of a dead letter
+    Fix {
+        #[clap(short = 's', long = "source")]
+        source: MetricsAlarmType,
+
+        id: String,
+
+        /// fixed metrics json, `-` to read from stdin
+        data: String,
+    },
+
+    /// drop dead letters
+    Remove {
+        #[clap(short = 's', long = "source")]
+        source: MetricsAlarmType,
+
+        ids: Vec<String>,
+    },
+}
+
+/// `dw_server_dead_letter` config, the same `redis`, `queue` and `dead_letter` sections as the consumer.
+#[derive(Debug, Default, Parser, Deserialize)]
+#[serde(default)]
+pub struct DeadLetterToolConfig {
+    /// toml config file, the consumer's works
+    #[clap(short = 'c', long = "config", env = "DW_CONFIG")]
+    #[serde(skip)]
+    pub config: Option<PathBuf>,
+
+    #[clap(flatten)]
+    pub redis: RedisConfig,
+
+    #[clap(flatten)]
+    pub queue: QueueConfig,
+
+    #[clap(flatten)]
+    pub dead_letter: DeadLetterConfig,
+
+    #[clap(subcommand)]
+    #[serde(skip)]
+    pub command: Option<DeadLetterCommand>,
+}
+
+impl ServerConfig for DeadLetterToolConfig {
+    fn config_file(&self) -> Option<&PathBuf> {
+        self.config.as_ref()
+    }
+
+    fn merge(self, file: Self) -> Self {
+        DeadLetterToolConfig {
+            config: self.config,
+            redis: self.redis.merge(file.redis),
+            queue: self.queue.merge(file.queue),
+            dead_letter: self.dead_letter.merge(file.dead_letter),
+            command: self.command,
+        }
+    }
+
+    fn validate(&self) -> Result<(), ServerError> {
+        match self.command {
+            Some(_) => self.queue.validate_multi_process(),
+            None => Err(ServerError::ConfigError("command is required".into())),
+        }
+    }
+}
+
+/// `dw_server_migrate` config, the same `mysql_url` and `database` options as the consumer.
+#[derive(Debug, Default, Parser, Deserialize)]
+#[serde(default)]
+pub struct MigrateToolConfig {
+    /// toml config file, the consumer's works
+    #[clap(short = 'c', long = "config", env = "DW_CONFIG")]
+    #[serde(skip)]
+    pub config: Option<PathBuf>,
+
+    /// mysql_url
+    #[clap(short = 'm', long = "mysql_url", env = "DW_MYSQL_URL")]
+    pub mysql_url: Option<String>,
+
+    #[clap(flatten)]
+    pub database: DatabaseConfig,
+
+    /// only print pending migrations
+    #[clap(long = "dry_run")]
+    #[serde(skip)]
+    pub dry_run: bool,
+
+    /// migrate every database having metrics tables and the configured prefix/suffix
+    #[clap(long = "all")]
+    #[serde(skip)]
+    pub all: bool,
+
+    /// envs to migrate
+    #[serde(skip)]
+    pub envs: Vec<String>,
+}
+
+impl ServerConfig for MigrateToolConfig {
+    fn config_file(&self) -> Option<&PathBuf> {
+        self.config.as_ref()
+    }
+
+    fn merge(self, file: Self) -> Self {
+        MigrateToolConfig {
+            config: self.config,
+            mysql_url: self.mysql_url.or(file.mysql_url),
+            database: self.database.merge(file.database),
+            dry_run: self.dry_run,
+            all: self.all,
+            envs: self.envs,
+        }
+    }
+
+    fn validate(&self) -> Result<(), ServerError> {
+        if self.mysql_url.is_none() {
+            return Err(ServerError::ConfigError("mysql_url is required".into()));
+        }
+        if self.all == !self.envs.is_empty() {
+            return Err(ServerError::ConfigError("give either envs or --all".into()));
+        }
+        self.database.validate()
+    }
+}
+
+impl MigrateToolConfig {
+    pub fn mysql_url(&self) -> String {
+        self.mysql_url.clone().unwrap_or_default()
+    }
+}
+
+/// `dw_server_rollup` config, the same `mysql_url` and `database` options as the consumer.
+#[derive(Debug, Default, Parser, Deserialize)]
+#[serde(default)]
+pub struct RollupToolConfig {
+    /// toml config file, the consumer's works
+    #[clap(short = 'c', long = "config", env = "DW_CONFIG")]
+    #[serde(skip)]
+    pub config: Option<PathBuf>,
+
+    /// mysql_url
+    #[clap(short = 'm', long = "mysql_url", env = "DW_MYSQL_URL")]
+    pub mysql_url: Option<String>,
+
+    #[clap(flatten)]
+    pub database: DatabaseConfig,
+
+    /// backfill raw rows since this unix timestamp, default the oldest row
+    #[clap(long = "since")]
+    #[serde(skip)]
+    pub since: Option<i64>,
+
+    /// backfill raw rows until this unix timestamp, default now
+    #[clap(long = "until")]
+    #[serde(skip)]
+    pub until: Option<i64>,
+
+    /// backfill every database having metrics tables and the configured prefix/suffix
+    #[clap(long = "all")]
+    #[serde(skip)]
+    pub all: bool,
+
+    /// envs to backfill
+    #[serde(skip)]
+    pub envs: Vec<String>,
+}
+
+impl ServerConfig for RollupToolConfig {
+    fn config_file(&self) -> Option<&PathBuf> {
+        self.config.as_ref()
+    }
+
+    fn merge(self, file: Self) -> Self {
+        RollupToolConfig {
+            config: self.config,
+            mysql_url: self.mysql_url.or(file.mysql_url),
+            database: self.database.merge(file.database),
+            since: self.since,
+            until: self.until,
+            all: self.all,
+            envs: self.envs,
+        }
+    }
+
+    fn validate(&self) -> Result<(), ServerError> {
+        if self.mysql_url.is_none() {
+            return Err(ServerError::ConfigError("mysql_url is required".into()));
+        }
+        if self.all == !self.envs.is_empty() {
+            return Err(ServerError::ConfigError("give either envs or --all".into()));
+        }
+        if let (Some(since), Some(until)) = (self.since, self.until) {
+            if since >= until {
+                return Err(ServerError::ConfigError("--since must be before --until".into()));
+            }
+        }
+        self.database.validate()
+    }
+}
+
+impl RollupToolConfig {
+    pub fn mysql_url(&self) -> String {
+        self.mysql_url.clone().unwrap_or_default()
+    }
+}
+
+/// File format of metrics archives.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ArchiveFormat {
+    #[default]
+    Parquet,
+    Csv,
+}
+
+impl ArchiveFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ArchiveFormat::Parquet => "parquet",
+            ArchiveFormat::Csv => "csv",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Subcommand)]
+pub enum ArchiveCommand {
+    /// write rows of env sent in [since, until) to one file per table and day, with a manifest
+    Export {
+        env: String,
+
+        /// unix timestamp
+        #[clap(long = "since")]
+        since: i64,
+
+        /// unix timestamp
+        #[clap(long = "until")]
+        until: i64,
+
+        #[clap(short = 'f', long = "format", value_enum, default_value_t = ArchiveFormat::Parquet)]
+        format: ArchiveFormat,
+
+        /// archive directory, created if not exist
+        #[clap(short = 'o', long = "out")]
+        out: PathBuf,
+    },
+
+    /// load an exported archive into the store, into the env of its manifest unless `--env`
+    Import {
+        /// manifest.json written by export
+        manifest: PathBuf,
+
+        #[clap(long = "env")]
+        env: Option<String>,
+    },
+}
+
+/// `dw_server_archive` config, the same `mysql_url`, `store` and `database` options as the consumer.
+#[derive(Debug, Default, Parser, Deserialize)]
+#[serde(default)]
+pub struct ArchiveToolConfig {
+    /// toml config file, the consumer's works
+    #[clap(short = 'c', long = "config", env = "DW_CONFIG")]
+    #[serde(skip)]
+    pub config: Option<PathBuf>,
+
+    /// mysql_url
+    #[clap(short = 'm', long = "mysql_url", env = "DW_MYSQL_URL")]
+    pub mysql_url: Option<String>,
+
+    #[clap(flatten)]
+    pub store: StoreConfig,
+
+    #[clap(flatten)]
+    pub database: DatabaseConfig,
+
+    #[clap(subcommand)]
+    #[serde(skip)]
+    pub command: Option<ArchiveCommand>,
+}
+
+impl ServerConfig for ArchiveToolConfig {
+    fn config_file(&self) -> Option<&PathBuf> {
+        self.config.as_ref()
+    }
+
+    fn merge(self, file: Self) -> Self {
+        ArchiveToolConfig {
+            config: self.config,
+            mysql_url: self.mysql_url.or(file.mysql_url),
+            store: self.store.merge(file.store),
+            database: self.database.merge(file.database),
+            command: self.command,
+        }
+    }
+
+    fn validate(&self) -> Result<(), ServerError> {
+        match self.command {
+            Some(ArchiveCommand::Export { since, until, .. }) if since >= until => {
+                return Err(ServerError::ConfigError("--since must be before --until".into()));
+            }
+            Some(_) => {}
+            None => return Err(ServerError::ConfigError("command is required".into())),
+        }
+        self.store.validate(&self.mysql_url)?;
+        self.database.validate()
+    }
+}
+
+impl ArchiveToolConfig {
+    pub fn mysql_url(&self) -> String {
+        self.mysql_url.clone().unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_proxy_config_merge() {
+        let file = toml::from_str::<ProxyConfig>(
+            r#"
+            [listen]
+            bind = "127.0.0.1"
+            port = 3001
+
+            [redis]
+            url = "redis://10.0.0.1:6380/2"
+            key_prefix = "file_prefix"
+            "#,
+        )
+        .unwrap();
+        let args = ProxyConfig::parse_from(["dw_server_proxy", "--port", "3002", "--redis_password", "pswd"]);
+        let config = args.merge(file);
+
+        assert_eq!(config.listen.socket_addr(), "127.0.0.1:3002".parse().unwrap());
+        assert_eq!(config.redis.key_prefix(), "file_prefix");
+
+        let info = config.redis.connection_info().unwrap();
+        assert_eq!(info.addr, ConnectionAddr::Tcp("10.0.0.1".into(), 6380));
+        assert_eq!(info.redis.db, 2);
+        assert_eq!(info.redis.password.as_deref(), Some("pswd"));
+    }
+
+    #[test]
+    fn test_redis_config_default() {
+        let config = ProxyConfig::parse_from(["dw_server_proxy"]);
+        assert_eq!(config.listen.socket_addr(), "0.0.0.0:3000".parse().unwrap());
+        assert_eq!(config.redis.key_prefix(), "");
+
+        let info = config.redis.connection_info().unwrap();
+        assert_eq!(info.addr, ConnectionAddr::Tcp("127.0.0.1".into(), 6379));
+        assert_eq!(info.redis.db, 0);
+    }
+
+    #[test]
+    fn test_redis_config_socket() {
+        let config = ConsumerConfig::parse_from([
+            "dw_server_consumer",
+            "-m",
+            "localhost:3306",
+            "--redis_socket",
+            "/var/run/redis.sock",
+            "--redis_db",
+            "3",
+        ]);
+        let info = config.redis.connection_info().unwrap();
+        assert_eq!(info.addr, ConnectionAddr::Unix("/var/run/redis.sock".into()));
+        assert_eq!(info.redis.db, 3);
+        assert!(config.validate().is_ok());
+        assert_eq!(config.stream.group(), "dw_consumer");
+        assert_eq!(config.stream.claim_idle(), Duration::from_secs(60));
+
+        let config = ConsumerConfig::parse_from(["dw_server_consumer"]);
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_database_config() {
+        let config = ConsumerConfig::parse_from(["dw_server_consumer", "-m", "localhost:3306"]);
+        assert_eq!(config.database.database_name("test env").unwrap(), "test_env");
+        assert!(config.database.database_name("").is_err());
+        assert!(config.database.may_auto_create("any_env"));
+        assert_eq!(config.database.split(), SplitMode::None);
+
+        let file = toml::from_str::<ConsumerConfig>(
+            r#"
+            [database]
+            prefix = "dw_"
+            suffix = "_metrics"
+            auto_create_envs = ["test-env"]
+            split = "day"
+            "#,
+        )
+        .unwrap();
+        let config = config.merge(file);
+        assert!(config.validate().is_ok());
+        assert_eq!(
+            config.database.database_name("test-env").unwrap(),
+            "dw_test_env_metrics"
+        );
+        assert_eq!(
+            config.database.database_name("x`; DROP DATABASE y").unwrap(),
+            "dw_x___DROP_DATABASE_y_metrics"
+        );
+        assert_eq!(config.database.database_name(&"a".repeat(60)).unwrap().len(), 63);
+        assert!(config.database.may_auto_create("test_env"));
+        assert!(!config.database.may_auto_create("prod_env"));
+        assert_eq!(config.database.split(), SplitMode::Day);
+        assert_eq!(config.database.split_ahead(), 3);
+
+        let config = ConsumerConfig::parse_from(["dw_server_consumer", "-m", "localhost:3306", "--db_prefix", "dw-"]);
+        assert!(config.validate().is_err());
+        let config = ConsumerConfig::parse_from(["dw_server_consumer", "--db_suffix", "_some_long_suffix"]);
+        assert!(config.database.database_name(&"a".repeat(60)).is_err());
+        let config = ConsumerConfig::parse_from(["dw_server_consumer", "--split", "month", "--split_ahead", "1"]);
+        assert_eq!(config.database.split(), SplitMode::Month);
+        assert_eq!(config.database.split_ahead(), 1);
+    }
+
+    #[test]
+    fn test_dead_letter_tool_config() {
+        let config = DeadLetterToolConfig::parse_from(["dw_server_dead_letter", "list", "-s", "counter"]);
+        assert!(config.validate().is_ok());
+        assert_eq!(config.dead_letter.backend(), DeadLetterBackend::File);
+        assert!(matches!(
+            config.command,
+            Some(DeadLetterCommand::List {
+                source: MetricsAlarmType::Counter,
+                limit: 100
+            })
+        ));
+
+        let config = DeadLetterToolConfig::parse_from([
+            "dw_server_dead_letter",
+            "--dead_letter",
+            "redis",
+            "replay",
+            "-s",
+            "timer",
+            "1-0",
+            "2-0",
+        ]);
+        assert_eq!(config.dead_letter.backend(), DeadLetterBackend::Redis);
+        assert!(
+            matches!(config.command, Some(DeadLetterCommand::Replay { all: false, ref ids, .. }) if ids.len() == 2)
+        );
+
+        assert!(DeadLetterToolConfig::try_parse_from(["dw_server_dead_letter", "list", "-s", "unknown"]).is_err());
+        let config = DeadLetterToolConfig::parse_from(["dw_server_dead_letter"]);
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_migrate_tool_config() {
+        let config =
+            MigrateToolConfig::parse_from(["dw_server_migrate", "-m", "localhost:3306", "--dry_run", "env1", "env2"]);
+        assert!(config.validate().is_ok());
+        assert!(config.dry_run);
+        assert_eq!(config.envs, vec!["env1", "env2"]);
+
+        let config = MigrateToolConfig::parse_from(["dw_server_migrate", "-m", "localhost:3306", "--all", "env1"]);
+        assert!(config.validate().is_err());
+        let config = MigrateToolConfig::parse_from(["dw_server_migrate", "-m", "localhost:3306"]);
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_rollup_tool_config() {
+        let config = RollupToolConfig::parse_from([
+            "dw_server_rollup",
+            "-m",
+            "localhost:3306",
+            "--since",
+            "1700000000",
+            "env1",
+        ]);
+        assert!(config.validate().is_ok());
+        assert_eq!(config.since, Some(1700000000));
+        assert_eq!(config.until, None);
+        assert!(config.database.rollup());
+
+        let config = RollupToolConfig::parse_from([
+            "dw_server_rollup",
+            "-m",
+            "localhost:3306",
+            "--since",
+            "1700000000",
+            "--until",
+            "1600000000",
+            "--all",
+        ]);
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_archive_tool_config() {
+        let config = ArchiveToolConfig::parse_from([
+            "dw_server_archive",
+            "--store",
+            "sqlite",
+            "export",
+            "env1",
+            "--since",
+            "1700000000",
+            "--until",
+            "1700086400",
+            "-o",
+            "./archive",
+        ]);
+        assert!(config.validate().is_ok());
+        assert!(matches!(
+            config.command,
+            Some(ArchiveCommand::Export {
+                format: ArchiveFormat::Parquet,
+                ..
+            })
+        ));
+
+        let config = ArchiveToolConfig::parse_from([
+            "dw_server_archive",
+            "-m",
+            "localhost:3306",
+            "import",
+            "./archive/manifest.json",
+            "--env",
+            "env2",
+        ]);
+        assert!(config.validate().is_ok());
+
+        // mysql store without mysql_url.
+        let config = ArchiveToolConfig::parse_from(["dw_server_archive", "import", "./archive/manifest.json"]);
+        assert!(config.validate().is_err());
+        let config = ArchiveToolConfig::parse_from(["dw_server_archive", "--store", "sqlite"]);
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_mysql_pool_config() {
+        let config = ConsumerConfig::parse_from(["dw_server_consumer", "-m", "localhost:3306"]);
+        assert!(config.validate().is_ok());
+        assert_eq!((config.mysql_pool.min(), config.mysql_pool.max()), (1, 16));
+
+        let file = toml::from_str::<ConsumerConfig>("[mysql_pool]\nmin = 4\nmax = 8").unwrap();
+        let config = config.merge(file);
+        assert_eq!((config.mysql_pool.min(), config.mysql_pool.max()), (4, 8));
+
+        let config =
+            StandaloneConfig::parse_from(["dw_server_standalone", "-m", "localhost:3306", "--mysql_pool_max", "0"]);
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_retention_config() {
+        let config = ConsumerConfig::parse_from(["dw_server_consumer", "-m", "localhost:3306"]);
+        assert_eq!(config.retention.days("prod_env", MetricsAlarmType::Timer), None);
+
+        let file = toml::from_str::<ConsumerConfig>(
+            r#"
+            [retention]
+            rules = [
+                { days = 365 },
+                { alarm_type = "timer", days = 14 },
+                { env = "prod-env", days = 90 },
+                { env = "prod-env", alarm_type = "timer", days = 30 },
+            ]
+            interval_secs = 600
+            "#,
+        )
+        .unwrap();
+        let config = config.merge(file);
+        assert!(config.validate().is_ok());
+        assert_eq!(config.retention.interval(), Duration::from_secs(600));
+        assert_eq!(config.retention.days("test_env", MetricsAlarmType::Counter), Some(365));
+        assert_eq!(config.retention.days("test_env", MetricsAlarmType::Timer), Some(14));
+        assert_eq!(config.retention.days("prod_env", MetricsAlarmType::Flow), Some(90));
+        assert_eq!(config.retention.days("prod_env", MetricsAlarmType::Timer), Some(30));
+
+        let config = ConsumerConfig::parse_from([
+            "dw_server_consumer",
+            "-m",
+            "localhost:3306",
+            "--retention",
+            "timer=14,flow=90,prod_env:*=180",
+        ]);
+        assert!(config.validate().is_ok());
+        assert_eq!(config.retention.days("test_env", MetricsAlarmType::Counter), None);
+        assert_eq!(config.retention.days("test_env", MetricsAlarmType::Flow), Some(90));
+        assert_eq!(config.retention.days("prod_env", MetricsAlarmType::Timer), Some(180));
+
+        assert!("timer".parse::<RetentionRule>().is_err());
+        assert!("gauge=1".parse::<RetentionRule>().is_err());
+        let config = ConsumerConfig::parse_from(["dw_server_consumer", "-m", "localhost:3306", "--retention", "*=0"]);
+        assert!(config.validate().is_err());
+
+        let database = DatabaseConfig {
+            prefix: Some("dw_".into()),
+            ..Default::default()
+        };
+        assert_eq!(database.env_of("dw_prod_env"), Some("prod_env"));
+        assert_eq!(database.env_of("other_db"), None);
+    }
+
+    #[test]
+    fn test_status_config() {
+        let config = ConsumerConfig::parse_from(["dw_server_consumer", "-m", "localhost:3306"]);
+        assert_eq!(config.status.socket_addr(), Some("0.0.0.0:3001".parse().unwrap()));
+        assert_eq!(config.status.stall(), Duration::from_secs(300));
+
+        let config = ConsumerConfig::parse_from(["dw_server_consumer", "-m", "localhost:3306", "--status_port", "0"]);
+        assert_eq!(config.status.socket_addr(), None);
+
+        let config = StandaloneConfig::parse_from([
+            "dw_server_standalone",
+            "-m",
+            "localhost:3306",
+            "--port",
+            "3000",
+            "--status_bind",
+            "127.0.0.1",
+            "--status_port",
+            "3002",
+        ]);
+        assert_eq!(config.listen.port, Some(3000));
+        assert_eq!(config.status.socket_addr(), Some("127.0.0.1:3002".parse().unwrap()));
+
+        let file = toml::from_str::<ConsumerConfig>("[status]\nport = 9101\nstall_secs = 60").unwrap();
+        let config = ConsumerConfig::parse_from(["dw_server_consumer", "-m", "localhost:3306"]).merge(file);
+        assert_eq!(config.status.socket_addr(), Some("0.0.0.0:9101".parse().unwrap()));
+        assert_eq!(config.status.stall(), Duration::from_secs(60));
+    }
+
+    #[test]
+    fn test_limit_config() {
+        let config = ProxyConfig::parse_from(["dw_server_proxy"]);
+        assert!(config.validate().is_ok());
+        assert_eq!(config.limit.max_body_bytes(), 4 * 1024 * 1024);
+        assert_eq!(config.limit.max_batch_items(), 1000);
+        assert_eq!(config.limit.env_rate(), None);
+        assert_eq!(config.limit.ip_rate(), None);
+
+        let config = ProxyConfig::parse_from(["dw_server_proxy", "--env_rate_limit", "200", "--ip_rate_limit", "2.5"]);
+        assert!(config.validate().is_ok());
+        assert_eq!(config.limit.env_rate(), Some((200.0, 1000.0)));
+        assert_eq!(config.limit.ip_rate(), Some((2.5, 3.0)));
+
+        let file = toml::from_str::<ProxyConfig>("[limit]\nmax_batch_items = 500\nenv_rate = 100.0\nenv_burst = 400.0")
+            .unwrap();
+        let config = ProxyConfig::parse_from(["dw_server_proxy"]).merge(file);
+        assert!(config.validate().is_err());
+        let config = ProxyConfig::parse_from(["dw_server_proxy", "--max_batch_items", "400"]).merge(config);
+        assert!(config.validate().is_ok());
+        assert_eq!(config.limit.env_rate(), Some((100.0, 400.0)));
+
+        let config = ProxyConfig::parse_from(["dw_server_proxy", "--ip_rate_limit", "1", "--ip_burst", "0.5"]);
+        assert!(config.validate().is_err());
+        let config = ProxyConfig::parse_from(["dw_server_proxy", "--max_body_bytes", "0"]);
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_spill_config() {
+        let config = ProxyConfig::parse_from(["dw_server_proxy"]);
+        assert!(config.validate().is_ok());
+        assert_eq!(config.spill.path(), None);
+        assert_eq!(config.spill.max_bytes(), 1024 * 1024 * 1024);
+        assert_eq!(config.spill.replay_interval(), Duration::from_secs(5));
+
+        let file =
+            toml::from_str::<ProxyConfig>("[spill]\npath = \"/data/dw_spill\"\nreplay_interval_secs = 0").unwrap();
+        let config = ProxyConfig::parse_from(["dw_server_proxy", "--spill_max_bytes", "1000"]).merge(file);
+        assert!(config.validate().is_ok());
+        assert_eq!(config.spill.path(), Some(Path::new("/data/dw_spill")));
+        assert_eq!(config.spill.max_bytes(), 1000);
+        assert_eq!(config.spill.replay_interval(), Duration::from_secs(1));
+
+        let config = StandaloneConfig::parse_from([
+            "dw_server_standalone",
+            "-m",
+            "localhost:3306",
+            "--queue",
+            "file",
+            "--spill_path",
+            "./dw_queue",
+        ]);
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_agents_config() {
+        let config = ProxyConfig::parse_from(["dw_server_proxy", "--agent_stale_secs", "60"]);
+        assert_eq!(config.agents.stale_after(), Duration::from_secs(60));
+        let config = ProxyConfig::parse_from(["dw_server_proxy"]).merge(config);
+        assert_eq!(config.agents.stale_after(), Duration::from_secs(60));
+        assert_eq!(AgentsConfig::default().stale_after(), Duration::from_secs(600));
+        assert_eq!(config.agents.config_path(), None);
+        let file = toml::from_str::<ProxyConfig>("[agents]\nconfig_path = \"/etc/dw/agent_config.toml\"").unwrap();
+        let config = config.merge(file);
+        assert_eq!(
+            config.agents.config_path(),
+            Some(Path::new("/etc/dw/agent_config.toml"))
+        );
+    }
+
+    #[test]
+    fn test_stream_config() {
+        let config = ConsumerConfig::parse_from(["dw_server_consumer", "--consumer_suffix", "2"]);
+        assert_eq!(config.stream.consumer_name_on(Some("node1".into())).unwrap(), "node1_2");
+        assert!(config.stream.consumer_name_on(None).is_err());
+        let file = toml::from_str::<ConsumerConfig>("[stream]\nconsumer_name = \"consumer_node_1\"").unwrap();
+        let config = config.merge(file);
+        assert_eq!(config.stream.consumer_name_on(None).unwrap(), "consumer_node_1");
+        let name = StreamConfig::default().consumer_name_on(Some("node1".into())).unwrap();
+        assert_eq!(name, "node1");
+
+        assert_eq!(config.stream.max_len(), None);
+        assert!(!config.stream.delete_acked());
+        let config = ConsumerConfig::parse_from([
+            "dw_server_consumer",
+            "--stream_max_len",
+            "1000000",
+            "--stream_delete_acked",
+            "true",
+        ]);
+        assert_eq!(config.stream.max_len(), Some(1_000_000));
+        assert!(config.stream.delete_acked());
+        let config = ConsumerConfig::parse_from(["dw_server_consumer", "--stream_max_len", "0"]);
+        assert_eq!(config.stream.max_len(), None);
+    }
+
+    #[test]
+    fn test_batch_config() {
+        let config = ConsumerConfig::parse_from(["dw_server_consumer", "-m", "localhost:3306"]);
+        let timer = config.batch.settings(MetricsAlarmType::Timer);
+        assert_eq!(timer.commit_rows(), DEFAULT_COMMIT_ROWS);
+        assert_eq!(timer.max_wait(), Duration::from_millis(DEFAULT_MAX_WAIT_MS));
+        assert_eq!(timer.poll_interval(), Duration::from_millis(DEFAULT_POLL_INTERVAL_MS));
+
+        let config = ConsumerConfig::parse_from([
+            "dw_server_consumer",
+            "-m",
+            "localhost:3306",
+            "--batch_commit_rows",
+            "500",
+        ]);
+        let file = toml::from_str::<ConsumerConfig>(
+            r#"
+            [batch.all]
+            commit_rows = 2000
+            max_wait_ms = 1000
+
+            [batch.timer]
+            commit_rows = 100
+            fetch_size = 50
+            "#,
+        )
+        .unwrap();
+        let config = config.merge(file);
+        assert!(config.validate().is_ok());
+        let counter = config.batch.settings(MetricsAlarmType::Counter);
+        assert_eq!(counter.commit_rows(), 500);
+        assert_eq!(counter.max_wait(), Duration::from_millis(1000));
+        assert_eq!(counter.fetch_size(), DEFAULT_FETCH_SIZE);
+        let timer = config.batch.settings(MetricsAlarmType::Timer);
+        assert_eq!(timer.commit_rows(), 100);
+        assert_eq!(timer.fetch_size(), 50);
+        assert_eq!(timer.max_wait(), Duration::from_millis(1000));
+
+        let config = ConsumerConfig::parse_from([
+            "dw_server_consumer",
+            "-m",
+            "localhost:3306",
+            "--batch_commit_rows",
+            "20000",
+        ]);
+        assert!(config.validate().is_err());
+        let config =
+            ConsumerConfig::parse_from(["dw_server_consumer", "-m", "localhost:3306", "--batch_fetch_size", "0"]);
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_store_config() {
+        let config = ConsumerConfig::parse_from(["dw_server_consumer"]);
+        assert_eq!(config.store.backend(), StoreBackend::Mysql);
+        assert!(config.validate().is_err());
+
+        let config = ConsumerConfig::parse_from(["dw_server_consumer", "--store", "sqlite"]);
+        assert!(config.validate().is_ok());
+        assert_eq!(config.store.sqlite_path(), Path::new("./dw_sqlite"));
+
+        let config = ConsumerConfig::parse_from(["dw_server_consumer", "--store", "postgres"]);
+        assert!(config.validate().is_err());
+        let file = toml::from_str::<ConsumerConfig>(
+            r#"
+            [store]
+            postgres_url = "host=localhost user=dw dbname=dw"
+            timescale = true
+            "#,
+        )
+        .unwrap();
+        let config = config.merge(file);
+        assert!(config.validate().is_ok());
+        assert_eq!(config.store.backend(), StoreBackend::Postgres);
+        assert!(config.store.timescale());
+
+        let config = ConsumerConfig::parse_from(["dw_server_consumer", "--store", "clickhouse"]);
+        assert!(config.validate().is_ok());
+        assert_eq!(config.store.clickhouse_url(), "http://127.0.0.1:8123");
+        let config = ConsumerConfig::parse_from([
+            "dw_server_consumer",
+            "--store",
+            "clickhouse",
+            "--clickhouse_url",
+            "https://clickhouse:8443",
+        ]);
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_queue_config() {
+        let config = ConsumerConfig::parse_from(["dw_server_consumer", "-m", "localhost:3306"]);
+        assert_eq!(config.queue.backend(), QueueBackend::Redis);
+        assert_eq!(config.dead_letter.path(), Path::new("./dw_dead_letter"));
+
+        let file = toml::from_str::<ConsumerConfig>(
+            r#"
+            [queue]
+            backend = "file"
+            path = "/data/dw_queue"
+            "#,
+        )
+        .unwrap();
+        let config = config.merge(file);
+        assert_eq!(config.queue.backend(), QueueBackend::File);
+        assert_eq!(config.queue.path(), Path::new("/data/dw_queue"));
+        assert!(config.validate().is_ok());
+
+        let config = ProxyConfig::parse_from(["dw_server_proxy", "--queue", "memory"]);
+        assert!(config.validate().is_err());
+
+        let config = StandaloneConfig::parse_from(["dw_server_standalone", "-m", "localhost:3306"]);
+        assert!(config.validate().is_ok());
+        assert_eq!(config.queue_config().backend(), QueueBackend::Memory);
+        let config = StandaloneConfig::parse_from(["dw_server_standalone", "-m", "localhost:3306", "--queue", "file"]);
+        assert_eq!(config.queue_config().backend(), QueueBackend::File);
+    }
+}
@@ -1,119 +1,488 @@
 #![allow(unused)]
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::sync::Arc;
 
-use mysql_async::Result;
 use serde::de::Deserialize;
+use serde::Serialize;
 
 use std::time::{Duration, Instant};
 
-use crate::mysql_conn::MysqlDBConn;
+use crate::config::{BatchSettings, DatabaseConfig, SplitMode};
+use crate::consumer_service::ConsumerStats;
+use crate::dead_letter::{DeadLetter, DeadLetterStore};
+use crate::error::StoreError;
+use crate::store::MetricsStore;
 use metrics_types::alarm_wrapper::AlarmWrapper;
-use metrics_types::{sql::SqlTable, MetricsAlarmType};
+use metrics_types::sql::{Resolution, RollupTable, SqlTable, TableSchema};
+use metrics_types::{format_env_name, MetricsAlarmType};
 
 /// Each AlarmType-DB have one Inner type.
 /// reserved for futher function.
-#[derive(Debug)]
 struct ConsumerBackendInner<UnitType> {
-    // _db_name: String,
+    alarm_type: MetricsAlarmType,
+    /// normalized env, to rebuild the metrics json of dead-lettered rows.
+    env: String,
+    /// queue ids of `cache_data`, one for each row.
+    cache_ids: Vec<String>,
     cache_data: Vec<UnitType>,
-    mysql_conn: MysqlDBConn,
+    /// queue ids of rows already inserted or dead-lettered, waiting to be acked.
+    committed_ids: Vec<String>,
+    store: Arc<dyn MetricsStore>,
+    db_name: String,
+    table: TableSchema,
+    dead_letter: Arc<dyn DeadLetterStore>,
+    stats: Arc<ConsumerStats>,
+    batch: BatchSettings,
+    /// rows committed at once, grows from `batch.commit_rows()` under backlog.
+    batch_rows: usize,
+    /// when the oldest cached row arrived, or earlier.
+    cache_time: Instant,
     commit_time: Instant,
+    /// failed attempts of the head rows in a row, next attempt not before `retry_time`.
+    retries: u32,
+    retry_time: Instant,
+    split: SplitMode,
+    split_ahead: u32,
+    /// partitions are checked again after `PARTITION_CHECK_INTERVAL`.
+    partition_time: Instant,
+    rollup: bool,
+    /// minute buckets of committed rows whose rollups are not recomputed yet.
+    rollup_pending: BTreeSet<i64>,
 }
 
-const CACHE_DATA_MUST_COMMIT_LEN: usize = 1000;
+/// transient insert errors retried before giving the rows up to dead letter.
+const INSERT_MAX_RETRIES: u32 = 5;
+const INSERT_MAX_BACKOFF: Duration = Duration::from_secs(60);
+const PARTITION_CHECK_INTERVAL: Duration = Duration::from_secs(3600);
 
 impl<UnitType> ConsumerBackendInner<UnitType>
 where
-    UnitType: SqlTable + Send + Sync,
+    UnitType: RollupTable + Serialize + Send + Sync,
 {
-    async fn new(mysql_url: String, db_name: &String) -> Result<Self> {
-        Ok(ConsumerBackendInner {
+    /// Backend of env database `db_name`, with the store and settings of `owner`.
+    async fn new(owner: &ConsumerBackend<UnitType>, db_name: &str, env: &str) -> Result<Option<Self>, StoreError> {
+        let ConsumerBackend {
+            store,
+            database,
+            batch,
+            alarm_type,
+            dead_letter,
+            stats,
+            ..
+        } = owner;
+        let auto_create = database.may_auto_create(env);
+        let env = format_env_name(env).unwrap_or_default();
+        if !store
+            .ensure_schema(db_name, auto_create, database.auto_migrate())
+            .await?
+        {
+            return Ok(None);
+        }
+        let mut inner = ConsumerBackendInner {
+            alarm_type: *alarm_type,
+            env,
+            cache_ids: Vec::new(),
             cache_data: Vec::new(),
-            mysql_conn: MysqlDBConn::new(mysql_url, db_name).await?,
+            committed_ids: Vec::new(),
+            store: store.clone(),
+            db_name: db_name.to_string(),
+            table: TableSchema::of::<UnitType>(),
+            dead_letter: dead_letter.clone(),
+            stats: stats.clone(),
+            batch: batch.clone(),
+            batch_rows: batch.commit_rows(),
+            cache_time: Instant::now(),
             commit_time: Instant::now(),
-        })
+            retries: 0,
+            retry_time: Instant::now(),
+            split: database.split(),
+            split_ahead: database.split_ahead(),
+            partition_time: Instant::now(),
+            rollup: database.rollup(),
+            rollup_pending: BTreeSet::new(),
+        };
+        // the table may be partitioned for the first time, don't insert into a table being rebuilt.
+        inner.ensure_partitions().await?;
+        Ok(Some(inner))
+    }
+
+    /// Keep `split_ahead` future partitions, so rows are never all put in the catch-all one.
+    async fn ensure_partitions(&mut self) -> Result<(), StoreError> {
+        self.partition_time = Instant::now();
+        let now = chrono::Utc::now().timestamp();
+        let created = self
+            .store
+            .ensure_partitions(&self.db_name, &self.table, self.split, self.split_ahead, now)
+            .await?;
+        if !created.is_empty() {
+            println!(
+                "{} {}: created partitions {}",
+                self.env,
+                UnitType::table_name(),
+                created.join(", ")
+            );
+        }
+        Ok(())
     }
 
     fn expired(&self) -> bool {
-        self.cache_data.is_empty() && self.commit_time.elapsed() > Duration::from_secs(120)
+        self.cache_data.is_empty()
+            && self.rollup_pending.is_empty()
+            && self.commit_time.elapsed() > self.batch.idle_expire()
     }
 
-    async fn close(self) -> Result<()> {
-        self.mysql_conn.close().await
+    /// Recompute rollups of committed rows, kept pending to retry on error.
+    async fn try_rollup(&mut self) {
+        if self.rollup_pending.is_empty() {
+            return;
+        }
+        match self
+            .store
+            .rollup(&self.db_name, &self.table, &self.rollup_pending)
+            .await
+        {
+            Ok(()) => self.rollup_pending.clear(),
+            Err(e) => println!("{} {}: rollup error: {}", self.env, UnitType::table_name(), e),
+        }
     }
 
-    async fn cache(&mut self, data: UnitType) -> Result<()> {
+    async fn cache(&mut self, id: String, data: UnitType) {
+        if self.cache_data.is_empty() {
+            self.cache_time = Instant::now();
+        }
+        self.cache_ids.push(id);
         self.cache_data.push(data);
-        if self.cache_data.len() > CACHE_DATA_MUST_COMMIT_LEN {
-            let _ = self.try_commit().await?;
+        if self.cache_data.len() >= self.batch_rows {
+            self.try_commit().await;
         }
-        Ok(())
     }
 
-    async fn try_commit(&mut self) -> Result<usize> {
-        if self.cache_data.len() > CACHE_DATA_MUST_COMMIT_LEN {
-            let r = self.cache_data.drain(0..CACHE_DATA_MUST_COMMIT_LEN).collect();
-            self.mysql_conn.insert(r).await?;
-            self.commit_time = Instant::now();
-        } else if self.commit_time.elapsed() > Duration::from_secs(3) && !self.cache_data.is_empty() {
-            let r = self.cache_data.drain(..).collect();
-            self.mysql_conn.insert(r).await?;
+    /// When the next commit is due, `None` if nothing to commit.
+    fn commit_due(&self) -> Option<Instant> {
+        match (self.cache_data.is_empty(), self.retries > 0) {
+            (true, _) => None,
+            (false, true) => Some(self.retry_time),
+            (false, false) if self.cache_data.len() >= self.batch_rows => Some(Instant::now()),
+            (false, false) => Some(self.cache_time + self.batch.max_wait()),
+        }
+    }
+
+    /// Insert the head rows if due. Rows failing are kept and retried with backoff,
+    /// rows the store keeps rejecting go to dead letter. Return rows left in cache.
+    async fn try_commit(&mut self) -> usize {
+        if self.partition_time.elapsed() > PARTITION_CHECK_INTERVAL {
+            if let Err(e) = self.ensure_partitions().await {
+                println!(
+                    "{} {}: ensure partitions error: {}",
+                    self.env,
+                    UnitType::table_name(),
+                    e
+                );
+            }
+        }
+        // rollups failed last time.
+        self.try_rollup().await;
+        if self.commit_due().map_or(true, |due| Instant::now() < due) {
+            return self.cache_data.len();
+        }
+        let len = self.cache_data.len().min(self.batch_rows);
+
+        let (failed, kept) = match self.insert(0, len).await {
+            Ok(()) => (Vec::new(), Vec::new()),
+            Err(e) if e.is_transient() && self.retries < INSERT_MAX_RETRIES => {
+                println!("insert {} rows error: {}, retry {}", len, e, self.retries + 1);
+                (Vec::new(), vec![(0, len)])
+            }
+            Err(e) if e.is_transient() => {
+                println!("insert {} rows error: {}, retries exhausted", len, e);
+                let e = e.to_string();
+                ((0..len).map(|i| (i, e.clone())).collect(), Vec::new())
+            }
+            Err(e) => {
+                println!("insert {} rows error: {}, bisect to find bad rows", len, e);
+                self.bisect_insert(len, e.to_string(), self.retries < INSERT_MAX_RETRIES)
+                    .await
+            }
+        };
+        self.finish_batch(len, failed, kept).await;
+        self.try_rollup().await;
+        self.cache_data.len()
+    }
+
+    /// Insert cached rows `[start, end)`.
+    async fn insert(&self, start: usize, end: usize) -> Result<(), StoreError> {
+        let rows = self.cache_data[start..end]
+            .iter()
+            .map(|row| row.to_params())
+            .collect::<Vec<_>>();
+        let begin = Instant::now();
+        let result = self.store.insert_batch(&self.db_name, &self.table, &rows).await;
+        match &result {
+            Ok(()) => self
+                .stats
+                .inserted(self.alarm_type, &self.env, rows.len(), begin.elapsed()),
+            Err(e) => self.stats.insert_failed(self.alarm_type, &self.env, e, begin.elapsed()),
+        }
+        result
+    }
+
+    /// Insert the head `len` rows, known to fail with `err`, half by half until every failing row is isolated.
+    /// If `retry_alike`, a range whose both halves fail with the same error is kept whole instead,
+    /// as that error is more likely the table's or server's than the rows'.
+    ///
+    /// Return rows rejected with their own error, and ranges to retry.
+    async fn bisect_insert(
+        &self,
+        len: usize,
+        err: String,
+        retry_alike: bool,
+    ) -> (Vec<(usize, String)>, Vec<(usize, usize)>) {
+        let mut failed = Vec::new();
+        let mut kept = Vec::new();
+        let mut failing = vec![(0, len, err)];
+        while let Some((start, end, err)) = failing.pop() {
+            if end - start == 1 {
+                failed.push((start, err));
+                continue;
+            }
+            let mid = (start + end) / 2;
+            let mut halves = Vec::new();
+            for (start, end) in [(mid, end), (start, mid)] {
+                match self.insert(start, end).await {
+                    Ok(()) => {}
+                    Err(e) if e.is_transient() => kept.push((start, end)),
+                    Err(e) => halves.push((start, end, e.to_string())),
+                }
+            }
+            match halves.as_slice() {
+                [(_, _, a), (_, _, b)] if retry_alike && a == b => {
+                    println!(
+                        "insert rows {}..{} error: both halves fail with {}, retry",
+                        start, end, a
+                    );
+                    kept.push((start, end));
+                }
+                _ => failing.append(&mut halves),
+            }
+        }
+        (failed, kept)
+    }
+
+    /// Remove the head `len` rows from cache, except `kept` ranges and `failed` rows not dead-lettered.
+    async fn finish_batch(&mut self, len: usize, failed: Vec<(usize, String)>, kept: Vec<(usize, usize)>) {
+        let mut keep = kept
+            .into_iter()
+            .flat_map(|(start, end)| start..end)
+            .collect::<HashSet<_>>();
+        if !failed.is_empty() {
+            let letters = failed
+                .iter()
+                .map(|(i, err)| self.dead_letter_of(&self.cache_data[*i], err.clone()))
+                .collect::<Vec<_>>();
+            match self.dead_letter.push(letters).await {
+                Ok(()) => self.stats.dead_lettered(self.alarm_type, &self.env, failed.len()),
+                Err(e) => {
+                    println!("push {} rows to dead letter error: {}", failed.len(), e);
+                    keep.extend(failed.iter().map(|(i, _)| *i));
+                }
+            }
+        }
+
+        let ids = self.cache_ids.drain(..len).collect::<Vec<_>>();
+        let rows = self.cache_data.drain(..len).collect::<Vec<_>>();
+        let (mut kept_ids, mut kept_rows) = (Vec::new(), Vec::new());
+        for (i, (id, row)) in ids.into_iter().zip(rows).enumerate() {
+            if keep.contains(&i) {
+                kept_ids.push(id);
+                kept_rows.push(row);
+            } else {
+                self.committed_ids.push(id);
+                if self.rollup {
+                    // recomputing buckets of dead-lettered rows too is harmless.
+                    self.rollup_pending
+                        .insert(Resolution::Minute.bucket(row.send_timestamp()));
+                }
+            }
+        }
+        self.cache_ids.splice(0..0, kept_ids);
+        self.cache_data.splice(0..0, kept_rows);
+
+        if keep.is_empty() {
+            self.retries = 0;
             self.commit_time = Instant::now();
+            // a full batch left behind means rows arrive faster than committed.
+            self.batch_rows = match self.cache_data.len() >= self.batch_rows {
+                true => (self.batch_rows * 2).min(self.batch.max_commit_rows()),
+                false if self.cache_data.is_empty() => (self.batch_rows / 2).max(self.batch.commit_rows()),
+                false => self.batch_rows,
+            };
+        } else {
+            self.retries += 1;
+            let backoff = Duration::from_secs(1 << self.retries.min(6)).min(INSERT_MAX_BACKOFF);
+            self.retry_time = Instant::now() + backoff;
         }
-        Ok(self.cache_data.len())
+    }
+
+    fn dead_letter_of(&self, row: &UnitType, error: String) -> DeadLetter {
+        let data = serde_json::to_string(&AlarmWrapper {
+            alarm_type: self.alarm_type,
+            env: self.env.clone(),
+            content: row,
+        })
+        .unwrap_or_default();
+        DeadLetter::new(self.alarm_type, self.env.clone(), error, data)
     }
 }
 
 /// Each `ConsumerBackend` server for specifical MetricsUnit, but all database concurrently.
 /// So the number of `ConsumerBackend` object should be equal to the number of MetricsAlarmType.
-#[derive(Debug)]
 pub struct ConsumerBackend<UnitType> {
-    mysql_url: String,
+    store: Arc<dyn MetricsStore>,
+    database: DatabaseConfig,
+    batch: BatchSettings,
+    alarm_type: MetricsAlarmType,
+    dead_letter: Arc<dyn DeadLetterStore>,
+    stats: Arc<ConsumerStats>,
     inner_cache: HashMap<String, ConsumerBackendInner<UnitType>>,
+    /// queue ids ready to ack but not held by any inner: dead-lettered data, or committed by a closed inner.
+    done_ids: Vec<String>,
 }
 
 impl<'de, UnitType> ConsumerBackend<UnitType>
 where
-    UnitType: Deserialize<'de> + SqlTable + Send + Sync,
+    UnitType: Deserialize<'de> + RollupTable + Serialize + Send + Sync,
 {
-    pub fn new(mysql_url: String, _alarm_type: MetricsAlarmType) -> Self {
+    pub fn new(
+        store: Arc<dyn MetricsStore>,
+        database: DatabaseConfig,
+        batch: BatchSettings,
+        alarm_type: MetricsAlarmType,
+        dead_letter: Arc<dyn DeadLetterStore>,
+        stats: Arc<ConsumerStats>,
+    ) -> Self {
         ConsumerBackend {
-            mysql_url,
+            store,
+            database,
+            batch,
+            alarm_type,
+            dead_letter,
+            stats,
             inner_cache: HashMap::new(),
+            done_ids: Vec::new(),
         }
     }
 
-    pub async fn cache(&mut self, data_str: &'de str) -> Result<()> {
+    /// Cache `data_str` read from queue entry `id`, the id is returned by `take_committed_ids` once committed.
+    ///
+    /// Data that can never be committed is dead-lettered instead. On error the item is not taken,
+    /// give it again later.
+    pub async fn cache(&mut self, id: String, data_str: &'de str) -> Result<(), StoreError> {
         // println!("{}", data_str);
-        let wrapped_unit: AlarmWrapper<UnitType> = serde_json::from_str(data_str).map_err(|e| {
-            println!("serde_json from_str err: {}, origin_data:{}", e, data_str);
-            mysql_async::Error::Other(Box::from("wrapped unit deserialize error"))
-        })?;
-        let db_name = wrapped_unit.env;
+        let wrapped_unit: AlarmWrapper<UnitType> = match serde_json::from_str(data_str) {
+            Ok(wrapped_unit) => wrapped_unit,
+            Err(e) => {
+                println!("serde_json from_str err: {}, origin_data:{}", e, data_str);
+                return self.give_up(id, data_str, e.to_string(), "invalid_data").await;
+            }
+        };
+        // env is client supplied, never trust it as a database name.
+        let db_name = match self.database.database_name(&wrapped_unit.env) {
+            Ok(db_name) => db_name,
+            Err(e) => {
+                println!("{}, origin_data:{}", e, data_str);
+                return self.give_up(id, data_str, e.to_string(), "invalid_env").await;
+            }
+        };
         if !self.inner_cache.contains_key(&db_name) {
-            self.inner_cache.insert(
-                db_name.clone(),
-                ConsumerBackendInner::new(self.mysql_url.clone(), &db_name).await?,
-            );
+            match ConsumerBackendInner::new(self, &db_name, &wrapped_unit.env).await {
+                Ok(Some(inner)) => {
+                    self.inner_cache.insert(db_name.clone(), inner);
+                }
+                Ok(None) => {
+                    let error = format!("database {} missing, env not allowlisted to create it", db_name);
+                    return self.give_up(id, data_str, error, "database_missing").await;
+                }
+                Err(e) if e.is_transient() => return Err(e),
+                Err(e) => {
+                    println!("open database {} error: {}", db_name, e);
+                    return self.give_up(id, data_str, e.to_string(), "database_error").await;
+                }
+            }
         }
         self.inner_cache
             .get_mut(&db_name)
             .unwrap()
-            .cache(wrapped_unit.content)
+            .cache(id, wrapped_unit.content)
+            .await;
+        Ok(())
+    }
+
+    /// Dead-letter item `id` that can never be committed, counted as dropped for `reason`.
+    /// It is acked once dead-lettered, fails if it can't be.
+    async fn give_up(
+        &mut self,
+        id: String,
+        data_str: &str,
+        error: String,
+        reason: &'static str,
+    ) -> Result<(), StoreError> {
+        let env = serde_json::from_str::<serde_json::Value>(data_str)
+            .ok()
+            .and_then(|v| v.get("env").and_then(|env| env.as_str()).map(String::from))
+            .unwrap_or_default();
+        let letter = DeadLetter::new(self.alarm_type, env.clone(), error, data_str.to_string());
+        self.dead_letter
+            .push(vec![letter])
             .await
+            .map_err(|e| StoreError::Transient(format!("push to dead letter error: {}", e)))?;
+        self.stats.dead_lettered(self.alarm_type, &env, 1);
+        self.stats.dropped(self.alarm_type, &env, reason);
+        self.done_ids.push(id);
+        Ok(())
+    }
+
+    /// Too many rows waiting for the store, stop fetching until they are committed.
+    pub fn backlogged(&self) -> bool {
+        self.inner_cache.values().map(|cb| cb.cache_data.len()).sum::<usize>() >= self.batch.max_cached_rows()
+    }
+
+    /// Rows cached by each open env backend, by normalized env.
+    pub fn cached_rows(&self) -> Vec<(String, usize)> {
+        self.inner_cache
+            .values()
+            .map(|cb| (cb.env.clone(), cb.cache_data.len()))
+            .collect()
+    }
+
+    /// How long until some cached rows are due to commit, `None` if nothing cached.
+    pub fn next_commit_in(&self) -> Option<Duration> {
+        self.inner_cache
+            .values()
+            .filter_map(|cb| cb.commit_due())
+            .min()
+            .map(|due| due.saturating_duration_since(Instant::now()))
+    }
+
+    /// Queue ids whose data is committed (or dropped), safe to ack now.
+    pub fn take_committed_ids(&mut self) -> Vec<String> {
+        let mut ids = std::mem::take(&mut self.done_ids);
+        for cb in self.inner_cache.values_mut() {
+            ids.append(&mut cb.committed_ids);
+        }
+        ids
     }
 
-    pub async fn try_commit_all(&mut self) -> Result<()> {
+    pub async fn try_commit_all(&mut self) -> Result<(), StoreError> {
         let mut expired_set = HashSet::new();
         for (db, cb) in self.inner_cache.iter_mut() {
-            let sz = cb.try_commit().await?;
+            let sz = cb.try_commit().await;
             if sz == 0 && cb.expired() {
                 expired_set.insert(db.clone());
             }
         }
         for db in expired_set {
-            if let Some(cb) = self.inner_cache.remove(&db) {
-                cb.close().await?;
+            if let Some(mut cb) = self.inner_cache.remove(&db) {
+                self.done_ids.append(&mut cb.committed_ids);
             }
         }
         Ok(())
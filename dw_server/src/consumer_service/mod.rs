@@ -0,0 +1,329 @@
+mod status;
+
+use std::num::NonZeroUsize;
+use std::sync::Arc;
+
+use metrics_types::{CounterUnit, FlowUnit, MetricsAlarmType, TimerUnit};
+use tokio::{
+    join,
+    time::{sleep, Duration},
+};
+
+use crate::config::{BatchConfig, BatchSettings, DatabaseConfig, RetentionConfig, StatusConfig};
+use crate::consumer_backend::ConsumerBackend;
+use crate::dead_letter::DeadLetterStore;
+use crate::error::ServerError;
+use crate::queue::MetricsQueue;
+use crate::retention;
+use crate::store::MetricsStore;
+
+pub use status::ConsumerStats;
+
+/// never spin faster than this when rows are due right away.
+const MIN_IDLE_WAIT: Duration = Duration::from_millis(10);
+
+macro_rules! HANDLE_UNIT {
+    ($func:ident, $unit_type:ident, $alarm_type:expr) => {
+        async fn $func(
+            store: Arc<dyn MetricsStore>,
+            database: DatabaseConfig,
+            batch: BatchSettings,
+            queue: Arc<dyn MetricsQueue>,
+            dead_letter: Arc<dyn DeadLetterStore>,
+            stats: Arc<ConsumerStats>,
+        ) {
+            let cnt = NonZeroUsize::new(batch.fetch_size()).unwrap_or(NonZeroUsize::MIN);
+            let poll_interval = batch.poll_interval();
+            let mut cb =
+                ConsumerBackend::<$unit_type>::new(store, database, batch, $alarm_type, dead_letter, stats.clone());
+            // items `cb` failed to take, given again on the next tick before fetching more.
+            let mut retry = Vec::new();
+            loop {
+                let (retrying, backlogged) = (!retry.is_empty(), cb.backlogged());
+                let fetch_result = match (retrying, backlogged) {
+                    (true, _) => Ok(std::mem::take(&mut retry)),
+                    // leave the data in queue until the store catches up.
+                    (false, true) => Ok(Vec::new()),
+                    (false, false) => queue.pop_batch(&$alarm_type, cnt).await,
+                };
+                let idle = match fetch_result {
+                    Ok(fetch_data) if !fetch_data.is_empty() => {
+                        if !retrying {
+                            stats.fetched($alarm_type, fetch_data.len());
+                        }
+                        for item in fetch_data {
+                            if let Err(e) = cb.cache(item.id.clone(), &item.data).await {
+                                println!("{} cache error {}, retry later", stringify!($func), e);
+                                retry.push(item);
+                            }
+                        }
+                        // wait before giving the failed items again.
+                        !retry.is_empty()
+                    }
+                    Ok(_) => {
+                        if !backlogged {
+                            stats.fetched($alarm_type, 0);
+                        }
+                        true
+                    }
+                    Err(e) => {
+                        println!("{} fetch error {}", stringify!($func), e);
+                        stats.fetch_failed($alarm_type, &e);
+                        true
+                    }
+                };
+                // commit rows due even while data keeps coming, so none waits past the latency target.
+                if let Err(e) = cb.try_commit_all().await {
+                    println!("{} commit error {}", stringify!($func), e);
+                }
+                stats.set_cached($alarm_type, cb.cached_rows());
+                // ack only after data committed, otherwise it will be delivered again.
+                let committed_ids = cb.take_committed_ids();
+                if let Err(e) = queue.ack(&$alarm_type, &committed_ids).await {
+                    println!("{} ack error {}", stringify!($func), e);
+                }
+                if idle {
+                    let wait = cb
+                        .next_commit_in()
+                        .map_or(poll_interval, |due| due.min(poll_interval));
+                    sleep(wait.max(MIN_IDLE_WAIT)).await;
+                }
+            }
+        }
+    };
+}
+
+HANDLE_UNIT!(handle_counter, CounterUnit, MetricsAlarmType::Counter);
+HANDLE_UNIT!(handle_timer, TimerUnit, MetricsAlarmType::Timer);
+HANDLE_UNIT!(handle_flow, FlowUnit, MetricsAlarmType::Flow);
+
+/// Consume all alarm types from `queue` into `store`, forever.
+/// Rows the store keeps rejecting go to `dead_letter`, rows older than `retention` are removed.
+///
+/// `/health` and `/metrics` are served as `status` says, failing at once if its port can't be listened.
+pub async fn run(
+    store: Arc<dyn MetricsStore>,
+    database: DatabaseConfig,
+    batch: BatchConfig,
+    retention: RetentionConfig,
+    status: StatusConfig,
+    queue: Arc<dyn MetricsQueue>,
+    dead_letter: Arc<dyn DeadLetterStore>,
+) -> Result<(), ServerError> {
+    let stats = Arc::new(ConsumerStats::default());
+    let status_server = async {
+        match status.socket_addr() {
+            Some(addr) => status::serve(addr, stats.clone(), queue.clone(), status.stall()).await,
+            None => std::future::pending().await,
+        }
+    };
+    let consume = async {
+        join!(
+            handle_counter(
+                store.clone(),
+                database.clone(),
+                batch.settings(MetricsAlarmType::Counter),
+                queue.clone(),
+                dead_letter.clone(),
+                stats.clone()
+            ),
+            handle_timer(
+                store.clone(),
+                database.clone(),
+                batch.settings(MetricsAlarmType::Timer),
+                queue.clone(),
+                dead_letter.clone(),
+                stats.clone()
+            ),
+            handle_flow(
+                store.clone(),
+                database.clone(),
+                batch.settings(MetricsAlarmType::Flow),
+                queue.clone(),
+                dead_letter.clone(),
+                stats.clone()
+            ),
+            retention::run(store.clone(), database.clone(), retention)
+        )
+    };
+    tokio::select! {
+        r = status_server => r,
+        _ = consume => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::{BTreeSet, HashMap};
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use async_trait::async_trait;
+    use clap::Parser;
+    use metrics_types::sql::{SqlValue, TableSchema};
+
+    use super::*;
+    use crate::config::{ConsumerConfig, SplitMode};
+    use crate::dead_letter::FileDeadLetter;
+    use crate::error::StoreError;
+    use crate::queue::MemoryQueue;
+    use crate::retention::Removed;
+    use crate::store::SqliteStore;
+
+    /// `SqliteStore` whose first `ensure_schema` calls fail as if the connection was lost,
+    /// and first `insert_batch` calls are rejected whatever the rows.
+    struct FlakyStore {
+        store: SqliteStore,
+        schema_failures: AtomicU32,
+        insert_rejects: AtomicU32,
+    }
+
+    /// Count down `n`, true if it was not 0 yet.
+    fn take(n: &AtomicU32) -> bool {
+        n.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+            .is_ok()
+    }
+
+    #[async_trait]
+    impl MetricsStore for FlakyStore {
+        async fn ensure_schema(
+            &self,
+            db_name: &str,
+            auto_create: bool,
+            auto_migrate: bool,
+        ) -> Result<bool, StoreError> {
+            if take(&self.schema_failures) {
+                return Err(StoreError::Transient("connection lost".into()));
+            }
+            self.store.ensure_schema(db_name, auto_create, auto_migrate).await
+        }
+
+        async fn insert_batch(
+            &self,
+            db_name: &str,
+            table: &TableSchema,
+            rows: &[Vec<SqlValue>],
+        ) -> Result<(), StoreError> {
+            if take(&self.insert_rejects) {
+                return Err(StoreError::Rejected("table is read only".into()));
+            }
+            self.store.insert_batch(db_name, table, rows).await
+        }
+
+        async fn query(
+            &self,
+            db_name: &str,
+            table: &TableSchema,
+            since: i64,
+            until: i64,
+        ) -> Result<Vec<Vec<SqlValue>>, StoreError> {
+            self.store.query(db_name, table, since, until).await
+        }
+
+        async fn databases(&self) -> Result<Vec<String>, StoreError> {
+            self.store.databases().await
+        }
+
+        async fn expire(&self, db_name: &str, table: &TableSchema, before: i64) -> Result<Removed, StoreError> {
+            self.store.expire(db_name, table, before).await
+        }
+
+        async fn ensure_partitions(
+            &self,
+            db_name: &str,
+            table: &TableSchema,
+            split: SplitMode,
+            ahead: u32,
+            now: i64,
+        ) -> Result<Vec<String>, StoreError> {
+            self.store.ensure_partitions(db_name, table, split, ahead, now).await
+        }
+
+        async fn rollup(
+            &self,
+            db_name: &str,
+            table: &TableSchema,
+            timestamps: &BTreeSet<i64>,
+        ) -> Result<(), StoreError> {
+            self.store.rollup(db_name, table, timestamps).await
+        }
+    }
+
+    /// Consume `items` counters of one env into `store`, until all are inserted.
+    /// Return the dead letters left.
+    async fn consume_all(store: Arc<FlakyStore>, items: usize, dir: &std::path::Path) -> usize {
+        let dead_letter = Arc::new(FileDeadLetter::new(&dir.join("dead_letter")).await.unwrap());
+        let queue: Arc<dyn MetricsQueue> = Arc::new(MemoryQueue::new(10));
+        let data = r#"{"alarm_type":"counter","env":"test_env","content":{"send_timestamp":"1669269373","public_ip":"127.0.0.1:9000","category":"xvm","tag":"contract_manager_counter","count":1,"value":1}}"#;
+        queue
+            .push(&HashMap::from([(
+                MetricsAlarmType::Counter,
+                vec![data.to_string(); items],
+            )]))
+            .await
+            .unwrap();
+        let config = ConsumerConfig::parse_from([
+            "dw_server_consumer",
+            "--batch_max_wait_ms",
+            "10",
+            "--batch_poll_interval_ms",
+            "10",
+        ]);
+        let db_name = config.database.database_name("test_env").unwrap();
+
+        let consume = handle_counter(
+            store.clone(),
+            config.database.clone(),
+            config.batch.settings(MetricsAlarmType::Counter),
+            queue.clone(),
+            dead_letter.clone(),
+            Arc::new(ConsumerStats::default()),
+        );
+        let inserted = async {
+            let table = TableSchema::of::<CounterUnit>();
+            loop {
+                match store.query(&db_name, &table, 0, i64::MAX).await {
+                    Ok(rows) if rows.len() == items => return,
+                    _ => sleep(Duration::from_millis(10)).await,
+                }
+            }
+        };
+        tokio::select! {
+            _ = consume => unreachable!(),
+            r = tokio::time::timeout(Duration::from_secs(10), inserted) => r.unwrap(),
+        }
+        dead_letter.list(&MetricsAlarmType::Counter, 10).await.unwrap().len()
+    }
+
+    async fn do_test_cache_retry() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Arc::new(FlakyStore {
+            store: SqliteStore::new(&dir.path().join("store")).await.unwrap(),
+            schema_failures: AtomicU32::new(1),
+            insert_rejects: AtomicU32::new(0),
+        });
+        assert_eq!(consume_all(store.clone(), 1, dir.path()).await, 0);
+        assert_eq!(store.schema_failures.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn test_cache_retry() {
+        tokio_test::block_on(do_test_cache_retry());
+    }
+
+    async fn do_test_batch_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        // the batch of 2, then both its halves.
+        let store = Arc::new(FlakyStore {
+            store: SqliteStore::new(&dir.path().join("store")).await.unwrap(),
+            schema_failures: AtomicU32::new(0),
+            insert_rejects: AtomicU32::new(3),
+        });
+        assert_eq!(consume_all(store.clone(), 2, dir.path()).await, 0);
+        assert_eq!(store.insert_rejects.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn test_batch_rejected() {
+        tokio_test::block_on(do_test_batch_rejected());
+    }
+}
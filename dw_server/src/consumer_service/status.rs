@@ -0,0 +1,433 @@
+use std::collections::{BTreeMap, HashMap};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use serde_json::json;
+
+use metrics_types::MetricsAlarmType;
+
+use crate::error::{ServerError, StoreError};
+use crate::queue::MetricsQueue;
+use crate::store::metrics_tables;
+use crate::telemetry::{health_response, metrics_response, Exposition, Histogram};
+
+#[derive(Debug, Default)]
+struct AlarmTypeStats {
+    rows_fetched: u64,
+    /// error of the last fetch, cleared by a successful one.
+    fetch_error: Option<String>,
+    rows_committed: BTreeMap<String, u64>,
+    /// by env and `transient` or `rejected`.
+    insert_errors: BTreeMap<(String, &'static str), u64>,
+    rows_dead_lettered: BTreeMap<String, u64>,
+    /// items not stored in any env database, by env and reason.
+    items_dropped: BTreeMap<(String, &'static str), u64>,
+    /// every insert statement, successful or not.
+    insert_latency: Histogram,
+    /// rows waiting in each open env backend.
+    cached_rows: BTreeMap<String, usize>,
+    last_commit: Option<Instant>,
+}
+
+impl AlarmTypeStats {
+    fn cached(&self) -> usize {
+        self.cached_rows.values().sum()
+    }
+}
+
+/// writes the samples of one family for an alarm type.
+type WriteSamples = fn(&mut Exposition, &str, &AlarmTypeStats);
+
+/// #### ConsumerStats
+///
+/// What the alarm type handlers of one consumer process did, served at `/metrics` and checked by `/health`.
+#[derive(Debug)]
+pub struct ConsumerStats {
+    started: Instant,
+    alarm_types: Mutex<HashMap<MetricsAlarmType, AlarmTypeStats>>,
+}
+
+impl Default for ConsumerStats {
+    fn default() -> Self {
+        ConsumerStats {
+            started: Instant::now(),
+            alarm_types: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl ConsumerStats {
+    fn update(&self, alarm_type: MetricsAlarmType, f: impl FnOnce(&mut AlarmTypeStats)) {
+        let mut alarm_types = self.alarm_types.lock().unwrap_or_else(|e| e.into_inner());
+        f(alarm_types.entry(alarm_type).or_default())
+    }
+
+    pub fn fetched(&self, alarm_type: MetricsAlarmType, rows: usize) {
+        self.update(alarm_type, |s| {
+            s.rows_fetched += rows as u64;
+            s.fetch_error = None;
+        })
+    }
+
+    pub fn fetch_failed(&self, alarm_type: MetricsAlarmType, error: &ServerError) {
+        self.update(alarm_type, |s| s.fetch_error = Some(error.to_string()))
+    }
+
+    pub fn inserted(&self, alarm_type: MetricsAlarmType, env: &str, rows: usize, latency: Duration) {
+        self.update(alarm_type, |s| {
+            *s.rows_committed.entry(env.to_string()).or_default() += rows as u64;
+            s.insert_latency.observe(latency.as_secs_f64());
+            s.last_commit = Some(Instant::now());
+        })
+    }
+
+    pub fn insert_failed(&self, alarm_type: MetricsAlarmType, env: &str, error: &StoreError, latency: Duration) {
+        let kind = match error.is_transient() {
+            true => "transient",
+            false => "rejected",
+        };
+        self.update(alarm_type, |s| {
+            *s.insert_errors.entry((env.to_string(), kind)).or_default() += 1;
+            s.insert_latency.observe(latency.as_secs_f64());
+        })
+    }
+
+    pub fn dead_lettered(&self, alarm_type: MetricsAlarmType, env: &str, rows: usize) {
+        self.update(alarm_type, |s| {
+            *s.rows_dead_lettered.entry(env.to_string()).or_default() += rows as u64
+        })
+    }
+
+    /// One item of `env` not stored for `reason`: `invalid_data`, `invalid_env`, `database_missing`
+    /// or `database_error`.
+    pub fn dropped(&self, alarm_type: MetricsAlarmType, env: &str, reason: &'static str) {
+        self.update(alarm_type, |s| {
+            *s.items_dropped.entry((env.to_string(), reason)).or_default() += 1
+        })
+    }
+
+    /// Rows now cached by each open env backend of `alarm_type`, envs not listed are closed.
+    pub fn set_cached(&self, alarm_type: MetricsAlarmType, cached: impl IntoIterator<Item = (String, usize)>) {
+        self.update(alarm_type, |s| s.cached_rows = cached.into_iter().collect())
+    }
+
+    /// Prometheus text of these stats, with the depth of each alarm type in `queue`.
+    pub async fn render(&self, queue: &dyn MetricsQueue) -> Exposition {
+        let mut depths = Vec::new();
+        for (alarm_type, _) in metrics_tables() {
+            match queue.depth(&alarm_type).await {
+                Ok(depth) => depths.push((alarm_type, depth, queue.dropped(&alarm_type))),
+                Err(e) => println!("{} queue depth error {}", alarm_type, e),
+            }
+        }
+
+        let mut out = Exposition::default();
+        out.family(
+            "dw_consumer_uptime_seconds",
+            "gauge",
+            "Seconds since the consumer started.",
+        )
+        .sample("dw_consumer_uptime_seconds", &[], self.started.elapsed().as_secs());
+        out.family(
+            "dw_consumer_queue_depth",
+            "gauge",
+            "Items the queue holds, not popped or not acked yet.",
+        );
+        for (alarm_type, depth, _) in &depths {
+            out.sample(
+                "dw_consumer_queue_depth",
+                &[("alarm_type", &alarm_type.to_string())],
+                depth,
+            );
+        }
+        out.family(
+            "dw_consumer_queue_dropped_total",
+            "counter",
+            "Items the queue removed before they were acked, e.g. over the stream max_len.",
+        );
+        for (alarm_type, _, dropped) in &depths {
+            out.sample(
+                "dw_consumer_queue_dropped_total",
+                &[("alarm_type", &alarm_type.to_string())],
+                dropped,
+            );
+        }
+
+        let alarm_types = self.alarm_types.lock().unwrap_or_else(|e| e.into_inner());
+        let mut alarm_types = alarm_types.iter().collect::<Vec<_>>();
+        alarm_types.sort_by_key(|(alarm_type, _)| alarm_type.to_string());
+        let families: [(&str, &str, &str, WriteSamples); 9] = [
+            (
+                "dw_consumer_rows_fetched_total",
+                "counter",
+                "Items fetched from the queue.",
+                |out, t, s| {
+                    out.sample("dw_consumer_rows_fetched_total", &[("alarm_type", t)], s.rows_fetched);
+                },
+            ),
+            (
+                "dw_consumer_rows_committed_total",
+                "counter",
+                "Rows inserted into the store.",
+                |out, t, s| {
+                    for (env, rows) in &s.rows_committed {
+                        out.sample(
+                            "dw_consumer_rows_committed_total",
+                            &[("alarm_type", t), ("env", env)],
+                            rows,
+                        );
+                    }
+                },
+            ),
+            (
+                "dw_consumer_insert_errors_total",
+                "counter",
+                "Failed insert statements, by transient or rejected error.",
+                |out, t, s| {
+                    for ((env, kind), errors) in &s.insert_errors {
+                        let labels = [("alarm_type", t), ("env", env.as_str()), ("kind", kind)];
+                        out.sample("dw_consumer_insert_errors_total", &labels, errors);
+                    }
+                },
+            ),
+            (
+                "dw_consumer_rows_dead_lettered_total",
+                "counter",
+                "Rows given up to dead letter.",
+                |out, t, s| {
+                    for (env, rows) in &s.rows_dead_lettered {
+                        out.sample(
+                            "dw_consumer_rows_dead_lettered_total",
+                            &[("alarm_type", t), ("env", env)],
+                            rows,
+                        );
+                    }
+                },
+            ),
+            (
+                "dw_consumer_items_dropped_total",
+                "counter",
+                "Items not stored in any env database, by reason, each one dead-lettered.",
+                |out, t, s| {
+                    for ((env, reason), items) in &s.items_dropped {
+                        let labels = [("alarm_type", t), ("env", env.as_str()), ("reason", reason)];
+                        out.sample("dw_consumer_items_dropped_total", &labels, items);
+                    }
+                },
+            ),
+            (
+                "dw_consumer_insert_duration_seconds",
+                "histogram",
+                "Insert statement latency.",
+                |out, t, s| {
+                    out.histogram(
+                        "dw_consumer_insert_duration_seconds",
+                        &[("alarm_type", t)],
+                        &s.insert_latency,
+                    );
+                },
+            ),
+            (
+                "dw_consumer_cached_rows",
+                "gauge",
+                "Rows waiting to be committed.",
+                |out, t, s| {
+                    for (env, rows) in &s.cached_rows {
+                        out.sample("dw_consumer_cached_rows", &[("alarm_type", t), ("env", env)], rows);
+                    }
+                },
+            ),
+            (
+                "dw_consumer_open_envs",
+                "gauge",
+                "Env backends open, each caching rows of one env database.",
+                |out, t, s| {
+                    out.sample("dw_consumer_open_envs", &[("alarm_type", t)], s.cached_rows.len());
+                },
+            ),
+            (
+                "dw_consumer_last_commit_age_seconds",
+                "gauge",
+                "Seconds since the last successful insert.",
+                |out, t, s| {
+                    if let Some(last_commit) = s.last_commit {
+                        let age = last_commit.elapsed().as_secs_f64();
+                        out.sample("dw_consumer_last_commit_age_seconds", &[("alarm_type", t)], age);
+                    }
+                },
+            ),
+        ];
+        for (name, kind, help, write) in families {
+            out.family(name, kind, help);
+            for (alarm_type, stats) in &alarm_types {
+                write(&mut out, &alarm_type.to_string(), stats);
+            }
+        }
+        out
+    }
+
+    /// Unhealthy if the last fetch of an alarm type failed,
+    /// or its cached rows are not committed for `stall` since the last commit (or start).
+    pub fn health(&self, stall: Duration) -> (bool, serde_json::Value) {
+        let alarm_types = self.alarm_types.lock().unwrap_or_else(|e| e.into_inner());
+        let mut healthy = true;
+        let mut report = serde_json::Map::new();
+        for (alarm_type, stats) in alarm_types.iter() {
+            let since_commit = stats.last_commit.unwrap_or(self.started).elapsed();
+            let stalled = stats.cached() > 0 && since_commit > stall;
+            let ok = stats.fetch_error.is_none() && !stalled;
+            healthy &= ok;
+            report.insert(
+                alarm_type.to_string(),
+                json!({
+                    "healthy": ok,
+                    "cached_rows": stats.cached(),
+                    "last_commit_age_secs": stats.last_commit.map(|t| t.elapsed().as_secs()),
+                    "fetch_error": stats.fetch_error,
+                }),
+            );
+        }
+        let status = match healthy {
+            true => "ok",
+            false => "unhealthy",
+        };
+        (
+            healthy,
+            json!({
+                "status": status,
+                "uptime_secs": self.started.elapsed().as_secs(),
+                "alarm_types": report,
+            }),
+        )
+    }
+}
+
+async fn handle(
+    req: Request<Body>,
+    stats: Arc<ConsumerStats>,
+    queue: Arc<dyn MetricsQueue>,
+    stall: Duration,
+) -> Response<Body> {
+    match (req.method(), req.uri().path()) {
+        (&Method::GET, "/health") => {
+            let (healthy, body) = stats.health(stall);
+            health_response(healthy, body)
+        }
+        (&Method::GET, "/metrics") => metrics_response(stats.render(queue.as_ref()).await),
+        _ => Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::from("404 NOT FOUND"))
+            .unwrap(),
+    }
+}
+
+/// Serve `/health` and `/metrics` of `stats` on `addr`, failing at once if it can't listen.
+pub async fn serve(
+    addr: SocketAddr,
+    stats: Arc<ConsumerStats>,
+    queue: Arc<dyn MetricsQueue>,
+    stall: Duration,
+) -> Result<(), ServerError> {
+    let make_service = make_service_fn(move |_| {
+        let (stats, queue) = (stats.clone(), queue.clone());
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                let (stats, queue) = (stats.clone(), queue.clone());
+                async move { Ok::<_, Infallible>(handle(req, stats, queue, stall).await) }
+            }))
+        }
+    });
+    let server = Server::try_bind(&addr)
+        .map_err(|e| ServerError::ConfigError(format!("status listen on {}: {}", addr, e)))?
+        .serve(make_service);
+    println!("Consumer status on http://{}", addr);
+    server
+        .await
+        .map_err(|e| ServerError::FileIOError(format!("status server: {}", e)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::queue::MemoryQueue;
+    use std::collections::HashMap;
+
+    async fn do_test_consumer_stats() {
+        let stats = Arc::new(ConsumerStats::default());
+        let queue: Arc<dyn MetricsQueue> = Arc::new(MemoryQueue::new(10));
+        queue
+            .push(&HashMap::from([(MetricsAlarmType::Timer, vec!["t1".to_string()])]))
+            .await
+            .unwrap();
+        let get = |path: &str| Request::get(path).body(Body::empty()).unwrap();
+
+        stats.fetched(MetricsAlarmType::Counter, 3);
+        stats.inserted(MetricsAlarmType::Counter, "env1", 2, Duration::from_millis(20));
+        stats.insert_failed(
+            MetricsAlarmType::Counter,
+            "env1",
+            &StoreError::Transient("lost".into()),
+            Duration::from_millis(2),
+        );
+        stats.dead_lettered(MetricsAlarmType::Counter, "env1", 1);
+        stats.dropped(MetricsAlarmType::Counter, "env1", "database_missing");
+        stats.set_cached(
+            MetricsAlarmType::Counter,
+            [("env1".to_string(), 1), ("env2".to_string(), 0)],
+        );
+
+        let resp = handle(get("/metrics"), stats.clone(), queue.clone(), Duration::from_secs(300)).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        let body = String::from_utf8_lossy(&body);
+        for line in [
+            "dw_consumer_queue_depth{alarm_type=\"timer\"} 1",
+            "dw_consumer_queue_depth{alarm_type=\"counter\"} 0",
+            "dw_consumer_queue_dropped_total{alarm_type=\"counter\"} 0",
+            "dw_consumer_rows_fetched_total{alarm_type=\"counter\"} 3",
+            "dw_consumer_rows_committed_total{alarm_type=\"counter\",env=\"env1\"} 2",
+            "dw_consumer_insert_errors_total{alarm_type=\"counter\",env=\"env1\",kind=\"transient\"} 1",
+            "dw_consumer_rows_dead_lettered_total{alarm_type=\"counter\",env=\"env1\"} 1",
+            "dw_consumer_items_dropped_total{alarm_type=\"counter\",env=\"env1\",reason=\"database_missing\"} 1",
+            "dw_consumer_insert_duration_seconds_bucket{alarm_type=\"counter\",le=\"0.025\"} 2",
+            "dw_consumer_insert_duration_seconds_count{alarm_type=\"counter\"} 2",
+            "dw_consumer_cached_rows{alarm_type=\"counter\",env=\"env2\"} 0",
+            "dw_consumer_open_envs{alarm_type=\"counter\"} 2",
+        ] {
+            assert!(body.lines().any(|l| l == line), "{} not in\n{}", line, body);
+        }
+        assert!(body.contains("dw_consumer_last_commit_age_seconds{alarm_type=\"counter\"}"));
+
+        let resp = handle(get("/health"), stats.clone(), queue.clone(), Duration::from_secs(300)).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        // cached rows not committed for longer than `stall`.
+        let resp = handle(get("/health"), stats.clone(), queue.clone(), Duration::ZERO).await;
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        stats.set_cached(MetricsAlarmType::Counter, []);
+        let resp = handle(get("/health"), stats.clone(), queue.clone(), Duration::ZERO).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        stats.fetch_failed(MetricsAlarmType::Flow, &ServerError::RedisError("down".into()));
+        let resp = handle(get("/health"), stats.clone(), queue.clone(), Duration::from_secs(300)).await;
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        let body = serde_json::from_slice::<serde_json::Value>(&body).unwrap();
+        assert_eq!(body["status"], "unhealthy");
+        assert_eq!(body["alarm_types"]["flow"]["fetch_error"], "Redis error down");
+        stats.fetched(MetricsAlarmType::Flow, 0);
+        let resp = handle(get("/health"), stats.clone(), queue.clone(), Duration::from_secs(300)).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let resp = handle(get("/nothing"), stats, queue, Duration::from_secs(300)).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn test_consumer_stats() {
+        tokio_test::block_on(do_test_consumer_stats());
+    }
+}
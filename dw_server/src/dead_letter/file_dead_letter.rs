@@ -0,0 +1,156 @@
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+use async_trait::async_trait;
+use metrics_types::MetricsAlarmType;
+use tokio::fs::{self, OpenOptions};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+
+use super::{decode, encode, DeadLetter, DeadLetterStore};
+use crate::error::ServerError;
+
+/// #### FileDeadLetter
+///
+/// Dead letters on local disk, both files append-only so the consumer and the
+/// dead letter tool may use them at the same time:
+///
+/// ``` text
+/// <path>/counter.jsonl     one dead letter json per line, its id is the line's byte offset
+/// <path>/counter.removed   ids of removed dead letters, one per line
+/// ```
+pub struct FileDeadLetter {
+    root: PathBuf,
+    lock: Mutex<()>,
+}
+
+impl FileDeadLetter {
+    pub async fn new(root: &Path) -> Result<Self, ServerError> {
+        fs::create_dir_all(root).await?;
+        Ok(FileDeadLetter {
+            root: root.to_path_buf(),
+            lock: Mutex::new(()),
+        })
+    }
+
+    fn letters_path(&self, source: &MetricsAlarmType) -> PathBuf {
+        self.root.join(format!("{}.jsonl", source))
+    }
+
+    fn removed_path(&self, source: &MetricsAlarmType) -> PathBuf {
+        self.root.join(format!("{}.removed", source))
+    }
+}
+
+async fn append(path: &Path, buf: &str) -> Result<(), ServerError> {
+    let mut file = OpenOptions::new().create(true).append(true).open(path).await?;
+    file.write_all(buf.as_bytes()).await?;
+    file.sync_data().await?;
+    Ok(())
+}
+
+/// file content, empty if not exist.
+async fn read_or_empty(path: &Path) -> Result<String, ServerError> {
+    match fs::read_to_string(path).await {
+        Ok(content) => Ok(content),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(String::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[async_trait]
+impl DeadLetterStore for FileDeadLetter {
+    async fn push(&self, letters: Vec<DeadLetter>) -> Result<(), ServerError> {
+        let mut lines = HashMap::<MetricsAlarmType, String>::new();
+        for letter in letters {
+            let buf = lines.entry(letter.source).or_default();
+            buf.push_str(&encode(&letter)?);
+            buf.push('\n');
+        }
+
+        let _guard = self.lock.lock().await;
+        for (source, buf) in lines {
+            append(&self.letters_path(&source), &buf).await?;
+        }
+        Ok(())
+    }
+
+    async fn list(&self, source: &MetricsAlarmType, cnt: usize) -> Result<Vec<(String, DeadLetter)>, ServerError> {
+        let _guard = self.lock.lock().await;
+        let removed = read_or_empty(&self.removed_path(source)).await?;
+        let removed = removed.lines().collect::<HashSet<_>>();
+        let content = read_or_empty(&self.letters_path(source)).await?;
+
+        let mut letters = Vec::new();
+        let mut offset = 0;
+        for line in content.split_inclusive('\n') {
+            let id = offset.to_string();
+            offset += line.len();
+            // a line still being appended by another process.
+            if !line.ends_with('\n') || removed.contains(id.as_str()) {
+                continue;
+            }
+            if letters.len() == cnt {
+                break;
+            }
+            letters.push((id, decode(line.trim_end())?));
+        }
+        Ok(letters)
+    }
+
+    async fn remove(&self, source: &MetricsAlarmType, ids: &[String]) -> Result<(), ServerError> {
+        if ids.is_empty() {
+            return Ok(());
+        }
+        let buf = ids.iter().map(|id| format!("{}\n", id)).collect::<String>();
+        let _guard = self.lock.lock().await;
+        append(&self.removed_path(source), &buf).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    async fn do_test_file_dead_letter() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = FileDeadLetter::new(dir.path()).await.unwrap();
+        let letter = |data: &str| {
+            DeadLetter::new(
+                MetricsAlarmType::Counter,
+                "test_db".into(),
+                "Data too long for column 'tag'".into(),
+                data.into(),
+            )
+        };
+        store
+            .push(vec![letter("c1"), letter("c2"), letter("c3")])
+            .await
+            .unwrap();
+        assert!(store.list(&MetricsAlarmType::Timer, 10).await.unwrap().is_empty());
+
+        let listed = store.list(&MetricsAlarmType::Counter, 2).await.unwrap();
+        assert_eq!(
+            listed.iter().map(|(_, l)| l.data.as_str()).collect::<Vec<_>>(),
+            vec!["c1", "c2"]
+        );
+        assert_eq!(listed[0].0, "0");
+
+        store
+            .remove(&MetricsAlarmType::Counter, &[listed[0].0.clone()])
+            .await
+            .unwrap();
+        let listed = store.list(&MetricsAlarmType::Counter, 10).await.unwrap();
+        assert_eq!(
+            listed.iter().map(|(_, l)| l.data.as_str()).collect::<Vec<_>>(),
+            vec!["c2", "c3"]
+        );
+        assert_eq!(listed[0].1.env, "test_db");
+        assert_eq!(listed[0].1.error, "Data too long for column 'tag'");
+    }
+
+    #[test]
+    fn test_file_dead_letter() {
+        tokio_test::block_on(do_test_file_dead_letter());
+    }
+}
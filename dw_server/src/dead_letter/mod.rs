@@ -0,0 +1,77 @@
+mod file_dead_letter;
+mod redis_dead_letter;
+
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use async_trait::async_trait;
+use metrics_types::MetricsAlarmType;
+use serde::{Deserialize, Serialize};
+
+use crate::config::{DeadLetterBackend, DeadLetterConfig, RedisConfig};
+use crate::error::ServerError;
+
+pub use file_dead_letter::FileDeadLetter;
+pub use redis_dead_letter::RedisDeadLetter;
+
+/// One metrics item the consumer gave up on, with why.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DeadLetter {
+    /// queue the item was popped from.
+    pub source: MetricsAlarmType,
+    /// empty if the item is not even json.
+    pub env: String,
+    pub error: String,
+    /// unix timestamp in seconds.
+    pub time: u64,
+    /// metrics json, same format as queued.
+    pub data: String,
+}
+
+impl DeadLetter {
+    pub fn new(source: MetricsAlarmType, env: String, error: String, data: String) -> Self {
+        DeadLetter {
+            source,
+            env,
+            error,
+            time: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+            data,
+        }
+    }
+}
+
+/// #### DeadLetterStore
+///
+/// Where the consumer keeps items it can't commit, instead of dropping them,
+/// until they are fixed and replayed, or removed.
+#[async_trait]
+pub trait DeadLetterStore: Send + Sync {
+    async fn push(&self, letters: Vec<DeadLetter>) -> Result<(), ServerError>;
+
+    /// the oldest `cnt` letters from `source`, with their id.
+    async fn list(&self, source: &MetricsAlarmType, cnt: usize) -> Result<Vec<(String, DeadLetter)>, ServerError>;
+
+    async fn remove(&self, source: &MetricsAlarmType, ids: &[String]) -> Result<(), ServerError>;
+}
+
+/// Open the dead letter store selected by `config`.
+pub async fn open_dead_letter(
+    config: &DeadLetterConfig,
+    redis: &RedisConfig,
+) -> Result<Arc<dyn DeadLetterStore>, ServerError> {
+    Ok(match config.backend() {
+        DeadLetterBackend::File => Arc::new(FileDeadLetter::new(config.path()).await?),
+        DeadLetterBackend::Redis => Arc::new(RedisDeadLetter::new(redis).await?),
+    })
+}
+
+fn encode(letter: &DeadLetter) -> Result<String, ServerError> {
+    serde_json::to_string(letter).map_err(|e| ServerError::QueueError(e.to_string()))
+}
+
+fn decode(line: &str) -> Result<DeadLetter, ServerError> {
+    serde_json::from_str(line).map_err(|e| ServerError::QueueError(format!("bad dead letter {}: {}", line, e)))
+}
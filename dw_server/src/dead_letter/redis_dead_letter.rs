@@ -0,0 +1,49 @@
+use std::collections::HashMap;
+
+use async_trait::async_trait;
+use metrics_types::MetricsAlarmType;
+
+use super::{decode, encode, DeadLetter, DeadLetterStore};
+use crate::config::RedisConfig;
+use crate::error::ServerError;
+use crate::redis_conn::RedisConn;
+
+/// Dead letters in redis stream `[<key_prefix>:]dead_letter:<alarm_type>`, ids are stream entry ids.
+pub struct RedisDeadLetter {
+    conn: RedisConn,
+}
+
+impl RedisDeadLetter {
+    pub async fn new(config: &RedisConfig) -> Result<Self, ServerError> {
+        Ok(RedisDeadLetter {
+            conn: RedisConn::new(config).await?,
+        })
+    }
+}
+
+#[async_trait]
+impl DeadLetterStore for RedisDeadLetter {
+    async fn push(&self, letters: Vec<DeadLetter>) -> Result<(), ServerError> {
+        let mut values = HashMap::<MetricsAlarmType, Vec<String>>::new();
+        for letter in letters {
+            values.entry(letter.source).or_default().push(encode(&letter)?);
+        }
+        for (source, value) in values {
+            self.conn.dead_letter_add(&source, &value).await?;
+        }
+        Ok(())
+    }
+
+    async fn list(&self, source: &MetricsAlarmType, cnt: usize) -> Result<Vec<(String, DeadLetter)>, ServerError> {
+        self.conn
+            .dead_letter_range(source, cnt)
+            .await?
+            .into_iter()
+            .map(|item| Ok((item.id, decode(&item.data)?)))
+            .collect()
+    }
+
+    async fn remove(&self, source: &MetricsAlarmType, ids: &[String]) -> Result<(), ServerError> {
+        Ok(self.conn.dead_letter_delete(source, ids).await?)
+    }
+}
@@ -0,0 +1,67 @@
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum ServerError {
+    #[error("Config error {0}")]
+    ConfigError(String),
+
+    #[error("File IO error {0}")]
+    FileIOError(String),
+
+    #[error("Redis error {0}")]
+    RedisError(String),
+
+    #[error("Queue error {0}")]
+    QueueError(String),
+
+    #[error("Store error {0}")]
+    StoreError(String),
+
+    #[error("Env name error {0}")]
+    EnvNameError(String),
+
+    #[error("Archive error {0}")]
+    ArchiveError(String),
+}
+
+impl From<std::io::Error> for ServerError {
+    fn from(value: std::io::Error) -> Self {
+        ServerError::FileIOError(value.to_string())
+    }
+}
+
+impl From<toml::de::Error> for ServerError {
+    fn from(value: toml::de::Error) -> Self {
+        ServerError::ConfigError(value.to_string())
+    }
+}
+
+impl From<redis::RedisError> for ServerError {
+    fn from(value: redis::RedisError) -> Self {
+        ServerError::RedisError(value.to_string())
+    }
+}
+
+/// Errors of a `MetricsStore`.
+#[derive(Debug, Error)]
+pub enum StoreError {
+    /// worth retrying the same rows: connection lost, lock wait timeout, deadlock, too many connections.
+    #[error("Transient store error {0}")]
+    Transient(String),
+
+    /// caused by the statement or the rows themselves.
+    #[error("Store error {0}")]
+    Rejected(String),
+}
+
+impl StoreError {
+    pub fn is_transient(&self) -> bool {
+        matches!(self, StoreError::Transient(_))
+    }
+}
+
+impl From<StoreError> for ServerError {
+    fn from(value: StoreError) -> Self {
+        ServerError::StoreError(value.to_string())
+    }
+}
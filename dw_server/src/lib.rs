@@ -1,4 +1,17 @@
+pub mod archive;
+pub mod config;
 pub mod consumer_backend;
+pub mod consumer_service;
+pub mod dead_letter;
+pub mod error;
+pub mod migration;
 pub mod mysql_conn;
+pub mod partition;
+pub mod proxy_service;
+pub mod queue;
 pub mod redis_conn;
+pub mod retention;
+pub mod rollup;
+pub mod store;
+pub mod telemetry;
 // pub use redis_conn::RedisConn;
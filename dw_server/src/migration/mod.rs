@@ -0,0 +1,199 @@
+use std::collections::HashMap;
+
+use mysql_async::prelude::{Query, Queryable};
+use mysql_async::{Conn, Result};
+
+use metrics_types::sql::{Migration, SqlTable};
+use metrics_types::{CounterUnit, FlowUnit, TimerUnit};
+
+use crate::mysql_conn::MysqlPool;
+
+const SCHEMA_VERSION_TABLE: &str = "schema_version";
+
+/// `GET_LOCK` name of the database `migrate` is using, `dw_migrate_<db>` cut to the 64 chars MySQL allows.
+const MIGRATE_LOCK_NAME: &str = "LEFT(CONCAT('dw_migrate_', DATABASE()), 64)";
+
+/// how long `migrate` waits for another consumer migrating the same database, rebuilding a big table may be slow.
+const MIGRATE_LOCK_TIMEOUT_SECS: u64 = 600;
+
+const NEW_SCHEMA_VERSION_TABLE_OPT: &str = r#"
+    CREATE TABLE IF NOT EXISTS schema_version(
+        table_name VARCHAR(64) NOT NULL,
+        version INT NOT NULL,
+        description VARCHAR(255) DEFAULT "",
+        applied_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+        PRIMARY KEY(table_name, version)
+    )ENGINE = InnoDB DEFAULT CHARSET = utf8;
+    "#;
+
+/// A migration of `table` not applied yet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PendingMigration {
+    pub table: &'static str,
+    pub migration: Migration,
+}
+
+impl std::fmt::Display for PendingMigration {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} v{}: {}",
+            self.table, self.migration.version, self.migration.description
+        )
+    }
+}
+
+fn pending_of<T: SqlTable>(versions: &HashMap<String, u32>, pending: &mut Vec<PendingMigration>) {
+    let current = versions.get(T::table_name()).copied().unwrap_or(0);
+    pending.extend(
+        T::migrations()
+            .into_iter()
+            .filter(|m| m.version > current)
+            .map(|migration| PendingMigration {
+                table: T::table_name(),
+                migration,
+            }),
+    );
+}
+
+/// Migrations of all metrics tables newer than `versions`, the applied version of each table.
+fn pending_from(versions: &HashMap<String, u32>) -> Vec<PendingMigration> {
+    let mut pending = Vec::new();
+    pending_of::<CounterUnit>(versions, &mut pending);
+    pending_of::<TimerUnit>(versions, &mut pending);
+    pending_of::<FlowUnit>(versions, &mut pending);
+    pending
+}
+
+/// Pending migrations of the database `conn` is using, without changing anything.
+///
+/// A database without `schema_version` is at version 0 of each table, its first
+/// migration `CREATE TABLE IF NOT EXISTS` is a no-op for tables created before versioning.
+pub async fn pending(conn: &mut Conn) -> Result<Vec<PendingMigration>> {
+    let exist: Option<String> = conn
+        .exec_first(
+            "SELECT TABLE_NAME FROM information_schema.TABLES WHERE TABLE_SCHEMA = DATABASE() AND TABLE_NAME = ?",
+            (SCHEMA_VERSION_TABLE,),
+        )
+        .await?;
+    let versions = match exist {
+        Some(_) => "SELECT table_name, MAX(version) FROM schema_version GROUP BY table_name"
+            .fetch::<(String, u32), _>(&mut *conn)
+            .await?
+            .into_iter()
+            .collect(),
+        None => HashMap::new(),
+    };
+    Ok(pending_from(&versions))
+}
+
+/// Apply pending migrations of the database `conn` is using, in order, each recorded once applied.
+///
+/// Consumers opening the same database at once take turns on a named lock, the later ones find
+/// nothing pending. A step whose table, column or index already exists, e.g. applied before a crash
+/// that lost its `schema_version` row, is recorded as applied.
+///
+/// With `dry_run`, only return what would be applied.
+pub async fn migrate(conn: &mut Conn, dry_run: bool) -> Result<Vec<PendingMigration>> {
+    let pending = pending(conn).await?;
+    if dry_run || pending.is_empty() {
+        return Ok(pending);
+    }
+    let locked: Option<i64> = conn
+        .exec_first(
+            format!("SELECT GET_LOCK({}, ?)", MIGRATE_LOCK_NAME),
+            (MIGRATE_LOCK_TIMEOUT_SECS,),
+        )
+        .await?;
+    if locked != Some(1) {
+        return Err(mysql_async::Error::Other(
+            format!("migrate lock not taken in {}s", MIGRATE_LOCK_TIMEOUT_SECS).into(),
+        ));
+    }
+    let r = migrate_locked(conn).await;
+    let released = format!("DO RELEASE_LOCK({})", MIGRATE_LOCK_NAME).run(&mut *conn).await;
+    // the lock is also released when the connection closes, if releasing it failed.
+    let pending = r?;
+    released?;
+    Ok(pending)
+}
+
+async fn migrate_locked(conn: &mut Conn) -> Result<Vec<PendingMigration>> {
+    // pending again, another consumer may have migrated while this one waited for the lock.
+    let pending = pending(conn).await?;
+    NEW_SCHEMA_VERSION_TABLE_OPT.run(&mut *conn).await?;
+    for p in pending.iter() {
+        println!("migrate {}", p);
+        match p.migration.sql.run(&mut *conn).await {
+            Err(e) if already_applied(&e) => println!("migrate {} already applied: {}", p, e),
+            r => {
+                r?;
+            }
+        }
+        conn.exec_drop(
+            "INSERT INTO schema_version (table_name, version, description) VALUES (?, ?, ?)",
+            (p.table, p.migration.version, p.migration.description),
+        )
+        .await?;
+    }
+    Ok(pending)
+}
+
+/// DDL failed because its table (1050), column (1060) or index (1061) already exists.
+fn already_applied(e: &mysql_async::Error) -> bool {
+    matches!(e, mysql_async::Error::Server(e) if matches!(e.code, 1050 | 1060 | 1061))
+}
+
+/// Databases having metrics tables.
+pub async fn metrics_databases(pool: &MysqlPool) -> Result<Vec<String>> {
+    let mut conn = pool.get_any_conn().await?;
+    conn.exec(
+        "SELECT DISTINCT TABLE_SCHEMA FROM information_schema.TABLES WHERE TABLE_NAME = ?",
+        (CounterUnit::table_name(),),
+    )
+    .await
+}
+
+/// `migrate` existing database `db_name`.
+pub async fn migrate_database(pool: &MysqlPool, db_name: &str, dry_run: bool) -> Result<Vec<PendingMigration>> {
+    let mut conn = pool.get_conn(db_name).await?;
+    migrate(&mut conn, dry_run).await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_pending_from() {
+        let pending = pending_from(&HashMap::new());
+        assert_eq!(pending.len(), 15);
+        assert_eq!(pending[0].to_string(), "metrics_counter v1: create table");
+        assert_eq!(pending[4].to_string(), "metrics_counter v5: create 1d rollup table");
+        assert_eq!(pending[5].to_string(), "metrics_timer v1: create table");
+
+        let versions = HashMap::from([(String::from("metrics_counter"), 5), (String::from("metrics_timer"), 1)]);
+        let pending = pending_from(&versions);
+        assert_eq!(pending.len(), 9);
+        assert_eq!(pending[0].to_string(), "metrics_timer v2: index send_timestamp");
+        assert_eq!(pending[4].to_string(), "metrics_flow v1: create table");
+    }
+
+    #[test]
+    fn test_already_applied() {
+        let server_error = |code| {
+            mysql_async::Error::Server(mysql_async::ServerError {
+                code,
+                message: String::new(),
+                state: String::new(),
+            })
+        };
+        for code in [1050, 1060, 1061] {
+            assert!(already_applied(&server_error(code)), "{}", code);
+        }
+        assert!(!already_applied(&server_error(1146)));
+        assert!(!already_applied(&mysql_async::Error::Driver(
+            mysql_async::DriverError::ConnectionClosed
+        )));
+    }
+}
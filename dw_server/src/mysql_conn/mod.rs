@@ -1,95 +1,148 @@
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
 use mysql_async::Result;
 
-use mysql_async::{prelude::Query, Pool};
+use mysql_async::prelude::{Query, Queryable};
+use mysql_async::{Conn, Opts, OptsBuilder, Pool, PoolConstraints, PoolOpts};
+use tokio::sync::Mutex;
 
-use metrics_types::{sql::SqlTable, CounterUnit, FlowUnit, TimerUnit};
+use crate::config::MysqlPoolConfig;
+use crate::migration;
 
-#[derive(Debug)]
-pub struct MysqlDBConn {
-    db_name: String,
-    pool: Pool,
+/// Quote `name` as a mysql identifier.
+pub(crate) fn quote_identifier(name: &str) -> String {
+    format!("`{}`", name.replace('`', "``"))
 }
 
-impl MysqlDBConn {
-    pub async fn new(mysql_url: String, db_name: &String) -> Result<Self> {
-        let default_opt = format!("mysql://{}/information_schema", &mysql_url);
-        let target_opt = format!("mysql://{}/{}", &mysql_url, db_name);
-
-        let pool = mysql_async::Pool::new(default_opt.as_ref());
+/// Errors worth retrying the same rows for: connection lost (io errors and timeouts, closed connection or
+/// pool), too many connections (1040), server shutting down (1053), table full (1114), table missing while
+/// migrated or partitioned (1146), lock wait timeout (1205), deadlock (1213), read-only server during
+/// failover (1290).
+///
+/// Other driver and server errors are caused by the rows or the statement themselves.
+pub(crate) fn is_transient_error(err: &mysql_async::Error) -> bool {
+    match err {
+        mysql_async::Error::Io(_) => true,
+        mysql_async::Error::Driver(e) => matches!(
+            e,
+            mysql_async::DriverError::ConnectionClosed | mysql_async::DriverError::PoolDisconnected
+        ),
+        mysql_async::Error::Server(e) => matches!(e.code, 1040 | 1053 | 1114 | 1146 | 1205 | 1213 | 1290),
+        _ => false,
+    }
+}
 
-        // create db if not exist:
-        let mut conn = pool.get_conn().await?;
-        let sql = format!(r#"SHOW DATABASES LIKE "{}";"#, db_name);
-        let db_exist_result = sql
-            .map::<String, Option<String>, _, &mut mysql_async::Conn>(&mut conn, Some)
-            .await?;
-        // println!("db_exist_result:{:?}", db_exist_result);
+/// Apply pending schema migrations of `db_name`, or only report them if not `apply`.
+async fn migrate(conn: &mut Conn, db_name: &str, apply: bool) -> Result<()> {
+    let pending = migration::migrate(conn, !apply).await?;
+    if !apply && !pending.is_empty() {
+        println!("{} has {} pending migrations, not applied:", db_name, pending.len());
+        pending.iter().for_each(|p| println!("    {}", p));
+    }
+    Ok(())
+}
 
-        let mut need_create = false;
+/// a database found missing is not looked up again for this long.
+const MISSING_DATABASE_TTL: Duration = Duration::from_secs(60);
 
-        if db_exist_result.is_empty() {
-            let _ = format!(r#"CREATE DATABASE {};"#, db_name).run(&mut conn).await?;
-            println!("CREATE DATABASE {}", db_name);
-            need_create = true
-        }
-
-        drop(conn);
-        pool.disconnect().await?;
+#[derive(Default)]
+struct OpenedDatabases {
+    /// exist and migrated.
+    opened: HashSet<String>,
+    /// not exist and not allowed to create, since when.
+    missing: HashMap<String, Instant>,
+}
 
-        // re connected to this database
-        let pool = mysql_async::Pool::new(target_opt.as_ref());
+/// #### MysqlPool
+///
+/// One bounded connection pool of a consumer, shared by all env databases.
+/// Each connection is switched to its database with `USE` on checkout.
+pub struct MysqlPool {
+    pool: Pool,
+    databases: Mutex<OpenedDatabases>,
+}
 
-        let db_conn = MysqlDBConn {
-            db_name: db_name.clone(),
-            pool,
-        };
+impl MysqlPool {
+    pub fn new(mysql_url: &str, config: &MysqlPoolConfig) -> Result<Self> {
+        let constraints = PoolConstraints::new(config.min(), config.max())
+            .ok_or_else(|| mysql_async::Error::Other(Box::from("invalid mysql pool constraints")))?;
+        let opts = OptsBuilder::from_opts(Opts::from_url(&format!("mysql://{}", mysql_url))?)
+            .pool_opts(PoolOpts::default().with_constraints(constraints));
+        Ok(MysqlPool {
+            pool: Pool::new(opts),
+            databases: Mutex::new(OpenedDatabases::default()),
+        })
+    }
 
-        if need_create {
-            db_conn.create_table().await?;
+    /// A connection using database `db_name`.
+    pub async fn get_conn(&self, db_name: &str) -> Result<Conn> {
+        let mut conn = self.pool.get_conn().await?;
+        if let Err(e) = format!("USE {}", quote_identifier(db_name)).run(&mut conn).await {
+            // dropped since opened, check it again on next open.
+            if matches!(&e, mysql_async::Error::Server(e) if e.code == 1049) {
+                self.databases.lock().await.opened.remove(db_name);
+            }
+            return Err(e);
         }
-        Ok(db_conn)
+        Ok(conn)
     }
 
-    pub async fn close(self) -> Result<()> {
-        self.pool.disconnect().await
+    /// A connection using any database, for statements with qualified table names only.
+    pub async fn get_any_conn(&self) -> Result<Conn> {
+        self.pool.get_conn().await
     }
 
-    async fn create_table(&self) -> Result<()> {
-        println!("create table for {}", self.db_name);
-        let mut conn = self.pool.get_conn().await?;
+    /// Use database `db_name`, create it if not exist and `auto_create`.
+    /// Pending schema migrations are applied if `auto_migrate` or the database is new.
+    ///
+    /// Both are checked once per pool, return `false` if database not exist and not `auto_create`.
+    pub async fn open(&self, db_name: &str, auto_create: bool, auto_migrate: bool) -> Result<bool> {
+        // held while opening, so a database is never created or migrated twice at the same time.
+        let mut databases = self.databases.lock().await;
+        if databases.opened.contains(db_name) {
+            return Ok(true);
+        }
+        if databases
+            .missing
+            .get(db_name)
+            .is_some_and(|since| since.elapsed() < MISSING_DATABASE_TTL)
+        {
+            return Ok(false);
+        }
 
-        // MetricsCounter
-        let _ = CounterUnit::new_sql_table_opt().run(&mut conn).await?;
-        // MetricsTimer
-        let _ = TimerUnit::new_sql_table_opt().run(&mut conn).await?;
-        // MetricsFlow
-        let _ = FlowUnit::new_sql_table_opt().run(&mut conn).await?;
+        let mut conn = self.get_any_conn().await?;
+        let db_exist_result: Vec<String> = conn
+            .exec(
+                "SELECT SCHEMA_NAME FROM information_schema.SCHEMATA WHERE SCHEMA_NAME = ?",
+                (db_name,),
+            )
+            .await?;
 
+        let mut need_create = false;
+        if db_exist_result.is_empty() {
+            if !auto_create {
+                println!("DATABASE {} not exist and not allowed to create", db_name);
+                databases.missing.insert(db_name.to_string(), Instant::now());
+                return Ok(false);
+            }
+            format!("CREATE DATABASE {};", quote_identifier(db_name))
+                .run(&mut conn)
+                .await?;
+            println!("CREATE DATABASE {}", db_name);
+            need_create = true
+        }
         drop(conn);
-        Ok(())
-    }
-
-    pub(crate) async fn insert<UnitType>(&self, insert_data: Vec<UnitType>) -> Result<()>
-    where
-        UnitType: SqlTable,
-    {
-        let mut conn = self.pool.get_conn().await?;
 
-        format!(
-            "{} {}",
-            UnitType::multi_insert_table_opt(),
-            insert_data
-                .iter()
-                .map(|d| d.to_param_value_str())
-                .collect::<Vec<_>>()
-                .join(", ")
-        )
-        .run(&mut conn)
-        .await?;
-
-        drop(conn);
+        let mut conn = self.get_conn(db_name).await?;
+        migrate(&mut conn, db_name, need_create || auto_migrate).await?;
+        databases.missing.remove(db_name);
+        databases.opened.insert(db_name.to_string());
+        Ok(true)
+    }
 
-        Ok(())
+    pub async fn disconnect(self) -> Result<()> {
+        self.pool.disconnect().await
     }
 }
 
@@ -98,10 +151,50 @@ mod test {
 
     use super::*;
 
-    async fn test_create_conn() -> Result<MysqlDBConn> {
-        let test_url_opt = String::from("dw-consumer:consumerPswd!1@localhost:3306");
-        let conn = MysqlDBConn::new(test_url_opt, &String::from("test_db")).await?;
-        Ok(conn)
+    async fn test_create_conn() -> Result<Conn> {
+        let test_url_opt = "dw-consumer:consumerPswd!1@localhost:3306";
+        let pool = MysqlPool::new(test_url_opt, &MysqlPoolConfig::default())?;
+        assert!(pool.open("test_db", true, true).await?);
+        pool.get_conn("test_db").await
+    }
+
+    #[test]
+    fn test_quote_identifier() {
+        assert_eq!(quote_identifier("test_db"), "`test_db`");
+        assert_eq!(
+            quote_identifier("a`; DROP DATABASE x; --"),
+            "`a``; DROP DATABASE x; --`"
+        );
+    }
+
+    #[test]
+    fn test_is_transient_error() {
+        let server_error = |code| {
+            mysql_async::Error::Server(mysql_async::ServerError {
+                code,
+                message: String::new(),
+                state: String::new(),
+            })
+        };
+        for code in [1040, 1053, 1114, 1146, 1205, 1213, 1290] {
+            assert!(is_transient_error(&server_error(code)), "{}", code);
+        }
+        // data too long, duplicate entry, command denied.
+        for code in [1406, 1062, 1142] {
+            assert!(!is_transient_error(&server_error(code)), "{}", code);
+        }
+        let driver_error = mysql_async::Error::Driver;
+        assert!(is_transient_error(&driver_error(
+            mysql_async::DriverError::ConnectionClosed
+        )));
+        assert!(is_transient_error(&driver_error(
+            mysql_async::DriverError::PoolDisconnected
+        )));
+        let mismatch = mysql_async::DriverError::StmtParamsMismatch {
+            required: 2,
+            supplied: 1,
+        };
+        assert!(!is_transient_error(&driver_error(mismatch)));
     }
 
     #[test]
@@ -0,0 +1,280 @@
+use chrono::{Datelike, NaiveDate, TimeZone, Utc};
+use mysql_async::prelude::{Query, Queryable};
+use mysql_async::{Conn, Result};
+
+use metrics_types::sql::SqlTable;
+use metrics_types::{CounterUnit, FlowUnit, TimerUnit};
+
+use crate::config::SplitMode;
+use crate::mysql_conn::{quote_identifier, MysqlPool};
+
+/// catch-all partition of rows newer than every period partition.
+const MAX_PARTITION: &str = "p_max";
+
+/// When first partitioning a table, rows older than this go to its first partition
+/// instead of one partition per period.
+const MAX_INITIAL_HISTORY: i64 = 366 * 86400;
+
+/// A range partition, holding rows with `send_timestamp` less than `less_than`, `None` for MAXVALUE.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Partition {
+    pub name: String,
+    pub less_than: Option<i64>,
+}
+
+/// Start (UTC) of the day or month containing `ts`.
+fn period_start(split: SplitMode, ts: i64) -> i64 {
+    match split {
+        SplitMode::None => ts,
+        SplitMode::Day => ts - ts.rem_euclid(86400),
+        SplitMode::Month => {
+            let date = Utc.timestamp_opt(ts, 0).unwrap().date_naive();
+            utc_timestamp(date.with_day(1).unwrap())
+        }
+    }
+}
+
+/// Start of the period after the one starting at `start`.
+fn next_period(split: SplitMode, start: i64) -> i64 {
+    match split {
+        SplitMode::None => start,
+        SplitMode::Day => start + 86400,
+        SplitMode::Month => {
+            let date = Utc.timestamp_opt(start, 0).unwrap().date_naive();
+            let (year, month) = match date.month() {
+                12 => (date.year() + 1, 1),
+                m => (date.year(), m + 1),
+            };
+            utc_timestamp(NaiveDate::from_ymd_opt(year, month, 1).unwrap())
+        }
+    }
+}
+
+fn utc_timestamp(date: NaiveDate) -> i64 {
+    Utc.from_utc_datetime(&date.and_hms_opt(0, 0, 0).unwrap()).timestamp()
+}
+
+/// `p20240115` for a day, `p202401` for a month.
+fn partition_name(split: SplitMode, start: i64) -> String {
+    let date = Utc.timestamp_opt(start, 0).unwrap().date_naive();
+    match split {
+        SplitMode::Month => format!("p{}", date.format("%Y%m")),
+        _ => format!("p{}", date.format("%Y%m%d")),
+    }
+}
+
+/// Partitions following boundary `from`, until the period `ahead` periods after `now`'s is covered.
+///
+/// `from` needs not be aligned to `split`, e.g. after switching from day to month.
+fn plan(split: SplitMode, from: i64, now: i64, ahead: u32) -> Vec<Partition> {
+    if split == SplitMode::None {
+        return Vec::new();
+    }
+    let mut until = next_period(split, period_start(split, now));
+    for _ in 0..ahead {
+        until = next_period(split, until);
+    }
+    let mut partitions = Vec::new();
+    let mut boundary = from;
+    while boundary < until {
+        let start = period_start(split, boundary);
+        boundary = next_period(split, start);
+        partitions.push(Partition {
+            name: partition_name(split, start),
+            less_than: Some(boundary),
+        });
+    }
+    partitions
+}
+
+fn partition_definitions(partitions: &[Partition]) -> String {
+    partitions
+        .iter()
+        .map(|p| match p.less_than {
+            Some(less_than) => format!("PARTITION {} VALUES LESS THAN ({})", p.name, less_than),
+            None => format!("PARTITION {} VALUES LESS THAN MAXVALUE", p.name),
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Partitions of `table` in the database `conn` is using, in order, empty if not partitioned.
+pub async fn partitions(conn: &mut Conn, table: &str) -> Result<Vec<Partition>> {
+    let rows: Vec<(Option<String>, Option<String>)> = conn
+        .exec(
+            "SELECT PARTITION_NAME, PARTITION_DESCRIPTION FROM information_schema.PARTITIONS \
+             WHERE TABLE_SCHEMA = DATABASE() AND TABLE_NAME = ? ORDER BY PARTITION_ORDINAL_POSITION",
+            (table,),
+        )
+        .await?;
+    Ok(rows
+        .into_iter()
+        .filter_map(|(name, description)| {
+            name.map(|name| Partition {
+                name,
+                less_than: description.and_then(|d| d.parse().ok()),
+            })
+        })
+        .collect())
+}
+
+/// Partition `table` by `send_timestamp` ranges of `split` periods, and create partitions up to
+/// `ahead` periods after `now`'s. Return the partitions created.
+///
+/// An unpartitioned table is rebuilt once, with a partition for each period since its oldest row
+/// (within `MAX_INITIAL_HISTORY`), this may take a while on a big table.
+pub async fn ensure_partitions(
+    conn: &mut Conn,
+    table: &str,
+    split: SplitMode,
+    ahead: u32,
+    now: i64,
+) -> Result<Vec<Partition>> {
+    if split == SplitMode::None {
+        return Ok(Vec::new());
+    }
+    let existing = partitions(conn, table).await?;
+    if existing.is_empty() {
+        let oldest: Option<i64> = format!("SELECT MIN(send_timestamp) FROM {}", quote_identifier(table))
+            .first::<Option<i64>, _>(&mut *conn)
+            .await?
+            .flatten();
+        let from = oldest.unwrap_or(now).clamp(now - MAX_INITIAL_HISTORY, now);
+        let mut created = plan(split, period_start(split, from), now, ahead);
+        created.push(Partition {
+            name: MAX_PARTITION.into(),
+            less_than: None,
+        });
+        format!(
+            "ALTER TABLE {} PARTITION BY RANGE (send_timestamp) ({})",
+            quote_identifier(table),
+            partition_definitions(&created)
+        )
+        .run(&mut *conn)
+        .await?;
+        return Ok(created);
+    }
+
+    // only the catch-all left if every period partition was dropped.
+    let last = existing
+        .iter()
+        .filter_map(|p| p.less_than)
+        .max()
+        .unwrap_or_else(|| period_start(split, now));
+    let created = plan(split, last, now, ahead);
+    if created.is_empty() {
+        return Ok(created);
+    }
+    let statement = match existing.iter().find(|p| p.less_than.is_none()) {
+        // split the catch-all partition, it holds rows of future periods only.
+        Some(max) => format!(
+            "ALTER TABLE {} REORGANIZE PARTITION {} INTO ({}, PARTITION {} VALUES LESS THAN MAXVALUE)",
+            quote_identifier(table),
+            max.name,
+            partition_definitions(&created),
+            max.name
+        ),
+        None => format!(
+            "ALTER TABLE {} ADD PARTITION ({})",
+            quote_identifier(table),
+            partition_definitions(&created)
+        ),
+    };
+    statement.run(&mut *conn).await?;
+    Ok(created)
+}
+
+/// Drop partitions of `table` holding only rows older than `before`. Return the partitions dropped.
+///
+/// Dropping a partition is a metadata change, much cheaper than deleting its rows.
+pub async fn drop_partitions_before(conn: &mut Conn, table: &str, before: i64) -> Result<Vec<Partition>> {
+    let dropped = partitions(conn, table)
+        .await?
+        .into_iter()
+        .filter(|p| p.less_than.is_some_and(|less_than| less_than <= before))
+        .collect::<Vec<_>>();
+    if !dropped.is_empty() {
+        format!(
+            "ALTER TABLE {} DROP PARTITION {}",
+            quote_identifier(table),
+            dropped.iter().map(|p| p.name.as_str()).collect::<Vec<_>>().join(", ")
+        )
+        .run(&mut *conn)
+        .await?;
+    }
+    Ok(dropped)
+}
+
+/// `ensure_partitions` of all metrics tables of existing database `db_name`.
+pub async fn partition_database(
+    pool: &MysqlPool,
+    db_name: &str,
+    split: SplitMode,
+    ahead: u32,
+) -> Result<Vec<(&'static str, Vec<Partition>)>> {
+    let mut conn = pool.get_conn(db_name).await?;
+    let now = Utc::now().timestamp();
+    let mut created = Vec::new();
+    for table in [
+        CounterUnit::table_name(),
+        TimerUnit::table_name(),
+        FlowUnit::table_name(),
+    ] {
+        created.push((table, ensure_partitions(&mut conn, table, split, ahead, now).await?));
+    }
+    Ok(created)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn ts(y: i32, m: u32, d: u32) -> i64 {
+        utc_timestamp(NaiveDate::from_ymd_opt(y, m, d).unwrap())
+    }
+
+    #[test]
+    fn test_period() {
+        let noon = ts(2024, 2, 29) + 12 * 3600;
+        assert_eq!(period_start(SplitMode::Day, noon), ts(2024, 2, 29));
+        assert_eq!(next_period(SplitMode::Day, ts(2024, 2, 29)), ts(2024, 3, 1));
+        assert_eq!(period_start(SplitMode::Month, noon), ts(2024, 2, 1));
+        assert_eq!(next_period(SplitMode::Month, ts(2024, 12, 1)), ts(2025, 1, 1));
+        assert_eq!(partition_name(SplitMode::Day, ts(2024, 2, 29)), "p20240229");
+        assert_eq!(partition_name(SplitMode::Month, ts(2024, 2, 1)), "p202402");
+    }
+
+    #[test]
+    fn test_plan() {
+        let now = ts(2024, 1, 30) + 3600;
+        let partitions = plan(SplitMode::Day, ts(2024, 1, 29), now, 3);
+        assert_eq!(
+            partitions.iter().map(|p| p.name.as_str()).collect::<Vec<_>>(),
+            vec!["p20240129", "p20240130", "p20240131", "p20240201", "p20240202"]
+        );
+        assert_eq!(partitions.last().unwrap().less_than, Some(ts(2024, 2, 3)));
+        assert!(plan(SplitMode::Day, ts(2024, 2, 3), now, 3).is_empty());
+
+        // switched from day to month, continue from the last day boundary.
+        let partitions = plan(SplitMode::Month, ts(2024, 1, 16), now, 1);
+        assert_eq!(
+            partitions,
+            vec![
+                Partition {
+                    name: "p202401".into(),
+                    less_than: Some(ts(2024, 2, 1))
+                },
+                Partition {
+                    name: "p202402".into(),
+                    less_than: Some(ts(2024, 3, 1))
+                },
+            ]
+        );
+        assert!(plan(SplitMode::None, 0, now, 3).is_empty());
+
+        assert_eq!(
+            partition_definitions(&partitions[..1]),
+            format!("PARTITION p202401 VALUES LESS THAN ({})", ts(2024, 2, 1))
+        );
+    }
+}
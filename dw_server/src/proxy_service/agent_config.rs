@@ -0,0 +1,186 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde::Deserialize;
+use tokio::sync::watch;
+use tokio::time::{sleep, timeout_at, Instant};
+
+use metrics_types::agent_config::{AgentConfig, VersionedAgentConfig};
+use metrics_types::format_env_name;
+
+use crate::config::AgentsConfig;
+use crate::error::ServerError;
+
+/// how often the agent config file is read again.
+const RELOAD_INTERVAL: Duration = Duration::from_secs(5);
+/// longest `/api/agent-config` holds a request for a change.
+pub const MAX_WAIT: Duration = Duration::from_secs(60);
+
+/// The agent config file: `default` for every env, or `envs.<env>` instead of it.
+///
+/// ``` toml
+/// [default]
+/// flush_interval_ms = 1000
+///
+/// [envs.prod]
+/// flush_interval_ms = 5000
+/// drop = [{ category = "debug" }]
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct AgentConfigFile {
+    default: AgentConfig,
+    /// by normalized env.
+    envs: HashMap<String, AgentConfig>,
+}
+
+impl AgentConfigFile {
+    fn parse(text: &str) -> Result<Self, ServerError> {
+        let file = toml::from_str::<AgentConfigFile>(text)?;
+        let envs = file
+            .envs
+            .into_iter()
+            .map(|(env, config)| {
+                let env =
+                    format_env_name(&env).map_err(|e| ServerError::ConfigError(format!("agent config: {}", e)))?;
+                Ok((env, config))
+            })
+            .collect::<Result<_, ServerError>>()?;
+        Ok(AgentConfigFile { envs, ..file })
+    }
+
+    fn versioned(&self, env: &str) -> VersionedAgentConfig {
+        let config = self.envs.get(env).unwrap_or(&self.default).clone();
+        VersionedAgentConfig {
+            env: env.to_string(),
+            version: version(&config),
+            config,
+        }
+    }
+}
+
+/// FNV-1a of the config json, the same on every proxy serving the same file.
+fn version(config: &AgentConfig) -> String {
+    let json = serde_json::to_string(config).unwrap_or_default();
+    let hash = json.bytes().fold(0xcbf29ce484222325_u64, |hash, b| {
+        (hash ^ b as u64).wrapping_mul(0x100000001b3)
+    });
+    format!("{:016x}", hash)
+}
+
+/// #### AgentConfigs
+///
+/// The config served to agents, from `agents.config_path` if set, waking long-polls when it changes.
+#[derive(Debug)]
+pub(super) struct AgentConfigs {
+    path: Option<PathBuf>,
+    current: watch::Sender<Arc<AgentConfigFile>>,
+}
+
+impl AgentConfigs {
+    /// Empty until `reload`.
+    pub fn new(config: &AgentsConfig) -> Self {
+        AgentConfigs {
+            path: config.config_path().map(PathBuf::from),
+            current: watch::channel(Arc::new(AgentConfigFile::default())).0,
+        }
+    }
+
+    /// Read the file again, keeping the config if it fails.
+    pub async fn reload(&self) -> Result<(), ServerError> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let text = tokio::fs::read_to_string(path)
+            .await
+            .map_err(|e| ServerError::ConfigError(format!("agent config {}: {}", path.display(), e)))?;
+        let file = AgentConfigFile::parse(&text)?;
+        self.current.send_if_modified(|current| {
+            let modified = **current != file;
+            if modified {
+                *current = Arc::new(file);
+            }
+            modified
+        });
+        Ok(())
+    }
+
+    /// The config of `env`, once its version differs from `known`, waiting up to `wait` for it.
+    ///
+    /// `None` if it is still `known`.
+    pub async fn get(&self, env: &str, known: Option<&str>, wait: Duration) -> Option<VersionedAgentConfig> {
+        let deadline = Instant::now() + wait.min(MAX_WAIT);
+        let mut changes = self.current.subscribe();
+        loop {
+            let versioned = changes.borrow_and_update().versioned(env);
+            if known != Some(versioned.version.as_str()) {
+                return Some(versioned);
+            }
+            match timeout_at(deadline, changes.changed()).await {
+                Ok(Ok(())) => {}
+                _ => return None,
+            }
+        }
+    }
+}
+
+/// Reload `configs` every `RELOAD_INTERVAL`, forever.
+pub(super) async fn run_reload(configs: Arc<AgentConfigs>) {
+    loop {
+        sleep(RELOAD_INTERVAL).await;
+        if let Err(e) = configs.reload().await {
+            println!("agent config reload error {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    async fn do_test_agent_configs() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("agent_config.toml");
+        std::fs::write(
+            &path,
+            "[default]\nflush_interval_ms = 1000\n[envs.prod-env]\nflush_interval_ms = 5000\n",
+        )
+        .unwrap();
+        let configs = AgentConfigs::new(&AgentsConfig {
+            config_path: Some(path.clone()),
+            ..Default::default()
+        });
+        let empty = configs.get("prod_env", None, Duration::ZERO).await.unwrap();
+        assert_eq!(empty.config, AgentConfig::default());
+        configs.reload().await.unwrap();
+
+        let prod = configs.get("prod_env", None, Duration::ZERO).await.unwrap();
+        assert_eq!(prod.config.flush_interval_ms, Some(5000));
+        let dev = configs.get("dev", Some(&prod.version), Duration::ZERO).await.unwrap();
+        assert_eq!(dev.config.flush_interval_ms, Some(1000));
+        assert_ne!(dev.version, prod.version);
+        assert_eq!(dev.version, version(&dev.config));
+        assert!(configs.get("dev", Some(&dev.version), Duration::ZERO).await.is_none());
+
+        // a broken file keeps the config.
+        std::fs::write(&path, "[envs.prod]\nflush_interval = 1\n").unwrap();
+        assert!(configs.reload().await.is_err());
+        assert!(configs.get("dev", Some(&dev.version), Duration::ZERO).await.is_none());
+
+        // a long-poll answers once the env's config changes.
+        std::fs::write(&path, "[default]\nflush_interval_ms = 2000\n").unwrap();
+        let (changed, reloaded) = tokio::join!(configs.get("dev", Some(&dev.version), MAX_WAIT), async {
+            sleep(Duration::from_millis(50)).await;
+            configs.reload().await
+        });
+        assert!(reloaded.is_ok());
+        assert_eq!(changed.unwrap().config.flush_interval_ms, Some(2000));
+    }
+
+    #[test]
+    fn test_agent_configs() {
+        tokio_test::block_on(do_test_agent_configs());
+    }
+}
@@ -0,0 +1,247 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use hyper::HeaderMap;
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use metrics_types::format_env_name;
+
+use crate::config::AgentsConfig;
+use crate::telemetry::Exposition;
+
+/// headers `dw_client` sends with each batch.
+pub const AGENT_VERSION_HEADER: &str = "x-dw-agent-version";
+pub const AGENT_ENV_HEADER: &str = "x-dw-env";
+pub const AGENT_NODE_HEADER: &str = "x-dw-node";
+
+/// the least recently seen agents are dropped past this.
+const MAX_AGENTS: usize = 100_000;
+/// agents name themselves, don't keep more of it than this.
+const MAX_NAME_CHARS: usize = 128;
+
+fn clip(s: &str) -> String {
+    s.chars().take(MAX_NAME_CHARS).collect()
+}
+
+/// Who sent a batch.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize)]
+pub(super) struct AgentKey {
+    /// normalized env.
+    pub env: String,
+    /// node address as the agent reports it.
+    pub node: String,
+}
+
+impl AgentKey {
+    /// From the agent headers, else from the first item with an `env` and a `content.public_ip`.
+    /// The node falls back to the remote address.
+    pub fn identify(headers: &HeaderMap, items: &[Value], addr: SocketAddr) -> Self {
+        let header = |name| headers.get(name).and_then(|v| v.to_str().ok());
+        let item = items.iter().find_map(|item| {
+            let env = item.get("env")?.as_str()?;
+            Some((env, item.get("content")?.get("public_ip")?.as_str()?))
+        });
+        let env = header(AGENT_ENV_HEADER)
+            .or(item.map(|(env, _)| env))
+            .unwrap_or_default();
+        let node = header(AGENT_NODE_HEADER).or(item.map(|(_, node)| node));
+        AgentKey {
+            env: clip(&format_env_name(env).unwrap_or_else(|_| env.to_string())),
+            node: node.map_or_else(|| addr.ip().to_string(), clip),
+        }
+    }
+}
+
+/// What became of one batch of an agent.
+#[derive(Debug, Default)]
+pub(super) struct BatchReport {
+    pub accepted: usize,
+    pub rejected: usize,
+    /// why items were rejected, the last one if several.
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct AgentRecord {
+    /// `None` for agents not sending `x-dw-agent-version`.
+    version: Option<String>,
+    remote_addr: String,
+    /// unix timestamps.
+    first_seen: i64,
+    last_seen: i64,
+    batches: u64,
+    items_accepted: u64,
+    items_rejected: u64,
+    last_error: Option<String>,
+    last_error_at: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+struct AgentListing<'a> {
+    #[serde(flatten)]
+    key: &'a AgentKey,
+    #[serde(flatten)]
+    record: &'a AgentRecord,
+    stale: bool,
+}
+
+/// #### AgentRegistry
+///
+/// The agents sending `/api/alarm` batches to this proxy, kept in memory since the proxy started.
+#[derive(Debug)]
+pub(super) struct AgentRegistry {
+    stale_after: Duration,
+    agents: Mutex<HashMap<AgentKey, AgentRecord>>,
+}
+
+impl AgentRegistry {
+    pub fn new(config: &AgentsConfig) -> Self {
+        AgentRegistry {
+            stale_after: config.stale_after(),
+            agents: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn is_stale(&self, record: &AgentRecord, now: i64) -> bool {
+        now.saturating_sub(record.last_seen) > self.stale_after.as_secs() as i64
+    }
+
+    /// One batch of `key`, sent from `remote`.
+    pub fn seen(&self, key: AgentKey, version: Option<&str>, remote: SocketAddr, report: BatchReport) {
+        self.seen_at(key, version, remote, report, chrono::Utc::now().timestamp())
+    }
+
+    fn seen_at(&self, key: AgentKey, version: Option<&str>, remote: SocketAddr, report: BatchReport, now: i64) {
+        let mut agents = self.agents.lock().unwrap_or_else(|e| e.into_inner());
+        if agents.len() >= MAX_AGENTS && !agents.contains_key(&key) {
+            agents.retain(|_, record| !self.is_stale(record, now));
+            while agents.len() >= MAX_AGENTS {
+                let oldest = agents.iter().min_by_key(|(_, r)| r.last_seen).map(|(k, _)| k.clone());
+                oldest.and_then(|k| agents.remove(&k));
+            }
+        }
+        let record = agents.entry(key).or_insert_with(|| AgentRecord {
+            version: None,
+            remote_addr: String::new(),
+            first_seen: now,
+            last_seen: now,
+            batches: 0,
+            items_accepted: 0,
+            items_rejected: 0,
+            last_error: None,
+            last_error_at: None,
+        });
+        record.version = version.map(clip);
+        record.remote_addr = remote.to_string();
+        record.last_seen = now;
+        record.batches += 1;
+        record.items_accepted += report.accepted as u64;
+        record.items_rejected += report.rejected as u64;
+        if let Some(error) = report.error {
+            record.last_error = Some(error);
+            record.last_error_at = Some(now);
+        }
+    }
+
+    /// `/api/agents` body, agents ordered by env and node.
+    pub fn list(&self) -> Value {
+        self.list_at(chrono::Utc::now().timestamp())
+    }
+
+    fn list_at(&self, now: i64) -> Value {
+        let agents = self.agents.lock().unwrap_or_else(|e| e.into_inner());
+        let mut listing = agents
+            .iter()
+            .map(|(key, record)| AgentListing {
+                key,
+                record,
+                stale: self.is_stale(record, now),
+            })
+            .collect::<Vec<_>>();
+        listing.sort_unstable_by(|a, b| a.key.cmp(b.key));
+        json!({
+            "stale_after_secs": self.stale_after.as_secs(),
+            "agents": listing,
+        })
+    }
+
+    /// Agents known, active and stale.
+    pub fn render(&self, out: &mut Exposition) {
+        let now = chrono::Utc::now().timestamp();
+        let agents = self.agents.lock().unwrap_or_else(|e| e.into_inner());
+        let stale = agents.values().filter(|r| self.is_stale(r, now)).count();
+        out.family("dw_proxy_agents", "gauge", "Agents seen since the proxy started.")
+            .sample("dw_proxy_agents", &[("state", "active")], agents.len() - stale)
+            .sample("dw_proxy_agents", &[("state", "stale")], stale);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_agent_registry() {
+        let addr: SocketAddr = "10.0.0.1:5000".parse().unwrap();
+        let items = [
+            json!({"alarm_type": "counter"}),
+            json!({"env": "prod-env", "content": {"public_ip": "1.2.3.4:9000"}}),
+        ];
+        let key = AgentKey::identify(&HeaderMap::new(), &items, addr);
+        assert_eq!((key.env.as_str(), key.node.as_str()), ("prod_env", "1.2.3.4:9000"));
+        let mut headers = HeaderMap::new();
+        headers.insert(AGENT_NODE_HEADER, "5.6.7.8:9000".parse().unwrap());
+        let key = AgentKey::identify(&headers, &items, addr);
+        assert_eq!((key.env.as_str(), key.node.as_str()), ("prod_env", "5.6.7.8:9000"));
+        let key = AgentKey::identify(&HeaderMap::new(), &items[..1], addr);
+        assert_eq!((key.env.as_str(), key.node.as_str()), ("", "10.0.0.1"));
+
+        let registry = AgentRegistry::new(&AgentsConfig {
+            stale_secs: Some(60),
+            ..Default::default()
+        });
+        let a = AgentKey::identify(&HeaderMap::new(), &items, addr);
+        let b = AgentKey {
+            env: "dev".into(),
+            node: "2.2.2.2:9000".into(),
+        };
+        let report = |accepted, rejected, error: Option<&str>| BatchReport {
+            accepted,
+            rejected,
+            error: error.map(str::to_string),
+        };
+        registry.seen_at(b.clone(), Some("0.1.0"), addr, report(3, 0, None), 1000);
+        registry.seen_at(a.clone(), None, addr, report(1, 1, Some("missing field `count`")), 1000);
+        registry.seen_at(a.clone(), Some("0.2.0"), addr, report(2, 0, None), 1030);
+
+        let listing = registry.list_at(1070);
+        assert_eq!(listing["stale_after_secs"], 60);
+        let agents = listing["agents"].as_array().unwrap();
+        assert_eq!(agents.len(), 2);
+        assert_eq!(agents[0]["env"], "dev");
+        assert_eq!(agents[0]["stale"], true);
+        let a = &agents[1];
+        assert_eq!(
+            (a["env"].as_str(), a["node"].as_str()),
+            (Some("prod_env"), Some("1.2.3.4:9000"))
+        );
+        assert_eq!(
+            (a["first_seen"].as_i64(), a["last_seen"].as_i64()),
+            (Some(1000), Some(1030))
+        );
+        assert_eq!(a["version"], "0.2.0");
+        assert_eq!(a["remote_addr"], "10.0.0.1:5000");
+        assert_eq!(
+            (a["batches"].as_u64(), a["items_accepted"].as_u64()),
+            (Some(2), Some(3))
+        );
+        assert_eq!(a["items_rejected"], 1);
+        assert_eq!(a["last_error"], "missing field `count`");
+        assert_eq!(a["last_error_at"], 1000);
+        assert_eq!(a["stale"], false);
+        assert_eq!(registry.list_at(1091)["agents"][1]["stale"], true);
+    }
+}
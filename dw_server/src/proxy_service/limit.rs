@@ -0,0 +1,123 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::config::LimitConfig;
+
+/// idle buckets are dropped once a limiter holds more keys than this.
+const MAX_BUCKETS: usize = 100_000;
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// #### RateLimiter
+///
+/// One token bucket per key, refilled at `rate` tokens per second up to `burst`.
+/// A key seen for the first time starts with a full bucket.
+#[derive(Debug)]
+pub(super) struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(rate: f64, burst: f64) -> Self {
+        RateLimiter {
+            rate,
+            burst,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn refilled(&self, bucket: Option<&Bucket>, now: Instant) -> f64 {
+        bucket.map_or(self.burst, |b| {
+            let elapsed = now.saturating_duration_since(b.updated).as_secs_f64();
+            (b.tokens + elapsed * self.rate).min(self.burst)
+        })
+    }
+
+    /// Take `cost` tokens from the bucket of each key, all of them or none.
+    ///
+    /// `Err` holds how long until all of them would be there.
+    pub fn acquire<'a>(&self, costs: impl IntoIterator<Item = (&'a str, f64)>) -> Result<(), Duration> {
+        self.acquire_at(costs, Instant::now())
+    }
+
+    fn acquire_at<'a>(&self, costs: impl IntoIterator<Item = (&'a str, f64)>, now: Instant) -> Result<(), Duration> {
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        let costs = costs
+            .into_iter()
+            .map(|(key, cost)| (key, cost, self.refilled(buckets.get(key), now)))
+            .collect::<Vec<_>>();
+        let wait = costs
+            .iter()
+            .map(|(_, cost, tokens)| (cost - tokens) / self.rate)
+            .fold(0.0, f64::max);
+        if wait > 0.0 {
+            return Err(Duration::from_secs_f64(wait));
+        }
+        if buckets.len() > MAX_BUCKETS {
+            buckets.retain(|_, b| self.refilled(Some(b), now) < self.burst);
+        }
+        for (key, cost, tokens) in costs {
+            let bucket = Bucket {
+                tokens: tokens - cost,
+                updated: now,
+            };
+            buckets.insert(key.to_string(), bucket);
+        }
+        Ok(())
+    }
+}
+
+/// `/api/alarm` limits, see `LimitConfig`.
+#[derive(Debug)]
+pub(super) struct Limits {
+    pub max_body_bytes: usize,
+    pub max_batch_items: usize,
+    /// metrics items by env.
+    pub env: Option<RateLimiter>,
+    /// requests by remote address.
+    pub ip: Option<RateLimiter>,
+}
+
+impl Limits {
+    pub fn new(config: &LimitConfig) -> Self {
+        Limits {
+            max_body_bytes: config.max_body_bytes(),
+            max_batch_items: config.max_batch_items(),
+            env: config.env_rate().map(|(rate, burst)| RateLimiter::new(rate, burst)),
+            ip: config.ip_rate().map(|(rate, burst)| RateLimiter::new(rate, burst)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_rate_limiter() {
+        let limiter = RateLimiter::new(10.0, 20.0);
+        let start = Instant::now();
+        assert!(limiter.acquire_at([("a", 15.0)], start).is_ok());
+        // 5 tokens left, 10 more needed.
+        assert_eq!(limiter.acquire_at([("a", 15.0)], start), Err(Duration::from_secs(1)));
+        // all or none: "b" is full but "a" isn't.
+        assert!(limiter.acquire_at([("b", 20.0), ("a", 6.0)], start).is_err());
+        assert!(limiter.acquire_at([("b", 20.0), ("a", 5.0)], start).is_ok());
+        assert!(limiter.acquire_at([("a", 1.0)], start).is_err());
+
+        let later = start + Duration::from_millis(500);
+        assert!(limiter.acquire_at([("a", 5.0)], later).is_ok());
+        assert!(limiter.acquire_at([("a", 1.0)], later).is_err());
+        // never refilled above burst.
+        let idle = start + Duration::from_secs(60);
+        assert!(limiter.acquire_at([("a", 20.0)], idle).is_ok());
+        assert!(limiter.acquire_at([("a", 1.0)], idle).is_err());
+    }
+}
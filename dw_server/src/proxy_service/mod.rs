@@ -0,0 +1,919 @@
+mod agent_config;
+mod agents;
+mod limit;
+mod spill;
+mod status;
+mod validate;
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use futures_util::future::join_all;
+use hyper::body::HttpBody;
+use hyper::server::conn::AddrStream;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+
+use metrics_types::{format_env_name, MetricsAlarmType};
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use crate::config::{AgentsConfig, LimitConfig, SpillConfig};
+use crate::error::ServerError;
+use crate::queue::MetricsQueue;
+use crate::telemetry::{health_response, metrics_response};
+
+use agent_config::AgentConfigs;
+use agents::{AgentKey, AgentRegistry, BatchReport, AGENT_ENV_HEADER, AGENT_VERSION_HEADER};
+use limit::Limits;
+use spill::Spill;
+pub use status::ProxyStats;
+use validate::{validate_item, AlarmSummary, ItemError};
+
+/// What all requests to one proxy share.
+struct ProxyState {
+    queue: Arc<dyn MetricsQueue>,
+    stats: ProxyStats,
+    limits: Limits,
+    /// takes what `queue` can't, if configured.
+    spill: Option<Arc<Spill>>,
+    agents: AgentRegistry,
+    agent_configs: Arc<AgentConfigs>,
+}
+
+impl ProxyState {
+    fn new(queue: Arc<dyn MetricsQueue>, limit: &LimitConfig, spill: Option<Spill>, agents: &AgentsConfig) -> Self {
+        ProxyState {
+            queue,
+            stats: ProxyStats::default(),
+            limits: Limits::new(limit),
+            spill: spill.map(Arc::new),
+            agents: AgentRegistry::new(agents),
+            agent_configs: Arc::new(AgentConfigs::new(agents)),
+        }
+    }
+
+    /// Push `batch` into the queue, one alarm type at a time so a failed push is known to take only
+    /// its own items, and the alarm types the queue fails on into the spill if configured.
+    ///
+    /// Return the alarm types neither queued nor spilled.
+    async fn push(&self, batch: HashMap<MetricsAlarmType, Vec<String>>) -> Vec<MetricsAlarmType> {
+        let pushes = batch.into_iter().map(|(key, values)| async move {
+            let single = HashMap::from([(key, values)]);
+            let begin = Instant::now();
+            let result = self.queue.push(&single).await;
+            self.stats.pushed(begin.elapsed());
+            (key, single, result)
+        });
+        let mut failed = Vec::new();
+        for (key, single, result) in join_all(pushes).await {
+            let Err(e) = result else {
+                continue;
+            };
+            let items = single[&key].len();
+            let Some(spill) = &self.spill else {
+                println!("queue push {} items of {} error {}", items, key, e);
+                failed.push(key);
+                continue;
+            };
+            match spill.push(&single).await {
+                Ok(()) => {
+                    println!("queue push {} items of {} error {}, spilled", items, key, e);
+                    self.stats.spilled(&key.to_string(), items);
+                }
+                Err(spill_error) => {
+                    println!(
+                        "queue push {} items of {} error {}, spill error {}",
+                        items, key, e, spill_error
+                    );
+                    failed.push(key);
+                }
+            }
+        }
+        failed
+    }
+
+    /// Ready if the queue can be reached, or the spill has room.
+    async fn ready(&self) -> (bool, Value) {
+        let (ready, mut body) = self.stats.ready(self.queue.as_ref()).await;
+        match &self.spill {
+            Some(spill) if !ready => {
+                let has_room = spill.has_room().await;
+                if has_room {
+                    body["status"] = "spilling".into();
+                }
+                body["spill_has_room"] = has_room.into();
+                (has_room, body)
+            }
+            _ => (ready, body),
+        }
+    }
+}
+
+/// Validate each item of `items`, push the valid ones into the queue unless their env is over its rate.
+async fn handle_items(items: Vec<Value>, state: &ProxyState) -> (Response<Body>, BatchReport) {
+    let stats = &state.stats;
+    let mut summary = AlarmSummary::default();
+    let mut batch = HashMap::<MetricsAlarmType, Vec<String>>::new();
+    let mut env_items = HashMap::<(MetricsAlarmType, String), usize>::new();
+    // index of each valid item, by alarm type.
+    let mut indexes = HashMap::<MetricsAlarmType, Vec<usize>>::new();
+    for (index, item) in items.into_iter().enumerate() {
+        match validate_item(item) {
+            Ok(valid) => {
+                indexes.entry(valid.alarm_type).or_default().push(index);
+                batch.entry(valid.alarm_type).or_default().push(valid.data);
+                *env_items.entry((valid.alarm_type, valid.env)).or_default() += 1;
+                summary.accepted += 1;
+            }
+            Err(e) => {
+                let alarm_type = e.alarm_type.map_or(String::from("unknown"), |t| t.to_string());
+                stats.rejected(&alarm_type, e.reason, 1);
+                summary.reject(index, e);
+            }
+        }
+    }
+    let mut report = BatchReport {
+        accepted: summary.accepted,
+        rejected: summary.rejected,
+        error: summary.errors.last().map(|e| e.error.clone()),
+    };
+    if batch.is_empty() {
+        return (json_response(summary.status(), &summary), report);
+    }
+    if let Some(env_limiter) = &state.limits.env {
+        let mut costs = HashMap::<&str, f64>::new();
+        for ((_, env), items) in &env_items {
+            *costs.entry(env.as_str()).or_default() += *items as f64;
+        }
+        if let Err(wait) = env_limiter.acquire(costs) {
+            stats.limited("env_rate");
+            for (key, values) in &batch {
+                stats.rejected(&key.to_string(), "env_rate", values.len());
+            }
+            report.rejected += std::mem::take(&mut report.accepted);
+            report.error = Some(String::from("rate limited"));
+            return (too_many_requests(wait), report);
+        }
+    }
+    let failed = state.push(batch).await;
+    for ((key, env), items) in env_items {
+        if !failed.contains(&key) {
+            stats.accepted(&key.to_string(), &env, items);
+        }
+    }
+    if failed.is_empty() {
+        return (json_response(summary.status(), &summary), report);
+    }
+    // items of other alarm types are queued, so only the failed ones are rejected for the agent to send again.
+    for key in &failed {
+        let indexes = indexes.remove(key).unwrap_or_default();
+        stats.rejected(&key.to_string(), "queue", indexes.len());
+        summary.accepted -= indexes.len();
+        for index in indexes {
+            summary.reject(index, ItemError::new(Some(*key), "queue", "metrics queue unavailable"));
+        }
+    }
+    summary.errors.sort_by_key(|e| e.index);
+    report.accepted = summary.accepted;
+    report.rejected = summary.rejected;
+    report.error = Some(String::from("metrics queue unavailable"));
+    if summary.accepted > 0 {
+        return (json_response(StatusCode::MULTI_STATUS, &summary), report);
+    }
+    let resp = json_response(
+        StatusCode::SERVICE_UNAVAILABLE,
+        &json!({"error": "metrics queue unavailable"}),
+    );
+    (resp, report)
+}
+
+/// Read the whole `body`, `None` once it is over `limit` bytes.
+async fn read_body(mut body: Body, limit: usize) -> Result<Option<Vec<u8>>, hyper::Error> {
+    let mut buf = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk?;
+        if buf.len() + chunk.len() > limit {
+            return Ok(None);
+        }
+        buf.extend_from_slice(&chunk);
+    }
+    Ok(Some(buf))
+}
+
+async fn handle_alarm(
+    req: Request<Body>,
+    addr: SocketAddr,
+    state: &ProxyState,
+) -> Result<Response<Body>, hyper::Error> {
+    let (stats, limits) = (&state.stats, &state.limits);
+    // println!("header: {:?}", req.headers());
+    if !req.headers().contains_key("content-type")
+        || req.headers().get("content-type").is_some_and(|value| {
+            value
+                .to_str()
+                .is_ok_and(|str| !str.to_lowercase().contains("application/json"))
+        })
+    {
+        return Ok(unprocessable_entity().unwrap());
+    }
+    if let Some(ip_limiter) = &limits.ip {
+        if let Err(wait) = ip_limiter.acquire([(addr.ip().to_string().as_str(), 1.0)]) {
+            stats.limited("ip_rate");
+            return Ok(too_many_requests(wait));
+        }
+    }
+
+    // content-length tells the size up front, chunked bodies are checked while read.
+    let (parts, body) = req.into_parts();
+    let whole_body = match body.size_hint().lower() > limits.max_body_bytes as u64 {
+        true => None,
+        false => read_body(body, limits.max_body_bytes).await?,
+    };
+    let Some(whole_body) = whole_body else {
+        stats.limited("body_bytes");
+        return Ok(payload_too_large(format!("body over {} bytes", limits.max_body_bytes)));
+    };
+    stats.received(whole_body.len());
+    let items = match serde_json::from_slice::<Vec<Value>>(&whole_body) {
+        Ok(items) if !items.is_empty() => items,
+        Ok(_) => {
+            println!("empty json array of {} bytes", whole_body.len());
+            return Ok(unprocessable_entity().unwrap());
+        }
+        Err(e) => {
+            println!("json parse error {}, body of {} bytes", e, whole_body.len());
+            return Ok(unprocessable_entity().unwrap());
+        }
+    };
+    if items.len() > limits.max_batch_items {
+        stats.limited("batch_items");
+        return Ok(payload_too_large(format!("over {} items", limits.max_batch_items)));
+    }
+    let agent = AgentKey::identify(&parts.headers, &items, addr);
+    let (resp, report) = handle_items(items, state).await;
+    let version = parts.headers.get(AGENT_VERSION_HEADER).and_then(|v| v.to_str().ok());
+    state.agents.seen(agent, version, addr, report);
+    Ok(resp)
+}
+
+/// `GET /api/agent-config?env=<env>&wait_secs=<secs>`, held up to `wait_secs` while `If-None-Match` is the
+/// current version, then 304.
+async fn handle_agent_config(req: &Request<Body>, state: &ProxyState) -> Response<Body> {
+    let query = form_urlencoded::parse(req.uri().query().unwrap_or_default().as_bytes()).collect::<HashMap<_, _>>();
+    let header_env = req.headers().get(AGENT_ENV_HEADER).and_then(|v| v.to_str().ok());
+    let env = query.get("env").map(|env| env.as_ref()).or(header_env);
+    let Some(env) = env.and_then(|env| format_env_name(env).ok()) else {
+        return json_response(StatusCode::BAD_REQUEST, &json!({"error": "missing or invalid env"}));
+    };
+    let wait = query
+        .get("wait_secs")
+        .and_then(|secs| secs.parse::<u64>().ok())
+        .map_or(Duration::ZERO, Duration::from_secs);
+    let known = req
+        .headers()
+        .get(hyper::header::IF_NONE_MATCH)
+        .and_then(|v| v.to_str().ok())
+        .map(|etag| etag.trim_start_matches("W/").trim_matches('"'));
+    let (mut resp, version) = match state.agent_configs.get(&env, known, wait).await {
+        Some(versioned) => (json_response(StatusCode::OK, &versioned), versioned.version),
+        None => {
+            let resp = Response::builder()
+                .status(StatusCode::NOT_MODIFIED)
+                .body(Body::empty())
+                .unwrap();
+            (resp, known.unwrap_or_default().to_string())
+        }
+    };
+    if let Ok(etag) = format!("\"{}\"", version).parse() {
+        resp.headers_mut().insert(hyper::header::ETAG, etag);
+    }
+    resp
+}
+
+/// Route `req` and count its response status.
+async fn handle(req: Request<Body>, addr: SocketAddr, state: Arc<ProxyState>) -> Result<Response<Body>, hyper::Error> {
+    let resp = route(req, addr, &state).await?;
+    state.stats.responded(resp.status());
+    Ok(resp)
+}
+
+/// This is our service handler. It receives a Request, routes on its
+/// path, and returns a Future of a Response.
+async fn route(req: Request<Body>, addr: SocketAddr, state: &ProxyState) -> Result<Response<Body>, hyper::Error> {
+    match (req.method(), req.uri().path()) {
+        // Serve some instructions at /
+        (&Method::GET, "/") => Ok(Response::new(Body::from("DW Server Proxy"))),
+
+        // liveness, answered as long as the process serves http.
+        (&Method::GET, "/healthz") => Ok(health_response(true, state.stats.health())),
+
+        // readiness, whether metrics can be queued now.
+        (&Method::GET, "/readyz") => {
+            let (ready, body) = state.ready().await;
+            Ok(health_response(ready, body))
+        }
+
+        (&Method::GET, "/metrics") => {
+            let mut out = state.stats.render();
+            if let Some(spill) = &state.spill {
+                spill.render(&mut out).await;
+            }
+            state.agents.render(&mut out);
+            Ok(metrics_response(out))
+        }
+
+        // get public ip
+        (&Method::GET, "/api/ip") => {
+            let ip = addr.ip().to_string();
+            Ok(Response::new(Body::from(ip)))
+        }
+
+        (&Method::POST, "/api/alarm") => handle_alarm(req, addr, state).await,
+
+        // agents that sent batches, flagged stale once silent for a while.
+        (&Method::GET, "/api/agents") => Ok(json_response(StatusCode::OK, &state.agents.list())),
+
+        // config for the agents of an env, long-polled.
+        (&Method::GET, "/api/agent-config") => Ok(handle_agent_config(&req, state).await),
+
+        // Return the 404 Not Found for other routes.
+        _ => Ok(not_found().unwrap()),
+    }
+}
+
+fn json_response(status: StatusCode, body: &impl Serialize) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header("content-type", "application/json")
+        .body(Body::from(serde_json::to_string(body).unwrap_or_default()))
+        .unwrap()
+}
+
+fn payload_too_large(error: String) -> Response<Body> {
+    json_response(StatusCode::PAYLOAD_TOO_LARGE, &json!({ "error": error }))
+}
+
+/// 429 with `Retry-After` in whole seconds, at least 1.
+fn too_many_requests(wait: Duration) -> Response<Body> {
+    let secs = (wait.as_secs_f64().ceil() as u64).max(1);
+    let mut resp = json_response(
+        StatusCode::TOO_MANY_REQUESTS,
+        &json!({"error": "rate limited", "retry_after_secs": secs}),
+    );
+    resp.headers_mut().insert("retry-after", secs.into());
+    resp
+}
+
+#[inline]
+fn not_found() -> hyper::http::Result<Response<Body>> {
+    Response::builder()
+        .status(StatusCode::NOT_FOUND)
+        .body(Body::from("404 NOT FOUND"))
+}
+
+#[inline]
+fn unprocessable_entity() -> hyper::http::Result<Response<Body>> {
+    Response::builder()
+        .status(StatusCode::UNPROCESSABLE_ENTITY)
+        .body(Body::from("Unprocessable Data"))
+}
+
+/// Serve the proxy http api on `addr`, pushing received metrics into `queue` within `limit`.
+///
+/// Metrics `queue` fails to take go to `spill` if configured, and are replayed into `queue` later.
+pub async fn serve(
+    addr: SocketAddr,
+    queue: Arc<dyn MetricsQueue>,
+    limit: &LimitConfig,
+    spill: &SpillConfig,
+    agents: &AgentsConfig,
+) -> Result<(), ServerError> {
+    let spill = Spill::open(spill).await?;
+    let state = Arc::new(ProxyState::new(queue.clone(), limit, spill, agents));
+    if let Some(spill) = &state.spill {
+        tokio::spawn(spill::run_replay(spill.clone(), queue));
+    }
+    state.agent_configs.reload().await?;
+    if agents.config_path().is_some() {
+        tokio::spawn(agent_config::run_reload(state.agent_configs.clone()));
+    }
+    let make_service = make_service_fn(move |conn: &AddrStream| {
+        let addr = conn.remote_addr();
+        let state = Arc::clone(&state);
+        async move {
+            let addr = addr;
+            let state = Arc::clone(&state);
+            Ok::<_, hyper::Error>(service_fn(move |req| handle(req, addr, Arc::clone(&state))))
+        }
+    });
+
+    let server = Server::bind(&addr).serve(make_service);
+
+    println!("Proxy Listening on http://{}", addr);
+
+    server
+        .await
+        .map_err(|e| ServerError::FileIOError(format!("proxy server: {}", e)))
+}
+
+#[cfg(test)]
+mod test {
+    use std::num::NonZeroUsize;
+
+    use super::*;
+    use crate::queue::MemoryQueue;
+
+    fn alarm_request(content_type: &str, body: impl Into<Body>) -> Request<Body> {
+        Request::builder()
+            .method(Method::POST)
+            .uri("/api/alarm")
+            .header("content-type", content_type)
+            .body(body.into())
+            .unwrap()
+    }
+
+    fn counter(env: &str) -> Value {
+        json!({
+            "alarm_type": "counter",
+            "env": env,
+            "content": {"send_timestamp": "1669269373", "public_ip": "127.0.0.1:9000", "category": "xvm",
+                "tag": "contract_manager_counter", "count": 1, "value": 1}
+        })
+    }
+
+    fn timer(env: &str) -> Value {
+        json!({
+            "alarm_type": "timer",
+            "env": env,
+            "content": {"send_timestamp": "1669269373", "public_ip": "127.0.0.1:9000", "category": "xcons",
+                "tag": "network_message_dispatch", "count": 3060, "max_time": 93926, "min_time": 18, "avg_time": 153}
+        })
+    }
+
+    async fn summary(resp: Response<Body>) -> Value {
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    async fn do_test_handle_alarm() {
+        let queue: Arc<dyn MetricsQueue> = Arc::new(MemoryQueue::new(100));
+        let state = Arc::new(ProxyState::new(
+            queue.clone(),
+            &LimitConfig::default(),
+            None,
+            &AgentsConfig::default(),
+        ));
+        let addr: SocketAddr = "127.0.0.1:9000".parse().unwrap();
+        let cnt = NonZeroUsize::new(10).unwrap();
+
+        let data = r#"[{"alarm_type":"counter","env":"test_db","content":{"send_timestamp":"1669269373","public_ip":"127.0.0.1:9000","category":"xvm","tag":"contract_manager_counter","count":1,"value":1}},{"alarm_type":"timer","env":"test_db","content":{"send_timestamp":"1669269373","public_ip":"127.0.0.1:9000","category":"xcons","tag":"network_message_dispatch","count":3060,"max_time":93926,"min_time":18,"avg_time":153}}]"#;
+        let resp = handle(alarm_request("application/json", data), addr, state.clone())
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(summary(resp).await, json!({"accepted": 2, "rejected": 0, "errors": []}));
+        assert_eq!(queue.pop_batch(&MetricsAlarmType::Counter, cnt).await.unwrap().len(), 1);
+        assert_eq!(queue.pop_batch(&MetricsAlarmType::Timer, cnt).await.unwrap().len(), 1);
+        assert!(queue.pop_batch(&MetricsAlarmType::Flow, cnt).await.unwrap().is_empty());
+
+        let mut no_env = counter("");
+        no_env.as_object_mut().unwrap().remove("env");
+        let mut bad_count = counter("test_db");
+        bad_count["content"]["count"] = "many".into();
+        let data = json!([
+            counter("a`b; DROP DATABASE x"),
+            counter("测试"),
+            no_env,
+            {"alarm_type": "unknown", "env": "test_db", "content": {}},
+            bad_count,
+            {"env": "test_db"},
+        ]);
+        let resp = handle(alarm_request("application/json", data.to_string()), addr, state.clone())
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::MULTI_STATUS);
+        let body = summary(resp).await;
+        assert_eq!(
+            (body["accepted"].as_u64(), body["rejected"].as_u64()),
+            (Some(1), Some(5))
+        );
+        let indexes = body["errors"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|e| e["index"].as_u64().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(indexes, [1, 2, 3, 4, 5]);
+        assert_eq!(body["errors"][2]["error"], "unknown alarm_type unknown");
+        let items = queue.pop_batch(&MetricsAlarmType::Counter, cnt).await.unwrap();
+        assert_eq!(items.len(), 1);
+        let item = serde_json::from_str::<Value>(&items[0].data).unwrap();
+        assert_eq!(item["env"], "a_b__DROP_DATABASE_x");
+
+        let data = json!([counter("测试"), {"alarm_type": "timer", "env": "test_db", "content": {}}]).to_string();
+        let resp = handle(alarm_request("application/json", data.clone()), addr, state.clone())
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(summary(resp).await["rejected"], 2);
+        assert!(queue.pop_batch(&MetricsAlarmType::Timer, cnt).await.unwrap().is_empty());
+
+        let resp = handle(alarm_request("text/plain", data), addr, state.clone())
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+        let resp = handle(alarm_request("application/json", "not json"), addr, state.clone())
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+        for data in ["[]", r#"{"alarm_type":"counter"}"#] {
+            let resp = handle(alarm_request("application/json", data), addr, state.clone())
+                .await
+                .unwrap();
+            assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        }
+    }
+
+    #[test]
+    fn test_handle_alarm() {
+        tokio_test::block_on(do_test_handle_alarm());
+    }
+
+    async fn do_test_proxy_status() {
+        let queue: Arc<dyn MetricsQueue> = Arc::new(MemoryQueue::new(100));
+        let state = Arc::new(ProxyState::new(
+            queue.clone(),
+            &LimitConfig::default(),
+            None,
+            &AgentsConfig::default(),
+        ));
+        let addr: SocketAddr = "127.0.0.1:9000".parse().unwrap();
+        let get = |path: &str| Request::get(path).body(Body::empty()).unwrap();
+
+        let mut flow = counter("测试");
+        flow["alarm_type"] = "flow".into();
+        let data = json!([counter("env1"), counter("env2"), flow, {"alarm_type": "gauge", "env": "env1"}]).to_string();
+        let resp = handle(alarm_request("application/json", data.clone()), addr, state.clone())
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::MULTI_STATUS);
+        let resp = handle(get("/nothing"), addr, state.clone()).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        let resp = handle(get("/healthz"), addr, state.clone()).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp = handle(get("/readyz"), addr, state.clone()).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let resp = handle(get("/metrics"), addr, state.clone()).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        let body = String::from_utf8_lossy(&body);
+        for line in [
+            "dw_proxy_requests_total{status=\"200\"} 2",
+            "dw_proxy_requests_total{status=\"207\"} 1",
+            "dw_proxy_requests_total{status=\"404\"} 1",
+            &format!("dw_proxy_payload_bytes_total {}", data.len()),
+            "dw_proxy_items_accepted_total{alarm_type=\"counter\"} 2",
+            "dw_proxy_items_rejected_total{alarm_type=\"flow\",reason=\"content\"} 1",
+            "dw_proxy_items_rejected_total{alarm_type=\"unknown\",reason=\"alarm_type\"} 1",
+            "dw_proxy_env_items_total{env=\"env2\",alarm_type=\"counter\"} 1",
+            "dw_proxy_queue_push_duration_seconds_count 1",
+            "dw_proxy_agents{state=\"active\"} 1",
+        ] {
+            assert!(body.lines().any(|l| l == line), "{} not in\n{}", line, body);
+        }
+
+        // the batch is from the agent of its first item.
+        let resp = handle(get("/api/agents"), addr, state.clone()).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let agents = summary(resp).await["agents"].take();
+        assert_eq!(agents.as_array().map(Vec::len), Some(1));
+        let agent = &agents[0];
+        assert_eq!(
+            (agent["env"].as_str(), agent["node"].as_str()),
+            (Some("env1"), Some("127.0.0.1:9000"))
+        );
+        assert_eq!(
+            (agent["items_accepted"].as_u64(), agent["items_rejected"].as_u64()),
+            (Some(2), Some(2))
+        );
+        assert_eq!(agent["version"], Value::Null);
+
+        // no agent config file, every env gets the empty config.
+        let resp = handle(get("/api/agent-config?env=prod-1"), addr, state.clone())
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let etag = resp.headers()[hyper::header::ETAG].clone();
+        let body = summary(resp).await;
+        assert_eq!(
+            (body["env"].as_str(), body["config"]["drop"].as_array().map(Vec::len)),
+            (Some("prod_1"), Some(0))
+        );
+        assert_eq!(etag, format!("\"{}\"", body["version"].as_str().unwrap()).as_str());
+        let req = Request::get("/api/agent-config?env=prod_1&wait_secs=0")
+            .header(hyper::header::IF_NONE_MATCH, etag.clone())
+            .body(Body::empty())
+            .unwrap();
+        let resp = handle(req, addr, state.clone()).await.unwrap();
+        assert_eq!(
+            (resp.status(), &resp.headers()[hyper::header::ETAG]),
+            (StatusCode::NOT_MODIFIED, &etag)
+        );
+        let resp = handle(get("/api/agent-config"), addr, state.clone()).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        // the queue directory is gone.
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("queue");
+        let queue: Arc<dyn MetricsQueue> = Arc::new(crate::queue::FileQueue::new(&path, 1024).await.unwrap());
+        std::fs::remove_dir(&path).unwrap();
+        let state = Arc::new(ProxyState::new(
+            queue,
+            &LimitConfig::default(),
+            None,
+            &AgentsConfig::default(),
+        ));
+        let resp = handle(get("/readyz"), addr, state).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        let body = serde_json::from_slice::<serde_json::Value>(&body).unwrap();
+        assert_eq!(body["status"], "unavailable");
+    }
+
+    #[test]
+    fn test_proxy_status() {
+        tokio_test::block_on(do_test_proxy_status());
+    }
+
+    async fn do_test_proxy_spill() {
+        let queue: Arc<dyn MetricsQueue> = Arc::new(MemoryQueue::new(2));
+        let addr: SocketAddr = "127.0.0.1:9000".parse().unwrap();
+        let cnt = NonZeroUsize::new(10).unwrap();
+        let post = |data: &Value| alarm_request("application/json", data.to_string());
+        let data = json!([counter("env1"), counter("env1")]);
+
+        // no spill, the full queue is a 503.
+        let state = Arc::new(ProxyState::new(
+            queue.clone(),
+            &LimitConfig::default(),
+            None,
+            &AgentsConfig::default(),
+        ));
+        let resp = handle(post(&data), addr, state.clone()).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp = handle(post(&data), addr, state.clone()).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(summary(resp).await["error"], "metrics queue unavailable");
+        // alarm types are pushed apart, the timer is queued though the counter is not.
+        let resp = handle(
+            post(&json!([counter("env1"), timer("env1"), counter("env1")])),
+            addr,
+            state.clone(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(resp.status(), StatusCode::MULTI_STATUS);
+        let body = summary(resp).await;
+        assert_eq!(
+            (body["accepted"].as_u64(), body["rejected"].as_u64()),
+            (Some(1), Some(2))
+        );
+        let errors = body["errors"].as_array().unwrap();
+        assert_eq!(
+            errors.iter().map(|e| e["index"].as_u64().unwrap()).collect::<Vec<_>>(),
+            vec![0, 2]
+        );
+        assert_eq!(errors[0]["error"], "metrics queue unavailable");
+        assert_eq!(queue.depth(&MetricsAlarmType::Timer).await.unwrap(), 1);
+        let body = state.stats.render().finish();
+        for line in [
+            "dw_proxy_items_rejected_total{alarm_type=\"counter\",reason=\"queue\"} 4",
+            "dw_proxy_items_accepted_total{alarm_type=\"timer\"} 1",
+        ] {
+            assert!(body.lines().any(|l| l == line), "{} not in\n{}", line, body);
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let config = SpillConfig {
+            path: Some(dir.path().join("spill")),
+            max_bytes: Some(1000),
+            ..Default::default()
+        };
+        let spill = Spill::open(&config).await.unwrap();
+        let state = Arc::new(ProxyState::new(
+            queue.clone(),
+            &LimitConfig::default(),
+            spill,
+            &AgentsConfig::default(),
+        ));
+        let resp = handle(post(&data), addr, state.clone()).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(summary(resp).await["accepted"], 2);
+        // only the counter is spilled, the timer is queued.
+        let mixed = json!([counter("env1"), timer("env1")]);
+        let resp = handle(post(&mixed), addr, state.clone()).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(queue.depth(&MetricsAlarmType::Timer).await.unwrap(), 2);
+        // until the spill is over max_bytes.
+        while handle(post(&data), addr, state.clone()).await.unwrap().status() == StatusCode::OK {}
+        let resp = handle(
+            Request::get("/readyz").body(Body::empty()).unwrap(),
+            addr,
+            state.clone(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let resp = handle(
+            Request::get("/metrics").body(Body::empty()).unwrap(),
+            addr,
+            state.clone(),
+        )
+        .await
+        .unwrap();
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        let body = String::from_utf8_lossy(&body);
+        let spilled = body
+            .lines()
+            .find_map(|l| l.strip_prefix("dw_proxy_spill_items{alarm_type=\"counter\"} "))
+            .and_then(|n| n.parse::<usize>().ok())
+            .unwrap();
+        assert!(spilled >= 3 && spilled % 2 == 1, "{}", body);
+        assert!(!body
+            .lines()
+            .any(|l| l.starts_with("dw_proxy_spill_items{alarm_type=\"timer\"}") && !l.ends_with(" 0")));
+        assert!(body.contains(&format!(
+            "dw_proxy_items_spilled_total{{alarm_type=\"counter\"}} {}",
+            spilled
+        )));
+
+        // a failed replay keeps everything spilled.
+        let spill = state.spill.as_ref().unwrap();
+        assert!(spill.replay(queue.as_ref()).await.is_err());
+        let target: Arc<dyn MetricsQueue> = Arc::new(MemoryQueue::new(100));
+        assert_eq!(spill.replay(target.as_ref()).await.unwrap(), spilled);
+        assert_eq!(
+            target.pop_batch(&MetricsAlarmType::Counter, cnt).await.unwrap().len(),
+            spilled
+        );
+        assert_eq!(spill.replay(target.as_ref()).await.unwrap(), 0);
+        let mut out = crate::telemetry::Exposition::default();
+        spill.render(&mut out).await;
+        assert!(out
+            .finish()
+            .contains("dw_proxy_spill_items{alarm_type=\"counter\"} 0\n"));
+    }
+
+    #[test]
+    fn test_proxy_spill() {
+        tokio_test::block_on(do_test_proxy_spill());
+    }
+
+    async fn do_test_proxy_limits() {
+        let queue: Arc<dyn MetricsQueue> = Arc::new(MemoryQueue::new(100));
+        let limit = LimitConfig {
+            max_body_bytes: Some(1000),
+            max_batch_items: Some(2),
+            env_rate: Some(1.0),
+            ..Default::default()
+        };
+        let state = Arc::new(ProxyState::new(queue.clone(), &limit, None, &AgentsConfig::default()));
+        let addr: SocketAddr = "127.0.0.1:9000".parse().unwrap();
+        let post = |data: &Value| alarm_request("application/json", data.to_string());
+
+        let resp = handle(post(&Value::from(vec![counter("env1"); 20])), addr, state.clone())
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        // without content-length.
+        let chunks = (0..20).map(|_| Ok::<_, std::io::Error>(counter("env1").to_string()));
+        let resp = handle(
+            alarm_request(
+                "application/json",
+                Body::wrap_stream(futures_util::stream::iter(chunks)),
+            ),
+            addr,
+            state.clone(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        let resp = handle(post(&json!([{}, {}, {}])), addr, state.clone()).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        // env1 may send max_batch_items at once, then 1 per second.
+        let resp = handle(post(&json!([counter("env1"), counter("env1")])), addr, state.clone())
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp = handle(post(&json!([counter("env2"), counter("env1")])), addr, state.clone())
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(resp.headers()["retry-after"], "1");
+        let resp = handle(post(&json!([counter("env2")])), addr, state.clone())
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let cnt = NonZeroUsize::new(10).unwrap();
+        assert_eq!(queue.pop_batch(&MetricsAlarmType::Counter, cnt).await.unwrap().len(), 3);
+
+        let limit = LimitConfig {
+            ip_rate: Some(0.5),
+            ..Default::default()
+        };
+        let state = Arc::new(ProxyState::new(queue.clone(), &limit, None, &AgentsConfig::default()));
+        let resp = handle(post(&json!([counter("env1")])), addr, state.clone())
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp = handle(post(&json!([counter("env1")])), addr, state.clone())
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(resp.headers()["retry-after"], "2");
+        let other: SocketAddr = "127.0.0.2:9000".parse().unwrap();
+        let resp = handle(post(&json!([counter("env1")])), other, state.clone())
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let resp = handle(Request::get("/metrics").body(Body::empty()).unwrap(), addr, state)
+            .await
+            .unwrap();
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        let body = String::from_utf8_lossy(&body);
+        for line in [
+            "dw_proxy_requests_limited_total{limit=\"ip_rate\"} 1",
+            "dw_proxy_requests_total{status=\"429\"} 1",
+        ] {
+            assert!(body.lines().any(|l| l == line), "{} not in\n{}", line, body);
+        }
+    }
+
+    #[test]
+    fn test_proxy_limits() {
+        tokio_test::block_on(do_test_proxy_limits());
+    }
+
+    /// `/api/alarm` throughput with 1 to 8 worker threads, 64 clients sending batches of 100 counters.
+    ///
+    /// `cargo test --release -p dw_server proxy_load -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn test_proxy_load() {
+        const CLIENTS: usize = 64;
+        const REQUESTS: usize = 50;
+        const BATCH: usize = 100;
+        let body = Value::from(vec![counter("load_env"); BATCH]).to_string();
+        let addr: SocketAddr = "127.0.0.1:9000".parse().unwrap();
+        for workers in [1, 2, 4, 8] {
+            let runtime = tokio::runtime::Builder::new_multi_thread()
+                .worker_threads(workers)
+                .enable_all()
+                .build()
+                .unwrap();
+            let queue: Arc<dyn MetricsQueue> = Arc::new(MemoryQueue::new(CLIENTS * REQUESTS * BATCH));
+            let state = Arc::new(ProxyState::new(
+                queue.clone(),
+                &LimitConfig::default(),
+                None,
+                &AgentsConfig::default(),
+            ));
+            let elapsed = runtime.block_on(async {
+                let begin = Instant::now();
+                let clients = (0..CLIENTS).map(|_| {
+                    let (body, state) = (body.clone(), state.clone());
+                    tokio::spawn(async move {
+                        for _ in 0..REQUESTS {
+                            let req = alarm_request("application/json", body.clone());
+                            let resp = handle(req, addr, state.clone()).await.unwrap();
+                            assert_eq!(resp.status(), StatusCode::OK);
+                        }
+                    })
+                });
+                for client in clients.collect::<Vec<_>>() {
+                    client.await.unwrap();
+                }
+                begin.elapsed()
+            });
+            let depth = runtime.block_on(queue.depth(&MetricsAlarmType::Counter)).unwrap();
+            assert_eq!(depth as usize, CLIENTS * REQUESTS * BATCH);
+            println!(
+                "workers {}: {} items in {:?}, {:.0} items/s",
+                workers,
+                depth,
+                elapsed,
+                depth as f64 / elapsed.as_secs_f64()
+            );
+        }
+    }
+}
@@ -0,0 +1,128 @@
+use std::collections::HashMap;
+use std::num::NonZeroUsize;
+use std::sync::Arc;
+use std::time::Duration;
+
+use metrics_types::MetricsAlarmType;
+use tokio::time::sleep;
+
+use crate::config::SpillConfig;
+use crate::error::ServerError;
+use crate::queue::{FileQueue, MetricsQueue};
+use crate::store::metrics_tables;
+use crate::telemetry::Exposition;
+
+/// segment size, smaller for a small spill: replayed items take room until their segment is deleted.
+const SPILL_SEGMENT_BYTES: u64 = 16 * 1024 * 1024;
+/// items moved from the spill to the queue at a time.
+const REPLAY_BATCH_SIZE: usize = 500;
+
+/// #### Spill
+///
+/// Metrics the queue couldn't take, kept in a `FileQueue` until `replay` moves them to the queue.
+pub(super) struct Spill {
+    files: FileQueue,
+    max_bytes: u64,
+    replay_interval: Duration,
+}
+
+impl Spill {
+    /// `None` if no spill path is configured.
+    pub async fn open(config: &SpillConfig) -> Result<Option<Self>, ServerError> {
+        let Some(path) = config.path() else {
+            return Ok(None);
+        };
+        let max_bytes = config.max_bytes();
+        let segment_bytes = (max_bytes / 16).clamp(1, SPILL_SEGMENT_BYTES);
+        Ok(Some(Spill {
+            files: FileQueue::new(path, segment_bytes).await?,
+            max_bytes,
+            replay_interval: config.replay_interval(),
+        }))
+    }
+
+    /// Whether the spill is below `max_bytes`.
+    pub async fn has_room(&self) -> bool {
+        self.files.bytes().await.is_ok_and(|bytes| bytes < self.max_bytes)
+    }
+
+    /// Append `batch`, failing if the spill is full.
+    pub async fn push(&self, batch: &HashMap<MetricsAlarmType, Vec<String>>) -> Result<(), ServerError> {
+        let bytes = self.files.bytes().await?;
+        if bytes >= self.max_bytes {
+            return Err(ServerError::QueueError(format!("spill full, {} bytes", bytes)));
+        }
+        self.files.push(batch).await
+    }
+
+    /// Move everything spilled to `queue`, stopping at the first push `queue` fails.
+    ///
+    /// Returns the items moved.
+    pub async fn replay(&self, queue: &dyn MetricsQueue) -> Result<usize, ServerError> {
+        let cnt = NonZeroUsize::new(REPLAY_BATCH_SIZE).unwrap();
+        // don't take items out just to spill them again.
+        queue.ping().await?;
+        let mut moved = 0;
+        for (alarm_type, _) in metrics_tables() {
+            loop {
+                let items = self.files.pop_batch(&alarm_type, cnt).await?;
+                if items.is_empty() {
+                    break;
+                }
+                let ids = items.iter().map(|item| item.id.clone()).collect::<Vec<_>>();
+                let batch = HashMap::from([(alarm_type, items.into_iter().map(|item| item.data).collect::<Vec<_>>())]);
+                let result = queue.push(&batch).await;
+                if result.is_err() {
+                    // popped items are only delivered again after a restart, spill them anew.
+                    self.files.push(&batch).await?;
+                }
+                self.files.ack(&alarm_type, &ids).await?;
+                match result {
+                    Ok(()) => moved += ids.len(),
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+        Ok(moved)
+    }
+
+    /// Spilled items by alarm type, and spill bytes.
+    pub async fn render(&self, out: &mut Exposition) {
+        out.family(
+            "dw_proxy_spill_items",
+            "gauge",
+            "Metrics items spilled, waiting for the queue.",
+        );
+        for (alarm_type, _) in metrics_tables() {
+            match self.files.depth(&alarm_type).await {
+                Ok(depth) => {
+                    out.sample(
+                        "dw_proxy_spill_items",
+                        &[("alarm_type", &alarm_type.to_string())],
+                        depth,
+                    );
+                }
+                Err(e) => println!("{} spill depth error {}", alarm_type, e),
+            }
+        }
+        match self.files.bytes().await {
+            Ok(bytes) => {
+                out.family("dw_proxy_spill_bytes", "gauge", "Size of the spill files.")
+                    .sample("dw_proxy_spill_bytes", &[], bytes);
+            }
+            Err(e) => println!("spill bytes error {}", e),
+        }
+    }
+}
+
+/// Replay `spill` into `queue` every replay interval, forever.
+pub(super) async fn run_replay(spill: Arc<Spill>, queue: Arc<dyn MetricsQueue>) {
+    loop {
+        sleep(spill.replay_interval).await;
+        match spill.replay(queue.as_ref()).await {
+            Ok(0) => {}
+            Ok(moved) => println!("replayed {} spilled items", moved),
+            Err(e) => println!("spill replay error {}", e),
+        }
+    }
+}
@@ -0,0 +1,189 @@
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use hyper::StatusCode;
+use serde_json::json;
+
+use crate::queue::MetricsQueue;
+use crate::telemetry::{Exposition, Histogram};
+
+/// `/readyz` fails if the queue doesn't answer in time.
+const READY_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Default)]
+struct Counts {
+    requests: BTreeMap<u16, u64>,
+    payload_bytes: u64,
+    accepted: BTreeMap<String, u64>,
+    /// by alarm type and reason.
+    rejected: BTreeMap<(String, &'static str), u64>,
+    spilled: BTreeMap<String, u64>,
+    /// requests refused by each limit.
+    limited: BTreeMap<&'static str, u64>,
+    /// items accepted, by env and alarm type.
+    env_items: BTreeMap<(String, String), u64>,
+    push_latency: Histogram,
+}
+
+/// #### ProxyStats
+///
+/// What one proxy process received, served at `/metrics`.
+#[derive(Debug)]
+pub struct ProxyStats {
+    started: Instant,
+    counts: Mutex<Counts>,
+}
+
+impl Default for ProxyStats {
+    fn default() -> Self {
+        ProxyStats {
+            started: Instant::now(),
+            counts: Mutex::new(Counts::default()),
+        }
+    }
+}
+
+impl ProxyStats {
+    fn update(&self, f: impl FnOnce(&mut Counts)) {
+        f(&mut self.counts.lock().unwrap_or_else(|e| e.into_inner()))
+    }
+
+    pub fn responded(&self, status: StatusCode) {
+        self.update(|c| *c.requests.entry(status.as_u16()).or_default() += 1)
+    }
+
+    /// Body bytes of one `/api/alarm` request.
+    pub fn received(&self, bytes: usize) {
+        self.update(|c| c.payload_bytes += bytes as u64)
+    }
+
+    /// `items` of `alarm_type` from `env` pushed to the queue.
+    pub fn accepted(&self, alarm_type: &str, env: &str, items: usize) {
+        self.update(|c| {
+            *c.accepted.entry(alarm_type.to_string()).or_default() += items as u64;
+            *c.env_items
+                .entry((env.to_string(), alarm_type.to_string()))
+                .or_default() += items as u64;
+        })
+    }
+
+    /// `items` of `alarm_type` dropped for `reason`.
+    pub fn rejected(&self, alarm_type: &str, reason: &'static str, items: usize) {
+        self.update(|c| *c.rejected.entry((alarm_type.to_string(), reason)).or_default() += items as u64)
+    }
+
+    /// One request refused by `limit`: `body_bytes`, `batch_items`, `env_rate` or `ip_rate`.
+    pub fn limited(&self, limit: &'static str) {
+        self.update(|c| *c.limited.entry(limit).or_default() += 1)
+    }
+
+    /// `items` of `alarm_type` the queue failed to take, written to the spill instead.
+    pub fn spilled(&self, alarm_type: &str, items: usize) {
+        self.update(|c| *c.spilled.entry(alarm_type.to_string()).or_default() += items as u64)
+    }
+
+    /// One queue push, successful or not.
+    pub fn pushed(&self, latency: Duration) {
+        self.update(|c| c.push_latency.observe(latency.as_secs_f64()))
+    }
+
+    /// Prometheus text of these stats.
+    pub fn render(&self) -> Exposition {
+        let c = self.counts.lock().unwrap_or_else(|e| e.into_inner());
+        let mut out = Exposition::default();
+        out.family("dw_proxy_uptime_seconds", "gauge", "Seconds since the proxy started.")
+            .sample("dw_proxy_uptime_seconds", &[], self.started.elapsed().as_secs());
+        out.family(
+            "dw_proxy_requests_total",
+            "counter",
+            "Http requests answered, by status.",
+        );
+        for (status, requests) in &c.requests {
+            out.sample("dw_proxy_requests_total", &[("status", &status.to_string())], requests);
+        }
+        out.family(
+            "dw_proxy_payload_bytes_total",
+            "counter",
+            "Body bytes received at /api/alarm.",
+        )
+        .sample("dw_proxy_payload_bytes_total", &[], c.payload_bytes);
+        out.family(
+            "dw_proxy_items_accepted_total",
+            "counter",
+            "Metrics items pushed to the queue.",
+        );
+        for (alarm_type, items) in &c.accepted {
+            out.sample("dw_proxy_items_accepted_total", &[("alarm_type", alarm_type)], items);
+        }
+        out.family(
+            "dw_proxy_items_rejected_total",
+            "counter",
+            "Metrics items dropped, by reason.",
+        );
+        for ((alarm_type, reason), items) in &c.rejected {
+            out.sample(
+                "dw_proxy_items_rejected_total",
+                &[("alarm_type", alarm_type), ("reason", reason)],
+                items,
+            );
+        }
+        out.family(
+            "dw_proxy_items_spilled_total",
+            "counter",
+            "Metrics items written to the spill as the queue failed.",
+        );
+        for (alarm_type, items) in &c.spilled {
+            out.sample("dw_proxy_items_spilled_total", &[("alarm_type", alarm_type)], items);
+        }
+        out.family(
+            "dw_proxy_requests_limited_total",
+            "counter",
+            "Requests refused by a size or rate limit.",
+        );
+        for (limit, requests) in &c.limited {
+            out.sample("dw_proxy_requests_limited_total", &[("limit", limit)], requests);
+        }
+        out.family(
+            "dw_proxy_env_items_total",
+            "counter",
+            "Metrics items accepted from each env.",
+        );
+        for ((env, alarm_type), items) in &c.env_items {
+            out.sample(
+                "dw_proxy_env_items_total",
+                &[("env", env), ("alarm_type", alarm_type)],
+                items,
+            );
+        }
+        out.family(
+            "dw_proxy_queue_push_duration_seconds",
+            "histogram",
+            "Queue push latency.",
+        )
+        .histogram("dw_proxy_queue_push_duration_seconds", &[], &c.push_latency);
+        out
+    }
+
+    /// `/healthz`, the process is up.
+    pub fn health(&self) -> serde_json::Value {
+        json!({
+            "status": "ok",
+            "uptime_secs": self.started.elapsed().as_secs(),
+        })
+    }
+
+    /// `/readyz`, ready if `queue` can be reached.
+    pub async fn ready(&self, queue: &dyn MetricsQueue) -> (bool, serde_json::Value) {
+        let error = match tokio::time::timeout(READY_TIMEOUT, queue.ping()).await {
+            Ok(Ok(())) => None,
+            Ok(Err(e)) => Some(e.to_string()),
+            Err(_) => Some(format!("queue not answering in {:?}", READY_TIMEOUT)),
+        };
+        let status = match error {
+            None => "ok",
+            Some(_) => "unavailable",
+        };
+        (error.is_none(), json!({ "status": status, "queue_error": error }))
+    }
+}
@@ -0,0 +1,146 @@
+use std::str::FromStr;
+
+use hyper::StatusCode;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::Value;
+
+use metrics_types::alarm_wrapper::AlarmWrapper;
+use metrics_types::{format_env_name, CounterUnit, FlowUnit, MetricsAlarmType, TimerUnit};
+
+/// One metrics item that deserializes into the `AlarmWrapper` of its alarm type.
+#[derive(Debug)]
+pub(super) struct ValidItem {
+    pub alarm_type: MetricsAlarmType,
+    /// normalized env.
+    pub env: String,
+    /// the wrapper json to queue.
+    pub data: String,
+}
+
+/// Why an item was rejected.
+#[derive(Debug)]
+pub(super) struct ItemError {
+    /// `None` if missing or unknown.
+    pub alarm_type: Option<MetricsAlarmType>,
+    /// metrics label: `alarm_type`, `env` or `content`.
+    pub reason: &'static str,
+    pub message: String,
+}
+
+impl ItemError {
+    pub fn new(alarm_type: Option<MetricsAlarmType>, reason: &'static str, message: impl ToString) -> Self {
+        ItemError {
+            alarm_type,
+            reason,
+            message: message.to_string(),
+        }
+    }
+}
+
+fn normalize<UnitType>(alarm_type: MetricsAlarmType, item: Value) -> Result<ValidItem, ItemError>
+where
+    UnitType: Serialize + DeserializeOwned,
+{
+    let error = |reason, e: &dyn ToString| ItemError::new(Some(alarm_type), reason, e.to_string());
+    let mut wrapper = serde_json::from_value::<AlarmWrapper<UnitType>>(item).map_err(|e| error("content", &e))?;
+    // env becomes a database name in consumer, normalize it in case the client didn't.
+    wrapper.env = format_env_name(&wrapper.env).map_err(|e| error("env", &e))?;
+    Ok(ValidItem {
+        alarm_type,
+        data: serde_json::to_string(&wrapper).map_err(|e| error("content", &e))?,
+        env: wrapper.env,
+    })
+}
+
+/// Check `item` is a metrics json the consumer can insert, the consumer would dead-letter it otherwise.
+pub(super) fn validate_item(item: Value) -> Result<ValidItem, ItemError> {
+    let alarm_type = match item.get("alarm_type") {
+        Some(Value::String(s)) => MetricsAlarmType::from_str(s)
+            .map_err(|_| ItemError::new(None, "alarm_type", format!("unknown alarm_type {}", s)))?,
+        Some(v) => return Err(ItemError::new(None, "alarm_type", format!("unknown alarm_type {}", v))),
+        None => return Err(ItemError::new(None, "alarm_type", "missing alarm_type")),
+    };
+    match alarm_type {
+        MetricsAlarmType::Counter => normalize::<CounterUnit>(alarm_type, item),
+        MetricsAlarmType::Timer => normalize::<TimerUnit>(alarm_type, item),
+        MetricsAlarmType::Flow => normalize::<FlowUnit>(alarm_type, item),
+        MetricsAlarmType::Invalid => Err(ItemError::new(None, "alarm_type", "unknown alarm_type invalid")),
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub(super) struct RejectedItem {
+    pub index: usize,
+    pub error: String,
+}
+
+/// `/api/alarm` response body.
+#[derive(Debug, Default, Serialize)]
+pub(super) struct AlarmSummary {
+    pub accepted: usize,
+    pub rejected: usize,
+    pub errors: Vec<RejectedItem>,
+}
+
+impl AlarmSummary {
+    pub fn reject(&mut self, index: usize, error: ItemError) {
+        self.rejected += 1;
+        self.errors.push(RejectedItem {
+            index,
+            error: error.message,
+        });
+    }
+
+    /// 200 if every item is accepted, 400 if none, 207 otherwise.
+    pub fn status(&self) -> StatusCode {
+        match (self.accepted, self.rejected) {
+            (_, 0) => StatusCode::OK,
+            (0, _) => StatusCode::BAD_REQUEST,
+            _ => StatusCode::MULTI_STATUS,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_validate_item() {
+        let item = serde_json::json!({
+            "alarm_type": "timer",
+            "env": "prod-env",
+            "content": {"send_timestamp": "1669269373", "public_ip": "127.0.0.1:9000", "category": "xcons",
+                "tag": "dispatch", "count": 3, "max_time": 9, "min_time": 1, "avg_time": 4}
+        });
+        let valid = validate_item(item.clone()).unwrap();
+        assert_eq!(valid.alarm_type, MetricsAlarmType::Timer);
+        assert_eq!(valid.env, "prod_env");
+        assert!(serde_json::from_str::<AlarmWrapper<TimerUnit>>(&valid.data).is_ok());
+
+        let reason = |item: Value| validate_item(item).unwrap_err().reason;
+        let mut counter = item.clone();
+        counter["alarm_type"] = "counter".into();
+        assert_eq!(reason(counter), "content");
+        let mut bad_env = item.clone();
+        bad_env["env"] = "测试".into();
+        assert_eq!(reason(bad_env), "env");
+        let mut no_env = item.clone();
+        no_env.as_object_mut().unwrap().remove("env");
+        assert_eq!(reason(no_env), "content");
+        let mut bad_count = item.clone();
+        bad_count["content"]["count"] = (-1).into();
+        assert_eq!(reason(bad_count), "content");
+        assert_eq!(reason(serde_json::json!({"alarm_type": "gauge"})), "alarm_type");
+        assert_eq!(reason(serde_json::json!({"alarm_type": 1})), "alarm_type");
+        assert_eq!(reason(serde_json::json!([])), "alarm_type");
+
+        let mut summary = AlarmSummary::default();
+        assert_eq!(summary.status(), StatusCode::OK);
+        summary.reject(1, ItemError::new(None, "alarm_type", "bad"));
+        assert_eq!(summary.status(), StatusCode::BAD_REQUEST);
+        summary.accepted = 1;
+        assert_eq!(summary.status(), StatusCode::MULTI_STATUS);
+    }
+}
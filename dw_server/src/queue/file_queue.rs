@@ -0,0 +1,418 @@
+use std::collections::{BTreeMap, HashMap};
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use async_trait::async_trait;
+use metrics_types::MetricsAlarmType;
+use tokio::fs::{self, File, OpenOptions};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufReader, SeekFrom};
+use tokio::sync::Mutex;
+
+use super::{MetricsQueue, QueueItem};
+use crate::error::ServerError;
+
+const SEGMENT_EXT: &str = "seg";
+const ACK_FILE: &str = "ack";
+
+/// #### FileQueue
+///
+/// Append-only segment files on local disk, one directory per alarm type:
+///
+/// ``` text
+/// <path>/counter/00000000000000000000.seg   one metrics json per line
+/// <path>/counter/00000000000000000001.seg
+/// <path>/counter/ack                        `<segment> <offset>`, everything before it is acked
+/// ```
+///
+/// A new segment is started once the current one reaches `segment_bytes`, and segments are deleted once
+/// fully acked. Popped items not acked are delivered again after a restart.
+///
+/// One proxy process may append while one consumer process pops on the same path.
+pub struct FileQueue {
+    root: PathBuf,
+    segment_bytes: u64,
+    queues: Mutex<HashMap<MetricsAlarmType, SegmentQueue>>,
+}
+
+impl FileQueue {
+    pub async fn new(root: &Path, segment_bytes: u64) -> Result<Self, ServerError> {
+        fs::create_dir_all(root).await?;
+        Ok(FileQueue {
+            root: root.to_path_buf(),
+            segment_bytes,
+            queues: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Size of the segment files of all alarm types, acked items counted until their segment is deleted.
+    pub async fn bytes(&self) -> Result<u64, ServerError> {
+        let mut bytes = 0;
+        let mut dirs = fs::read_dir(&self.root).await?;
+        while let Some(dir) = dirs.next_entry().await? {
+            if !dir.file_type().await?.is_dir() {
+                continue;
+            }
+            for segment in list_segments(&dir.path()).await? {
+                match fs::metadata(segment_path(&dir.path(), segment)).await {
+                    Ok(meta) => bytes += meta.len(),
+                    // deleted by an ack meanwhile.
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                    Err(e) => return Err(e.into()),
+                }
+            }
+        }
+        Ok(bytes)
+    }
+}
+
+#[async_trait]
+impl MetricsQueue for FileQueue {
+    async fn push(&self, values: &HashMap<MetricsAlarmType, Vec<String>>) -> Result<(), ServerError> {
+        let mut queues = self.queues.lock().await;
+        for (key, value) in values.iter().filter(|(_, v)| !v.is_empty()) {
+            if value.iter().any(|v| v.contains('\n')) {
+                return Err(ServerError::QueueError("file queue item contains newline".into()));
+            }
+            let q = open_segment_queue(&mut queues, &self.root, key).await?;
+            q.push(value, self.segment_bytes).await?;
+        }
+        Ok(())
+    }
+
+    async fn pop_batch(&self, key: &MetricsAlarmType, cnt: NonZeroUsize) -> Result<Vec<QueueItem>, ServerError> {
+        let mut queues = self.queues.lock().await;
+        open_segment_queue(&mut queues, &self.root, key).await?.pop(cnt).await
+    }
+
+    async fn ack(&self, key: &MetricsAlarmType, ids: &[String]) -> Result<(), ServerError> {
+        let mut queues = self.queues.lock().await;
+        open_segment_queue(&mut queues, &self.root, key).await?.ack(ids).await
+    }
+
+    /// Lines after the ack position, counted by reading the segments, not holding the queue meanwhile.
+    async fn depth(&self, key: &MetricsAlarmType) -> Result<u64, ServerError> {
+        let (dir, acked) = {
+            let mut queues = self.queues.lock().await;
+            let q = open_segment_queue(&mut queues, &self.root, key).await?;
+            (q.dir.clone(), q.acked)
+        };
+        let mut depth = 0;
+        let mut buf = vec![0u8; 64 * 1024];
+        for segment in list_segments(&dir).await?.into_iter().filter(|s| *s >= acked.segment) {
+            let mut file = match File::open(segment_path(&dir, segment)).await {
+                Ok(f) => f,
+                // deleted by an ack meanwhile.
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            };
+            if segment == acked.segment {
+                file.seek(SeekFrom::Start(acked.offset)).await?;
+            }
+            loop {
+                let n = file.read(&mut buf).await?;
+                if n == 0 {
+                    break;
+                }
+                depth += buf[..n].iter().filter(|b| **b == b'\n').count() as u64;
+            }
+        }
+        Ok(depth)
+    }
+
+    /// The queue directory is still there, e.g. the disk is mounted.
+    async fn ping(&self) -> Result<(), ServerError> {
+        match fs::metadata(&self.root).await?.is_dir() {
+            true => Ok(()),
+            false => Err(ServerError::QueueError(format!(
+                "{} is not a directory",
+                self.root.display()
+            ))),
+        }
+    }
+}
+
+async fn open_segment_queue<'a>(
+    queues: &'a mut HashMap<MetricsAlarmType, SegmentQueue>,
+    root: &Path,
+    key: &MetricsAlarmType,
+) -> Result<&'a mut SegmentQueue, ServerError> {
+    if !queues.contains_key(key) {
+        let q = SegmentQueue::open(root.join(key.to_string())).await?;
+        queues.insert(*key, q);
+    }
+    Ok(queues.get_mut(key).unwrap())
+}
+
+/// Position in the queue, also used as item id: `<segment>-<offset>`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+struct Position {
+    segment: u64,
+    offset: u64,
+}
+
+impl std::fmt::Display for Position {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}-{}", self.segment, self.offset)
+    }
+}
+
+impl FromStr for Position {
+    type Err = ServerError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || ServerError::QueueError(format!("invalid file queue position {}", s));
+        let (segment, offset) = s.split_once(['-', ' ']).ok_or_else(err)?;
+        Ok(Position {
+            segment: segment.trim().parse().map_err(|_| err())?,
+            offset: offset.trim().parse().map_err(|_| err())?,
+        })
+    }
+}
+
+struct SegmentWriter {
+    segment: u64,
+    file: File,
+    size: u64,
+}
+
+struct SegmentQueue {
+    dir: PathBuf,
+    writer: Option<SegmentWriter>,
+    read_pos: Position,
+    /// popped but not acked, start -> end position.
+    in_flight: BTreeMap<Position, Position>,
+    acked: Position,
+}
+
+impl SegmentQueue {
+    async fn open(dir: PathBuf) -> Result<Self, ServerError> {
+        fs::create_dir_all(&dir).await?;
+        let acked = match fs::read_to_string(dir.join(ACK_FILE)).await {
+            Ok(s) => s.parse()?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Position {
+                segment: list_segments(&dir).await?.first().copied().unwrap_or(0),
+                offset: 0,
+            },
+            Err(e) => return Err(e.into()),
+        };
+        Ok(SegmentQueue {
+            dir,
+            writer: None,
+            read_pos: acked,
+            in_flight: BTreeMap::new(),
+            acked,
+        })
+    }
+
+    fn segment_path(&self, segment: u64) -> PathBuf {
+        segment_path(&self.dir, segment)
+    }
+
+    async fn open_writer(&self, segment: u64) -> Result<SegmentWriter, ServerError> {
+        let mut segment = segment;
+        loop {
+            let mut file = OpenOptions::new()
+                .create(true)
+                .read(true)
+                .append(true)
+                .open(self.segment_path(segment))
+                .await?;
+            let size = file.metadata().await?.len();
+            if size > 0 {
+                // last write might be cut by a crash, never append after a partial line.
+                let mut last = [0u8; 1];
+                file.seek(SeekFrom::End(-1)).await?;
+                file.read_exact(&mut last).await?;
+                if last[0] != b'\n' {
+                    segment += 1;
+                    continue;
+                }
+            }
+            return Ok(SegmentWriter { segment, file, size });
+        }
+    }
+
+    async fn push(&mut self, values: &[String], segment_bytes: u64) -> Result<(), ServerError> {
+        let writer = match self.writer.take() {
+            Some(w) if w.size >= segment_bytes => self.open_writer(w.segment + 1).await?,
+            Some(w) => w,
+            None => {
+                let last = list_segments(&self.dir).await?.last().copied();
+                self.open_writer(last.unwrap_or(self.acked.segment)).await?
+            }
+        };
+        let writer = self.writer.insert(writer);
+        let mut buf = values.join("\n");
+        buf.push('\n');
+        writer.file.write_all(buf.as_bytes()).await?;
+        writer.file.flush().await?;
+        writer.size += buf.len() as u64;
+        Ok(())
+    }
+
+    async fn pop(&mut self, cnt: NonZeroUsize) -> Result<Vec<QueueItem>, ServerError> {
+        let mut items = Vec::new();
+        while items.len() < cnt.get() {
+            let file = match File::open(self.segment_path(self.read_pos.segment)).await {
+                Ok(f) => Some(f),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+                Err(e) => return Err(e.into()),
+            };
+            if let Some(file) = file {
+                let mut reader = BufReader::new(file);
+                reader.seek(SeekFrom::Start(self.read_pos.offset)).await?;
+                while items.len() < cnt.get() {
+                    let mut line = Vec::new();
+                    let n = reader.read_until(b'\n', &mut line).await?;
+                    if n == 0 || line.last() != Some(&b'\n') {
+                        // reach the end, or a line still being written.
+                        break;
+                    }
+                    let start = self.read_pos;
+                    self.read_pos.offset += n as u64;
+                    self.in_flight.insert(start, self.read_pos);
+                    line.pop();
+                    items.push(QueueItem {
+                        id: start.to_string(),
+                        data: String::from_utf8_lossy(&line).into_owned(),
+                    });
+                }
+                if items.len() >= cnt.get() {
+                    break;
+                }
+            }
+            // the writer only moves on after finishing a segment.
+            match fs::try_exists(self.segment_path(self.read_pos.segment + 1)).await? {
+                true => {
+                    self.read_pos = Position {
+                        segment: self.read_pos.segment + 1,
+                        offset: 0,
+                    }
+                }
+                false => break,
+            }
+        }
+        Ok(items)
+    }
+
+    async fn ack(&mut self, ids: &[String]) -> Result<(), ServerError> {
+        for id in ids {
+            if let Ok(pos) = id.parse::<Position>() {
+                self.in_flight.remove(&pos);
+            }
+        }
+        let committed = self.in_flight.keys().next().copied().unwrap_or(self.read_pos);
+        if committed <= self.acked {
+            return Ok(());
+        }
+        let tmp = self.dir.join(format!("{}.tmp", ACK_FILE));
+        fs::write(&tmp, format!("{} {}", committed.segment, committed.offset)).await?;
+        fs::rename(&tmp, self.dir.join(ACK_FILE)).await?;
+        for segment in self.acked.segment..committed.segment {
+            match fs::remove_file(self.segment_path(segment)).await {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+        }
+        self.acked = committed;
+        Ok(())
+    }
+}
+
+fn segment_path(dir: &Path, segment: u64) -> PathBuf {
+    dir.join(format!("{:020}.{}", segment, SEGMENT_EXT))
+}
+
+/// segment numbers in `dir`, ascending.
+async fn list_segments(dir: &Path) -> Result<Vec<u64>, ServerError> {
+    let mut segments = Vec::new();
+    let mut entries = fs::read_dir(dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        if path.extension().is_some_and(|ext| ext == SEGMENT_EXT) {
+            if let Some(segment) = path.file_stem().and_then(|s| s.to_str()?.parse::<u64>().ok()) {
+                segments.push(segment);
+            }
+        }
+    }
+    segments.sort_unstable();
+    Ok(segments)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn data(items: &[QueueItem]) -> Vec<&str> {
+        items.iter().map(|i| i.data.as_str()).collect()
+    }
+
+    fn values(key: MetricsAlarmType, v: &[&str]) -> HashMap<MetricsAlarmType, Vec<String>> {
+        HashMap::from([(key, v.iter().map(|s| s.to_string()).collect())])
+    }
+
+    async fn do_test_file_queue(root: &Path) {
+        let cnt = NonZeroUsize::new(3).unwrap();
+        let key = MetricsAlarmType::Timer;
+        let q = FileQueue::new(root, 8).await.unwrap();
+        q.push(&values(key, &["t1", "t2"])).await.unwrap();
+        q.push(&values(key, &["t3", "t4", "t5"])).await.unwrap();
+        q.push(&values(key, &["t6"])).await.unwrap();
+        assert!(q.push(&values(key, &["bad\nline"])).await.is_err());
+
+        let first = q.pop_batch(&key, cnt).await.unwrap();
+        assert_eq!(data(&first), vec!["t1", "t2", "t3"]);
+        let second = q.pop_batch(&key, cnt).await.unwrap();
+        assert_eq!(data(&second), vec!["t4", "t5", "t6"]);
+        assert!(q.pop_batch(&key, cnt).await.unwrap().is_empty());
+        // popped, not acked.
+        assert_eq!(q.depth(&key).await.unwrap(), 6);
+        assert_eq!(q.bytes().await.unwrap(), 18);
+        assert!(q.pop_batch(&MetricsAlarmType::Flow, cnt).await.unwrap().is_empty());
+
+        // ack the second batch only, nothing can be dropped yet.
+        let ids = second.iter().map(|i| i.id.clone()).collect::<Vec<_>>();
+        q.ack(&key, &ids).await.unwrap();
+        assert_eq!(list_segments(&root.join("timer")).await.unwrap(), vec![0, 1]);
+        drop(q);
+
+        // unacked items are delivered again after reopen.
+        let q = FileQueue::new(root, 8).await.unwrap();
+        let again = q.pop_batch(&key, cnt).await.unwrap();
+        assert_eq!(data(&again), vec!["t1", "t2", "t3"]);
+        let ids = again.iter().map(|i| i.id.clone()).collect::<Vec<_>>();
+        q.ack(&key, &ids).await.unwrap();
+        let again = q.pop_batch(&key, cnt).await.unwrap();
+        assert_eq!(data(&again), vec!["t4", "t5", "t6"]);
+        q.push(&values(key, &["t7"])).await.unwrap();
+        let last = q.pop_batch(&key, cnt).await.unwrap();
+        assert_eq!(data(&last), vec!["t7"]);
+        let ids = again
+            .iter()
+            .chain(last.iter())
+            .map(|i| i.id.clone())
+            .collect::<Vec<_>>();
+        q.ack(&key, &ids).await.unwrap();
+        // fully acked segments are deleted.
+        assert_eq!(list_segments(&root.join("timer")).await.unwrap(), vec![1]);
+        assert_eq!(q.depth(&key).await.unwrap(), 0);
+    }
+
+    #[test]
+    fn test_file_queue() {
+        let dir = tempfile::tempdir().unwrap();
+        tokio_test::block_on(do_test_file_queue(dir.path()));
+    }
+
+    #[test]
+    fn test_position() {
+        let pos = Position {
+            segment: 3,
+            offset: 120,
+        };
+        assert_eq!(pos.to_string().parse::<Position>().unwrap(), pos);
+        assert_eq!("3 120".parse::<Position>().unwrap(), pos);
+        assert!("3:120".parse::<Position>().is_err());
+    }
+}
@@ -0,0 +1,116 @@
+use std::collections::{HashMap, VecDeque};
+use std::num::NonZeroUsize;
+use std::sync::Mutex;
+
+use async_trait::async_trait;
+use metrics_types::MetricsAlarmType;
+
+use super::{MetricsQueue, QueueItem};
+use crate::error::ServerError;
+
+/// In-process queue, for proxy and consumer running in one process.
+///
+/// Nothing survives the process, so `ack` is a no-op. Each alarm type holds at most `capacity` items,
+/// push fails beyond that instead of growing without bound while the consumer is stuck.
+#[derive(Debug)]
+pub struct MemoryQueue {
+    capacity: usize,
+    inner: Mutex<MemoryQueueInner>,
+}
+
+#[derive(Debug, Default)]
+struct MemoryQueueInner {
+    next_id: u64,
+    queues: HashMap<MetricsAlarmType, VecDeque<QueueItem>>,
+}
+
+impl MemoryQueue {
+    pub fn new(capacity: usize) -> Self {
+        MemoryQueue {
+            capacity,
+            inner: Mutex::new(MemoryQueueInner::default()),
+        }
+    }
+}
+
+#[async_trait]
+impl MetricsQueue for MemoryQueue {
+    async fn push(&self, values: &HashMap<MetricsAlarmType, Vec<String>>) -> Result<(), ServerError> {
+        let mut inner = self.inner.lock().unwrap();
+        for (key, value) in values.iter() {
+            let len = inner.queues.get(key).map_or(0, |q| q.len());
+            if len + value.len() > self.capacity {
+                return Err(ServerError::QueueError(format!("memory queue {} full", key)));
+            }
+        }
+        for (key, value) in values.iter() {
+            for v in value {
+                inner.next_id += 1;
+                let item = QueueItem {
+                    id: inner.next_id.to_string(),
+                    data: v.clone(),
+                };
+                inner.queues.entry(*key).or_default().push_back(item);
+            }
+        }
+        Ok(())
+    }
+
+    async fn pop_batch(&self, key: &MetricsAlarmType, cnt: NonZeroUsize) -> Result<Vec<QueueItem>, ServerError> {
+        let mut inner = self.inner.lock().unwrap();
+        Ok(match inner.queues.get_mut(key) {
+            Some(q) => {
+                let n = q.len().min(cnt.get());
+                q.drain(..n).collect()
+            }
+            None => Vec::new(),
+        })
+    }
+
+    async fn ack(&self, _key: &MetricsAlarmType, _ids: &[String]) -> Result<(), ServerError> {
+        Ok(())
+    }
+
+    async fn depth(&self, key: &MetricsAlarmType) -> Result<u64, ServerError> {
+        let inner = self.inner.lock().unwrap();
+        Ok(inner.queues.get(key).map_or(0, |q| q.len() as u64))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    async fn do_test_memory_queue() {
+        let q = MemoryQueue::new(3);
+        let cnt = NonZeroUsize::new(2).unwrap();
+        q.push(&HashMap::from([
+            (
+                MetricsAlarmType::Counter,
+                vec!["c1".to_string(), "c2".to_string(), "c3".to_string()],
+            ),
+            (MetricsAlarmType::Timer, vec!["t1".to_string()]),
+        ]))
+        .await
+        .unwrap();
+        assert!(q
+            .push(&HashMap::from([(MetricsAlarmType::Counter, vec!["c4".to_string()])]))
+            .await
+            .is_err());
+
+        let r = q.pop_batch(&MetricsAlarmType::Counter, cnt).await.unwrap();
+        assert_eq!(r.iter().map(|i| i.data.as_str()).collect::<Vec<_>>(), vec!["c1", "c2"]);
+        let r = q.pop_batch(&MetricsAlarmType::Counter, cnt).await.unwrap();
+        assert_eq!(r.iter().map(|i| i.data.as_str()).collect::<Vec<_>>(), vec!["c3"]);
+        assert!(q.pop_batch(&MetricsAlarmType::Counter, cnt).await.unwrap().is_empty());
+        assert!(q.pop_batch(&MetricsAlarmType::Flow, cnt).await.unwrap().is_empty());
+        assert_eq!(q.depth(&MetricsAlarmType::Timer).await.unwrap(), 1);
+        assert_eq!(q.pop_batch(&MetricsAlarmType::Timer, cnt).await.unwrap().len(), 1);
+        assert_eq!(q.depth(&MetricsAlarmType::Timer).await.unwrap(), 0);
+    }
+
+    #[test]
+    fn test_memory_queue() {
+        tokio_test::block_on(do_test_memory_queue());
+    }
+}
@@ -0,0 +1,70 @@
+mod file_queue;
+mod memory_queue;
+mod redis_queue;
+
+use std::collections::HashMap;
+use std::num::NonZeroUsize;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use metrics_types::MetricsAlarmType;
+
+use crate::config::{QueueBackend, QueueConfig, RedisConfig, StreamConfig};
+use crate::error::ServerError;
+
+pub use file_queue::FileQueue;
+pub use memory_queue::MemoryQueue;
+pub use redis_queue::RedisQueue;
+
+/// One queued metrics json, `ack` its `id` once `data` is committed.
+#[derive(Debug, Clone)]
+pub struct QueueItem {
+    pub id: String,
+    pub data: String,
+}
+
+/// #### MetricsQueue
+///
+/// Queue between proxy and consumer, one logical queue per `MetricsAlarmType`.
+///
+/// Items popped but not acked are delivered again, to this or another consumer,
+/// if the backend can outlive the consumer (redis, file).
+#[async_trait]
+pub trait MetricsQueue: Send + Sync {
+    /// push all values, grouped by alarm type.
+    async fn push(&self, values: &HashMap<MetricsAlarmType, Vec<String>>) -> Result<(), ServerError>;
+
+    /// pop at most `cnt` items without blocking, empty if nothing queued.
+    async fn pop_batch(&self, key: &MetricsAlarmType, cnt: NonZeroUsize) -> Result<Vec<QueueItem>, ServerError>;
+
+    /// ack popped items whose data is committed.
+    async fn ack(&self, key: &MetricsAlarmType, ids: &[String]) -> Result<(), ServerError>;
+
+    /// items still held: not popped yet, or popped and not acked if the backend keeps them until acked.
+    async fn depth(&self, key: &MetricsAlarmType) -> Result<u64, ServerError>;
+
+    /// items removed from the queue before every reader acked them, e.g. by a length cap, since start.
+    fn dropped(&self, _key: &MetricsAlarmType) -> u64 {
+        0
+    }
+
+    /// check the backend can be reached now, e.g. redis answers `PING`.
+    async fn ping(&self) -> Result<(), ServerError> {
+        Ok(())
+    }
+}
+
+/// Open the queue backend selected by `config`.
+///
+/// `stream` is only used by the redis backend when popping.
+pub async fn open_queue(
+    config: &QueueConfig,
+    redis: &RedisConfig,
+    stream: &StreamConfig,
+) -> Result<Arc<dyn MetricsQueue>, ServerError> {
+    Ok(match config.backend() {
+        QueueBackend::Redis => Arc::new(RedisQueue::new(redis, stream).await?),
+        QueueBackend::Memory => Arc::new(MemoryQueue::new(config.memory_capacity())),
+        QueueBackend::File => Arc::new(FileQueue::new(config.path(), config.segment_bytes()).await?),
+    })
+}
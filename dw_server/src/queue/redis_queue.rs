@@ -0,0 +1,144 @@
+use std::collections::HashMap;
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use metrics_types::MetricsAlarmType;
+use tokio::sync::Mutex;
+
+use super::{MetricsQueue, QueueItem};
+use crate::config::{RedisConfig, StreamConfig};
+use crate::error::ServerError;
+use crate::redis_conn::RedisConn;
+use crate::store::metrics_tables;
+
+/// Redis streams queue, popped through one consumer group.
+///
+/// On first pop of an alarm type, this consumer's own pending entries left by last run are read again,
+/// and every `claim_idle` entries pending that long are claimed from dead consumers, `cnt` per pop
+/// until the whole pending list was scanned.
+/// After each ack, entries every group has acked are trimmed. With `max_len`, the stream is also capped
+/// to about that many entries, dropping unacked ones too; those are counted in `dropped`.
+pub struct RedisQueue {
+    conn: RedisConn,
+    group: String,
+    consumer: String,
+    claim_idle: Duration,
+    max_len: Option<usize>,
+    delete_acked: bool,
+    dropped: HashMap<MetricsAlarmType, AtomicU64>,
+    read_state: Mutex<HashMap<MetricsAlarmType, ReadState>>,
+}
+
+struct ReadState {
+    /// re-reading own pending entries after this id, `None` once done.
+    pending_cursor: Option<String>,
+    /// `XAUTOCLAIM` scanning the pending list from this id, `None` until `claim_idle` passed again.
+    claim_cursor: Option<String>,
+    claim_time: Instant,
+}
+
+impl RedisQueue {
+    pub async fn new(redis: &RedisConfig, stream: &StreamConfig) -> Result<Self, ServerError> {
+        Ok(RedisQueue {
+            conn: RedisConn::new(redis).await?,
+            group: stream.group().to_string(),
+            consumer: stream.consumer_name()?,
+            claim_idle: stream.claim_idle(),
+            max_len: stream.max_len(),
+            delete_acked: stream.delete_acked(),
+            dropped: metrics_tables()
+                .into_iter()
+                .map(|(key, _)| (key, AtomicU64::new(0)))
+                .collect(),
+            read_state: Mutex::new(HashMap::new()),
+        })
+    }
+}
+
+#[async_trait]
+impl MetricsQueue for RedisQueue {
+    async fn push(&self, values: &HashMap<MetricsAlarmType, Vec<String>>) -> Result<(), ServerError> {
+        Ok(self.conn.stream_add_multi(values).await?)
+    }
+
+    async fn pop_batch(&self, key: &MetricsAlarmType, cnt: NonZeroUsize) -> Result<Vec<QueueItem>, ServerError> {
+        let (group, consumer) = (self.group.as_str(), self.consumer.as_str());
+        let mut read_state = self.read_state.lock().await;
+        if !read_state.contains_key(key) {
+            self.conn.stream_create_group(key, group).await?;
+            read_state.insert(
+                *key,
+                ReadState {
+                    pending_cursor: Some(String::from("0")),
+                    claim_cursor: None,
+                    claim_time: Instant::now(),
+                },
+            );
+        }
+        let state = read_state.get_mut(key).unwrap();
+
+        if let Some(cursor) = &state.pending_cursor {
+            let r = self.conn.stream_read_group(key, group, consumer, cursor, cnt).await?;
+            state.pending_cursor = r.last().map(|e| e.id.clone());
+            if !r.is_empty() {
+                return Ok(r);
+            }
+        }
+        if state.claim_cursor.is_some() || state.claim_time.elapsed() > self.claim_idle {
+            // a failed claim starts the scan over on next pop.
+            let start_id = state.claim_cursor.take().unwrap_or_else(|| String::from("0-0"));
+            let (next_id, r) = self
+                .conn
+                .stream_auto_claim(key, group, consumer, self.claim_idle, &start_id, cnt)
+                .await?;
+            if next_id == "0-0" {
+                state.claim_time = Instant::now();
+            } else {
+                state.claim_cursor = Some(next_id);
+            }
+            if !r.is_empty() {
+                return Ok(r);
+            }
+        }
+        Ok(self.conn.stream_read_group(key, group, consumer, ">", cnt).await?)
+    }
+
+    async fn ack(&self, key: &MetricsAlarmType, ids: &[String]) -> Result<(), ServerError> {
+        self.conn.stream_ack(key, &self.group, ids, self.delete_acked).await?;
+        if ids.is_empty() {
+            return Ok(());
+        }
+        // acked already, a failed trim is done by the next ack.
+        if let Err(e) = self.conn.stream_trim_acked(key).await {
+            println!("{} stream trim error {}", key, e);
+        }
+        if let Some(max_len) = self.max_len {
+            match self.conn.stream_trim(key, max_len).await {
+                // entries every group acked are trimmed above, so these were not acked by some group.
+                Ok(0) => {}
+                Ok(n) => {
+                    println!("{} stream over max_len {}, dropped {} unacked entries", key, max_len, n);
+                    if let Some(dropped) = self.dropped.get(key) {
+                        dropped.fetch_add(n, Ordering::Relaxed);
+                    }
+                }
+                Err(e) => println!("{} stream trim error {}", key, e),
+            }
+        }
+        Ok(())
+    }
+
+    async fn depth(&self, key: &MetricsAlarmType) -> Result<u64, ServerError> {
+        Ok(self.conn.stream_backlog(key, &self.group).await?)
+    }
+
+    fn dropped(&self, key: &MetricsAlarmType) -> u64 {
+        self.dropped.get(key).map_or(0, |n| n.load(Ordering::Relaxed))
+    }
+
+    async fn ping(&self) -> Result<(), ServerError> {
+        Ok(self.conn.ping().await?)
+    }
+}
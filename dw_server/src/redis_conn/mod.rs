@@ -1,53 +1,324 @@
+use std::collections::HashMap;
 use std::num::NonZeroUsize;
+use std::time::Duration;
 
 use metrics_types::MetricsAlarmType;
-use redis::{Client, Commands, Connection, RedisResult};
+use redis::streams::{
+    StreamId, StreamMaxlen, StreamPendingReply, StreamRangeReply, StreamReadOptions, StreamReadReply,
+};
+use redis::{aio::ConnectionManager, AsyncCommands, Client, FromRedisValue, RedisResult, Value};
 
+use crate::config::RedisConfig;
+use crate::queue::QueueItem;
+
+/// stream entry field holding the metrics json.
+const STREAM_DATA_FIELD: &str = "data";
+
+impl From<StreamId> for QueueItem {
+    fn from(value: StreamId) -> Self {
+        QueueItem {
+            data: value.get(STREAM_DATA_FIELD).unwrap_or_default(),
+            id: value.id,
+        }
+    }
+}
+
+/// `<ms>-<seq>` of a stream entry id, ordered like redis orders the entries.
+fn parse_stream_id(id: &str) -> Option<(u64, u64)> {
+    let (ms, seq) = id.split_once('-')?;
+    Some((ms.parse().ok()?, seq.parse().ok()?))
+}
+
+/// Async redis connection, backed by one multiplexed connection.
+///
+/// Cheap to share: every command clones the multiplexed handle, so concurrent
+/// requests are pipelined on the same socket instead of waiting for each other.
+/// A lost connection is reconnected in the background, commands fail meanwhile.
+#[derive(Clone)]
 pub struct RedisConn {
-    _client: Client,
-    conn: Connection,
+    conn: ConnectionManager,
+    key_prefix: String,
 }
 
 impl RedisConn {
-    pub fn new() -> RedisResult<Self> {
-        let _client = Client::open("redis://127.0.0.1")?;
-        let conn = _client.get_connection()?;
-        Ok(RedisConn { _client, conn })
+    pub async fn new(config: &RedisConfig) -> RedisResult<Self> {
+        let client = Client::open(config.connection_info()?)?;
+        let conn = client.get_tokio_connection_manager().await?;
+        Ok(RedisConn {
+            conn,
+            key_prefix: config.key_prefix().to_string(),
+        })
+    }
+
+    /// `{key_prefix}:{alarm_type}`, or just `{alarm_type}` without prefix.
+    fn key(&self, key: &MetricsAlarmType) -> String {
+        match self.key_prefix.is_empty() {
+            true => key.as_redis_key(),
+            false => format!("{}:{}", self.key_prefix, key.as_redis_key()),
+        }
     }
 
-    pub fn list_push(&mut self, key: &MetricsAlarmType, value: String) -> RedisResult<()> {
-        self.conn.lpush::<String, String, ()>(key.as_redis_key(), value)?;
+    /// `{key_prefix}:dead_letter:{alarm_type}`, or `dead_letter:{alarm_type}` without prefix.
+    fn dead_letter_key(&self, key: &MetricsAlarmType) -> String {
+        match self.key_prefix.is_empty() {
+            true => format!("dead_letter:{}", key.as_redis_key()),
+            false => format!("{}:dead_letter:{}", self.key_prefix, key.as_redis_key()),
+        }
+    }
+
+    /// `XADD` dead letters to their own stream, not read by any consumer group.
+    pub async fn dead_letter_add(&self, key: &MetricsAlarmType, values: &[String]) -> RedisResult<()> {
+        let key = self.dead_letter_key(key);
+        let mut pipe = redis::pipe();
+        for v in values {
+            pipe.xadd(&key, "*", &[(STREAM_DATA_FIELD, v)]).ignore();
+        }
+        let mut conn = self.conn.clone();
+        pipe.query_async::<_, ()>(&mut conn).await
+    }
+
+    /// `XRANGE` the oldest `cnt` dead letters.
+    pub async fn dead_letter_range(&self, key: &MetricsAlarmType, cnt: usize) -> RedisResult<Vec<QueueItem>> {
+        let mut conn = self.conn.clone();
+        let r: StreamRangeReply = conn.xrange_count(self.dead_letter_key(key), "-", "+", cnt).await?;
+        Ok(r.ids.into_iter().map(QueueItem::from).collect())
+    }
+
+    pub async fn dead_letter_delete(&self, key: &MetricsAlarmType, ids: &[String]) -> RedisResult<()> {
+        if ids.is_empty() {
+            return Ok(());
+        }
+        let mut conn = self.conn.clone();
+        conn.xdel::<String, String, ()>(self.dead_letter_key(key), ids).await
+    }
+
+    pub async fn list_push(&self, key: &MetricsAlarmType, value: String) -> RedisResult<()> {
+        let mut conn = self.conn.clone();
+        conn.lpush::<String, String, ()>(self.key(key), value).await?;
         Ok(())
     }
 
-    pub fn list_pop(&mut self, key: &MetricsAlarmType) -> RedisResult<String> {
-        let r = self.conn.lpop(key.as_redis_key(), None)?;
+    /// Push all values with one `LPUSH` per alarm type, sent together in one pipeline.
+    pub async fn list_push_multi(&self, values: &HashMap<MetricsAlarmType, Vec<String>>) -> RedisResult<()> {
+        let mut pipe = redis::pipe();
+        for (key, value) in values.iter().filter(|(_, v)| !v.is_empty()) {
+            pipe.lpush(self.key(key), value).ignore();
+        }
+        let mut conn = self.conn.clone();
+        pipe.query_async::<_, ()>(&mut conn).await
+    }
+
+    /// `XADD` all values, sent together in one `MULTI` transaction, so either all or none are added.
+    pub async fn stream_add_multi(&self, values: &HashMap<MetricsAlarmType, Vec<String>>) -> RedisResult<()> {
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+        for (key, value) in values.iter() {
+            let key = self.key(key);
+            for v in value {
+                pipe.xadd(&key, "*", &[(STREAM_DATA_FIELD, v)]).ignore();
+            }
+        }
+        let mut conn = self.conn.clone();
+        pipe.query_async::<_, ()>(&mut conn).await
+    }
+
+    /// Create consumer `group` reading from the stream's beginning, ok if it already exists.
+    pub async fn stream_create_group(&self, key: &MetricsAlarmType, group: &str) -> RedisResult<()> {
+        let mut conn = self.conn.clone();
+        match conn
+            .xgroup_create_mkstream::<String, &str, &str, ()>(self.key(key), group, "0")
+            .await
+        {
+            Err(e) if e.code() == Some("BUSYGROUP") => Ok(()),
+            r => r,
+        }
+    }
+
+    /// `XREADGROUP` without blocking, `start_id` is `">"` for new entries,
+    /// or an entry id to re-read this consumer's own pending entries after it.
+    pub async fn stream_read_group(
+        &self,
+        key: &MetricsAlarmType,
+        group: &str,
+        consumer: &str,
+        start_id: &str,
+        cnt: NonZeroUsize,
+    ) -> RedisResult<Vec<QueueItem>> {
+        let mut conn = self.conn.clone();
+        let opts = StreamReadOptions::default().group(group, consumer).count(cnt.get());
+        let r: StreamReadReply = conn.xread_options(&[self.key(key)], &[start_id], &opts).await?;
+        Ok(r.keys
+            .into_iter()
+            .flat_map(|k| k.ids.into_iter().map(QueueItem::from))
+            .collect())
+    }
+
+    /// `XACK` committed entries, then `XDEL` them in the same transaction if `delete`.
+    ///
+    /// Deleting also drops the entries for other groups reading the stream, bound it with `stream_trim_acked` instead.
+    pub async fn stream_ack(
+        &self,
+        key: &MetricsAlarmType,
+        group: &str,
+        ids: &[String],
+        delete: bool,
+    ) -> RedisResult<()> {
+        if ids.is_empty() {
+            return Ok(());
+        }
+        let key = self.key(key);
+        let mut pipe = redis::pipe();
+        pipe.atomic().xack(&key, group, ids).ignore();
+        if delete {
+            pipe.xdel(&key, ids).ignore();
+        }
+        let mut conn = self.conn.clone();
+        pipe.query_async::<_, ()>(&mut conn).await
+    }
+
+    /// `XTRIM MINID` the entries every group has acked: those below the lowest pending id of each group,
+    /// or below its last delivered id if nothing is pending. Returns how many entries were removed.
+    ///
+    /// Nothing is trimmed if the stream has no group yet.
+    pub async fn stream_trim_acked(&self, key: &MetricsAlarmType) -> RedisResult<u64> {
+        let key = self.key(key);
+        let mut conn = self.conn.clone();
+        let groups: Vec<HashMap<String, Value>> = redis::cmd("XINFO")
+            .arg("GROUPS")
+            .arg(&key)
+            .query_async(&mut conn)
+            .await?;
+        let mut min_id: Option<(u64, u64)> = None;
+        for info in groups {
+            let field = |name| info.get(name).map(Option::<String>::from_redis_value).transpose();
+            let (Some(Some(name)), Some(Some(last_delivered))) = (field("name")?, field("last-delivered-id")?) else {
+                return Ok(0);
+            };
+            let pending: StreamPendingReply = conn.xpending(&key, &name).await?;
+            let id = match &pending {
+                StreamPendingReply::Data(data) if data.count > 0 => data.start_id.as_str(),
+                _ => last_delivered.as_str(),
+            };
+            let Some(id) = parse_stream_id(id) else {
+                return Ok(0);
+            };
+            min_id = Some(min_id.map_or(id, |min_id| min_id.min(id)));
+        }
+        let Some((ms, seq)) = min_id else {
+            return Ok(0);
+        };
+        redis::cmd("XTRIM")
+            .arg(&key)
+            .arg("MINID")
+            .arg(format!("{}-{}", ms, seq))
+            .query_async(&mut conn)
+            .await
+    }
+
+    /// `XTRIM MAXLEN ~`, keep about the newest `max_len` entries, acked or not.
+    /// Returns how many entries were removed.
+    pub async fn stream_trim(&self, key: &MetricsAlarmType, max_len: usize) -> RedisResult<u64> {
+        let mut conn = self.conn.clone();
+        conn.xtrim(self.key(key), StreamMaxlen::Approx(max_len)).await
+    }
+
+    /// `PING`, fails if the connection is lost.
+    pub async fn ping(&self) -> RedisResult<()> {
+        let mut conn = self.conn.clone();
+        redis::cmd("PING").query_async::<_, String>(&mut conn).await.map(|_| ())
+    }
+
+    /// Entries `group` has not acked yet, pending plus not delivered (`lag`) from `XINFO GROUPS`.
+    ///
+    /// `XLEN` if the group is not created yet, or redis can't tell its lag.
+    pub async fn stream_backlog(&self, key: &MetricsAlarmType, group: &str) -> RedisResult<u64> {
+        let key = self.key(key);
+        let mut conn = self.conn.clone();
+        let len: u64 = conn.xlen(&key).await?;
+        if len == 0 {
+            return Ok(0);
+        }
+        let groups: Vec<HashMap<String, Value>> = redis::cmd("XINFO")
+            .arg("GROUPS")
+            .arg(&key)
+            .query_async(&mut conn)
+            .await?;
+        for info in groups {
+            if info.get("name").map(String::from_redis_value).transpose()?.as_deref() != Some(group) {
+                continue;
+            }
+            let field = |name| info.get(name).map(Option::<u64>::from_redis_value).transpose();
+            return Ok(match (field("pending")?.flatten(), field("lag")?.flatten()) {
+                (Some(pending), Some(lag)) => pending + lag,
+                _ => len,
+            });
+        }
+        Ok(len)
+    }
+
+    /// `XAUTOCLAIM` entries pending longer than `min_idle`, e.g. left by a dead consumer,
+    /// scanning the pending entries from `start_id`, `"0-0"` for the first.
+    ///
+    /// Returns the id to scan from next time, `"0-0"` once the whole pending list was scanned.
+    /// Needs redis >= 7.0.
+    pub async fn stream_auto_claim(
+        &self,
+        key: &MetricsAlarmType,
+        group: &str,
+        consumer: &str,
+        min_idle: Duration,
+        start_id: &str,
+        cnt: NonZeroUsize,
+    ) -> RedisResult<(String, Vec<QueueItem>)> {
+        let mut conn = self.conn.clone();
+        let r: Value = redis::cmd("XAUTOCLAIM")
+            .arg(self.key(key))
+            .arg(group)
+            .arg(consumer)
+            .arg(min_idle.as_millis() as u64)
+            .arg(start_id)
+            .arg("COUNT")
+            .arg(cnt.get())
+            .query_async(&mut conn)
+            .await?;
+        // reply: [next_start_id, [entries], [deleted_ids]]
+        let (next_id, entries) = match &r {
+            Value::Bulk(items) if items.len() > 1 => (
+                String::from_redis_value(&items[0])?,
+                StreamRangeReply::from_redis_value(&items[1])?,
+            ),
+            _ => (String::from("0-0"), StreamRangeReply::default()),
+        };
+        Ok((next_id, entries.ids.into_iter().map(QueueItem::from).collect()))
+    }
+
+    pub async fn list_pop(&self, key: &MetricsAlarmType) -> RedisResult<String> {
+        let mut conn = self.conn.clone();
+        let r = conn.lpop(self.key(key), None).await?;
         Ok(r)
     }
 
-    pub fn list_pop_block(&mut self, key: &MetricsAlarmType) -> RedisResult<String> {
-        let r = self.conn.blpop::<String, (String, String)>(key.as_redis_key(), 0)?;
+    /// Blocking pop holds the whole multiplexed connection, use a dedicated `RedisConn` for it.
+    pub async fn list_pop_block(&self, key: &MetricsAlarmType) -> RedisResult<String> {
+        let mut conn = self.conn.clone();
+        let r = conn.blpop::<String, (String, String)>(self.key(key), 0).await?;
         Ok(r.1)
     }
 
-    pub fn list_pop_multi(&mut self, key: &MetricsAlarmType, cnt: NonZeroUsize) -> RedisResult<Vec<String>> {
-        let r = self.conn.lmpop::<String, (String, Vec<String>)>(
-            1,
-            key.as_redis_key(),
-            redis::Direction::Left,
-            cnt.get(),
-        )?;
+    pub async fn list_pop_multi(&self, key: &MetricsAlarmType, cnt: NonZeroUsize) -> RedisResult<Vec<String>> {
+        let mut conn = self.conn.clone();
+        let r = conn
+            .lmpop::<String, (String, Vec<String>)>(1, self.key(key), redis::Direction::Left, cnt.get())
+            .await?;
         Ok(r.1)
     }
 
-    pub fn list_pop_block_multi(&mut self, key: &MetricsAlarmType, cnt: NonZeroUsize) -> RedisResult<Vec<String>> {
-        let r = self.conn.blmpop::<String, (String, Vec<String>)>(
-            0,
-            1,
-            key.as_redis_key(),
-            redis::Direction::Left,
-            cnt.get(),
-        )?;
+    /// Blocking pop holds the whole multiplexed connection, use a dedicated `RedisConn` for it.
+    pub async fn list_pop_block_multi(&self, key: &MetricsAlarmType, cnt: NonZeroUsize) -> RedisResult<Vec<String>> {
+        let mut conn = self.conn.clone();
+        let r = conn
+            .blmpop::<String, (String, Vec<String>)>(0, 1, self.key(key), redis::Direction::Left, cnt.get())
+            .await?;
 
         Ok(r.1)
     }
@@ -58,42 +329,49 @@ mod test {
 
     use super::*;
 
-    #[test]
-    fn test_redis() {
-        let mut c = RedisConn::new().unwrap();
+    async fn do_test_redis() {
+        let c = RedisConn::new(&RedisConfig::default()).await.unwrap();
         c.list_push(&MetricsAlarmType::Counter, "{some metrics data}".to_string())
+            .await
             .unwrap();
-        let r = c.list_pop(&MetricsAlarmType::Counter).unwrap();
+        let r = c.list_pop(&MetricsAlarmType::Counter).await.unwrap();
         assert_eq!(r, String::from("{some metrics data}"));
 
         c.list_push(&MetricsAlarmType::Flow, "some flow metrics data".to_string())
+            .await
             .unwrap();
 
-        let r = c.list_pop_block(&MetricsAlarmType::Flow).unwrap();
+        let r = c.list_pop_block(&MetricsAlarmType::Flow).await.unwrap();
         assert_eq!(r, String::from("some flow metrics data"));
 
         c.list_push(&MetricsAlarmType::Timer, "some time metrics data".to_string())
+            .await
             .unwrap();
 
         c.list_push(&MetricsAlarmType::Timer, "some time metrics data".to_string())
+            .await
             .unwrap();
 
         let r = c
             .list_pop_multi(&MetricsAlarmType::Timer, NonZeroUsize::new(2).unwrap())
+            .await
             .unwrap();
         assert_eq!(r, vec!["some time metrics data", "some time metrics data"]);
 
-        c.list_push(&MetricsAlarmType::Timer, "some time metrics data".to_string())
-            .unwrap();
-
-        c.list_push(&MetricsAlarmType::Timer, "some time metrics data".to_string())
-            .unwrap();
-
-        c.list_push(&MetricsAlarmType::Timer, "some time metrics data".to_string())
-            .unwrap();
+        c.list_push_multi(&HashMap::from([(
+            MetricsAlarmType::Timer,
+            vec![
+                "some time metrics data".to_string(),
+                "some time metrics data".to_string(),
+                "some time metrics data".to_string(),
+            ],
+        )]))
+        .await
+        .unwrap();
 
         let r = c
             .list_pop_block_multi(&MetricsAlarmType::Timer, NonZeroUsize::new(3).unwrap())
+            .await
             .unwrap();
         assert_eq!(
             r,
@@ -104,4 +382,116 @@ mod test {
             ]
         )
     }
+
+    #[test]
+    fn test_redis() {
+        tokio_test::block_on(do_test_redis());
+    }
+
+    async fn do_test_redis_stream() {
+        let config = RedisConfig {
+            key_prefix: Some(String::from("test_stream")),
+            ..Default::default()
+        };
+        let c = RedisConn::new(&config).await.unwrap();
+        let cnt = NonZeroUsize::new(10).unwrap();
+        c.stream_create_group(&MetricsAlarmType::Counter, "test_group")
+            .await
+            .unwrap();
+        // create twice is ok
+        c.stream_create_group(&MetricsAlarmType::Counter, "test_group")
+            .await
+            .unwrap();
+
+        c.stream_add_multi(&HashMap::from([(
+            MetricsAlarmType::Counter,
+            vec!["counter data 1".to_string(), "counter data 2".to_string()],
+        )]))
+        .await
+        .unwrap();
+
+        let r = c
+            .stream_read_group(&MetricsAlarmType::Counter, "test_group", "consumer_a", ">", cnt)
+            .await
+            .unwrap();
+        assert_eq!(
+            r.iter().map(|e| e.data.as_str()).collect::<Vec<_>>(),
+            vec!["counter data 1", "counter data 2"]
+        );
+
+        // not acked: consumer_a can re-read it, consumer_b can claim it.
+        let pending = c
+            .stream_read_group(&MetricsAlarmType::Counter, "test_group", "consumer_a", "0", cnt)
+            .await
+            .unwrap();
+        assert_eq!(pending.len(), 2);
+        // one entry per call, the cursor goes on to the next one.
+        let one = NonZeroUsize::new(1).unwrap();
+        let mut claimed = Vec::new();
+        let mut cursor = String::from("0-0");
+        loop {
+            let (next, r) = c
+                .stream_auto_claim(
+                    &MetricsAlarmType::Counter,
+                    "test_group",
+                    "consumer_b",
+                    Duration::from_millis(0),
+                    &cursor,
+                    one,
+                )
+                .await
+                .unwrap();
+            claimed.extend(r);
+            if next == "0-0" {
+                break;
+            }
+            cursor = next;
+        }
+        assert_eq!(claimed.len(), 2);
+
+        let ids = claimed.into_iter().map(|e| e.id).collect::<Vec<_>>();
+        assert_eq!(
+            c.stream_backlog(&MetricsAlarmType::Counter, "test_group")
+                .await
+                .unwrap(),
+            2
+        );
+        // pending entries are kept by the trim.
+        c.stream_trim_acked(&MetricsAlarmType::Counter).await.unwrap();
+        let pending = c
+            .stream_read_group(&MetricsAlarmType::Counter, "test_group", "consumer_b", "0", cnt)
+            .await
+            .unwrap();
+        assert_eq!(pending.len(), 2);
+        c.stream_ack(&MetricsAlarmType::Counter, "test_group", &ids, false)
+            .await
+            .unwrap();
+        assert_eq!(
+            c.stream_backlog(&MetricsAlarmType::Counter, "test_group")
+                .await
+                .unwrap(),
+            0
+        );
+        // acked, all but the last delivered entry are trimmed.
+        assert!(c.stream_trim_acked(&MetricsAlarmType::Counter).await.unwrap() >= 1);
+        c.stream_trim(&MetricsAlarmType::Counter, 0).await.unwrap();
+        let (next, claimed) = c
+            .stream_auto_claim(
+                &MetricsAlarmType::Counter,
+                "test_group",
+                "consumer_b",
+                Duration::from_millis(0),
+                "0-0",
+                cnt,
+            )
+            .await
+            .unwrap();
+        assert_eq!(next, "0-0");
+        assert!(claimed.is_empty());
+    }
+
+    #[test]
+    fn test_redis_stream() {
+        tokio_test::block_on(do_test_redis_stream());
+    }
 }
@@ -0,0 +1,112 @@
+use mysql_async::prelude::{Query, Queryable};
+use std::sync::Arc;
+
+use mysql_async::{Conn, Result};
+use tokio::time::sleep;
+
+use crate::config::{DatabaseConfig, RetentionConfig};
+use crate::error::StoreError;
+use crate::mysql_conn::quote_identifier;
+use crate::partition;
+use crate::store::{metrics_tables, MetricsStore};
+
+/// rows deleted by one statement, small enough not to hold locks for long.
+const DELETE_BATCH_ROWS: u64 = 10000;
+
+/// What one retention run removed from a table.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Removed {
+    pub partitions: usize,
+    pub rows: u64,
+}
+
+/// Remove rows of `table` older than `before`: drop partitions holding only such rows,
+/// then delete the rest in batches.
+pub async fn expire_table(conn: &mut Conn, table: &str, before: i64) -> Result<Removed> {
+    let expired = partition::partitions(conn, table)
+        .await?
+        .into_iter()
+        .filter(|p| p.less_than.is_some_and(|less_than| less_than <= before))
+        .map(|p| p.name)
+        .collect::<Vec<_>>();
+    let mut removed = Removed::default();
+    if !expired.is_empty() {
+        let rows: Option<u64> = format!(
+            "SELECT COUNT(*) FROM {} PARTITION ({})",
+            quote_identifier(table),
+            expired.join(", ")
+        )
+        .first(&mut *conn)
+        .await?;
+        removed.rows = rows.unwrap_or_default();
+        removed.partitions = partition::drop_partitions_before(conn, table, before).await?.len();
+    }
+
+    let delete = format!(
+        "DELETE FROM {} WHERE send_timestamp < ? LIMIT {}",
+        quote_identifier(table),
+        DELETE_BATCH_ROWS
+    );
+    loop {
+        conn.exec_drop(&delete, (before,)).await?;
+        removed.rows += conn.affected_rows();
+        if conn.affected_rows() < DELETE_BATCH_ROWS {
+            return Ok(removed);
+        }
+    }
+}
+
+/// Apply `retention` of `env` to each metrics table of existing database `db_name`, as of `now`.
+pub async fn expire_database(
+    store: &dyn MetricsStore,
+    db_name: &str,
+    env: &str,
+    retention: &RetentionConfig,
+    now: i64,
+) -> std::result::Result<Vec<(&'static str, Removed)>, StoreError> {
+    let mut removed = Vec::new();
+    for (alarm_type, table) in metrics_tables() {
+        if let Some(days) = retention.days(env, alarm_type) {
+            removed.push((
+                table.name,
+                store.expire(db_name, &table, now - days as i64 * 86400).await?,
+            ));
+        }
+    }
+    Ok(removed)
+}
+
+/// Enforce `retention` on every env database, every `retention.interval()`, forever.
+/// Return at once if there is no retention rule.
+pub async fn run(store: Arc<dyn MetricsStore>, database: DatabaseConfig, retention: RetentionConfig) {
+    if retention.rules().is_empty() {
+        return;
+    }
+    loop {
+        let db_names = match store.databases().await {
+            Ok(db_names) => db_names,
+            Err(e) => {
+                println!("retention list databases error: {}", e);
+                Vec::new()
+            }
+        };
+        let now = chrono::Utc::now().timestamp();
+        for db_name in db_names {
+            let Some(env) = database.env_of(&db_name) else {
+                continue;
+            };
+            match expire_database(store.as_ref(), &db_name, env, &retention, now).await {
+                Ok(removed) => {
+                    for (table, removed) in removed.into_iter().filter(|(_, r)| *r != Removed::default()) {
+                        println!(
+                            "retention {} {}: dropped {} partitions, removed {} rows",
+                            db_name, table, removed.partitions, removed.rows
+                        );
+                    }
+                }
+                Err(e) => println!("retention {} error: {}", db_name, e),
+            }
+        }
+        sleep(retention.interval()).await;
+    }
+}
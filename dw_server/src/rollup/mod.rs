@@ -0,0 +1,105 @@
+use std::collections::BTreeSet;
+
+use mysql_async::prelude::{Query, Queryable};
+use mysql_async::{Conn, Result};
+
+use metrics_types::sql::{rollup_statement, Resolution, RollupTable, SqlTable};
+use metrics_types::{CounterUnit, FlowUnit, TimerUnit};
+
+use crate::mysql_conn::{quote_identifier, MysqlPool};
+
+/// `[start, end)` ranges of `resolution` buckets containing `timestamps`, adjacent buckets merged.
+fn bucket_ranges(timestamps: &BTreeSet<i64>, resolution: Resolution) -> Vec<(i64, i64)> {
+    let mut ranges: Vec<(i64, i64)> = Vec::new();
+    for bucket in timestamps.iter().map(|ts| resolution.bucket(*ts)) {
+        match ranges.last_mut() {
+            Some((_, end)) if *end >= bucket => *end = (*end).max(bucket + resolution.secs()),
+            _ => ranges.push((bucket, bucket + resolution.secs())),
+        }
+    }
+    ranges
+}
+
+/// Recompute `T`'s rollup buckets containing `timestamps`, finest first as each is computed from the previous one.
+pub async fn rollup<T: RollupTable>(conn: &mut Conn, timestamps: &BTreeSet<i64>) -> Result<()> {
+    for resolution in Resolution::ALL {
+        let statement = rollup_statement::<T>(resolution);
+        for (start, end) in bucket_ranges(timestamps, resolution) {
+            conn.exec_drop(&statement, (start, end)).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Recompute `T`'s rollups of raw rows in `[since, until)` day by day, `since` defaults to the oldest row.
+/// Return days recomputed.
+///
+/// Buckets are replaced by what the raw rows left give, so don't go back past the raw retention.
+pub async fn backfill<T: RollupTable>(conn: &mut Conn, since: Option<i64>, until: i64) -> Result<usize> {
+    let since = match since {
+        Some(since) => Some(since),
+        None => format!("SELECT MIN(send_timestamp) FROM {}", quote_identifier(T::table_name()))
+            .first::<Option<i64>, _>(&mut *conn)
+            .await?
+            .flatten(),
+    };
+    let Some(since) = since else {
+        return Ok(0);
+    };
+    let mut days = 0;
+    let mut day = Resolution::Day.bucket(since);
+    while day < until {
+        // every bucket of the day, finest first.
+        for resolution in Resolution::ALL {
+            conn.exec_drop(rollup_statement::<T>(resolution), (day, day + Resolution::Day.secs()))
+                .await?;
+        }
+        days += 1;
+        day += Resolution::Day.secs();
+    }
+    Ok(days)
+}
+
+/// `backfill` all metrics tables of existing database `db_name`.
+pub async fn backfill_database(
+    pool: &MysqlPool,
+    db_name: &str,
+    since: Option<i64>,
+    until: i64,
+) -> Result<Vec<(&'static str, usize)>> {
+    let mut conn = pool.get_conn(db_name).await?;
+    Ok(vec![
+        (
+            CounterUnit::table_name(),
+            backfill::<CounterUnit>(&mut conn, since, until).await?,
+        ),
+        (
+            TimerUnit::table_name(),
+            backfill::<TimerUnit>(&mut conn, since, until).await?,
+        ),
+        (
+            FlowUnit::table_name(),
+            backfill::<FlowUnit>(&mut conn, since, until).await?,
+        ),
+    ])
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_bucket_ranges() {
+        let timestamps = BTreeSet::from([59, 60, 61, 130, 3600 * 5 + 1]);
+        assert_eq!(
+            bucket_ranges(&timestamps, Resolution::Minute),
+            vec![(0, 180), (18000, 18060)]
+        );
+        assert_eq!(
+            bucket_ranges(&timestamps, Resolution::Hour),
+            vec![(0, 3600), (18000, 21600)]
+        );
+        assert_eq!(bucket_ranges(&timestamps, Resolution::Day), vec![(0, 86400)]);
+        assert!(bucket_ranges(&BTreeSet::new(), Resolution::Day).is_empty());
+    }
+}
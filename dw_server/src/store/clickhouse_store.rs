@@ -0,0 +1,524 @@
+use std::collections::HashSet;
+
+use async_trait::async_trait;
+use chrono::{Months, NaiveDate};
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, Request, StatusCode};
+use tokio::sync::Mutex;
+
+use metrics_types::sql::{SqlType, SqlValue, TableSchema};
+
+use super::{metrics_tables, MetricsStore};
+use crate::error::StoreError;
+use crate::retention::Removed;
+
+/// clickhouse errors worth retrying: timeout, too many queries, socket timeout, network error,
+/// memory limit exceeded, too many parts.
+const TRANSIENT_ERROR_CODES: [u32; 6] = [159, 202, 209, 210, 241, 252];
+const EXCEPTION_CODE_HEADER: &str = "X-ClickHouse-Exception-Code";
+
+/// Quote `name` as a clickhouse identifier.
+fn quote_identifier(name: &str) -> String {
+    format!("`{}`", name.replace('\\', "\\\\").replace('`', "\\`"))
+}
+
+fn qualified(db_name: &str, table: &str) -> String {
+    format!("{}.{}", quote_identifier(db_name), quote_identifier(table))
+}
+
+fn sql_type(sql_type: SqlType) -> &'static str {
+    match sql_type {
+        SqlType::Int => "Int32",
+        SqlType::BigInt => "Int64",
+        SqlType::Double => "Float64",
+        SqlType::Varchar(_) => "String",
+    }
+}
+
+/// MergeTree `table` of `db_name`, sorted by the same key as the mysql index, partitioned by month.
+fn create_table_statement(db_name: &str, table: &TableSchema) -> String {
+    let columns = table
+        .columns
+        .iter()
+        .zip(table.types)
+        .map(|(name, t)| format!("{} {}", name, sql_type(*t)))
+        .collect::<Vec<_>>();
+    format!(
+        "CREATE TABLE IF NOT EXISTS {} ( {} ) ENGINE = MergeTree \
+         PARTITION BY toYYYYMM(toDateTime(send_timestamp)) \
+         ORDER BY (category, tag, public_ip, send_timestamp)",
+        qualified(db_name, table.name),
+        columns.join(", ")
+    )
+}
+
+/// `rows` of `table` as JSONEachRow lines.
+fn json_each_row(table: &TableSchema, rows: &[Vec<SqlValue>]) -> String {
+    rows.iter()
+        .map(|row| {
+            let object = table
+                .columns
+                .iter()
+                .zip(row)
+                .map(|(name, value)| {
+                    let value = match value {
+                        SqlValue::Int(v) => serde_json::Value::from(*v),
+                        SqlValue::UInt(v) => serde_json::Value::from(*v),
+                        SqlValue::Double(v) => serde_json::Value::from(*v),
+                        SqlValue::Text(v) => serde_json::Value::from(v.as_str()),
+                    };
+                    (name.to_string(), value)
+                })
+                .collect::<serde_json::Map<_, _>>();
+            serde_json::Value::Object(object).to_string()
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Rows of a JSONCompactEachRow response, values in `types` order.
+fn from_json_compact_each_row(body: &str, types: &[SqlType]) -> Result<Vec<Vec<SqlValue>>, StoreError> {
+    body.lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| {
+            let values = serde_json::from_str::<Vec<serde_json::Value>>(line)
+                .map_err(|e| StoreError::Rejected(format!("unexpected clickhouse row {}: {}", line, e)))?;
+            values
+                .iter()
+                .zip(types)
+                .map(|(value, sql_type)| {
+                    match sql_type {
+                        SqlType::Int | SqlType::BigInt => value.as_i64().map(SqlValue::Int),
+                        SqlType::Double => value.as_f64().map(SqlValue::Double),
+                        SqlType::Varchar(_) => value.as_str().map(|v| SqlValue::Text(v.to_string())),
+                    }
+                    .ok_or_else(|| StoreError::Rejected(format!("unexpected clickhouse value {}", value)))
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// End of the month of a `toYYYYMM` partition id.
+fn month_partition_end(partition: &str) -> Option<i64> {
+    let start = NaiveDate::parse_from_str(&format!("{}01", partition), "%Y%m%d").ok()?;
+    let end = start.checked_add_months(Months::new(1))?;
+    Some(end.and_hms_opt(0, 0, 0)?.and_utc().timestamp())
+}
+
+/// #### ClickhouseStore
+///
+/// Each env database is a clickhouse database, used through the http interface at `url`.
+/// Rows are inserted as JSONEachRow, metrics tables are MergeTree partitioned by month.
+pub struct ClickhouseStore {
+    url: String,
+    user: Option<String>,
+    password: Option<String>,
+    client: Client<HttpConnector>,
+    /// databases known up to date, not checked again.
+    opened: Mutex<HashSet<String>>,
+}
+
+impl ClickhouseStore {
+    pub fn new(url: &str, user: Option<String>, password: Option<String>) -> Self {
+        ClickhouseStore {
+            url: url.trim_end_matches('/').to_string(),
+            user,
+            password,
+            client: Client::new(),
+            opened: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// Run `query` with url `params`, `body` being the data of an INSERT. Return the response body.
+    ///
+    /// `{name:Type}` placeholders of `query` are bound by `param_<name>` params, never formatted into it.
+    async fn execute(&self, query: &str, params: &[(&str, String)], body: String) -> Result<String, StoreError> {
+        let url = {
+            let mut url = form_urlencoded::Serializer::new(format!("{}/?", self.url));
+            url.append_pair("query", query);
+            url.append_pair("output_format_json_quote_64bit_integers", "0");
+            for (name, value) in params {
+                url.append_pair(name, value);
+            }
+            url.finish()
+        };
+        let mut request = Request::post(url);
+        if let Some(user) = &self.user {
+            request = request.header("X-ClickHouse-User", user);
+        }
+        if let Some(password) = &self.password {
+            request = request.header("X-ClickHouse-Key", password);
+        }
+        let request = request
+            .body(Body::from(body))
+            .map_err(|e| StoreError::Rejected(e.to_string()))?;
+
+        let response = self
+            .client
+            .request(request)
+            .await
+            .map_err(|e| StoreError::Transient(e.to_string()))?;
+        let status = response.status();
+        let code = response
+            .headers()
+            .get(EXCEPTION_CODE_HEADER)
+            .and_then(|code| code.to_str().ok())
+            .and_then(|code| code.parse::<u32>().ok());
+        let body = hyper::body::to_bytes(response.into_body())
+            .await
+            .map_err(|e| StoreError::Transient(e.to_string()))?;
+        let body = String::from_utf8_lossy(&body).into_owned();
+        if status.is_success() && code.is_none() {
+            return Ok(body);
+        }
+        let error = format!("clickhouse {}: {}", status, body.trim());
+        Err(match code {
+            Some(code) if TRANSIENT_ERROR_CODES.contains(&code) => StoreError::Transient(error),
+            None if matches!(
+                status,
+                StatusCode::BAD_GATEWAY | StatusCode::SERVICE_UNAVAILABLE | StatusCode::GATEWAY_TIMEOUT
+            ) =>
+            {
+                StoreError::Transient(error)
+            }
+            _ => StoreError::Rejected(error),
+        })
+    }
+
+    /// First column of each row of `query`.
+    async fn select_column(
+        &self,
+        query: &str,
+        params: &[(&str, String)],
+    ) -> Result<Vec<serde_json::Value>, StoreError> {
+        let body = self
+            .execute(&format!("{} FORMAT JSONCompactEachRow", query), params, String::new())
+            .await?;
+        body.lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| {
+                serde_json::from_str::<Vec<serde_json::Value>>(line)
+                    .ok()
+                    .and_then(|row| row.into_iter().next())
+                    .ok_or_else(|| StoreError::Rejected(format!("unexpected clickhouse row {}", line)))
+            })
+            .collect()
+    }
+
+    async fn count_before(&self, db_name: &str, table: &str, before: i64) -> Result<u64, StoreError> {
+        let count = self
+            .select_column(
+                &format!(
+                    "SELECT count() FROM {} WHERE send_timestamp < {{before:Int64}}",
+                    qualified(db_name, table)
+                ),
+                &[("param_before", before.to_string())],
+            )
+            .await?;
+        Ok(count.first().and_then(|count| count.as_u64()).unwrap_or_default())
+    }
+}
+
+#[async_trait]
+impl MetricsStore for ClickhouseStore {
+    async fn ensure_schema(&self, db_name: &str, auto_create: bool, _auto_migrate: bool) -> Result<bool, StoreError> {
+        let mut opened = self.opened.lock().await;
+        if opened.contains(db_name) {
+            return Ok(true);
+        }
+        let exist = !self
+            .select_column(
+                "SELECT name FROM system.databases WHERE name = {db:String}",
+                &[("param_db", db_name.to_string())],
+            )
+            .await?
+            .is_empty();
+        if !exist && !auto_create {
+            println!("DATABASE {} not exist and not allowed to create", db_name);
+            return Ok(false);
+        }
+        self.execute(
+            &format!("CREATE DATABASE IF NOT EXISTS {}", quote_identifier(db_name)),
+            &[],
+            String::new(),
+        )
+        .await?;
+        for (_, table) in metrics_tables() {
+            self.execute(&create_table_statement(db_name, &table), &[], String::new())
+                .await?;
+        }
+        if !exist {
+            println!("CREATE DATABASE {}", db_name);
+        }
+        opened.insert(db_name.to_string());
+        Ok(true)
+    }
+
+    async fn insert_batch(&self, db_name: &str, table: &TableSchema, rows: &[Vec<SqlValue>]) -> Result<(), StoreError> {
+        if rows.is_empty() {
+            return Ok(());
+        }
+        // one insert of less than a million rows is one block, written all or none.
+        self.execute(
+            &format!(
+                "INSERT INTO {} ( {} ) FORMAT JSONEachRow",
+                qualified(db_name, table.name),
+                table.columns.join(", ")
+            ),
+            &[],
+            json_each_row(table, rows),
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn query(
+        &self,
+        db_name: &str,
+        table: &TableSchema,
+        since: i64,
+        until: i64,
+    ) -> Result<Vec<Vec<SqlValue>>, StoreError> {
+        let body = self
+            .execute(
+                &format!(
+                    "SELECT {} FROM {} WHERE send_timestamp >= {{since:Int64}} AND send_timestamp < {{until:Int64}} \
+                     ORDER BY send_timestamp FORMAT JSONCompactEachRow",
+                    table.columns.join(", "),
+                    qualified(db_name, table.name)
+                ),
+                &[("param_since", since.to_string()), ("param_until", until.to_string())],
+                String::new(),
+            )
+            .await?;
+        from_json_compact_each_row(&body, table.types)
+    }
+
+    async fn databases(&self) -> Result<Vec<String>, StoreError> {
+        let databases = self
+            .select_column(
+                "SELECT DISTINCT database FROM system.tables WHERE name = {table:String}",
+                &[("param_table", metrics_tables()[0].1.name.to_string())],
+            )
+            .await?;
+        Ok(databases
+            .into_iter()
+            .filter_map(|db| db.as_str().map(str::to_string))
+            .collect())
+    }
+
+    async fn expire(&self, db_name: &str, table: &TableSchema, before: i64) -> Result<Removed, StoreError> {
+        let mut removed = Removed {
+            partitions: 0,
+            rows: self.count_before(db_name, table.name, before).await?,
+        };
+        if removed.rows == 0 {
+            return Ok(removed);
+        }
+        let partitions = self
+            .select_column(
+                "SELECT DISTINCT partition FROM system.parts WHERE database = {db:String} AND table = {table:String} AND active",
+                &[
+                    ("param_db", db_name.to_string()),
+                    ("param_table", table.name.to_string()),
+                ],
+            )
+            .await?;
+        for partition in partitions {
+            let Some(partition) = partition.as_str() else {
+                continue;
+            };
+            // months holding only expired rows.
+            if month_partition_end(partition).is_some_and(|end| end <= before) {
+                self.execute(
+                    &format!(
+                        "ALTER TABLE {} DROP PARTITION ID {{partition:String}}",
+                        qualified(db_name, table.name)
+                    ),
+                    &[("param_partition", partition.to_string())],
+                    String::new(),
+                )
+                .await?;
+                removed.partitions += 1;
+            }
+        }
+        if self.count_before(db_name, table.name, before).await? > 0 {
+            self.execute(
+                &format!(
+                    "ALTER TABLE {} DELETE WHERE send_timestamp < {{before:Int64}}",
+                    qualified(db_name, table.name)
+                ),
+                &[
+                    ("param_before", before.to_string()),
+                    ("mutations_sync", "1".to_string()),
+                ],
+                String::new(),
+            )
+            .await?;
+        }
+        Ok(removed)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+    use std::sync::{Arc, Mutex as StdMutex};
+
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Response, Server};
+    use metrics_types::{CounterUnit, FlowUnit, TimerUnit};
+
+    use super::*;
+
+    type Received = Arc<StdMutex<Vec<(String, String)>>>;
+
+    /// Answer `query` as clickhouse would: status, exception code and body.
+    fn respond(query: &str) -> (StatusCode, Option<u32>, &'static str) {
+        if query.contains("system.databases") {
+            (StatusCode::OK, None, "")
+        } else if query.starts_with("SELECT send_timestamp") {
+            (StatusCode::OK, None, "[100,\"127.0.0.1\",\"category\",\"tag\",1,-1]\n")
+        } else if query.starts_with("INSERT INTO `dw_test`.`metrics_flow`") {
+            (StatusCode::INTERNAL_SERVER_ERROR, Some(252), "Too many parts")
+        } else if query.starts_with("INSERT INTO `dw_test`.`metrics_timer`") {
+            (StatusCode::BAD_REQUEST, Some(27), "Cannot parse input")
+        } else {
+            (StatusCode::OK, None, "")
+        }
+    }
+
+    /// A local http endpoint answering with `respond`, recording each query and body received.
+    async fn mock_clickhouse() -> (String, Received) {
+        let received: Received = Arc::default();
+        let recorder = received.clone();
+        let make_service = make_service_fn(move |_| {
+            let recorder = recorder.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req: hyper::Request<Body>| {
+                    let recorder = recorder.clone();
+                    async move {
+                        let query = form_urlencoded::parse(req.uri().query().unwrap_or_default().as_bytes())
+                            .find(|(name, _)| name == "query")
+                            .map(|(_, query)| query.into_owned())
+                            .unwrap_or_default();
+                        let body = hyper::body::to_bytes(req.into_body()).await.unwrap_or_default();
+                        recorder
+                            .lock()
+                            .unwrap()
+                            .push((query.clone(), String::from_utf8_lossy(&body).into_owned()));
+                        let (status, code, body) = respond(&query);
+                        let mut response = Response::builder().status(status);
+                        if let Some(code) = code {
+                            response = response.header(EXCEPTION_CODE_HEADER, code);
+                        }
+                        Ok::<_, Infallible>(response.body(Body::from(body)).unwrap())
+                    }
+                }))
+            }
+        });
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+        let url = format!("http://{}", server.local_addr());
+        tokio::spawn(server);
+        (url, received)
+    }
+
+    fn counter_row(tag: &str) -> Vec<SqlValue> {
+        vec![
+            SqlValue::UInt(100),
+            SqlValue::Text("127.0.0.1".into()),
+            SqlValue::Text("category".into()),
+            SqlValue::Text(tag.into()),
+            SqlValue::UInt(1),
+            SqlValue::Int(-1),
+        ]
+    }
+
+    #[test]
+    fn test_clickhouse_statements() {
+        assert_eq!(
+            create_table_statement("dw_test", &TableSchema::of::<CounterUnit>()),
+            "CREATE TABLE IF NOT EXISTS `dw_test`.`metrics_counter` ( send_timestamp Int32, public_ip String, \
+             category String, tag String, count Int64, value Int64 ) ENGINE = MergeTree \
+             PARTITION BY toYYYYMM(toDateTime(send_timestamp)) ORDER BY (category, tag, public_ip, send_timestamp)"
+        );
+        assert_eq!(quote_identifier("a`b"), "`a\\`b`");
+        assert_eq!(month_partition_end("202312"), Some(1704067200));
+        assert_eq!(month_partition_end("tuple()"), None);
+    }
+
+    #[test]
+    fn test_clickhouse_store() {
+        tokio_test::block_on(async {
+            let (url, received) = mock_clickhouse().await;
+            let store = ClickhouseStore::new(&url, Some("dw".into()), None);
+
+            assert!(!store.ensure_schema("dw_test", false, true).await.unwrap());
+            assert!(store.ensure_schema("dw_test", true, true).await.unwrap());
+            {
+                let received = received.lock().unwrap();
+                assert!(received
+                    .iter()
+                    .any(|(q, _)| q == "CREATE DATABASE IF NOT EXISTS `dw_test`"));
+                let created = received
+                    .iter()
+                    .filter(|(q, _)| q.contains("ENGINE = MergeTree"))
+                    .count();
+                assert_eq!(created, 3);
+            }
+            // checked once.
+            assert!(store.ensure_schema("dw_test", false, true).await.unwrap());
+
+            let hostile_tag = r#"a"b'); DROP TABLE metrics_counter; -- "#;
+            let counter = TableSchema::of::<CounterUnit>();
+            store
+                .insert_batch("dw_test", &counter, &[counter_row("a"), counter_row(hostile_tag)])
+                .await
+                .unwrap();
+            let (query, body) = received.lock().unwrap().last().cloned().unwrap();
+            assert_eq!(
+                query,
+                "INSERT INTO `dw_test`.`metrics_counter` ( send_timestamp, public_ip, category, tag, count, value ) \
+                 FORMAT JSONEachRow"
+            );
+            let lines = body.lines().collect::<Vec<_>>();
+            assert_eq!(lines.len(), 2);
+            let row = serde_json::from_str::<serde_json::Value>(lines[1]).unwrap();
+            assert_eq!(row["tag"], hostile_tag);
+            assert_eq!(row["value"], -1);
+
+            let rows = store.query("dw_test", &counter, 0, 200).await.unwrap();
+            assert_eq!(
+                rows,
+                vec![counter_row("tag")
+                    .into_iter()
+                    .map(|v| match v {
+                        SqlValue::UInt(v) => SqlValue::Int(v as i64),
+                        v => v,
+                    })
+                    .collect::<Vec<_>>()]
+            );
+
+            let err = store
+                .insert_batch("dw_test", &TableSchema::of::<FlowUnit>(), &[vec![]])
+                .await
+                .unwrap_err();
+            assert!(err.is_transient());
+            let err = store
+                .insert_batch("dw_test", &TableSchema::of::<TimerUnit>(), &[vec![]])
+                .await
+                .unwrap_err();
+            assert!(!err.is_transient());
+
+            // nothing listening.
+            let store = ClickhouseStore::new("http://127.0.0.1:1", None, None);
+            assert!(store
+                .ensure_schema("dw_test", true, true)
+                .await
+                .unwrap_err()
+                .is_transient());
+        });
+    }
+}
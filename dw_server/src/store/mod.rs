@@ -0,0 +1,172 @@
+mod clickhouse_store;
+mod mysql_store;
+mod postgres_store;
+mod sqlite_store;
+
+use std::collections::BTreeSet;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use metrics_types::sql::{SqlType, SqlValue, TableSchema};
+use metrics_types::{CounterUnit, FlowUnit, MetricsAlarmType, TimerUnit};
+
+use crate::config::{MysqlPoolConfig, SplitMode, StoreBackend, StoreConfig};
+use crate::error::{ServerError, StoreError};
+use crate::mysql_conn::MysqlPool;
+use crate::retention::Removed;
+
+pub use clickhouse_store::ClickhouseStore;
+pub use mysql_store::MysqlStore;
+pub use postgres_store::PostgresStore;
+pub use sqlite_store::SqliteStore;
+
+/// Metrics tables of every env database, with the alarm type each one stores.
+pub fn metrics_tables() -> [(MetricsAlarmType, TableSchema); 3] {
+    [
+        (MetricsAlarmType::Counter, TableSchema::of::<CounterUnit>()),
+        (MetricsAlarmType::Timer, TableSchema::of::<TimerUnit>()),
+        (MetricsAlarmType::Flow, TableSchema::of::<FlowUnit>()),
+    ]
+}
+
+/// #### MetricsStore
+///
+/// Where the consumer keeps metrics rows, one database per env, named by `DatabaseConfig`:
+/// mysql, postgres (optionally timescaledb), sqlite or clickhouse.
+///
+/// Versioned migrations, partitions and rollups are mysql only, other stores create the
+/// tables as they are now and skip the rest.
+#[async_trait]
+pub trait MetricsStore: Send + Sync {
+    /// Create env database `db_name` if not exist and `auto_create`, then bring its metrics tables up to date.
+    /// Pending mysql migrations are applied if `auto_migrate` or the database is new.
+    ///
+    /// Return `false` if the database not exist and not `auto_create`.
+    async fn ensure_schema(&self, db_name: &str, auto_create: bool, auto_migrate: bool) -> Result<bool, StoreError>;
+
+    /// Insert `rows` into `table` of `db_name`, values in `table.columns` order.
+    ///
+    /// All or none: on error no row is left inserted, so the consumer may retry or bisect the same rows.
+    async fn insert_batch(&self, db_name: &str, table: &TableSchema, rows: &[Vec<SqlValue>]) -> Result<(), StoreError>;
+
+    /// Rows of `table` of `db_name` sent in `[since, until)`, oldest first, values in `table.columns` order.
+    async fn query(
+        &self,
+        db_name: &str,
+        table: &TableSchema,
+        since: i64,
+        until: i64,
+    ) -> Result<Vec<Vec<SqlValue>>, StoreError>;
+
+    /// Env databases having metrics tables.
+    async fn databases(&self) -> Result<Vec<String>, StoreError>;
+
+    /// Remove rows of `table` of `db_name` sent before `before`.
+    async fn expire(&self, db_name: &str, table: &TableSchema, before: i64) -> Result<Removed, StoreError>;
+
+    /// Keep `table` of `db_name` split in `split` periods up to `ahead` periods after `now`.
+    /// Return names of the partitions created.
+    async fn ensure_partitions(
+        &self,
+        _db_name: &str,
+        _table: &TableSchema,
+        _split: SplitMode,
+        _ahead: u32,
+        _now: i64,
+    ) -> Result<Vec<String>, StoreError> {
+        Ok(Vec::new())
+    }
+
+    /// Recompute rollup buckets of `table` of `db_name` containing `timestamps`.
+    async fn rollup(
+        &self,
+        _db_name: &str,
+        _table: &TableSchema,
+        _timestamps: &BTreeSet<i64>,
+    ) -> Result<(), StoreError> {
+        Ok(())
+    }
+}
+
+/// Open the metrics store selected by `config`, `mysql_url` and `mysql_pool` are only used by mysql.
+pub async fn open_store(
+    config: &StoreConfig,
+    mysql_url: &str,
+    mysql_pool: &MysqlPoolConfig,
+) -> Result<Arc<dyn MetricsStore>, ServerError> {
+    Ok(match config.backend() {
+        StoreBackend::Mysql => Arc::new(MysqlStore::new(
+            MysqlPool::new(mysql_url, mysql_pool).map_err(|e| ServerError::StoreError(e.to_string()))?,
+        )),
+        StoreBackend::Postgres => Arc::new(PostgresStore::new(&config.postgres_url(), config.timescale()).await?),
+        StoreBackend::Sqlite => Arc::new(SqliteStore::new(config.sqlite_path()).await?),
+        StoreBackend::Clickhouse => Arc::new(ClickhouseStore::new(
+            config.clickhouse_url(),
+            config.clickhouse_user.clone(),
+            config.clickhouse_password.clone(),
+        )),
+    })
+}
+
+/// Quote `name` as an ansi sql identifier, as postgres and sqlite take it.
+fn quote_identifier(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+/// `CREATE TABLE IF NOT EXISTS` of `table` named `qualified`, with each column type given by `sql_type`,
+/// and its indexes, the same as the mysql ones. Index names are per schema or file.
+fn create_table_statements(qualified: &str, table: &TableSchema, sql_type: fn(SqlType) -> String) -> Vec<String> {
+    let columns = table
+        .columns
+        .iter()
+        .zip(table.types)
+        .map(|(name, t)| {
+            let default = match t {
+                SqlType::Varchar(_) => "''",
+                _ => "0",
+            };
+            format!("{} {} NOT NULL DEFAULT {}", name, sql_type(*t), default)
+        })
+        .collect::<Vec<_>>();
+    vec![
+        format!("CREATE TABLE IF NOT EXISTS {} ( {} )", qualified, columns.join(", ")),
+        format!(
+            "CREATE INDEX IF NOT EXISTS {} ON {} (category, tag, public_ip, send_timestamp)",
+            quote_identifier(&format!("{}_key", table.name)),
+            qualified
+        ),
+        format!(
+            "CREATE INDEX IF NOT EXISTS {} ON {} (send_timestamp)",
+            quote_identifier(&format!("{}_send_timestamp", table.name)),
+            qualified
+        ),
+    ]
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_create_table_statements() {
+        let statements = create_table_statements(
+            "\"dw_test\".\"metrics_counter\"",
+            &TableSchema::of::<CounterUnit>(),
+            |t| match t {
+                SqlType::Varchar(n) => format!("VARCHAR({})", n),
+                _ => "BIGINT".to_string(),
+            },
+        );
+        assert_eq!(
+            statements[0],
+            "CREATE TABLE IF NOT EXISTS \"dw_test\".\"metrics_counter\" ( send_timestamp BIGINT NOT NULL DEFAULT 0, \
+             public_ip VARCHAR(40) NOT NULL DEFAULT '', category VARCHAR(30) NOT NULL DEFAULT '', \
+             tag VARCHAR(100) NOT NULL DEFAULT '', count BIGINT NOT NULL DEFAULT 0, value BIGINT NOT NULL DEFAULT 0 )"
+        );
+        assert_eq!(
+            statements[2],
+            "CREATE INDEX IF NOT EXISTS \"metrics_counter_send_timestamp\" ON \"dw_test\".\"metrics_counter\" (send_timestamp)"
+        );
+        assert_eq!(quote_identifier("a\"b"), "\"a\"\"b\"");
+    }
+}
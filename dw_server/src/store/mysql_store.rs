@@ -0,0 +1,286 @@
+use std::collections::BTreeSet;
+
+use async_trait::async_trait;
+use mysql_async::prelude::Queryable;
+use mysql_async::{from_value_opt, Params, Row, TxOpts, Value};
+
+use metrics_types::sql::{multi_insert_statement, SqlTable, SqlType, SqlValue, TableSchema};
+use metrics_types::{CounterUnit, FlowUnit, TimerUnit};
+
+use super::MetricsStore;
+use crate::config::SplitMode;
+use crate::error::StoreError;
+use crate::migration;
+use crate::mysql_conn::{is_transient_error, quote_identifier, MysqlPool};
+use crate::partition;
+use crate::retention::{self, Removed};
+use crate::rollup;
+
+/// mysql limits a prepared statement to 65535 placeholders.
+const MAX_PREPARED_PLACEHOLDERS: usize = 65535;
+
+impl From<mysql_async::Error> for StoreError {
+    fn from(value: mysql_async::Error) -> Self {
+        match is_transient_error(&value) {
+            true => StoreError::Transient(value.to_string()),
+            false => StoreError::Rejected(value.to_string()),
+        }
+    }
+}
+
+fn to_mysql_value(value: &SqlValue) -> Value {
+    match value {
+        SqlValue::Int(v) => Value::Int(*v),
+        SqlValue::UInt(v) => Value::UInt(*v),
+        SqlValue::Double(v) => Value::Double(*v),
+        SqlValue::Text(v) => Value::Bytes(v.clone().into_bytes()),
+    }
+}
+
+/// `value` of a `sql_type` column, NULL read as the column default.
+fn from_mysql_value(value: Value, sql_type: SqlType) -> Result<SqlValue, StoreError> {
+    let rejected = |e: mysql_async::FromValueError| StoreError::Rejected(format!("unexpected value {:?}", e.0));
+    Ok(match sql_type {
+        SqlType::Int | SqlType::BigInt => SqlValue::Int(
+            from_value_opt::<Option<i64>>(value)
+                .map_err(rejected)?
+                .unwrap_or_default(),
+        ),
+        SqlType::Double => SqlValue::Double(
+            from_value_opt::<Option<f64>>(value)
+                .map_err(rejected)?
+                .unwrap_or_default(),
+        ),
+        SqlType::Varchar(_) => SqlValue::Text(
+            from_value_opt::<Option<String>>(value)
+                .map_err(rejected)?
+                .unwrap_or_default(),
+        ),
+    })
+}
+
+/// #### MysqlStore
+///
+/// Each env database is a mysql database, all of them used through one `MysqlPool`.
+pub struct MysqlStore {
+    pool: MysqlPool,
+}
+
+impl MysqlStore {
+    pub fn new(pool: MysqlPool) -> Self {
+        MysqlStore { pool }
+    }
+}
+
+#[async_trait]
+impl MetricsStore for MysqlStore {
+    async fn ensure_schema(&self, db_name: &str, auto_create: bool, auto_migrate: bool) -> Result<bool, StoreError> {
+        Ok(self.pool.open(db_name, auto_create, auto_migrate).await?)
+    }
+
+    async fn insert_batch(&self, db_name: &str, table: &TableSchema, rows: &[Vec<SqlValue>]) -> Result<(), StoreError> {
+        if rows.is_empty() {
+            return Ok(());
+        }
+        let mut conn = self.pool.get_conn(db_name).await?;
+
+        // all or none, the chunks of a failed batch must not be left behind to be inserted again.
+        let mut tx = conn.start_transaction(TxOpts::default()).await?;
+        let rows_per_stmt = (MAX_PREPARED_PLACEHOLDERS / table.columns.len()).max(1);
+        for chunk in rows.chunks(rows_per_stmt) {
+            let params = chunk.iter().flatten().map(to_mysql_value).collect::<Vec<_>>();
+            tx.exec_drop(multi_insert_statement(table, chunk.len()), Params::Positional(params))
+                .await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn query(
+        &self,
+        db_name: &str,
+        table: &TableSchema,
+        since: i64,
+        until: i64,
+    ) -> Result<Vec<Vec<SqlValue>>, StoreError> {
+        let mut conn = self.pool.get_conn(db_name).await?;
+        let rows: Vec<Row> = conn
+            .exec(
+                format!(
+                    "SELECT {} FROM {} WHERE send_timestamp >= ? AND send_timestamp < ? ORDER BY send_timestamp",
+                    table.columns.join(", "),
+                    quote_identifier(table.name)
+                ),
+                (since, until),
+            )
+            .await?;
+        rows.into_iter()
+            .map(|row| {
+                row.unwrap()
+                    .into_iter()
+                    .zip(table.types)
+                    .map(|(value, sql_type)| from_mysql_value(value, *sql_type))
+                    .collect()
+            })
+            .collect()
+    }
+
+    async fn databases(&self) -> Result<Vec<String>, StoreError> {
+        Ok(migration::metrics_databases(&self.pool).await?)
+    }
+
+    async fn expire(&self, db_name: &str, table: &TableSchema, before: i64) -> Result<Removed, StoreError> {
+        let mut conn = self.pool.get_conn(db_name).await?;
+        Ok(retention::expire_table(&mut conn, table.name, before).await?)
+    }
+
+    async fn ensure_partitions(
+        &self,
+        db_name: &str,
+        table: &TableSchema,
+        split: SplitMode,
+        ahead: u32,
+        now: i64,
+    ) -> Result<Vec<String>, StoreError> {
+        let mut conn = self.pool.get_conn(db_name).await?;
+        let created = partition::ensure_partitions(&mut conn, table.name, split, ahead, now).await?;
+        Ok(created.into_iter().map(|p| p.name).collect())
+    }
+
+    async fn rollup(&self, db_name: &str, table: &TableSchema, timestamps: &BTreeSet<i64>) -> Result<(), StoreError> {
+        let mut conn = self.pool.get_conn(db_name).await?;
+        match table.name {
+            name if name == CounterUnit::table_name() => rollup::rollup::<CounterUnit>(&mut conn, timestamps).await?,
+            name if name == TimerUnit::table_name() => rollup::rollup::<TimerUnit>(&mut conn, timestamps).await?,
+            name if name == FlowUnit::table_name() => rollup::rollup::<FlowUnit>(&mut conn, timestamps).await?,
+            name => return Err(StoreError::Rejected(format!("{} has no rollup tables", name))),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::MysqlPoolConfig;
+
+    /// Store on the mysql of `DW_TEST_MYSQL_URL`, e.g. `user:password@localhost:3306`, with a fresh
+    /// `db_name`. `None` if not set, the test is skipped then.
+    async fn test_store(db_name: &str) -> Option<MysqlStore> {
+        let Ok(mysql_url) = std::env::var("DW_TEST_MYSQL_URL") else {
+            println!("DW_TEST_MYSQL_URL not set, skipped");
+            return None;
+        };
+        let pool = MysqlPool::new(&mysql_url, &MysqlPoolConfig::default()).unwrap();
+        let mut conn = pool.get_conn("information_schema").await.unwrap();
+        conn.query_drop(format!("DROP DATABASE IF EXISTS {}", quote_identifier(db_name)))
+            .await
+            .unwrap();
+        drop(conn);
+        let store = MysqlStore::new(pool);
+        assert!(store.ensure_schema(db_name, true, true).await.unwrap());
+        Some(store)
+    }
+
+    fn counter_row(send_timestamp: u64, tag: &str) -> Vec<SqlValue> {
+        vec![
+            SqlValue::UInt(send_timestamp),
+            SqlValue::Text("127.0.0.1".into()),
+            SqlValue::Text("category".into()),
+            SqlValue::Text(tag.into()),
+            SqlValue::UInt(1),
+            SqlValue::Int(-1),
+        ]
+    }
+
+    async fn do_test_mysql_insert_all_or_none() {
+        let Some(store) = test_store("dw_test_all_or_none").await else {
+            return;
+        };
+        let table = TableSchema::of::<CounterUnit>();
+        let rows_per_stmt = MAX_PREPARED_PLACEHOLDERS / table.columns.len();
+        // the first statement inserts fine, the second one fails.
+        let mut rows = vec![counter_row(100, "a"); rows_per_stmt + 1];
+        rows[rows_per_stmt] = vec![SqlValue::Int(1)];
+        assert!(store.insert_batch("dw_test_all_or_none", &table, &rows).await.is_err());
+        assert!(store
+            .query("dw_test_all_or_none", &table, 0, 1000)
+            .await
+            .unwrap()
+            .is_empty());
+
+        rows[rows_per_stmt] = counter_row(200, "b");
+        store.insert_batch("dw_test_all_or_none", &table, &rows).await.unwrap();
+        let inserted = store.query("dw_test_all_or_none", &table, 0, 1000).await.unwrap();
+        assert_eq!(inserted.len(), rows_per_stmt + 1);
+    }
+
+    #[test]
+    fn test_mysql_insert_all_or_none() {
+        tokio_test::block_on(do_test_mysql_insert_all_or_none());
+    }
+
+    async fn do_test_mysql_round_trip() {
+        let Some(store) = test_store("dw_test_round_trip").await else {
+            return;
+        };
+        let table = TableSchema::of::<CounterUnit>();
+        // tables are utf8 (3 bytes), so no 4 byte characters.
+        let strings = [
+            r#"a"b\c'); DROP TABLE metrics_counter; -- "#,
+            r#"\'\"\\%_"#,
+            "测试ü標\n\t",
+        ];
+        let rows = strings
+            .iter()
+            .enumerate()
+            .map(|(i, s)| {
+                let mut row = counter_row(100 + i as u64, s);
+                row[2] = SqlValue::Text(s.chars().take(30).collect());
+                row
+            })
+            .collect::<Vec<_>>();
+        store.insert_batch("dw_test_round_trip", &table, &rows).await.unwrap();
+
+        let queried = store.query("dw_test_round_trip", &table, 0, 1000).await.unwrap();
+        // integer columns are queried as Int.
+        let expected = rows
+            .into_iter()
+            .map(|row| {
+                row.into_iter()
+                    .map(|value| match value {
+                        SqlValue::UInt(v) => SqlValue::Int(v as i64),
+                        value => value,
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(queried, expected);
+    }
+
+    #[test]
+    fn test_mysql_round_trip() {
+        tokio_test::block_on(do_test_mysql_round_trip());
+    }
+
+    #[test]
+    fn test_mysql_value() {
+        assert_eq!(
+            to_mysql_value(&SqlValue::Text("tag".into())),
+            Value::Bytes(b"tag".to_vec())
+        );
+        assert_eq!(
+            from_mysql_value(Value::Bytes(b"42".to_vec()), SqlType::BigInt).unwrap(),
+            SqlValue::Int(42)
+        );
+        assert_eq!(
+            from_mysql_value(Value::NULL, SqlType::Varchar(40)).unwrap(),
+            SqlValue::Text(String::new())
+        );
+        assert_eq!(
+            from_mysql_value(Value::Double(1.5), SqlType::Double).unwrap(),
+            SqlValue::Double(1.5)
+        );
+        assert!(from_mysql_value(Value::Bytes(b"x".to_vec()), SqlType::Int).is_err());
+    }
+}
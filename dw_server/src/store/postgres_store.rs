@@ -0,0 +1,316 @@
+use std::collections::HashSet;
+use std::error::Error;
+
+use async_trait::async_trait;
+use tokio::sync::{Mutex, MutexGuard};
+use tokio_postgres::error::SqlState;
+use tokio_postgres::types::ToSql;
+use tokio_postgres::{Client, NoTls, Row};
+
+use metrics_types::sql::{SqlType, SqlValue, TableSchema};
+
+use super::{create_table_statements, metrics_tables, quote_identifier, MetricsStore};
+use crate::error::{ServerError, StoreError};
+use crate::retention::Removed;
+
+/// postgres limits a statement to 65535 parameters.
+const MAX_STATEMENT_PARAMS: usize = 65535;
+/// timescaledb chunk of a metrics table, in `send_timestamp` seconds.
+const HYPERTABLE_CHUNK_SECS: i32 = 86400;
+
+impl From<tokio_postgres::Error> for StoreError {
+    fn from(value: tokio_postgres::Error) -> Self {
+        let transient = value.is_closed()
+            || value.source().is_some_and(|e| e.is::<std::io::Error>())
+            || value.code().is_some_and(|code| {
+                [
+                    SqlState::T_R_SERIALIZATION_FAILURE,
+                    SqlState::T_R_DEADLOCK_DETECTED,
+                    SqlState::LOCK_NOT_AVAILABLE,
+                    SqlState::TOO_MANY_CONNECTIONS,
+                    SqlState::ADMIN_SHUTDOWN,
+                ]
+                .contains(code)
+            });
+        match transient {
+            true => StoreError::Transient(value.to_string()),
+            false => StoreError::Rejected(value.to_string()),
+        }
+    }
+}
+
+fn sql_type(sql_type: SqlType) -> String {
+    match sql_type {
+        SqlType::Int => "INTEGER".to_string(),
+        SqlType::BigInt => "BIGINT".to_string(),
+        SqlType::Double => "DOUBLE PRECISION".to_string(),
+        SqlType::Varchar(n) => format!("VARCHAR({})", n),
+    }
+}
+
+/// `value` as the rust type postgres takes for a `sql_type` column, it doesn't convert integers.
+fn to_postgres_param(value: &SqlValue, sql_type: SqlType) -> Result<Box<dyn ToSql + Send + Sync>, StoreError> {
+    let out_of_range = || StoreError::Rejected(format!("{:?} out of range of {:?}", value, sql_type));
+    Ok(match (sql_type, value) {
+        (SqlType::Int, SqlValue::Int(v)) => Box::new(i32::try_from(*v).map_err(|_| out_of_range())?),
+        (SqlType::Int, SqlValue::UInt(v)) => Box::new(i32::try_from(*v).map_err(|_| out_of_range())?),
+        (SqlType::BigInt, SqlValue::Int(v)) => Box::new(*v),
+        (SqlType::BigInt, SqlValue::UInt(v)) => Box::new(i64::try_from(*v).map_err(|_| out_of_range())?),
+        (SqlType::Double, SqlValue::Int(v)) => Box::new(*v as f64),
+        (SqlType::Double, SqlValue::UInt(v)) => Box::new(*v as f64),
+        (SqlType::Double, SqlValue::Double(v)) => Box::new(*v),
+        (SqlType::Varchar(_), SqlValue::Text(v)) => Box::new(v.clone()),
+        _ => return Err(StoreError::Rejected(format!("{:?} is not {:?}", value, sql_type))),
+    })
+}
+
+fn from_postgres_row(row: &Row, table: &TableSchema) -> Result<Vec<SqlValue>, StoreError> {
+    table
+        .types
+        .iter()
+        .enumerate()
+        .map(|(i, sql_type)| {
+            Ok(match sql_type {
+                SqlType::Int => SqlValue::Int(row.try_get::<_, i32>(i)? as i64),
+                SqlType::BigInt => SqlValue::Int(row.try_get(i)?),
+                SqlType::Double => SqlValue::Double(row.try_get(i)?),
+                SqlType::Varchar(_) => SqlValue::Text(row.try_get(i)?),
+            })
+        })
+        .collect()
+}
+
+/// `"schema"."table"`, env databases being postgres schemas.
+fn qualified(db_name: &str, table: &str) -> String {
+    format!("{}.{}", quote_identifier(db_name), quote_identifier(table))
+}
+
+/// `INSERT INTO {qualified} ( {columns} ) VALUES ($1, ...), ...` with `rows` rows of placeholders.
+fn multi_insert_statement(qualified: &str, table: &TableSchema, rows: usize) -> String {
+    let width = table.columns.len();
+    let values = (0..rows)
+        .map(|r| {
+            let row = (1..=width).map(|c| format!("${}", r * width + c)).collect::<Vec<_>>();
+            format!("({})", row.join(", "))
+        })
+        .collect::<Vec<_>>();
+    format!(
+        "INSERT INTO {} ( {} ) VALUES {}",
+        qualified,
+        table.columns.join(", "),
+        values.join(", ")
+    )
+}
+
+/// #### PostgresStore
+///
+/// Each env database is a schema of the postgres database `postgres_url` connects to.
+/// Statements of all envs go through one connection, reconnected once lost.
+///
+/// With `timescale`, metrics tables are made hypertables chunked by day on `send_timestamp`,
+/// and expired chunks are dropped as a whole.
+pub struct PostgresStore {
+    url: String,
+    timescale: bool,
+    client: Mutex<Option<Client>>,
+    /// schemas known up to date, not checked again.
+    opened: Mutex<HashSet<String>>,
+}
+
+impl PostgresStore {
+    pub async fn new(url: &str, timescale: bool) -> Result<Self, ServerError> {
+        let store = PostgresStore {
+            url: url.to_string(),
+            timescale,
+            client: Mutex::new(None),
+            opened: Mutex::new(HashSet::new()),
+        };
+        // fail at start on a wrong url, rather than on the first rows.
+        drop(store.client().await?);
+        Ok(store)
+    }
+
+    async fn client(&self) -> Result<MutexGuard<'_, Option<Client>>, StoreError> {
+        let mut client = self.client.lock().await;
+        if client.as_ref().map_or(true, |c| c.is_closed()) {
+            let (new_client, connection) = tokio_postgres::connect(&self.url, NoTls).await?;
+            tokio::spawn(async move {
+                if let Err(e) = connection.await {
+                    println!("postgres connection error: {}", e);
+                }
+            });
+            *client = Some(new_client);
+        }
+        Ok(client)
+    }
+}
+
+#[async_trait]
+impl MetricsStore for PostgresStore {
+    async fn ensure_schema(&self, db_name: &str, auto_create: bool, _auto_migrate: bool) -> Result<bool, StoreError> {
+        let mut opened = self.opened.lock().await;
+        if opened.contains(db_name) {
+            return Ok(true);
+        }
+        let mut guard = self.client().await?;
+        let client = guard.as_mut().expect("connected");
+        let exist = client
+            .query_opt(
+                "SELECT schema_name FROM information_schema.schemata WHERE schema_name = $1",
+                &[&db_name],
+            )
+            .await?
+            .is_some();
+        if !exist && !auto_create {
+            println!("SCHEMA {} not exist and not allowed to create", db_name);
+            return Ok(false);
+        }
+
+        let tx = client.transaction().await?;
+        tx.batch_execute(&format!("CREATE SCHEMA IF NOT EXISTS {}", quote_identifier(db_name)))
+            .await?;
+        for (_, table) in metrics_tables() {
+            let qualified = qualified(db_name, table.name);
+            for statement in create_table_statements(&qualified, &table, sql_type) {
+                tx.batch_execute(&statement).await?;
+            }
+            if self.timescale {
+                tx.execute(
+                    "SELECT create_hypertable($1::text::regclass, 'send_timestamp', \
+                     chunk_time_interval => $2::integer, if_not_exists => TRUE, migrate_data => TRUE)",
+                    &[&qualified, &HYPERTABLE_CHUNK_SECS],
+                )
+                .await?;
+            }
+        }
+        tx.commit().await?;
+        if !exist {
+            println!("CREATE SCHEMA {}", db_name);
+        }
+        opened.insert(db_name.to_string());
+        Ok(true)
+    }
+
+    async fn insert_batch(&self, db_name: &str, table: &TableSchema, rows: &[Vec<SqlValue>]) -> Result<(), StoreError> {
+        if rows.is_empty() {
+            return Ok(());
+        }
+        let mut params = Vec::with_capacity(rows.len() * table.columns.len());
+        for row in rows {
+            for (value, sql_type) in row.iter().zip(table.types) {
+                params.push(to_postgres_param(value, *sql_type)?);
+            }
+        }
+
+        let qualified = qualified(db_name, table.name);
+        let mut guard = self.client().await?;
+        let tx = guard.as_mut().expect("connected").transaction().await?;
+        let rows_per_stmt = (MAX_STATEMENT_PARAMS / table.columns.len()).max(1);
+        for chunk in params.chunks(rows_per_stmt * table.columns.len()) {
+            let chunk = chunk
+                .iter()
+                .map(|p| p.as_ref() as &(dyn ToSql + Sync))
+                .collect::<Vec<_>>();
+            tx.execute(
+                &multi_insert_statement(&qualified, table, chunk.len() / table.columns.len()),
+                &chunk,
+            )
+            .await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn query(
+        &self,
+        db_name: &str,
+        table: &TableSchema,
+        since: i64,
+        until: i64,
+    ) -> Result<Vec<Vec<SqlValue>>, StoreError> {
+        let guard = self.client().await?;
+        let rows = guard
+            .as_ref()
+            .expect("connected")
+            .query(
+                &format!(
+                    "SELECT {} FROM {} WHERE send_timestamp >= $1::bigint AND send_timestamp < $2::bigint ORDER BY send_timestamp",
+                    table.columns.join(", "),
+                    qualified(db_name, table.name)
+                ),
+                &[&since, &until],
+            )
+            .await?;
+        rows.iter().map(|row| from_postgres_row(row, table)).collect()
+    }
+
+    async fn databases(&self) -> Result<Vec<String>, StoreError> {
+        let guard = self.client().await?;
+        let rows = guard
+            .as_ref()
+            .expect("connected")
+            .query(
+                "SELECT DISTINCT table_schema FROM information_schema.tables WHERE table_name = $1",
+                &[&metrics_tables()[0].1.name],
+            )
+            .await?;
+        Ok(rows.iter().map(|row| row.get(0)).collect())
+    }
+
+    async fn expire(&self, db_name: &str, table: &TableSchema, before: i64) -> Result<Removed, StoreError> {
+        let qualified = qualified(db_name, table.name);
+        let guard = self.client().await?;
+        let client = guard.as_ref().expect("connected");
+        let delete = format!("DELETE FROM {} WHERE send_timestamp < $1::bigint", qualified);
+        let mut removed = Removed::default();
+        if self.timescale {
+            let rows: i64 = client
+                .query_one(
+                    &format!("SELECT COUNT(*) FROM {} WHERE send_timestamp < $1::bigint", qualified),
+                    &[&before],
+                )
+                .await?
+                .get(0);
+            // chunks holding only expired rows.
+            let older_than = before.clamp(i32::MIN as i64, i32::MAX as i64) as i32;
+            removed.partitions = client
+                .query(
+                    "SELECT drop_chunks($1::text::regclass, older_than => $2::integer)",
+                    &[&qualified, &older_than],
+                )
+                .await?
+                .len();
+            client.execute(&delete, &[&before]).await?;
+            removed.rows = rows as u64;
+        } else {
+            removed.rows = client.execute(&delete, &[&before]).await?;
+        }
+        Ok(removed)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use metrics_types::CounterUnit;
+
+    #[test]
+    fn test_postgres_statements() {
+        let table = TableSchema::of::<CounterUnit>();
+        assert_eq!(qualified("dw_test", table.name), "\"dw_test\".\"metrics_counter\"");
+        assert_eq!(
+            multi_insert_statement(&qualified("dw_test", table.name), &table, 2),
+            "INSERT INTO \"dw_test\".\"metrics_counter\" ( send_timestamp, public_ip, category, tag, count, value ) \
+             VALUES ($1, $2, $3, $4, $5, $6), ($7, $8, $9, $10, $11, $12)"
+        );
+    }
+
+    #[test]
+    fn test_postgres_params() {
+        assert!(to_postgres_param(&SqlValue::UInt(1700000000), SqlType::Int).is_ok());
+        assert!(to_postgres_param(&SqlValue::UInt(u64::MAX), SqlType::BigInt).is_err());
+        assert!(to_postgres_param(&SqlValue::Int(i64::MAX), SqlType::Int).is_err());
+        assert!(to_postgres_param(&SqlValue::Text("tag".into()), SqlType::BigInt).is_err());
+        assert!(to_postgres_param(&SqlValue::UInt(3), SqlType::Double).is_ok());
+    }
+}
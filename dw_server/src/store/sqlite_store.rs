@@ -0,0 +1,293 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex as StdMutex};
+
+use async_trait::async_trait;
+use rusqlite::types::Value;
+use rusqlite::{params_from_iter, Connection, ErrorCode};
+use tokio::sync::Mutex;
+
+use metrics_types::sql::{SqlType, SqlValue, TableSchema};
+
+use super::{create_table_statements, metrics_tables, quote_identifier, MetricsStore};
+use crate::error::{ServerError, StoreError};
+use crate::retention::Removed;
+
+const SQLITE_EXTENSION: &str = "sqlite";
+/// wait for a lock held by another process this long before failing.
+const BUSY_TIMEOUT_MS: u32 = 5000;
+
+impl From<rusqlite::Error> for StoreError {
+    fn from(value: rusqlite::Error) -> Self {
+        match value.sqlite_error_code() {
+            Some(ErrorCode::DatabaseBusy | ErrorCode::DatabaseLocked) => StoreError::Transient(value.to_string()),
+            _ => StoreError::Rejected(value.to_string()),
+        }
+    }
+}
+
+fn sql_type(sql_type: SqlType) -> String {
+    match sql_type {
+        SqlType::Int | SqlType::BigInt => "INTEGER".to_string(),
+        SqlType::Double => "REAL".to_string(),
+        SqlType::Varchar(_) => "TEXT".to_string(),
+    }
+}
+
+fn to_sqlite_value(value: &SqlValue) -> Result<Value, StoreError> {
+    Ok(match value {
+        SqlValue::Int(v) => Value::Integer(*v),
+        SqlValue::UInt(v) => Value::Integer(
+            i64::try_from(*v).map_err(|_| StoreError::Rejected(format!("{} out of range of INTEGER", v)))?,
+        ),
+        SqlValue::Double(v) => Value::Real(*v),
+        SqlValue::Text(v) => Value::Text(v.clone()),
+    })
+}
+
+/// Open database file `path` and create its metrics tables if not yet.
+fn open(path: &Path) -> rusqlite::Result<Connection> {
+    let conn = Connection::open(path)?;
+    conn.pragma_update(None, "journal_mode", "WAL")?;
+    conn.pragma_update(None, "busy_timeout", BUSY_TIMEOUT_MS)?;
+    for (_, table) in metrics_tables() {
+        for statement in create_table_statements(&quote_identifier(table.name), &table, sql_type) {
+            conn.execute(&statement, [])?;
+        }
+    }
+    Ok(conn)
+}
+
+/// #### SqliteStore
+///
+/// Each env database is a file `<db_name>.sqlite` in directory `path`, nothing else to run.
+/// Statements run in blocking threads, one at a time for each database.
+pub struct SqliteStore {
+    path: PathBuf,
+    opened: Mutex<HashMap<String, Arc<StdMutex<Connection>>>>,
+}
+
+impl SqliteStore {
+    pub async fn new(path: &Path) -> Result<Self, ServerError> {
+        tokio::fs::create_dir_all(path).await?;
+        Ok(SqliteStore {
+            path: path.to_path_buf(),
+            opened: Mutex::new(HashMap::new()),
+        })
+    }
+
+    fn file_of(&self, db_name: &str) -> PathBuf {
+        self.path.join(format!("{}.{}", db_name, SQLITE_EXTENSION))
+    }
+
+    /// Connection of `db_name`, opened if the file exists or `create`, `None` if not.
+    async fn conn(&self, db_name: &str, create: bool) -> Result<Option<Arc<StdMutex<Connection>>>, StoreError> {
+        let mut opened = self.opened.lock().await;
+        if let Some(conn) = opened.get(db_name) {
+            return Ok(Some(conn.clone()));
+        }
+        let file = self.file_of(db_name);
+        if !create && !file.exists() {
+            return Ok(None);
+        }
+        let conn = tokio::task::spawn_blocking(move || open(&file))
+            .await
+            .map_err(|e| StoreError::Rejected(e.to_string()))??;
+        let conn = Arc::new(StdMutex::new(conn));
+        opened.insert(db_name.to_string(), conn.clone());
+        Ok(Some(conn))
+    }
+
+    /// Run `f` on existing database `db_name` in a blocking thread.
+    async fn with_conn<F, R>(&self, db_name: &str, f: F) -> Result<R, StoreError>
+    where
+        F: FnOnce(&mut Connection) -> rusqlite::Result<R> + Send + 'static,
+        R: Send + 'static,
+    {
+        let conn = self
+            .conn(db_name, false)
+            .await?
+            .ok_or_else(|| StoreError::Rejected(format!("database {} not exist", db_name)))?;
+        tokio::task::spawn_blocking(move || {
+            let mut conn = conn.lock().unwrap_or_else(|e| e.into_inner());
+            f(&mut conn)
+        })
+        .await
+        .map_err(|e| StoreError::Rejected(e.to_string()))?
+        .map_err(StoreError::from)
+    }
+}
+
+#[async_trait]
+impl MetricsStore for SqliteStore {
+    async fn ensure_schema(&self, db_name: &str, auto_create: bool, _auto_migrate: bool) -> Result<bool, StoreError> {
+        let exist = self.file_of(db_name).exists();
+        if !exist && !auto_create {
+            println!("DATABASE {} not exist and not allowed to create", db_name);
+            return Ok(false);
+        }
+        self.conn(db_name, auto_create).await?;
+        if !exist {
+            println!("CREATE DATABASE {}", db_name);
+        }
+        Ok(true)
+    }
+
+    async fn insert_batch(&self, db_name: &str, table: &TableSchema, rows: &[Vec<SqlValue>]) -> Result<(), StoreError> {
+        if rows.is_empty() {
+            return Ok(());
+        }
+        let rows = rows
+            .iter()
+            .map(|row| row.iter().map(to_sqlite_value).collect::<Result<Vec<_>, _>>())
+            .collect::<Result<Vec<_>, _>>()?;
+        let insert = format!(
+            "INSERT INTO {} ( {} ) VALUES ({})",
+            quote_identifier(table.name),
+            table.columns.join(", "),
+            vec!["?"; table.columns.len()].join(", ")
+        );
+        self.with_conn(db_name, move |conn| {
+            let tx = conn.transaction()?;
+            {
+                let mut stmt = tx.prepare_cached(&insert)?;
+                for row in rows {
+                    stmt.execute(params_from_iter(row))?;
+                }
+            }
+            tx.commit()
+        })
+        .await
+    }
+
+    async fn query(
+        &self,
+        db_name: &str,
+        table: &TableSchema,
+        since: i64,
+        until: i64,
+    ) -> Result<Vec<Vec<SqlValue>>, StoreError> {
+        let select = format!(
+            "SELECT {} FROM {} WHERE send_timestamp >= ?1 AND send_timestamp < ?2 ORDER BY send_timestamp",
+            table.columns.join(", "),
+            quote_identifier(table.name)
+        );
+        let types = table.types;
+        self.with_conn(db_name, move |conn| {
+            let mut stmt = conn.prepare(&select)?;
+            let rows = stmt.query_map((since, until), |row| {
+                types
+                    .iter()
+                    .enumerate()
+                    .map(|(i, sql_type)| {
+                        Ok(match sql_type {
+                            SqlType::Int | SqlType::BigInt => SqlValue::Int(row.get(i)?),
+                            SqlType::Double => SqlValue::Double(row.get(i)?),
+                            SqlType::Varchar(_) => SqlValue::Text(row.get(i)?),
+                        })
+                    })
+                    .collect::<rusqlite::Result<Vec<_>>>()
+            })?;
+            rows.collect()
+        })
+        .await
+    }
+
+    async fn databases(&self) -> Result<Vec<String>, StoreError> {
+        let mut databases = Vec::new();
+        let mut dir = tokio::fs::read_dir(&self.path)
+            .await
+            .map_err(|e| StoreError::Rejected(e.to_string()))?;
+        while let Some(entry) = dir
+            .next_entry()
+            .await
+            .map_err(|e| StoreError::Rejected(e.to_string()))?
+        {
+            let path = entry.path();
+            if path.extension().is_some_and(|ext| ext == SQLITE_EXTENSION) {
+                if let Some(db_name) = path.file_stem().and_then(|s| s.to_str()) {
+                    databases.push(db_name.to_string());
+                }
+            }
+        }
+        databases.sort();
+        Ok(databases)
+    }
+
+    async fn expire(&self, db_name: &str, table: &TableSchema, before: i64) -> Result<Removed, StoreError> {
+        let delete = format!("DELETE FROM {} WHERE send_timestamp < ?1", quote_identifier(table.name));
+        let rows = self
+            .with_conn(db_name, move |conn| conn.execute(&delete, (before,)))
+            .await?;
+        Ok(Removed {
+            partitions: 0,
+            rows: rows as u64,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use metrics_types::CounterUnit;
+
+    fn counter_row(send_timestamp: u64, tag: &str) -> Vec<SqlValue> {
+        vec![
+            SqlValue::UInt(send_timestamp),
+            SqlValue::Text("127.0.0.1".into()),
+            SqlValue::Text("category".into()),
+            SqlValue::Text(tag.into()),
+            SqlValue::UInt(1),
+            SqlValue::Int(-1),
+        ]
+    }
+
+    #[test]
+    fn test_sqlite_store() {
+        tokio_test::block_on(async {
+            let dir = tempfile::tempdir().unwrap();
+            let store = SqliteStore::new(dir.path()).await.unwrap();
+            let table = TableSchema::of::<CounterUnit>();
+
+            assert!(!store.ensure_schema("dw_test", false, true).await.unwrap());
+            assert!(store
+                .insert_batch("dw_test", &table, &[counter_row(100, "a")])
+                .await
+                .is_err());
+            assert!(store.ensure_schema("dw_test", true, true).await.unwrap());
+            assert_eq!(store.databases().await.unwrap(), vec!["dw_test"]);
+
+            let hostile_tag = r#"a"b'); DROP TABLE metrics_counter; -- "#;
+            store
+                .insert_batch(
+                    "dw_test",
+                    &table,
+                    &[counter_row(200, "b"), counter_row(100, hostile_tag)],
+                )
+                .await
+                .unwrap();
+            let rows = store.query("dw_test", &table, 0, 300).await.unwrap();
+            assert_eq!(rows.len(), 2);
+            assert_eq!(rows[0][0], SqlValue::Int(100));
+            assert_eq!(rows[0][3], SqlValue::Text(hostile_tag.into()));
+            assert_eq!(rows[1][5], SqlValue::Int(-1));
+            assert_eq!(store.query("dw_test", &table, 150, 300).await.unwrap().len(), 1);
+
+            // all or none.
+            let err = store
+                .insert_batch("dw_test", &table, &[counter_row(300, "c"), vec![SqlValue::Int(1)]])
+                .await
+                .unwrap_err();
+            assert!(!err.is_transient());
+            assert_eq!(store.query("dw_test", &table, 0, 400).await.unwrap().len(), 2);
+
+            assert_eq!(store.expire("dw_test", &table, 150).await.unwrap().rows, 1);
+            assert_eq!(store.query("dw_test", &table, 0, 400).await.unwrap().len(), 1);
+
+            // opened again from the file.
+            let store = SqliteStore::new(dir.path()).await.unwrap();
+            assert!(store.ensure_schema("dw_test", false, true).await.unwrap());
+            assert_eq!(store.query("dw_test", &table, 0, 400).await.unwrap().len(), 1);
+        });
+    }
+}
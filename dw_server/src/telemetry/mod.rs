@@ -0,0 +1,162 @@
+use std::fmt::{Display, Write};
+
+use hyper::{Body, Response, StatusCode};
+
+/// content type of the prometheus text format.
+pub const METRICS_CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+/// upper bounds of latency buckets, in seconds.
+pub const LATENCY_BUCKETS: [f64; 12] = [0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0];
+
+/// Prometheus histogram, observations counted in the first bucket whose bound they don't exceed.
+#[derive(Debug, Clone)]
+pub struct Histogram {
+    bounds: &'static [f64],
+    /// one more than `bounds`, the last one being `+Inf`.
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    pub fn new(bounds: &'static [f64]) -> Self {
+        Histogram {
+            bounds,
+            counts: vec![0; bounds.len() + 1],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    pub fn observe(&mut self, value: f64) {
+        let bucket = self
+            .bounds
+            .iter()
+            .position(|b| value <= *b)
+            .unwrap_or(self.bounds.len());
+        self.counts[bucket] += 1;
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Histogram::new(&LATENCY_BUCKETS)
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+fn write_labels(out: &mut String, labels: &[(&str, &str)]) {
+    if labels.is_empty() {
+        return;
+    }
+    let labels = labels
+        .iter()
+        .map(|(name, value)| format!("{}=\"{}\"", name, escape_label(value)))
+        .collect::<Vec<_>>();
+    let _ = write!(out, "{{{}}}", labels.join(","));
+}
+
+/// #### Exposition
+///
+/// Prometheus text format being written, each family declared by `family` before its samples.
+#[derive(Debug, Default)]
+pub struct Exposition {
+    out: String,
+}
+
+impl Exposition {
+    /// Declare family `name` of `kind`: counter, gauge or histogram.
+    pub fn family(&mut self, name: &str, kind: &str, help: &str) -> &mut Self {
+        let _ = writeln!(self.out, "# HELP {} {}", name, help);
+        let _ = writeln!(self.out, "# TYPE {} {}", name, kind);
+        self
+    }
+
+    pub fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: impl Display) -> &mut Self {
+        self.out.push_str(name);
+        write_labels(&mut self.out, labels);
+        let _ = writeln!(self.out, " {}", value);
+        self
+    }
+
+    /// `<name>_bucket` samples with cumulative counts, then `<name>_sum` and `<name>_count`.
+    pub fn histogram(&mut self, name: &str, labels: &[(&str, &str)], histogram: &Histogram) -> &mut Self {
+        let bucket = format!("{}_bucket", name);
+        let mut cumulative = 0;
+        for (i, count) in histogram.counts.iter().enumerate() {
+            cumulative += count;
+            let le = histogram.bounds.get(i).map_or("+Inf".to_string(), |b| b.to_string());
+            let mut labels = labels.to_vec();
+            labels.push(("le", &le));
+            self.sample(&bucket, &labels, cumulative);
+        }
+        self.sample(&format!("{}_sum", name), labels, histogram.sum);
+        self.sample(&format!("{}_count", name), labels, histogram.count)
+    }
+
+    pub fn finish(self) -> String {
+        self.out
+    }
+}
+
+/// `/metrics` response of `exposition`.
+pub fn metrics_response(exposition: Exposition) -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::OK)
+        .header("content-type", METRICS_CONTENT_TYPE)
+        .body(Body::from(exposition.finish()))
+        .unwrap()
+}
+
+/// Health check response, 200 if `healthy`, 503 otherwise, with a json `body`.
+pub fn health_response(healthy: bool, body: serde_json::Value) -> Response<Body> {
+    Response::builder()
+        .status(match healthy {
+            true => StatusCode::OK,
+            false => StatusCode::SERVICE_UNAVAILABLE,
+        })
+        .header("content-type", "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_exposition() {
+        let mut histogram = Histogram::new(&[0.5, 1.0]);
+        histogram.observe(0.25);
+        histogram.observe(0.5);
+        histogram.observe(3.0);
+
+        let mut exposition = Exposition::default();
+        exposition
+            .family("dw_rows_total", "counter", "rows")
+            .sample("dw_rows_total", &[("env", "a\"b\\c")], 3)
+            .sample("dw_rows_total", &[], 1);
+        exposition
+            .family("dw_latency_seconds", "histogram", "latency")
+            .histogram("dw_latency_seconds", &[("alarm_type", "timer")], &histogram);
+        assert_eq!(
+            exposition.finish(),
+            r#"# HELP dw_rows_total rows
+# TYPE dw_rows_total counter
+dw_rows_total{env="a\"b\\c"} 3
+dw_rows_total 1
+# HELP dw_latency_seconds latency
+# TYPE dw_latency_seconds histogram
+dw_latency_seconds_bucket{alarm_type="timer",le="0.5"} 2
+dw_latency_seconds_bucket{alarm_type="timer",le="1"} 2
+dw_latency_seconds_bucket{alarm_type="timer",le="+Inf"} 3
+dw_latency_seconds_sum{alarm_type="timer"} 3.75
+dw_latency_seconds_count{alarm_type="timer"} 3
+"#
+        );
+    }
+}
@@ -0,0 +1,28 @@
+use dw_server::config::{ServerConfig, StandaloneConfig};
+use dw_server::dead_letter::open_dead_letter;
+use dw_server::queue::open_queue;
+use dw_server::store::open_store;
+use dw_server::{consumer_service, proxy_service};
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let config = StandaloneConfig::load()?;
+    let addr = config.listen.socket_addr();
+    let queue = open_queue(&config.queue_config(), &config.redis, &config.stream).await?;
+    let dead_letter = open_dead_letter(&config.dead_letter, &config.redis).await?;
+    let store = open_store(&config.store, &config.mysql_url(), &config.mysql_pool).await?;
+
+    tokio::select! {
+        r = proxy_service::serve(addr, queue.clone(), &config.limit, &config.spill, &config.agents) => r?,
+        r = consumer_service::run(
+            store,
+            config.database.clone(),
+            config.batch.clone(),
+            config.retention.clone(),
+            config.status.clone(),
+            queue,
+            dead_letter,
+        ) => r?,
+    }
+    Ok(())
+}
@@ -0,0 +1,131 @@
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::MetricsAlarmType;
+
+const DEFAULT_FLUSH_INTERVAL_MS: u64 = 1000;
+const MIN_FLUSH_INTERVAL_MS: u64 = 100;
+
+/// Metrics a rule applies to, a field left out matches any.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsSelector {
+    pub alarm_type: Option<MetricsAlarmType>,
+    pub category: Option<String>,
+    pub tag: Option<String>,
+}
+
+impl MetricsSelector {
+    pub fn matches(&self, alarm_type: MetricsAlarmType, category: &str, tag: &str) -> bool {
+        self.alarm_type.map_or(true, |t| t == alarm_type)
+            && self.category.as_deref().map_or(true, |c| c == category)
+            && self.tag.as_deref().map_or(true, |t| t == tag)
+    }
+}
+
+/// Rename the category and/or tag of the metrics `select` matches.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RelabelRule {
+    pub select: MetricsSelector,
+    pub category: Option<String>,
+    pub tag: Option<String>,
+}
+
+/// #### AgentConfig
+///
+/// What the proxy tells the agents of an env at `/api/agent-config`, applied by `dw_client` at runtime.
+///
+/// ``` toml
+/// flush_interval_ms = 2000
+/// drop = [{ category = "debug" }, { alarm_type = "flow", tag = "noisy" }]
+/// relabel = [{ select = { category = "xcons" }, category = "consensus" }]
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AgentConfig {
+    /// longest a metrics line waits before it is sent, default 1000
+    pub flush_interval_ms: Option<u64>,
+    /// metrics not sent.
+    pub drop: Vec<MetricsSelector>,
+    /// applied in order, each to the result of the previous, after `drop`.
+    pub relabel: Vec<RelabelRule>,
+}
+
+impl AgentConfig {
+    pub fn flush_interval(&self) -> Duration {
+        Duration::from_millis(
+            self.flush_interval_ms
+                .unwrap_or(DEFAULT_FLUSH_INTERVAL_MS)
+                .max(MIN_FLUSH_INTERVAL_MS),
+        )
+    }
+
+    /// The `(category, tag)` to send a metrics as, `None` if it is dropped.
+    pub fn apply(&self, alarm_type: MetricsAlarmType, category: &str, tag: &str) -> Option<(String, String)> {
+        if self.drop.iter().any(|s| s.matches(alarm_type, category, tag)) {
+            return None;
+        }
+        let (mut category, mut tag) = (category.to_string(), tag.to_string());
+        for rule in &self.relabel {
+            if rule.select.matches(alarm_type, &category, &tag) {
+                if let Some(c) = &rule.category {
+                    category = c.clone();
+                }
+                if let Some(t) = &rule.tag {
+                    tag = t.clone();
+                }
+            }
+        }
+        Some((category, tag))
+    }
+}
+
+/// `/api/agent-config` response body.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VersionedAgentConfig {
+    pub env: String,
+    /// also the `ETag`, changes with `config`.
+    pub version: String,
+    pub config: AgentConfig,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_agent_config() {
+        let config = serde_json::from_str::<AgentConfig>(
+            r#"{"drop": [{"category": "debug"}, {"alarm_type": "flow", "tag": "noisy"}],
+                "relabel": [{"select": {"category": "xcons"}, "category": "consensus"},
+                    {"select": {"category": "consensus", "tag": "dispatch"}, "tag": "net_dispatch"}]}"#,
+        )
+        .unwrap();
+        assert_eq!(config.flush_interval(), Duration::from_secs(1));
+        assert_eq!(config.apply(MetricsAlarmType::Counter, "debug", "x"), None);
+        assert_eq!(config.apply(MetricsAlarmType::Flow, "vhost", "noisy"), None);
+        let kept = |category: &str, tag: &str| Some((category.to_string(), tag.to_string()));
+        assert_eq!(
+            config.apply(MetricsAlarmType::Timer, "vhost", "noisy"),
+            kept("vhost", "noisy")
+        );
+        assert_eq!(
+            config.apply(MetricsAlarmType::Timer, "xcons", "other"),
+            kept("consensus", "other")
+        );
+        assert_eq!(
+            config.apply(MetricsAlarmType::Timer, "xcons", "dispatch"),
+            kept("consensus", "net_dispatch")
+        );
+
+        let config = AgentConfig {
+            flush_interval_ms: Some(10),
+            ..Default::default()
+        };
+        assert_eq!(config.flush_interval(), Duration::from_millis(100));
+        assert!(serde_json::from_str::<AgentConfig>(r#"{"flush_interval": 10}"#).is_err());
+        assert!(serde_json::from_str::<AgentConfig>(r#"{"drop": [{"alarm_type": "gauge"}]}"#).is_err());
+    }
+}
@@ -4,7 +4,7 @@ use serde::{Deserialize, Serialize};
 
 use crate::TypeError;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum MetricsAlarmType {
     Invalid,
     Counter,
@@ -44,13 +44,13 @@ impl FromStr for MetricsAlarmType {
     }
 }
 
-impl ToString for MetricsAlarmType {
-    fn to_string(&self) -> String {
+impl std::fmt::Display for MetricsAlarmType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            MetricsAlarmType::Counter => String::from("counter"),
-            MetricsAlarmType::Timer => String::from("timer"),
-            MetricsAlarmType::Flow => String::from("flow"),
-            MetricsAlarmType::Invalid => String::from("invalid"),
+            MetricsAlarmType::Counter => write!(f, "counter"),
+            MetricsAlarmType::Timer => write!(f, "timer"),
+            MetricsAlarmType::Flow => write!(f, "flow"),
+            MetricsAlarmType::Invalid => write!(f, "invalid"),
         }
     }
 }
@@ -0,0 +1,48 @@
+use crate::TypeError;
+
+/// mysql database name max length is 64, keep some room for server side prefix/suffix.
+const ENV_NAME_MAX_LEN: usize = 52;
+
+/// Normalize `env` to a name safe to be used as (part of) a database name.
+///
+/// Non ascii name is rejected, other characters than `[0-9a-zA-Z]` are replaced by `_`,
+/// too long name keeps its head and tail.
+pub fn format_env_name(env: &str) -> Result<String, TypeError> {
+    if env.is_empty() {
+        return Err(TypeError::EnvNameInvalid("env_name is empty".into()));
+    }
+    if !env.is_ascii() {
+        return Err(TypeError::EnvNameInvalid("env_name contain non ascii character".into()));
+    }
+    let name = env
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect::<String>();
+
+    if name.len() > ENV_NAME_MAX_LEN {
+        let half = (ENV_NAME_MAX_LEN - 2) / 2;
+        return Ok(String::from(&name[0..half]) + "__" + &name[name.len() - half..]);
+    }
+    Ok(name)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_format_env_name() {
+        assert_eq!(format_env_name("test_db").unwrap(), "test_db");
+        assert_eq!(
+            format_env_name("a`b; DROP DATABASE x; --").unwrap(),
+            "a_b__DROP_DATABASE_x____"
+        );
+        assert_eq!(format_env_name(r#"x"y'z\"#).unwrap(), "x_y_z_");
+        assert!(format_env_name("").is_err());
+        assert!(format_env_name("测试").is_err());
+
+        let long = format_env_name(&("a".repeat(30) + &"b".repeat(30))).unwrap();
+        assert_eq!(long.len(), ENV_NAME_MAX_LEN);
+        assert!(long.starts_with("aaaa") && long.ends_with("bbbb") && long.contains("__"));
+    }
+}
@@ -40,9 +40,9 @@ impl<'de> Deserialize<'de> for IpAddress {
     }
 }
 
-impl ToString for IpAddress {
-    fn to_string(&self) -> String {
-        self.ip.clone() + ":" + &self.port.to_string()
+impl std::fmt::Display for IpAddress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.ip, self.port)
     }
 }
 
@@ -78,7 +78,7 @@ impl IpAddress {
         }
     }
 
-    pub async fn public_ip_default_port(server_ip_port: &String) -> Result<IpAddress, TypeError> {
+    pub async fn public_ip_default_port(server_ip_port: &str) -> Result<IpAddress, TypeError> {
         let req = Request::builder()
             .method("GET")
             .uri(String::from("http://") + server_ip_port + "/api/ip")
@@ -34,6 +34,11 @@ impl MetaInfos {
     pub fn alarm_api(&self) -> &str {
         &self.server_alarm_api
     }
+
+    /// `/api/agent-config` of this env.
+    pub fn agent_config_api(&self) -> String {
+        format!("http://{}/api/agent-config?env={}", self.server_ip_port, self.env_name)
+    }
 }
 
 impl std::fmt::Display for MetaInfos {
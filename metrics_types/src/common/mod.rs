@@ -1,9 +1,11 @@
 mod alarm_type;
+mod env_name;
 mod ipaddress;
 mod metainfos;
 mod timestamp;
 
 pub use alarm_type::MetricsAlarmType;
+pub use env_name::format_env_name;
 pub(crate) use ipaddress::IpAddress;
 pub use metainfos::MetaInfos;
 pub(crate) use timestamp::TimeStamp;
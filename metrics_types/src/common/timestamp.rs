@@ -33,9 +33,9 @@ impl<'de> Deserialize<'de> for TimeStamp {
     }
 }
 
-impl ToString for TimeStamp {
-    fn to_string(&self) -> String {
-        self.ts.to_string()
+impl std::fmt::Display for TimeStamp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.ts)
     }
 }
 
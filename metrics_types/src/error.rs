@@ -8,6 +8,9 @@ pub enum TypeError {
     #[error("metrics alarm type invalid")]
     MetricsAlarmTypeInvalid,
 
+    #[error("env name invalid: {0}")]
+    EnvNameInvalid(String),
+
     #[error("type error custom: {0}")]
     CustomError(String),
 }
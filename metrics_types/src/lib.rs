@@ -4,11 +4,12 @@ mod metrics_counter;
 mod metrics_flow;
 mod metrics_timer;
 
+pub mod agent_config;
 pub mod alarm_wrapper;
 pub mod sql;
 pub mod unit_jsonlog_handler;
 
-pub use common::{MetaInfos, MetricsAlarmType};
+pub use common::{format_env_name, MetaInfos, MetricsAlarmType};
 pub use error::TypeError;
 
 pub use metrics_counter::CounterUnit;
@@ -6,7 +6,7 @@ use crate::common::MetaInfos;
 use crate::unit_jsonlog_handler::UnitJsonLogHandler;
 
 use super::common::{IpAddress, TimeStamp};
-use super::sql::SqlTable;
+use super::sql::{rollup_table_opt, Migration, RollupColumn, RollupTable, SqlTable, SqlType, SqlValue};
 
 #[cfg(feature = "fake_data")]
 use fake::faker::lorem::en::Word;
@@ -45,11 +45,27 @@ impl CounterUnit {
     }
 }
 
+/// `metrics_counter` rollup table `$table`, rows of a bucket merged into one.
+macro_rules! counter_rollup_table_opt {
+    ($table:literal) => {
+        rollup_table_opt!(
+            $table,
+            r#"
+            count BIGINT(20) NOT NULL DEFAULT 0,
+            value BIGINT(20) NOT NULL DEFAULT 0,"#
+        )
+    };
+}
+
 impl SqlTable for CounterUnit {
     type TypeSelf = CounterUnit;
+    fn table_name() -> &'static str {
+        "metrics_counter"
+    }
+
     fn new_sql_table_opt() -> &'static str {
         r#"
-        CREATE TABLE metrics_counter(
+        CREATE TABLE IF NOT EXISTS metrics_counter(
             send_timestamp INT(10) DEFAULT 0,
             public_ip VARCHAR(40) DEFAULT "",
             category VARCHAR(30) DEFAULT "",
@@ -61,23 +77,81 @@ impl SqlTable for CounterUnit {
         "#
     }
 
-    fn multi_insert_table_opt() -> &'static str {
-        r#"
-        INSERT INTO metrics_counter ( send_timestamp, public_ip, category, tag, count, value )
-        VALUES
-        "#
+    fn migrations() -> Vec<Migration> {
+        vec![
+            Migration {
+                version: 1,
+                description: "create table",
+                sql: Self::new_sql_table_opt(),
+            },
+            Migration {
+                version: 2,
+                description: "index send_timestamp",
+                sql: "ALTER TABLE metrics_counter ADD INDEX idx_send_timestamp(send_timestamp);",
+            },
+            Migration {
+                version: 3,
+                description: "create 1m rollup table",
+                sql: counter_rollup_table_opt!("metrics_counter_1m"),
+            },
+            Migration {
+                version: 4,
+                description: "create 1h rollup table",
+                sql: counter_rollup_table_opt!("metrics_counter_1h"),
+            },
+            Migration {
+                version: 5,
+                description: "create 1d rollup table",
+                sql: counter_rollup_table_opt!("metrics_counter_1d"),
+            },
+        ]
     }
 
-    fn to_param_value_str(&self) -> String {
-        format!(
-            r#"({},"{}","{}","{}",{},{})"#,
-            self.send_timestamp.data(),
-            self.public_ip.to_string(),
-            self.category.clone(),
-            self.tag.clone(),
-            self.count,
-            self.value
-        )
+    fn columns() -> &'static [&'static str] {
+        &["send_timestamp", "public_ip", "category", "tag", "count", "value"]
+    }
+
+    fn column_types() -> &'static [SqlType] {
+        &[
+            SqlType::Int,
+            SqlType::Varchar(40),
+            SqlType::Varchar(30),
+            SqlType::Varchar(100),
+            SqlType::BigInt,
+            SqlType::BigInt,
+        ]
+    }
+
+    fn to_params(&self) -> Vec<SqlValue> {
+        vec![
+            SqlValue::UInt(self.send_timestamp.data() as u64),
+            SqlValue::Text(self.public_ip.to_string()),
+            SqlValue::Text(self.category.clone()),
+            SqlValue::Text(self.tag.clone()),
+            SqlValue::UInt(self.count),
+            SqlValue::Int(self.value),
+        ]
+    }
+}
+
+impl RollupTable for CounterUnit {
+    fn rollup_columns() -> &'static [RollupColumn] {
+        &[
+            RollupColumn {
+                name: "count",
+                from_raw: "SUM(count)",
+                from_rollup: "SUM(count)",
+            },
+            RollupColumn {
+                name: "value",
+                from_raw: "SUM(value)",
+                from_rollup: "SUM(value)",
+            },
+        ]
+    }
+
+    fn send_timestamp(&self) -> i64 {
+        self.send_timestamp.data() as i64
     }
 }
 
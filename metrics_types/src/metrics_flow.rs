@@ -6,7 +6,7 @@ use crate::common::MetaInfos;
 use crate::unit_jsonlog_handler::UnitJsonLogHandler;
 
 use super::common::{IpAddress, TimeStamp};
-use super::sql::SqlTable;
+use super::sql::{rollup_table_opt, Migration, RollupColumn, RollupTable, SqlTable, SqlType, SqlValue};
 
 #[cfg(feature = "fake_data")]
 use fake::faker::lorem::en::Word;
@@ -60,11 +60,32 @@ impl FlowUnit {
     }
 }
 
+/// `metrics_flow` rollup table `$table`, rows of a bucket merged into one.
+macro_rules! flow_rollup_table_opt {
+    ($table:literal) => {
+        rollup_table_opt!(
+            $table,
+            r#"
+            count BIGINT(20) NOT NULL DEFAULT 0,
+            max_flow BIGINT(20) NOT NULL DEFAULT 0,
+            min_flow BIGINT(20) NOT NULL DEFAULT 0,
+            sum_flow BIGINT(20) NOT NULL DEFAULT 0,
+            avg_flow DOUBLE AS (IF(count = 0, 0, sum_flow / count)),
+            tps_flow BIGINT(20) NOT NULL DEFAULT 0,
+            tps DOUBLE NOT NULL DEFAULT 0.00,"#
+        )
+    };
+}
+
 impl SqlTable for FlowUnit {
     type TypeSelf = FlowUnit;
+    fn table_name() -> &'static str {
+        "metrics_flow"
+    }
+
     fn new_sql_table_opt() -> &'static str {
         r#"
-        CREATE TABLE metrics_flow(
+        CREATE TABLE IF NOT EXISTS metrics_flow(
             send_timestamp INT(10) DEFAULT 0,
             public_ip VARCHAR(40) DEFAULT "",
             category VARCHAR(30) DEFAULT "",
@@ -81,28 +102,123 @@ impl SqlTable for FlowUnit {
         "#
     }
 
-    fn multi_insert_table_opt() -> &'static str {
-        r#"
-        INSERT INTO metrics_flow ( send_timestamp, public_ip, category, tag, count, max_flow, min_flow, sum_flow, avg_flow, tps_flow, tps )
-        VALUES
-        "#
+    fn migrations() -> Vec<Migration> {
+        vec![
+            Migration {
+                version: 1,
+                description: "create table",
+                sql: Self::new_sql_table_opt(),
+            },
+            Migration {
+                version: 2,
+                description: "index send_timestamp",
+                sql: "ALTER TABLE metrics_flow ADD INDEX idx_send_timestamp(send_timestamp);",
+            },
+            Migration {
+                version: 3,
+                description: "create 1m rollup table",
+                sql: flow_rollup_table_opt!("metrics_flow_1m"),
+            },
+            Migration {
+                version: 4,
+                description: "create 1h rollup table",
+                sql: flow_rollup_table_opt!("metrics_flow_1h"),
+            },
+            Migration {
+                version: 5,
+                description: "create 1d rollup table",
+                sql: flow_rollup_table_opt!("metrics_flow_1d"),
+            },
+        ]
     }
 
-    fn to_param_value_str(&self) -> String {
-        format! {
-            r#"({},"{}","{}","{}",{},{},{},{},{},{},{})"#,
-            self.send_timestamp.data(),
-            self.public_ip.to_string(),
-            self.category.clone(),
-            self.tag.clone(),
-            self.count,
-            self.max_flow,
-            self.min_flow,
-            self.sum_flow,
-            self.avg_flow,
-            self.tps_flow,
-            self.tps,
-        }
+    fn columns() -> &'static [&'static str] {
+        &[
+            "send_timestamp",
+            "public_ip",
+            "category",
+            "tag",
+            "count",
+            "max_flow",
+            "min_flow",
+            "sum_flow",
+            "avg_flow",
+            "tps_flow",
+            "tps",
+        ]
+    }
+
+    fn column_types() -> &'static [SqlType] {
+        &[
+            SqlType::Int,
+            SqlType::Varchar(40),
+            SqlType::Varchar(30),
+            SqlType::Varchar(100),
+            SqlType::BigInt,
+            SqlType::BigInt,
+            SqlType::BigInt,
+            SqlType::BigInt,
+            SqlType::BigInt,
+            SqlType::BigInt,
+            SqlType::Double,
+        ]
+    }
+
+    fn to_params(&self) -> Vec<SqlValue> {
+        vec![
+            SqlValue::UInt(self.send_timestamp.data() as u64),
+            SqlValue::Text(self.public_ip.to_string()),
+            SqlValue::Text(self.category.clone()),
+            SqlValue::Text(self.tag.clone()),
+            SqlValue::UInt(self.count),
+            SqlValue::Int(self.max_flow),
+            SqlValue::Int(self.min_flow),
+            SqlValue::Int(self.sum_flow),
+            SqlValue::Int(self.avg_flow),
+            SqlValue::Int(self.tps_flow),
+            SqlValue::Double(self.tps),
+        ]
+    }
+}
+
+impl RollupTable for FlowUnit {
+    fn rollup_columns() -> &'static [RollupColumn] {
+        &[
+            RollupColumn {
+                name: "count",
+                from_raw: "SUM(count)",
+                from_rollup: "SUM(count)",
+            },
+            RollupColumn {
+                name: "max_flow",
+                from_raw: "MAX(max_flow)",
+                from_rollup: "MAX(max_flow)",
+            },
+            RollupColumn {
+                name: "min_flow",
+                from_raw: "MIN(min_flow)",
+                from_rollup: "MIN(min_flow)",
+            },
+            RollupColumn {
+                name: "sum_flow",
+                from_raw: "SUM(sum_flow)",
+                from_rollup: "SUM(sum_flow)",
+            },
+            RollupColumn {
+                name: "tps_flow",
+                from_raw: "MAX(tps_flow)",
+                from_rollup: "MAX(tps_flow)",
+            },
+            RollupColumn {
+                name: "tps",
+                from_raw: "MAX(tps)",
+                from_rollup: "MAX(tps)",
+            },
+        ]
+    }
+
+    fn send_timestamp(&self) -> i64 {
+        self.send_timestamp.data() as i64
     }
 }
 
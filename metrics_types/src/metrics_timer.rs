@@ -6,7 +6,7 @@ use crate::common::MetaInfos;
 use crate::unit_jsonlog_handler::UnitJsonLogHandler;
 
 use super::common::{IpAddress, TimeStamp};
-use super::sql::SqlTable;
+use super::sql::{rollup_table_opt, Migration, RollupColumn, RollupTable, SqlTable, SqlType, SqlValue};
 
 #[cfg(feature = "fake_data")]
 use fake::faker::lorem::en::Word;
@@ -51,11 +51,30 @@ impl TimerUnit {
     }
 }
 
+/// `metrics_timer` rollup table `$table`, rows of a bucket merged into one.
+macro_rules! timer_rollup_table_opt {
+    ($table:literal) => {
+        rollup_table_opt!(
+            $table,
+            r#"
+            count BIGINT(20) NOT NULL DEFAULT 0,
+            max_time BIGINT(20) NOT NULL DEFAULT 0,
+            min_time BIGINT(20) NOT NULL DEFAULT 0,
+            sum_time BIGINT(20) NOT NULL DEFAULT 0,
+            avg_time DOUBLE AS (IF(count = 0, 0, sum_time / count)),"#
+        )
+    };
+}
+
 impl SqlTable for TimerUnit {
     type TypeSelf = TimerUnit;
+    fn table_name() -> &'static str {
+        "metrics_timer"
+    }
+
     fn new_sql_table_opt() -> &'static str {
         r#"
-        CREATE TABLE metrics_timer(
+        CREATE TABLE IF NOT EXISTS metrics_timer(
             send_timestamp INT(10) DEFAULT 0,
             public_ip VARCHAR(40) DEFAULT "",
             category VARCHAR(30) DEFAULT "",
@@ -69,25 +88,104 @@ impl SqlTable for TimerUnit {
         "#
     }
 
-    fn multi_insert_table_opt() -> &'static str {
-        r#"
-        INSERT INTO metrics_timer ( send_timestamp, public_ip, category, tag, count, max_time, min_time, avg_time )
-        VALUES
-        "#
+    fn migrations() -> Vec<Migration> {
+        vec![
+            Migration {
+                version: 1,
+                description: "create table",
+                sql: Self::new_sql_table_opt(),
+            },
+            Migration {
+                version: 2,
+                description: "index send_timestamp",
+                sql: "ALTER TABLE metrics_timer ADD INDEX idx_send_timestamp(send_timestamp);",
+            },
+            Migration {
+                version: 3,
+                description: "create 1m rollup table",
+                sql: timer_rollup_table_opt!("metrics_timer_1m"),
+            },
+            Migration {
+                version: 4,
+                description: "create 1h rollup table",
+                sql: timer_rollup_table_opt!("metrics_timer_1h"),
+            },
+            Migration {
+                version: 5,
+                description: "create 1d rollup table",
+                sql: timer_rollup_table_opt!("metrics_timer_1d"),
+            },
+        ]
     }
 
-    fn to_param_value_str(&self) -> String {
-        format! {
-            r#"({},"{}","{}","{}",{},{},{},{})"#,
-            self.send_timestamp.data(),
-            self.public_ip.to_string(),
-            self.category.clone(),
-            self.tag.clone(),
-            self.count,
-            self.max_time,
-            self.min_time,
-            self.avg_time,
-        }
+    fn columns() -> &'static [&'static str] {
+        &[
+            "send_timestamp",
+            "public_ip",
+            "category",
+            "tag",
+            "count",
+            "max_time",
+            "min_time",
+            "avg_time",
+        ]
+    }
+
+    fn column_types() -> &'static [SqlType] {
+        &[
+            SqlType::Int,
+            SqlType::Varchar(40),
+            SqlType::Varchar(30),
+            SqlType::Varchar(100),
+            SqlType::BigInt,
+            SqlType::BigInt,
+            SqlType::BigInt,
+            SqlType::BigInt,
+        ]
+    }
+
+    fn to_params(&self) -> Vec<SqlValue> {
+        vec![
+            SqlValue::UInt(self.send_timestamp.data() as u64),
+            SqlValue::Text(self.public_ip.to_string()),
+            SqlValue::Text(self.category.clone()),
+            SqlValue::Text(self.tag.clone()),
+            SqlValue::UInt(self.count),
+            SqlValue::UInt(self.max_time),
+            SqlValue::UInt(self.min_time),
+            SqlValue::UInt(self.avg_time),
+        ]
+    }
+}
+
+impl RollupTable for TimerUnit {
+    fn rollup_columns() -> &'static [RollupColumn] {
+        &[
+            RollupColumn {
+                name: "count",
+                from_raw: "SUM(count)",
+                from_rollup: "SUM(count)",
+            },
+            RollupColumn {
+                name: "max_time",
+                from_raw: "MAX(max_time)",
+                from_rollup: "MAX(max_time)",
+            },
+            RollupColumn {
+                name: "min_time",
+                from_raw: "MIN(min_time)",
+                from_rollup: "MIN(min_time)",
+            },
+            RollupColumn {
+                name: "sum_time",
+                from_raw: "SUM(avg_time * count)",
+                from_rollup: "SUM(sum_time)",
+            },
+        ]
+    }
+
+    fn send_timestamp(&self) -> i64 {
+        self.send_timestamp.data() as i64
     }
 }
 
@@ -1,8 +1,169 @@
+mod rollup;
+
+pub(crate) use rollup::rollup_table_opt;
+pub use rollup::{rollup_statement, Resolution, RollupColumn, RollupTable};
+
+/// Typed column value, bound as a statement parameter, never formatted into sql text.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SqlValue {
+    Int(i64),
+    UInt(u64),
+    Double(f64),
+    Text(String),
+}
+
+/// Portable column type, each store maps it to its own sql type.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SqlType {
+    /// 32 bits, unix seconds.
+    Int,
+    BigInt,
+    Double,
+    /// text of at most this many chars.
+    Varchar(u32),
+}
+
+/// One schema change of a table, applied once to each database in `version` order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Migration {
+    /// starts from 1, increased by 1 each step.
+    pub version: u32,
+    pub description: &'static str,
+    pub sql: &'static str,
+}
+
 pub trait SqlTable {
     type TypeSelf;
+
+    fn table_name() -> &'static str;
+
+    /// the table as first released, it must not change: later changes are appended to `migrations`.
     fn new_sql_table_opt() -> &'static str;
 
-    fn multi_insert_table_opt() -> &'static str;
+    /// ordered schema changes, the first one creates the table.
+    fn migrations() -> Vec<Migration> {
+        vec![Migration {
+            version: 1,
+            description: "create table",
+            sql: Self::new_sql_table_opt(),
+        }]
+    }
+
+    /// column names, in the same order as `to_params`.
+    fn columns() -> &'static [&'static str];
+
+    /// column types, in the same order as `columns`.
+    fn column_types() -> &'static [SqlType];
+
+    fn to_params(&self) -> Vec<SqlValue>;
+}
+
+/// Name and columns of a `SqlTable`, for code not generic over the table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TableSchema {
+    pub name: &'static str,
+    pub columns: &'static [&'static str],
+    /// in the same order as `columns`.
+    pub types: &'static [SqlType],
+}
+
+impl TableSchema {
+    pub fn of<T: SqlTable>() -> Self {
+        TableSchema {
+            name: T::table_name(),
+            columns: T::columns(),
+            types: T::column_types(),
+        }
+    }
+}
+
+/// `INSERT INTO {table} ( {columns} ) VALUES (?, ...), ...` with `rows` rows of placeholders.
+pub fn multi_insert_statement(table: &TableSchema, rows: usize) -> String {
+    let row = format!("({})", vec!["?"; table.columns.len()].join(", "));
+    format!(
+        "INSERT INTO {} ( {} ) VALUES {}",
+        table.name,
+        table.columns.join(", "),
+        vec![row.as_str(); rows].join(", ")
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{CounterUnit, FlowUnit, TimerUnit};
+
+    #[test]
+    fn test_multi_insert_statement() {
+        assert_eq!(
+            multi_insert_statement(&TableSchema::of::<CounterUnit>(), 2),
+            "INSERT INTO metrics_counter ( send_timestamp, public_ip, category, tag, count, value ) VALUES (?, ?, ?, ?, ?, ?), (?, ?, ?, ?, ?, ?)"
+        );
+    }
+
+    #[test]
+    fn test_rollup_statement() {
+        assert_eq!(
+            rollup_statement::<CounterUnit>(Resolution::Minute),
+            "REPLACE INTO metrics_counter_1m ( bucket, public_ip, category, tag, count, value ) \
+             SELECT send_timestamp DIV 60 * 60, public_ip, category, tag, SUM(count), SUM(value) FROM metrics_counter \
+             WHERE send_timestamp >= ? AND send_timestamp < ? GROUP BY send_timestamp DIV 60 * 60, public_ip, category, tag"
+        );
+        let statement = rollup_statement::<TimerUnit>(Resolution::Day);
+        assert!(statement.starts_with("REPLACE INTO metrics_timer_1d"));
+        assert!(statement.contains("SUM(sum_time) FROM metrics_timer_1h WHERE bucket >= ?"));
+
+        assert_eq!(Resolution::Hour.bucket(7199), 3600);
+        assert_eq!(FlowUnit::rollup_table_name(Resolution::Day), "metrics_flow_1d");
+    }
+
+    fn check_migrations<T: RollupTable>() {
+        let migrations = T::migrations();
+        assert!(migrations[0].sql.contains("CREATE TABLE IF NOT EXISTS"));
+        for resolution in Resolution::ALL {
+            let table = T::rollup_table_name(resolution);
+            assert!(migrations.iter().any(|m| m.sql.contains(&format!("EXISTS {}(", table))));
+        }
+        for (i, m) in migrations.iter().enumerate() {
+            assert_eq!(m.version, i as u32 + 1);
+        }
+        assert_eq!(T::column_types().len(), T::columns().len());
+    }
+
+    #[test]
+    fn test_migrations() {
+        check_migrations::<CounterUnit>();
+        check_migrations::<TimerUnit>();
+        check_migrations::<FlowUnit>();
+    }
+
+    #[test]
+    fn test_hostile_params() {
+        let hostile_tag = r#"a"b\c'); DROP TABLE metrics_counter; -- "#;
+        let counter_unit_str = serde_json::json!({
+            "send_timestamp": "123456",
+            "public_ip": "123.12.34.21:1024",
+            "category": "some\"cat\\",
+            "tag": hostile_tag,
+            "count": 10,
+            "value": -100
+        })
+        .to_string();
+        let counter_unit = serde_json::from_str::<CounterUnit>(&counter_unit_str).unwrap();
 
-    fn to_param_value_str(&self) -> String;
+        let params = counter_unit.to_params();
+        assert_eq!(params.len(), CounterUnit::columns().len());
+        assert_eq!(
+            params,
+            vec![
+                SqlValue::UInt(123456),
+                SqlValue::Text(String::from("123.12.34.21:1024")),
+                SqlValue::Text(String::from("some\"cat\\")),
+                SqlValue::Text(String::from(hostile_tag)),
+                SqlValue::UInt(10),
+                SqlValue::Int(-100),
+            ]
+        );
+        assert!(!multi_insert_statement(&TableSchema::of::<CounterUnit>(), 1).contains("DROP"));
+    }
 }
@@ -0,0 +1,119 @@
+use super::SqlTable;
+
+/// Bucket size of a rollup table, each computed from the next finer one, the finest from raw rows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Resolution {
+    Minute,
+    Hour,
+    Day,
+}
+
+impl Resolution {
+    /// finest first, the order rollups must be computed in.
+    pub const ALL: [Resolution; 3] = [Resolution::Minute, Resolution::Hour, Resolution::Day];
+
+    pub fn secs(&self) -> i64 {
+        match self {
+            Resolution::Minute => 60,
+            Resolution::Hour => 3600,
+            Resolution::Day => 86400,
+        }
+    }
+
+    /// rollup this one is computed from, `None` for raw rows.
+    pub fn source(&self) -> Option<Resolution> {
+        match self {
+            Resolution::Minute => None,
+            Resolution::Hour => Some(Resolution::Minute),
+            Resolution::Day => Some(Resolution::Hour),
+        }
+    }
+
+    pub fn suffix(&self) -> &'static str {
+        match self {
+            Resolution::Minute => "1m",
+            Resolution::Hour => "1h",
+            Resolution::Day => "1d",
+        }
+    }
+
+    /// start of the bucket containing `ts`.
+    pub fn bucket(&self, ts: i64) -> i64 {
+        ts - ts.rem_euclid(self.secs())
+    }
+}
+
+/// A rollup table column, with its aggregate over raw rows and over rows of a finer rollup.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RollupColumn {
+    pub name: &'static str,
+    pub from_raw: &'static str,
+    pub from_rollup: &'static str,
+}
+
+/// A metrics table rolled up into `<table>_1m`, `<table>_1h` and `<table>_1d`,
+/// keyed by `bucket` start, `category`, `tag` and `public_ip`.
+pub trait RollupTable: SqlTable {
+    /// columns besides the key, generated columns excluded.
+    fn rollup_columns() -> &'static [RollupColumn];
+
+    fn send_timestamp(&self) -> i64;
+
+    fn rollup_table_name(resolution: Resolution) -> String {
+        format!("{}_{}", Self::table_name(), resolution.suffix())
+    }
+}
+
+/// `CREATE TABLE IF NOT EXISTS` of a rollup table, `$columns` being its non key columns.
+macro_rules! rollup_table_opt {
+    ($table:literal, $columns:literal) => {
+        concat!(
+            r#"
+        CREATE TABLE IF NOT EXISTS "#,
+            $table,
+            r#"(
+            bucket INT(10) NOT NULL,
+            public_ip VARCHAR(40) NOT NULL DEFAULT "",
+            category VARCHAR(30) NOT NULL DEFAULT "",
+            tag VARCHAR(100) NOT NULL DEFAULT "","#,
+            $columns,
+            r#"
+            PRIMARY KEY(category,tag,public_ip,bucket),
+            INDEX(bucket)
+        )ENGINE = InnoDB DEFAULT CHARSET = utf8;
+        "#
+        )
+    };
+}
+pub(crate) use rollup_table_opt;
+
+/// `REPLACE INTO {rollup} ... SELECT ... FROM {source} ... GROUP BY ...`, recomputing the `resolution`
+/// buckets starting in `[?, ?)` from their source. Rerunning it is harmless.
+pub fn rollup_statement<T: RollupTable>(resolution: Resolution) -> String {
+    let (source, time_column) = match resolution.source() {
+        Some(source) => (T::rollup_table_name(source), "bucket"),
+        None => (T::table_name().to_string(), "send_timestamp"),
+    };
+    let bucket = format!("{} DIV {} * {}", time_column, resolution.secs(), resolution.secs());
+    let columns = T::rollup_columns();
+    format!(
+        "REPLACE INTO {} ( bucket, public_ip, category, tag, {} ) \
+         SELECT {}, public_ip, category, tag, {} FROM {} \
+         WHERE {} >= ? AND {} < ? GROUP BY {}, public_ip, category, tag",
+        T::rollup_table_name(resolution),
+        columns.iter().map(|c| c.name).collect::<Vec<_>>().join(", "),
+        bucket,
+        columns
+            .iter()
+            .map(|c| match resolution.source() {
+                Some(_) => c.from_rollup,
+                None => c.from_raw,
+            })
+            .collect::<Vec<_>>()
+            .join(", "),
+        source,
+        time_column,
+        time_column,
+        bucket
+    )
+}